@@ -1,4 +1,11 @@
+use std::io::Write;
+use std::path::PathBuf;
+use regex::RegexBuilder;
 use structopt::StructOpt;
+use structopt::clap::Shell;
+use crate::configuration::OutputFormat;
+use crate::profiles::ConfigFormat;
+use crate::parsed_line::normalize_date_string;
 
 /// Represents command-line arguments.
 #[derive(StructOpt, Debug)]
@@ -26,34 +33,489 @@ pub struct Arguments {
     #[structopt(short = "m", long = "max-message-length")]
     pub max_message_length: Option<usize>,
 
+    /// The format of the output files, either "csv", "json", "ndjson" or "pretty". Defaults
+    /// to "csv". The JSON format is a single array of objects keyed by column name, the NDJSON
+    /// format writes one object per line with no enclosing array, which is better for
+    /// streaming. The pretty format is for reading at a terminal, see `--highlight`.
+    #[structopt(long = "output-format")]
+    pub output_format: Option<OutputFormat>,
+
+    /// Writes the lines to stdout like grep, with the values which matched the sysref,
+    /// where and match filters highlighted in color, instead of writing files. The same as
+    /// "--output-format pretty". Colors are only used when stdout is a terminal and the
+    /// NO_COLOR environment variable is not set.
+    #[structopt(long = "highlight")]
+    pub highlight: bool,
+
+    /// The delimiter to use between fields in CSV output. Must be a single ASCII character,
+    /// "\t" or "tab" may be used to specify a tab. Defaults to ",".
+    #[structopt(long = "delimiter", parse(try_from_str = "parse_delimiter"))]
+    pub delimiter: Option<u8>,
+
+    /// Shorthand for `--delimiter tab`, i.e. write tab-separated output.
+    #[structopt(long = "tsv")]
+    pub tsv: bool,
+
+    /// The file to write the successfully parsed lines to. Defaults to "consolidated.csv"
+    /// (or ".json"/".ndjson") in the current directory. Unless `--error-output` is given,
+    /// the errors are written alongside it, e.g. "out/run1.csv" has errors written to
+    /// "out/run1.errors.csv". Missing directories are created.
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    pub output: Option<PathBuf>,
+
+    /// The file to write the lines which could not be parsed to.
+    #[structopt(long = "error-output", parse(from_os_str))]
+    pub error_output: Option<PathBuf>,
+
+    /// If specified, a summary of the run is written to this file as JSON after processing:
+    /// the bytes and files processed, the ok and error line counts and the elapsed time.
+    /// With `--stats` it also has the line counts per log level and per application.
+    #[structopt(long = "summary-json", parse(from_os_str))]
+    pub summary_json: Option<PathBuf>,
+
+    /// A column to sort by instead of the LogDate, for example "CorrelationKey" to group
+    /// the lines for each request together. Lines with the same value are sorted by
+    /// LogDate, and lines without the column sort as if it were blank.
+    #[structopt(long = "sort-by")]
+    pub sort_by: Option<String>,
+
+    /// A column to sort by when lines have identical timestamps, for example an incrementing
+    /// "SourceInstance" or "EventId". Values are compared numerically if they are both numbers.
+    #[structopt(long = "then-by")]
+    pub then_by: Option<String>,
+
+    /// If true, lines are not sorted, which is faster. They are written in the order they
+    /// appear in each file, with lines which could not be parsed left in their original
+    /// positions. Useful when processing a single file which is already in order.
+    #[structopt(long = "no-sort")]
+    pub no_sort: bool,
+
+    /// If true, lines are sorted newest first. Lines which could not be parsed still
+    /// go at the front of the errors file, in the order they appear in the input.
+    #[structopt(long = "reverse", alias = "desc")]
+    pub reverse: bool,
+
+    /// The number of successfully parsed rows the output is expected to contain. If the
+    /// actual number differs, the program exits with a non-zero exit code. Useful as a
+    /// sanity check in pipelines, for example after changing a profile.
+    #[structopt(long = "expect-rows")]
+    pub expect_rows: Option<usize>,
+
+    /// How far the actual number of rows may be from `--expect-rows` and still pass.
+    #[structopt(long = "expect-rows-tolerance", default_value = "0")]
+    pub expect_rows_tolerance: usize,
+
+    /// The maximum number of rows to write, taken after sorting. Errors sort to the front,
+    /// so both successfully parsed lines and errors count towards the limit.
+    #[structopt(long = "limit", parse(try_from_str = "parse_limit"))]
+    pub limit: Option<usize>,
+
+    /// The number of rows to skip before writing any, taken after sorting and before
+    /// `--limit` is applied, so the two can be used to page through the output. Errors
+    /// sort to the front, so they are skipped first.
+    #[structopt(long = "skip", default_value = "0")]
+    pub skip: usize,
+
+    /// Keeps only the latest N rows, taken after sorting and before `--skip` and `--limit`.
+    /// This means the latest N even with `--reverse`, when they are written newest first.
+    /// Errors have no timestamp so they count as the oldest rows, and are only kept if
+    /// there are fewer than N successfully parsed lines.
+    #[structopt(long = "tail")]
+    pub tail: Option<usize>,
+
+    /// If true, prints the number of lines written for each application, from
+    /// the "AppName" column, with the noisiest first.
+    #[structopt(long = "stats")]
+    pub stats: bool,
+
+    /// The number of applications to print for `--stats`.
+    #[structopt(long = "stats-top", default_value = "20")]
+    pub stats_top: usize,
+
+    /// If true, prints the count, min, max, mean and 50th, 95th and 99th percentiles of
+    /// the "CallRecorderExecutionTime" values of the lines. Values which are not whole
+    /// numbers are ignored.
+    #[structopt(long = "timing-stats")]
+    pub timing_stats: bool,
+
+    /// Breaks down `--timing-stats` by the values of this column, e.g. "Action" or "Source".
+    /// Implies `--timing-stats`.
+    #[structopt(long = "timing-stats-by")]
+    pub timing_stats_by: Option<String>,
+
+    /// If true, the lines are parsed and filtered as normal but no output files are
+    /// written, only the number of ok lines and error lines is printed. Takes
+    /// precedence over `--per-file`.
+    #[structopt(long = "count")]
+    pub count: bool,
+
+    /// Instead of one row per line, write one row per distinct value of this column, e.g.
+    /// "CorrelationKey", with the earliest and latest LogDate, the number of lines and their
+    /// messages. Takes precedence over `--per-file`.
+    #[structopt(long = "group-by")]
+    pub group_by: Option<String>,
+
+    /// If true, lines with no value for the `--group-by` column are grouped together
+    /// under "(none)". By default they are skipped.
+    #[structopt(long = "group-missing")]
+    pub group_missing: bool,
+
+    /// If true, each input file is written to its own output file, e.g. "app.log.csv",
+    /// sorted within that file, instead of merging everything into one consolidated file.
+    /// Errors are likewise written to a file per input file.
+    #[structopt(long = "per-file")]
+    pub per_file: bool,
+
+    /// If true, a single file is followed as it grows, like `tail -f`, and each line that
+    /// is appended to it is written to stdout as CSV. The file is read again from the start
+    /// if it is truncated or rotated. There is no sorting in this mode.
+    #[structopt(long = "follow")]
+    pub follow: bool,
+
+    /// If true, the lines for each day are written to their own CSV file, named after the
+    /// date, e.g. "consolidated-2018-09-26.csv". The errors still go to a single file.
+    /// The files are not split by `--max-output-size` as well.
+    #[structopt(long = "split-by-day")]
+    pub split_by_day: bool,
+
+    /// If true, the consolidated file is gzip-compressed, e.g. "consolidated.csv.gz".
+    #[structopt(long = "gzip-output")]
+    pub gzip_output: bool,
+
+    /// If true, the errors file is gzip-compressed, e.g. "errors.csv.gz".
+    #[structopt(long = "gzip-errors")]
+    pub gzip_errors: bool,
+
+    /// If true, input files are memory-mapped rather than read into memory,
+    /// which can be faster for large files.
+    #[structopt(long = "mmap")]
+    pub mmap: bool,
+
+    /// If true, files are processed one at a time and only the column values which will be
+    /// written to the output are kept in memory. This uses much less memory when there are
+    /// many large files, especially if most lines are filtered out or only a few columns are
+    /// written. It cannot be used with --group-by, --tail, --skip, --limit or the pretty
+    /// format, which need all the lines, so they read all the files into memory as usual.
+    #[structopt(long = "low-memory")]
+    pub low_memory: bool,
+
+    /// If true, the consolidated file is opened in the default application for its
+    /// type (e.g. your spreadsheet program) once processing finishes.
+    #[structopt(long = "open")]
+    pub open: bool,
+
+    /// If specified, the consolidated CSV is split into numbered parts such as
+    /// "consolidated.000.csv", "consolidated.001.csv" etc. A new part is started once the
+    /// current one exceeds about this many bytes, before any compression. Useful for tools
+    /// which choke on huge files.
+    #[structopt(long = "max-output-size")]
+    pub max_output_size: Option<u64>,
+
+    /// If true, only the errors file is written. The consolidated file is not created,
+    /// which is much faster when hunting for lines which fail to parse.
+    #[structopt(long = "only-errors")]
+    pub only_errors: bool,
+
+    /// If true, a single log stream is read from stdin instead of from files. The source of
+    /// each line is shown as "<stdin>". Specifying "-" as a FILE does the same thing.
+    #[structopt(long = "stdin")]
+    pub stdin: bool,
+
+    /// If true, the consolidated output is written to stdout and the errors to stderr,
+    /// instead of to files. Useful when piping the output into another process.
+    #[structopt(long = "stdout")]
+    pub stdout: bool,
+
+    /// If true, which is the default, files which look binary, such as a stray file matched
+    /// by a glob, are skipped with a warning instead of producing an error row for every
+    /// "line". A file looks binary if more than `--binary-threshold` percent of its first
+    /// 8KB are NULs or other control characters. The skipped files are listed at the end.
+    #[structopt(long = "skip-binary", overrides_with = "no_skip_binary")]
+    pub skip_binary: bool,
+
+    /// If true, files which look binary are processed like any other file.
+    #[structopt(long = "no-skip-binary", overrides_with = "skip_binary")]
+    pub no_skip_binary: bool,
+
+    /// The percentage of NULs and other control characters in the start of a file above
+    /// which it looks binary.
+    #[structopt(long = "binary-threshold", default_value = "10", parse(try_from_str = "parse_percentage"))]
+    pub binary_threshold: usize,
+
+    /// If true, the fractional seconds of the LogDate are allowed to contain non-digits,
+    /// as long as all the separators are correct. Useful for loggers which occasionally
+    /// garble a character. By default such lines are treated as errors.
+    #[structopt(long = "lenient-dates")]
+    pub lenient_dates: bool,
+
+    /// The separator between the fields of the prologue, if it is not "|", e.g. "::".
+    /// Overrides the separator in the profile.
+    #[structopt(long = "field-separator", parse(try_from_str = "parse_field_separator"))]
+    pub field_separator: Option<String>,
+
+    /// If true, all the values of a KVP which appears more than once in a line are kept,
+    /// and they are written joined by the `--duplicate-kvp-separator`. By default only
+    /// the first value is kept.
+    #[structopt(long = "allow-duplicate-kvps")]
+    pub allow_duplicate_kvps: bool,
+
+    /// The separator used to join the values of a KVP which appears more than once in a
+    /// line, when `--allow-duplicate-kvps` is specified.
+    #[structopt(long = "duplicate-kvp-separator", default_value = ";")]
+    pub duplicate_kvp_separator: String,
+
+    /// Optional list of columns to output, separated by commas. If specified, this
+    /// completely replaces the columns from the profile, and they are written in
+    /// the order given, e.g. "--columns LogDate,SysRef,Message".
+    #[structopt(long = "columns", use_delimiter = true, raw(number_of_values = "1"))]
+    pub columns: Vec<String>,
+
+    /// Optional list of columns whose values are percent-encoded, separated by commas.
+    /// The values are decoded when they are written, so "%40" becomes "@" and "+"
+    /// becomes a space. These are added to any columns listed in the profile.
+    #[structopt(long = "url-decode", use_delimiter = true, raw(number_of_values = "1"))]
+    pub url_decode: Vec<String>,
+
+    /// If true, the LogDate is written to CSV files as an Excel formula, ="2018-09-26 12:34:56.1146655",
+    /// so that Excel displays it exactly rather than converting it to a date and losing precision.
+    #[structopt(long = "excel-dates")]
+    pub excel_dates: bool,
+
+    /// If true, CSV fields which begin with '=', '+', '-' or '@' are prefixed with a single
+    /// quote, so that spreadsheet applications do not run them as formulas.
+    #[structopt(long = "sanitize-formulas")]
+    pub sanitize_formulas: bool,
+
+    /// If true, a UTF-8 byte order mark is written at the start of CSV output, so that
+    /// Excel does not mangle non-ASCII characters. Only the consolidated file gets one,
+    /// not the errors file. It is written to stdout too when combined with `--stdout`.
+    #[structopt(long = "bom")]
+    pub bom: bool,
+
+    /// If true, the header row is not written to CSV output, neither to the consolidated
+    /// file nor to the errors file. Useful when concatenating the output of several runs.
+    #[structopt(long = "no-header")]
+    pub no_header: bool,
+
+    /// The text written to CSV output for a column which has no value in a line, e.g. "\N"
+    /// or "NULL". A KVP which is present but empty, such as "SysRef=", is still written as
+    /// an empty field. Defaults to an empty field.
+    #[structopt(long = "null-text")]
+    pub null_text: Option<String>,
+
+    /// If true, line breaks in the values are written as the two-character sequences
+    /// "\r" and "\n", and backslashes are doubled, so that multi-line messages can be
+    /// recovered. By default line breaks are replaced with spaces.
+    #[structopt(long = "escape-newlines")]
+    pub escape_newlines: bool,
+
+    /// If specified, line breaks in the values are replaced with this text instead of
+    /// spaces, e.g. " | ", so that multi-line values such as quoted KVPs stay readable.
+    /// A "\r\n" counts as one line break. Values without line breaks are unaffected.
+    #[structopt(long = "join-char", conflicts_with = "escape_newlines")]
+    pub join_char: Option<String>,
+
+    /// If true, the LogDate column is replaced by separate "LogDateOnly" and "LogTimeOnly"
+    /// columns, which are easier to use in pivot tables. The columns can also be listed
+    /// individually in a profile.
+    #[structopt(long = "split-date")]
+    pub split_date: bool,
+
+    /// If true, adds a "Category" column which classifies each line as "error", "request",
+    /// "heartbeat" or "info". The rules can be overridden using `category_regexes` in
+    /// the configuration file.
+    #[structopt(long = "classify")]
+    pub classify: bool,
+
+    /// The number of threads used to read, parse and sort the files. By default there is one
+    /// per CPU core. Specify 1 to process everything on a single thread.
+    #[structopt(long = "threads")]
+    pub threads: Option<usize>,
+
     /// If true, dumps an example configuration file, based on the default configuration,
     /// to stdout.
     #[structopt(short = "d", long = "dump-config")]
     pub dump_config: bool,
 
+    /// Prints a completion script for the shell, one of "bash", "zsh", "fish", "powershell"
+    /// or "elvish", to stdout and exits, e.g. "--completions bash > lpf.bash".
+    #[structopt(long = "completions", raw(hidden = "true", possible_values = "&Shell::variants()", case_insensitive = "true"))]
+    pub completions: Option<Shell>,
+
+    /// The format of the configuration file written by `--dump-config`, either "json"
+    /// or "toml". Save it as "~/.lpf.json" or "~/.lpf.toml" respectively.
+    #[structopt(long = "format", default_value = "json")]
+    pub format: ConfigFormat,
+
+    /// If true, lists the files which would be processed, with their sizes and the total
+    /// size, then exits without reading any of them. Useful for checking what a pattern
+    /// matches before starting a long run.
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+
+    /// The configuration file to use instead of "~/.lpf.json" or "~/.lpf.toml". If not
+    /// specified, the LPF_CONFIG environment variable is used. Unlike the file in the home
+    /// directory, it is an error if this file does not exist.
+    #[structopt(long = "config", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// If true, checks the configuration file for problems such as invalid regexes
+    /// and reports them, then exits.
+    #[structopt(long = "validate-config")]
+    pub validate_config: bool,
+
+    /// If true, a configuration file which cannot be read or parsed is reported and
+    /// the default configuration is used instead, rather than exiting.
+    #[structopt(long = "ignore-bad-config")]
+    pub ignore_bad_config: bool,
+
     /// Optional list of sysrefs to filter by. Separate them by commas.
     #[structopt(short = "s", long = "sysrefs", use_delimiter = true)]
     pub sysrefs: Vec<String>,
 
+    /// If true, sysrefs are matched ignoring case, so "QU090700" matches "qu090700".
+    #[structopt(long = "case-insensitive-sysref")]
+    pub case_insensitive_sysref: bool,
+
+    /// Filtering: Only show records where a KVP has a particular value, e.g. "SysRef=QU090700".
+    /// Alternate column names are checked too, and the comparison ignores case. Can be specified
+    /// multiple times, in which case all must match. An empty value, e.g. "SysRef=", matches
+    /// records where the KVP is present but blank.
+    #[structopt(long = "where", parse(try_from_str = "parse_where_clause"), raw(number_of_values = "1"))]
+    pub where_clauses: Vec<(String, String)>,
+
+    /// Filtering: Only show records where a column matches a regular expression, e.g.
+    /// "Action=^Get". Alternate column names are checked too, and the match ignores case.
+    /// Can be specified multiple times, in which case all must match.
+    #[structopt(long = "match", parse(try_from_str = "parse_match_clause"), raw(number_of_values = "1"))]
+    pub match_clauses: Vec<(String, String)>,
+
+    /// Filtering: Only show records from this process id. Can be specified multiple times,
+    /// in which case records from any of the processes are shown.
+    #[structopt(long = "pid", raw(number_of_values = "1"))]
+    pub pids: Vec<String>,
+
+    /// Filtering: Only show records from this thread id. Can be specified multiple times,
+    /// in which case records from any of the threads are shown.
+    #[structopt(long = "tid", raw(number_of_values = "1"))]
+    pub tids: Vec<String>,
+
+    /// Filtering: Only show records which have one of these log levels. Separate them by
+    /// commas. The brackets are optional and case does not matter, e.g. "error,warng".
+    /// Any log levels added by the profile can be used too.
+    #[structopt(long = "level", use_delimiter = true, raw(number_of_values = "1"))]
+    pub levels: Vec<String>,
+
+    /// Filtering: Only show records which have a non-blank value for this column.
+    /// Can be specified multiple times, in which case all the columns must have a value.
+    /// Alternate column names are checked too. Useful for finding lines which have
+    /// something like a "CallRecorderExecutionTime".
+    #[structopt(long = "require-column")]
+    pub required_columns: Vec<String>,
+
+    /// Filtering: Invert the filters, like "grep -v", so that only the records which the
+    /// sysref, where, match, pid, tid, level and required column filters would have dropped are
+    /// shown. The filters are inverted as a whole: a record is shown unless it passes all
+    /// of them. The date filters are not inverted, and lines which could not be parsed
+    /// are always written to the errors file.
+    #[structopt(short = "v", long = "invert-match")]
+    pub invert_match: bool,
+
     /// Filtering: Only show records whose LogDate is greater than or equal to this date.
     /// If not specified, all records back to the beginning of time will be shown.
     /// The format is the same as the LogDate: "YYYY-MM-DD HH:MM:SS". It will also accept
-    /// "YYYY-MM-DD", "HH:MM" (assumed to be today) and "yesterday".
-    #[structopt(short = "f", long = "from")]
+    /// "YYYY-MM-DD", "HH:MM" (assumed to be today), "today" and "yesterday".
+    #[structopt(short = "f", long = "from", parse(try_from_str = "parse_date"))]
     pub from: Option<String>,
 
     /// Filtering: Only show records whose LogDate is less than or equal to this date.
     /// If not specified, all records back up to the end of time will be shown.
     /// The format is the same as the LogDate: "YYYY-MM-DD HH:MM:SS". It will also accept
-    /// "YYYY-MM-DD", "HH:MM" (assumed to be today) and "yesterday".
-    #[structopt(short = "t", long = "to")]
+    /// "YYYY-MM-DD", "HH:MM" (assumed to be today), "today" and "yesterday".
+    #[structopt(short = "t", long = "to", parse(try_from_str = "parse_date"))]
     pub to: Option<String>,
 
-    /// List of files to process. Defaults to "*.log".
+    /// If true, and no files are specified here or in the profile, all the log files in
+    /// the current directory and its subdirectories are processed, i.e. "**/*.log" and "**/*.log.gz".
+    #[structopt(short = "r", long = "recursive")]
+    pub recursive: bool,
+
+    /// List of files to process. Defaults to "*.log" and "*.log.gz". Use "-" to read from stdin.
+    /// Patterns may contain "**" to match any number of directories, e.g. "logs/**/*.log".
     #[structopt(name = "FILE")]
     pub files: Vec<String>,
 }
 
+/// Writes the completion script for a shell.
+pub fn write_completions<W: Write>(shell: Shell, writer: &mut W) {
+    Arguments::clap().gen_completions_to(env!("CARGO_PKG_NAME"), shell, writer);
+}
+
+/// Parses a where clause argument of the form "KEY=VALUE" into the key and value.
+fn parse_where_clause(s: &str) -> Result<(String, String), String> {
+    match s.find('=') {
+        Some(idx) if idx > 0 => Ok((s[..idx].to_string(), s[idx + 1..].to_string())),
+        _ => Err(format!("The where clause must be of the form KEY=VALUE, but was '{}'", s)),
+    }
+}
+
+/// Parses a match clause argument of the form "KEY=REGEX" into the key and pattern,
+/// checking that the pattern is a valid regular expression.
+fn parse_match_clause(s: &str) -> Result<(String, String), String> {
+    let (key, pattern) = parse_where_clause(s)
+        .map_err(|_| format!("The match clause must be of the form KEY=REGEX, but was '{}'", s))?;
+    RegexBuilder::new(&pattern).case_insensitive(true).build()
+        .map_err(|e| format!("The match clause '{}' has an invalid regex: {}", s, e))?;
+    Ok((key, pattern))
+}
+
+/// Parses a field separator, which must have something other than whitespace in it.
+fn parse_field_separator(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        Err("The field separator must not be empty or whitespace".to_string())
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+/// Parses the limit argument, which must be a number greater than zero.
+fn parse_limit(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err(format!("The limit must be a number greater than 0, but was '{}'", s)),
+    }
+}
+
+/// Parses a `--from` or `--to` date into the canonical LogDate form. An unrecognised
+/// date is a usage error rather than a panic later on, when the filter is built.
+fn parse_date(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(String::new());
+    }
+
+    normalize_date_string(s).map_err(|_| format!(
+        "The date must be \"YYYY-MM-DD HH:MM:SS\", \"YYYY-MM-DD\", \"HH:MM\", \"today\" or \"yesterday\", but was '{}'", s
+    ))
+}
+
+/// Parses a percentage, which must be a whole number from 0 to 100.
+fn parse_percentage(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(pct) if pct <= 100 => Ok(pct),
+        _ => Err(format!("The percentage must be a whole number from 0 to 100, but was '{}'", s)),
+    }
+}
+
+/// Parses the delimiter argument, which must be a single ASCII character or a tab.
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "\\t" | "tab" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(format!("The delimiter must be a single ASCII character, but was '{}'", s)),
+    }
+}
+
 #[cfg(test)]
 impl Default for Arguments {
     fn default() -> Self {
@@ -64,11 +526,275 @@ impl Default for Arguments {
             no_default_profile: false,
             quiet: None,
             max_message_length: None,
+            output_format: None,
+            highlight: false,
+            delimiter: None,
+            tsv: false,
+            sort_by: None,
+            then_by: None,
+            no_sort: false,
+            reverse: false,
+            expect_rows: None,
+            expect_rows_tolerance: 0,
+            limit: None,
+            skip: 0,
+            tail: None,
+            stats: false,
+            stats_top: 20,
+            timing_stats: false,
+            timing_stats_by: None,
+            count: false,
+            group_by: None,
+            group_missing: false,
+            per_file: false,
+            follow: false,
+            split_by_day: false,
+            gzip_output: false,
+            gzip_errors: false,
+            mmap: false,
+            low_memory: false,
+            open: false,
+            output: None,
+            error_output: None,
+            summary_json: None,
+            max_output_size: None,
+            only_errors: false,
+            stdin: false,
+            stdout: false,
+            skip_binary: false,
+            no_skip_binary: false,
+            binary_threshold: 10,
+            lenient_dates: false,
+            field_separator: None,
+            allow_duplicate_kvps: false,
+            duplicate_kvp_separator: ";".to_string(),
+            columns: vec![],
+            url_decode: vec![],
+            excel_dates: false,
+            sanitize_formulas: false,
+            bom: false,
+            no_header: false,
+            null_text: None,
+            escape_newlines: false,
+            join_char: None,
+            split_date: false,
+            classify: false,
+            threads: None,
             dump_config: false,
+            completions: None,
+            format: ConfigFormat::Json,
+            dry_run: false,
+            config: None,
+            validate_config: false,
+            ignore_bad_config: false,
             sysrefs: vec![],
+            case_insensitive_sysref: false,
+            where_clauses: vec![],
+            match_clauses: vec![],
+            pids: vec![],
+            tids: vec![],
+            levels: vec![],
+            required_columns: vec![],
+            invert_match: false,
             from: None,
             to: None,
+            recursive: false,
             files: vec![],
         }
     }
 }
+
+#[cfg(test)]
+mod parse_limit_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_zero_returns_error() {
+        assert!(parse_limit("0").is_err());
+    }
+
+    #[test]
+    pub fn for_non_number_returns_error() {
+        assert!(parse_limit("ten").is_err());
+    }
+
+    #[test]
+    pub fn for_positive_number_returns_limit() {
+        assert_eq!(parse_limit("10"), Ok(10));
+    }
+}
+
+#[cfg(test)]
+mod parse_date_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_full_date_and_time_returns_canonical_form() {
+        assert_eq!(parse_date("2018-09-26 12:34:56"), Ok("2018-09-26 12:34:56.0000000".to_string()));
+    }
+
+    #[test]
+    pub fn for_date_returns_start_of_day() {
+        assert_eq!(parse_date(" 2018-09-26 "), Ok("2018-09-26 00:00:00.0000000".to_string()));
+    }
+
+    #[test]
+    pub fn for_relative_dates_returns_canonical_form() {
+        for s in &["today", "yesterday", "12:34"] {
+            let date = parse_date(s).unwrap();
+            assert_eq!(date.len(), "2018-09-26 12:34:56.0000000".len(), "for {}", s);
+        }
+        assert!(parse_date("12:34").unwrap().ends_with(" 12:34:00.0000000"));
+    }
+
+    #[test]
+    pub fn for_invalid_date_returns_error() {
+        assert!(parse_date("next week").is_err());
+    }
+
+    #[test]
+    pub fn for_invalid_date_on_command_line_is_usage_error() {
+        let result = Arguments::from_iter_safe(&["log-file-processor", "--from", "next week"]);
+        assert_eq!(result.unwrap_err().kind, structopt::clap::ErrorKind::ValueValidation);
+    }
+}
+
+#[cfg(test)]
+mod parse_percentage_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_zero_to_one_hundred_returns_percentage() {
+        assert_eq!(parse_percentage("0"), Ok(0));
+        assert_eq!(parse_percentage("100"), Ok(100));
+    }
+
+    #[test]
+    pub fn for_invalid_percentage_returns_error() {
+        assert!(parse_percentage("101").is_err());
+        assert!(parse_percentage("-1").is_err());
+        assert!(parse_percentage("5%").is_err());
+    }
+}
+
+#[cfg(test)]
+mod skip_binary_tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Arguments {
+        Arguments::from_iter_safe(std::iter::once("log-file-processor").chain(args.iter().cloned())).unwrap()
+    }
+
+    #[test]
+    pub fn last_of_skip_binary_and_no_skip_binary_wins() {
+        let args = parse(&["--skip-binary", "--no-skip-binary"]);
+        assert!(args.no_skip_binary);
+        assert!(!args.skip_binary);
+
+        let args = parse(&["--no-skip-binary", "--skip-binary"]);
+        assert!(!args.no_skip_binary);
+        assert!(args.skip_binary);
+    }
+
+    #[test]
+    pub fn binary_threshold_defaults_to_ten_percent() {
+        assert_eq!(parse(&[]).binary_threshold, 10);
+        assert_eq!(parse(&["--binary-threshold", "30"]).binary_threshold, 30);
+    }
+}
+
+#[cfg(test)]
+mod parse_delimiter_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_single_ascii_char_returns_char() {
+        assert_eq!(parse_delimiter(","), Ok(b','));
+        assert_eq!(parse_delimiter(";"), Ok(b';'));
+    }
+
+    #[test]
+    pub fn for_tab_returns_tab() {
+        assert_eq!(parse_delimiter("\t"), Ok(b'\t'));
+        assert_eq!(parse_delimiter("\\t"), Ok(b'\t'));
+        assert_eq!(parse_delimiter("tab"), Ok(b'\t'));
+    }
+
+    #[test]
+    pub fn for_invalid_delimiter_returns_error() {
+        assert!(parse_delimiter("").is_err());
+        assert!(parse_delimiter(",,").is_err());
+        assert!(parse_delimiter("£").is_err());
+    }
+}
+
+#[cfg(test)]
+mod parse_where_clause_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_key_and_value_returns_both() {
+        assert_eq!(parse_where_clause("SysRef=QU1"), Ok(("SysRef".to_string(), "QU1".to_string())));
+    }
+
+    #[test]
+    pub fn for_value_containing_equals_splits_on_first() {
+        assert_eq!(parse_where_clause("Query=a=b"), Ok(("Query".to_string(), "a=b".to_string())));
+    }
+
+    #[test]
+    pub fn for_empty_value_returns_empty_value() {
+        assert_eq!(parse_where_clause("SysRef="), Ok(("SysRef".to_string(), "".to_string())));
+    }
+
+    #[test]
+    pub fn for_missing_key_or_equals_returns_error() {
+        assert!(parse_where_clause("SysRef").is_err());
+        assert!(parse_where_clause("=QU1").is_err());
+    }
+}
+
+#[cfg(test)]
+mod completions_tests {
+    use super::*;
+
+    fn completions(shell: Shell) -> String {
+        let mut buf = Vec::new();
+        write_completions(shell, &mut buf);
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    pub fn for_each_shell_completes_the_options() {
+        for shell in &[Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = completions(*shell);
+            assert!(script.contains("log-file-processor"), "{:?}", shell);
+            assert!(script.contains("no-default-profile"), "{:?}", shell);
+        }
+    }
+
+    #[test]
+    pub fn shell_name_is_parsed_ignoring_case() {
+        let args = Arguments::from_iter_safe(&["lpf", "--completions", "ZSH"]).unwrap();
+        assert!(matches!(args.completions, Some(Shell::Zsh)));
+        assert!(Arguments::from_iter_safe(&["lpf", "--completions", "tcsh"]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod parse_match_clause_tests {
+    use super::*;
+
+    #[test]
+    pub fn splits_on_first_equals() {
+        assert_eq!(parse_match_clause("Action=^Get"), Ok(("Action".to_string(), "^Get".to_string())));
+        assert_eq!(parse_match_clause("Query=a=b+"), Ok(("Query".to_string(), "a=b+".to_string())));
+    }
+
+    #[test]
+    pub fn for_missing_key_or_bad_regex_returns_error() {
+        assert!(parse_match_clause("Action").is_err());
+        assert!(parse_match_clause("=^Get").is_err());
+        assert!(parse_match_clause("Action=(Get").is_err());
+    }
+}
@@ -44,6 +44,20 @@ pub trait ByteSliceExtensions {
     //fn make_safe<'f>(&'f self) -> Cow<'f, [u8]>;
     fn make_safe(&self) -> Cow<[u8]>;
         // TODO: should be where T: std::clone::Clone, and Self is [T]
+
+    /// Appends the slice to `buf` with line breaks replaced exactly as `make_safe` does.
+    /// This lets a caller reuse one buffer, clearing it between values, instead of
+    /// allocating a new one for every value that contains a line break.
+    fn make_safe_into(&self, buf: &mut Vec<u8>);
+
+    /// Appends the slice to `buf` with each line break replaced by `separator`. A "\r\n"
+    /// pair counts as one line break, as does a lone '\r' or '\n'.
+    fn join_lines_into(&self, separator: &[u8], buf: &mut Vec<u8>);
+
+    /// An alternative to `make_safe` which preserves the line breaks, by replacing any
+    /// '\r' and '\n' characters with the two-character sequences "\r" and "\n". Any '\'
+    /// is doubled first, so that the escaped value can be decoded unambiguously.
+    fn escape_newlines(&self) -> Cow<'_, [u8]>;
 }
 
 impl ByteSliceExtensions for [u8] {
@@ -112,6 +126,42 @@ impl ByteSliceExtensions for [u8] {
             self.into()
         }
     }
+
+    fn make_safe_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.iter().map(|&c| if c == b'\r' || c == b'\n' { b' ' } else { c }));
+    }
+
+    fn join_lines_into(&self, separator: &[u8], buf: &mut Vec<u8>) {
+        let mut idx = 0;
+        while idx < self.len() {
+            match self[idx] {
+                b'\r' if self.get(idx + 1) == Some(&b'\n') => {
+                    buf.extend_from_slice(separator);
+                    idx += 1;
+                },
+                b'\r' | b'\n' => buf.extend_from_slice(separator),
+                c => buf.push(c),
+            }
+            idx += 1;
+        }
+    }
+
+    fn escape_newlines(&self) -> Cow<'_, [u8]> {
+        if !self.iter().any(|&c| c == b'\r' || c == b'\n' || c == b'\\') {
+            return self.into();
+        }
+
+        let mut escaped = Vec::with_capacity(self.len() + 8);
+        for &c in self {
+            match c {
+                b'\\' => escaped.extend_from_slice(b"\\\\"),
+                b'\r' => escaped.extend_from_slice(b"\\r"),
+                b'\n' => escaped.extend_from_slice(b"\\n"),
+                _ => escaped.push(c),
+            }
+        }
+        escaped.into()
+    }
 }
 
 #[cfg(test)]
@@ -177,4 +227,54 @@ mod tests {
         let slice = &b"  ";
         assert_eq!(slice.trim_while(ByteExtensions::is_whitespace), b"");
     }
+
+    #[test]
+    pub fn make_safe() {
+        assert_eq!(b"a\r\nb\nc".make_safe().as_ref(), b"a  b c");
+        assert!(matches!(b"abc".make_safe(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    pub fn make_safe_into_matches_make_safe() {
+        let mut buf = Vec::new();
+        for value in [&b"a\r\nb\nc"[..], b"abc", b"", b"\r", b"\n\n", b"trace\r\n   at Foo()\r\n"] {
+            buf.clear();
+            value.make_safe_into(&mut buf);
+            assert_eq!(buf, value.make_safe().as_ref());
+        }
+    }
+
+    #[test]
+    pub fn join_lines_into_replaces_each_line_break_with_separator() {
+        let mut buf = b"x".to_vec();
+        b"a\r\nb\nc\rd".join_lines_into(b" | ", &mut buf);
+        assert_eq!(buf, b"xa | b | c | d");
+    }
+
+    #[test]
+    pub fn join_lines_into_for_single_line_appends_it_unchanged() {
+        let mut buf = vec![];
+        b"abc".join_lines_into(b" | ", &mut buf);
+        assert_eq!(buf, b"abc");
+    }
+
+    #[test]
+    pub fn make_safe_into_appends_to_buffer() {
+        let mut buf = b"x".to_vec();
+        b"a\nb".make_safe_into(&mut buf);
+        assert_eq!(buf, b"xa b");
+    }
+
+    #[test]
+    pub fn escape_newlines() {
+        assert_eq!(b"a\r\nb\nc".escape_newlines().as_ref(), b"a\\r\\nb\\nc");
+        assert!(matches!(b"abc".escape_newlines(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    pub fn escape_newlines_doubles_backslashes() {
+        assert_eq!(br"C:\new".escape_newlines().as_ref(), br"C:\\new");
+        assert_eq!(b"C:\\new\nline".escape_newlines().as_ref(), br"C:\\new\nline");
+        assert_eq!(br"\r".escape_newlines().as_ref(), br"\\r");
+    }
 }
\ No newline at end of file
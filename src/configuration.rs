@@ -1,18 +1,192 @@
 use std::collections::{HashMap};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use regex::{Regex, RegexBuilder};
+use regex::bytes::{Regex as BytesRegex, RegexBuilder as BytesRegexBuilder};
 use chrono::prelude::*;
 use crate::arguments::Arguments;
+use crate::kvp;
 use crate::profiles::{Profile, ProfileSet, vec_add_entry};
-use crate::parsed_line::string_to_utc_datetime_and_panic;
+use crate::parsed_line::{string_to_utc_datetime_and_panic, ParseOptions};
 
 pub const DEFAULT_PROFILE_NAME: &str = "default";
 pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 1_000_000;
+pub const STDIN_FILE_PATTERN: &str = "-";
+pub const DEFAULT_STATS_TOP: usize = 20;
+pub const DEFAULT_BINARY_THRESHOLD: usize = 10;
+pub const DEFAULT_DUPLICATE_KVP_SEPARATOR: &str = ";";
+
+/// The format of the output files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    Ndjson,
+    Pretty,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "pretty" => Ok(OutputFormat::Pretty),
+            _ => Err(format!("Unknown output format '{}', expected 'csv', 'json', 'ndjson' or 'pretty'", s)),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Gets the file extension used for output in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Pretty => "txt",
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Configuration {
     pub name: String,
     pub quiet: bool,
     pub max_message_length: usize,
+    pub output_format: OutputFormat,
+
+    /// The delimiter to use between fields in CSV output.
+    pub delimiter: u8,
+
+    /// If true, the LogDate is written to CSV output in a form which Excel will not mangle.
+    pub excel_dates: bool,
+
+    /// If true, CSV fields which a spreadsheet would treat as a formula are prefixed with a quote.
+    pub sanitize_formulas: bool,
+
+    /// If true, a UTF-8 byte order mark is written at the start of the CSV success output.
+    pub bom: bool,
+
+    /// If true, no header rows are written to CSV output.
+    pub no_header: bool,
+
+    /// The text written to CSV output for a column which is missing from a line.
+    pub null_text: String,
+
+    /// If true, line breaks in the output are escaped rather than replaced with spaces.
+    pub escape_newlines: bool,
+
+    /// The text which replaces each line break in the output, if not a space.
+    pub join_char: Option<Vec<u8>>,
+
+    /// A column used to order lines before their timestamps.
+    pub sort_by: Option<String>,
+
+    /// A column used to order lines which have identical timestamps.
+    pub then_by: Option<String>,
+
+    /// If true, lines are not sorted and are written in input order.
+    pub no_sort: bool,
+
+    /// If true, lines are sorted in descending order, newest first.
+    pub reverse: bool,
+
+    /// The file to write successfully parsed lines to. If not specified, a default
+    /// name in the current directory is used. See `success_file`.
+    pub output_file: Option<PathBuf>,
+
+    /// The file to write errors to. If not specified, it is derived from the
+    /// output file. See `error_file`.
+    pub error_output_file: Option<PathBuf>,
+
+    /// The file to write a JSON summary of the run to, if any.
+    pub summary_json: Option<PathBuf>,
+
+    /// The expected number of successfully parsed rows, and how far the actual
+    /// number is allowed to differ from it.
+    pub expect_rows: Option<usize>,
+    pub expect_rows_tolerance: usize,
+
+    /// The maximum number of rows (lines and errors) to write, taken after sorting.
+    pub limit: Option<usize>,
+
+    /// The number of rows (lines and errors) to skip, taken after sorting and before the limit.
+    pub skip: usize,
+
+    /// If specified, only the latest N rows are kept, taken after sorting and before skip and limit.
+    pub tail: Option<usize>,
+
+    /// If true, the number of lines for each application is printed, limited
+    /// to the top `stats_top` applications.
+    pub stats: bool,
+    pub stats_top: usize,
+
+    /// If true, statistics of the execution times are printed, grouped by
+    /// the `timing_stats_by` column if there is one.
+    pub timing_stats: bool,
+    pub timing_stats_by: Option<String>,
+
+    /// If true, the lines are only counted, and no output files are written.
+    pub count: bool,
+
+    /// If set, one row is written per distinct value of this column rather than per line.
+    pub group_by: Option<String>,
+
+    /// If true, lines with no value for the `group_by` column are written as a group of their own.
+    pub group_missing: bool,
+
+    /// If true, each input file is written to its own output files rather than
+    /// to a single consolidated file.
+    pub per_file: bool,
+
+    /// If true, a single file is followed as it grows and its new lines are written to stdout.
+    pub follow: bool,
+
+    /// If true, CSV output is split into a file per day.
+    pub split_by_day: bool,
+
+    /// If true, the consolidated file and the errors file, respectively, are gzip-compressed.
+    /// This is done by giving them a ".gz" extension, see `success_file` and `error_file`.
+    pub gzip_output: bool,
+    pub gzip_errors: bool,
+
+    /// If true, input files are memory-mapped rather than read into memory.
+    pub mmap: bool,
+
+    /// If true, files are processed one at a time to reduce memory usage.
+    pub low_memory: bool,
+
+    /// If true, the consolidated file is opened once it has been written.
+    pub open: bool,
+
+    /// If specified, the consolidated CSV is split into parts of about this many bytes.
+    pub max_output_size: Option<u64>,
+
+    /// If true, only the errors file is written.
+    pub only_errors: bool,
+
+    /// If true, input is read from stdin rather than from the files.
+    pub stdin: bool,
+
+    /// If true, output is written to stdout and errors to stderr instead of to files.
+    pub stdout: bool,
+
+    /// If true, files which look binary are skipped, i.e. those where more than
+    /// `binary_threshold` percent of the start of the file are NULs or control characters.
+    pub skip_binary: bool,
+    pub binary_threshold: usize,
+
+    /// Options which control how lines are parsed.
+    pub parse_options: ParseOptions,
+
+    /// The separator used to join the values of KVPs which appear more than once in a line.
+    /// Only relevant if `parse_options.allow_duplicate_kvps` is true.
+    pub duplicate_kvp_separator: String,
 
     /// A simple list of column names, these will become the headers in the output file.
     pub columns: Vec<String>,
@@ -25,20 +199,66 @@ pub struct Configuration {
     /// a value using "AppName" or "ApplicationName".
     pub alternate_column_names: HashMap<String, Vec<String>>,
 
+    /// A sparse map of ColumnName -> Header, used to give a column a different header
+    /// in the output file. Values are still located using the column name.
+    pub column_headers: HashMap<String, String>,
+
     /// The files to process.
     pub file_patterns: Vec<String>,
 
+    /// The columns whose values are percent-decoded when they are written.
+    pub url_decode_columns: Vec<String>,
+
+    /// The columns whose values are whole numbers, which are sorted numerically.
+    pub numeric_columns: Vec<String>,
+
     /// A sparse map of ColumnName -> Regex, regular expressions to be used to extract
     /// each column. If a column has no entry in here, then it is retrieved from the
     /// extracted KVPs or using a default regex to probe the message text itself.
     pub column_regexes: HashMap<String, Regex>,
 
+    /// A list of Category -> Regex, custom rules used to classify each line for the
+    /// Category column. They are checked before the built-in rules, in alphabetical
+    /// order of category, and the first match wins. Regexes are applied to the raw
+    /// bytes of the line, so no UTF-8 conversion is needed.
+    pub category_regexes: Vec<(String, BytesRegex)>,
+
+    /// A list of ColumnName -> Regex, from `--match`, to filter by. Can be empty, in which
+    /// case no filtering is done. If non-empty, then the value of every column must match
+    /// its regex for the line to be written. Case is ignored.
+    pub match_regexes: Vec<(String, BytesRegex)>,
+
     /// List of sysrefs to filter by. Can be empty, in which case no filtering is done.
     /// If non-empty, then the line must have one of these sysrefs to be written to
     /// the output. Converted to Vec<u8> at init time to make comparison later on
     /// faster and easier.
     pub sysrefs: Vec<Vec<u8>>,
 
+    /// If true, sysrefs are compared ignoring case.
+    pub case_insensitive_sysref: bool,
+
+    /// List of KVP name and value pairs to filter by. Can be empty, in which case no
+    /// filtering is done. If non-empty, then the line must have every one of the KVPs,
+    /// with the given value, to be written to the output. Values are compared ignoring case.
+    pub where_clauses: Vec<(String, Vec<u8>)>,
+
+    /// Lists of pids and tids to filter by. Can be empty, in which case no filtering
+    /// is done. If non-empty, then the line must have one of these values for its
+    /// "pid" or "tid" KVP to be written to the output.
+    pub pids: Vec<Vec<u8>>,
+    pub tids: Vec<Vec<u8>>,
+
+    /// List of log levels to filter by. Can be empty, in which case no filtering is done.
+    /// If non-empty, then the line must have one of these log levels to be written.
+    pub log_levels: Vec<Vec<u8>>,
+
+    /// List of columns which must have a non-blank value for the line to be written
+    /// to the output. Can be empty, in which case no filtering is done.
+    pub required_columns: Vec<String>,
+
+    /// If true, the result of the content filters (everything except the dates) is inverted.
+    pub invert_match: bool,
+
     /// Filtering: Only show records whose LogDate is greater than or equal to this date.
     /// If not specified, all records back to the beginning of time will be shown.
     /// The format is the same as the LogDate: "YYYY-MM-DD HH:MM:SS" which allows us to
@@ -56,43 +276,123 @@ fn make_kvp_pattern(key_name: &str) -> String {
     format!(r###"\W{0}="(.*?)"|\W{0}=(\S*)"###, regex::escape(key_name))
 }
 
-fn make_case_insensitive_regex_for_pattern(pattern: &str) -> Regex {
-    RegexBuilder::new(pattern).case_insensitive(true).build().unwrap()
+fn make_case_insensitive_regex_for_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+fn make_case_insensitive_bytes_regex_for_pattern(pattern: &str) -> Result<BytesRegex, regex::Error> {
+    BytesRegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+/// The file patterns to use when neither a profile nor the command line specifies any.
+/// Compressed logs are included because they are decompressed as they are read.
+pub fn default_file_patterns(recursive: bool) -> [&'static str; 2] {
+    if recursive { ["**/*.log", "**/*.log.gz"] } else { ["*.log", "*.log.gz"] }
+}
+
+/// Checks whether the path has a ".gz" extension, ignoring case.
+pub fn has_gz_extension(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
 }
 
-impl From<Profile> for Configuration {
-    fn from(p: Profile) -> Self {
+/// Builds the configuration for a profile. A regex or timestamp format in the profile which
+/// cannot be used is reported as an error; `Profile::regex_problems` finds the same problems.
+impl TryFrom<Profile> for Configuration {
+    type Error = String;
+
+    fn try_from(p: Profile) -> Result<Self, String> {
         let mut config = Configuration {
             name: p.name,
             quiet: p.quiet.unwrap_or(false),
             max_message_length: p.max_message_length.unwrap_or(DEFAULT_MAX_MESSAGE_LENGTH),
+            output_format: OutputFormat::default(),
+            delimiter: b',',
+            excel_dates: false,
+            sanitize_formulas: false,
+            bom: false,
+            no_header: false,
+            null_text: String::new(),
+            escape_newlines: false,
+            join_char: None,
+            sort_by: None,
+            then_by: None,
+            no_sort: false,
+            reverse: false,
+            output_file: None,
+            error_output_file: None,
+            summary_json: None,
+            expect_rows: None,
+            expect_rows_tolerance: 0,
+            limit: None,
+            skip: 0,
+            tail: None,
+            stats: false,
+            stats_top: DEFAULT_STATS_TOP,
+            timing_stats: false,
+            timing_stats_by: None,
+            count: false,
+            group_by: None,
+            group_missing: false,
+            per_file: false,
+            follow: false,
+            split_by_day: false,
+            gzip_output: false,
+            gzip_errors: false,
+            mmap: false,
+            low_memory: false,
+            open: false,
+            max_output_size: None,
+            only_errors: false,
+            stdin: false,
+            stdout: false,
+            skip_binary: true,
+            binary_threshold: DEFAULT_BINARY_THRESHOLD,
+            parse_options: ParseOptions::default(),
+            duplicate_kvp_separator: DEFAULT_DUPLICATE_KVP_SEPARATOR.to_string(),
             columns: p.columns,
             alternate_column_names: p.alternate_column_names,
+            column_headers: p.column_headers,
             file_patterns: p.file_patterns,
+            url_decode_columns: p.url_decode,
+            numeric_columns: p.numeric_columns,
             column_regexes: HashMap::new(),
+            category_regexes: vec![],
             sysrefs: vec![],
+            case_insensitive_sysref: false,
+            where_clauses: vec![],
+            match_regexes: vec![],
+            pids: vec![],
+            tids: vec![],
+            log_levels: vec![],
+            required_columns: vec![],
+            invert_match: false,
             from: None,
             to: None
         };
 
         // Insert any custom regexes.
         for (column_name, pattern) in p.column_regexes {
-            config.add_column_regex(column_name, &pattern);
+            config.add_column_regex(column_name.clone(), &pattern)
+                .map_err(|e| format!("Profile '{}': the regex for column '{}' is invalid: {}", config.name, column_name, e))?;
         }
 
-        // For all columns that don't have a custom regex, use a standard KVP one.
-        // We need a separate regex for each column because the name of the column
-        // is included in the regex pattern.
-        let cols = config.columns.clone();
-        for column in cols {
-            if !config.column_regexes.contains_key(&column) {
-                let pattern = make_kvp_pattern(&column);
-                config.add_column_regex(column, &pattern);
-            }
+        for (category, pattern) in p.category_regexes {
+            config.add_category_regex(category.clone(), &pattern)
+                .map_err(|e| format!("Profile '{}': the regex for category '{}' is invalid: {}", config.name, category, e))?;
         }
 
+        config.add_kvp_column_regexes();
+        config.set_timestamp_format(&p.timestamp_format)
+            .map_err(|e| format!("Profile '{}': {}", config.name, e))?;
+        config.set_field_separator(&p.field_separator);
+        // The built-in log levels come from the default profile. If there are none by the
+        // end of `get_config` they are used anyway, see there.
+        config.parse_options.log_levels.clear();
+        for level in &p.log_levels {
+            config.add_log_level(level);
+        }
         config.set_from_and_to(&p.from, &p.to);
-        config
+        Ok(config)
     }
 }
 
@@ -103,6 +403,67 @@ impl Configuration {
         vec_add_entry(column_name, &mut self.columns);
     }
 
+    pub fn add_url_decode_column<S>(&mut self, column_name: S)
+        where S: Into<String>
+    {
+        vec_add_entry(column_name, &mut self.url_decode_columns);
+    }
+
+    /// Returns true if the values of the column should be percent-decoded.
+    pub fn is_url_decode_column(&self, column_name: &str) -> bool {
+        self.url_decode_columns.iter().any(|c| c.eq_ignore_ascii_case(column_name))
+    }
+
+    pub fn add_numeric_column<S>(&mut self, column_name: S)
+        where S: Into<String>
+    {
+        vec_add_entry(column_name, &mut self.numeric_columns);
+    }
+
+    /// Returns true if the values of the column are whole numbers, which sort numerically.
+    pub fn is_numeric_column(&self, column_name: &str) -> bool {
+        self.numeric_columns.iter().any(|c| c.eq_ignore_ascii_case(column_name))
+    }
+
+    /// Replaces the columns completely, rather than adding to them.
+    pub fn set_columns(&mut self, column_names: &[String]) {
+        self.columns.clear();
+        for column_name in column_names {
+            self.add_column(column_name.clone());
+        }
+
+        self.add_kvp_column_regexes();
+    }
+
+    /// Replaces the LogDate column with the separate date and time columns, in the same
+    /// position. If there is no LogDate column they are added at the end.
+    pub fn split_log_date_column(&mut self) {
+        let split_columns = [kvp::LOG_DATE_ONLY.to_string(), kvp::LOG_TIME_ONLY.to_string()];
+        match self.columns.iter().position(|c| c == kvp::LOG_DATE) {
+            Some(idx) => {
+                self.columns.splice(idx..=idx, split_columns.iter().cloned());
+            },
+            None => {
+                for column in &split_columns {
+                    self.add_column(column.clone());
+                }
+            }
+        }
+    }
+
+    /// For all columns that don't have a custom regex, use a standard KVP one.
+    /// We need a separate regex for each column because the name of the column
+    /// is included in the regex pattern.
+    fn add_kvp_column_regexes(&mut self) {
+        let cols = self.columns.clone();
+        for column in cols {
+            if !self.column_regexes.contains_key(&column) {
+                let pattern = make_kvp_pattern(&column);
+                self.add_column_regex(column, &pattern).expect("The column name is escaped in KVP patterns");
+            }
+        }
+    }
+
     pub fn add_alternate_column<S>(&mut self, main_column_name: &str, alternate_column_name: S)
         where S: Into<String>
     {
@@ -110,17 +471,123 @@ impl Configuration {
         vec_add_entry(alternate_column_name, alternate_names);
     }
 
+    /// Gets the header to write for a column, which is the column name unless
+    /// it has been given a different header.
+    pub fn column_header<'a>(&'a self, column_name: &'a str) -> &'a str {
+        self.column_headers.get(column_name).map_or(column_name, |h| h.as_str())
+    }
+
     pub fn add_file_pattern<S>(&mut self, file_pattern: S)
         where S: Into<String>
     {
         vec_add_entry(file_pattern, &mut self.file_patterns);
     }
 
-    pub fn add_column_regex<S>(&mut self, column_name: S, pattern: &str)
+    /// Adds a custom regex for extracting a column. Returns the error if the regex is invalid.
+    pub fn add_column_regex<S>(&mut self, column_name: S, pattern: &str) -> Result<(), regex::Error>
         where S: Into<String>
     {
-        let regex = make_case_insensitive_regex_for_pattern(pattern);
+        let regex = make_case_insensitive_regex_for_pattern(pattern)?;
         self.column_regexes.insert(column_name.into(), regex);
+        Ok(())
+    }
+
+    /// Adds a custom rule for classifying lines, replacing any existing rule for the category.
+    /// Returns the error if the regex is invalid.
+    pub fn add_category_regex<S>(&mut self, category: S, pattern: &str) -> Result<(), regex::Error>
+        where S: Into<String>
+    {
+        let category = category.into();
+        let regex = make_case_insensitive_bytes_regex_for_pattern(pattern)?;
+        self.category_regexes.retain(|(c, _)| *c != category);
+        self.category_regexes.push((category, regex));
+        self.category_regexes.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(())
+    }
+
+    /// Gets the path of the file that successfully parsed lines are written to.
+    pub fn success_file(&self) -> PathBuf {
+        let path = match self.output_file {
+            Some(ref path) => path.clone(),
+            None => PathBuf::from(format!("consolidated.{}", self.output_format.extension())),
+        };
+
+        self.gzip_path(path, self.gzip_output)
+    }
+
+    /// Gets the path of the file that errors are written to. Unless specified explicitly,
+    /// this is next to the output file, so "out/run1.csv" has errors in "out/run1.errors.csv".
+    pub fn error_file(&self) -> PathBuf {
+        let extension = self.output_format.extension();
+        let path = match (&self.error_output_file, &self.output_file) {
+            (Some(path), _) => path.clone(),
+            (None, Some(path)) => {
+                // Don't let a compressed output file leave its ".csv" in the errors file name.
+                let path = if has_gz_extension(path) { path.with_extension("") } else { path.clone() };
+                path.with_extension(format!("errors.{}", extension))
+            },
+            (None, None) => PathBuf::from(format!("errors.{}", extension)),
+        };
+
+        self.gzip_path(path, self.gzip_errors)
+    }
+
+    /// Adds a ".gz" extension to the path if the file is to be compressed
+    /// and it does not already have one.
+    pub fn gzip_path(&self, path: PathBuf, gzip: bool) -> PathBuf {
+        if gzip && !has_gz_extension(&path) {
+            let mut path = path.into_os_string();
+            path.push(".gz");
+            PathBuf::from(path)
+        } else {
+            path
+        }
+    }
+
+    /// Adds a log level to be recognized when parsing, if it is not already known.
+    /// The brackets are added if they are missing.
+    pub fn add_log_level(&mut self, level: &str) {
+        let level = level.trim().trim_start_matches('[').trim_end_matches(']');
+        if level.is_empty() {
+            return;
+        }
+
+        let level = format!("[{}]", level).into_bytes();
+        if !self.parse_options.log_levels.contains(&level) {
+            self.parse_options.log_levels.push(level);
+        }
+    }
+
+    /// Finds the known log level for a name such as "error" or "[ERROR]", as it appears
+    /// in the log file. The brackets are optional and case does not matter.
+    pub fn find_log_level(&self, s: &str) -> Result<Vec<u8>, String> {
+        let name = s.trim().trim_start_matches('[').trim_end_matches(']');
+        let levels = &self.parse_options.log_levels;
+        levels.iter()
+            .find(|level| level[1..level.len() - 1].eq_ignore_ascii_case(name.as_bytes()))
+            .cloned()
+            .ok_or_else(|| {
+                let expected = levels.iter().map(|level| String::from_utf8_lossy(level)).collect::<Vec<_>>().join(", ");
+                format!("Unknown log level '{}', expected one of {}", s, expected)
+            })
+    }
+
+    /// Sets the prologue field separator, if one is specified.
+    pub fn set_field_separator(&mut self, field_separator: &Option<String>) {
+        if let Some(separator) = field_separator {
+            if !separator.trim().is_empty() {
+                self.parse_options.field_separator = separator.trim().as_bytes().to_vec();
+            }
+        }
+    }
+
+    /// Sets the timestamp format, if one is specified. Returns an error describing the
+    /// problem if the format is invalid, in which case the current format is kept.
+    pub fn set_timestamp_format(&mut self, timestamp_format: &Option<String>) -> Result<(), String> {
+        if let Some(pattern) = timestamp_format {
+            self.parse_options.timestamp_format = Some(pattern.parse()?);
+        }
+        Ok(())
     }
 
     pub fn set_from_and_to(&mut self, from: &Option<String>, to: &Option<String>)
@@ -145,7 +612,9 @@ impl Configuration {
 ///    the profiles (as loaded from file)
 ///    to which the arguments have been applied
 ///    then the appropriate inputs constructed.
-pub fn get_config(profiles: &ProfileSet, args: &Arguments) -> Configuration {
+/// Returns an error if a profile is missing, or has a regex or timestamp format which
+/// cannot be used.
+pub fn get_config(profiles: &ProfileSet, args: &Arguments) -> Result<Configuration, String> {
     // Determine the baseline profile to which we will apply any overrides.
     // If there is a profile named "default" in the .lpf.json file we use it - this allows
     // the user to customize the default profile - otherwise we just generate one in code.
@@ -155,66 +624,210 @@ pub fn get_config(profiles: &ProfileSet, args: &Arguments) -> Configuration {
         profiles.get(DEFAULT_PROFILE_NAME).map_or(Profile::default(), |p| p.clone())
     };
 
-    let mut config = Configuration::from(profile);
+    let mut config = Configuration::try_from(profile)?;
 
     // If the user specified that they want to use a particular profile, we then
     // add its distinctiveness to our own. This is an *additive* operation only.
+    // Any profiles it extends are added first, so that its own settings win.
     if args.profile != DEFAULT_PROFILE_NAME {
-        let override_profile = profiles.get(&args.profile).unwrap_or_else(|| panic!("Profile '{}' does not exist", args.profile));
-
-        config.name = override_profile.name.clone();
-        if let Some(quiet) = override_profile.quiet {
-            config.quiet = quiet;
-        }
-
-        for column_name in &override_profile.columns {
-            config.add_column(column_name.clone());
-        }
-
-        for (main_column_name, alternate_names_for_column) in &override_profile.alternate_column_names {
-            for alt_name in alternate_names_for_column {
-                config.add_alternate_column(main_column_name, alt_name.to_string());
-            }
-        }
-
-        for pat in &override_profile.file_patterns {
-            config.add_file_pattern(pat.to_string());
-        }
-
-        for (column_name, pattern) in &override_profile.column_regexes {
-            config.add_column_regex(column_name.clone(), &pattern);
+        for override_profile in profiles.get_with_ancestors(&args.profile)? {
+            apply_profile(&mut config, override_profile)?;
         }
+    }
 
-        config.set_from_and_to(&override_profile.from, &override_profile.to);
+    // Without the default profile, and with no profile listing log levels, lines would
+    // never have a level, which is never what is wanted.
+    if config.parse_options.log_levels.is_empty() {
+        config.parse_options.log_levels = ParseOptions::default().log_levels;
     }
 
     // Now apply overrides from the command line arguments.
+
     if let Some(quiet) = args.quiet {
         config.quiet = quiet;
     }
     if let Some(max_message_length) = args.max_message_length {
         config.max_message_length = max_message_length;
     }
+    if let Some(output_format) = args.output_format {
+        config.output_format = output_format;
+    }
+    if args.highlight {
+        config.output_format = OutputFormat::Pretty;
+    }
+    if let Some(delimiter) = args.delimiter {
+        config.delimiter = delimiter;
+    }
+    if args.tsv {
+        config.delimiter = b'\t';
+    }
+    config.excel_dates = args.excel_dates;
+    config.sanitize_formulas = args.sanitize_formulas;
+    config.bom = args.bom;
+    config.no_header = args.no_header;
+    if let Some(ref null_text) = args.null_text {
+        config.null_text = null_text.clone();
+    }
+    config.escape_newlines = args.escape_newlines;
+    config.join_char = args.join_char.as_ref().map(|s| s.as_bytes().to_vec());
+    if let Some(ref sort_by) = args.sort_by {
+        config.sort_by = Some(sort_by.clone());
+    }
+    if let Some(ref then_by) = args.then_by {
+        config.then_by = Some(then_by.clone());
+    }
+    config.no_sort = args.no_sort;
+    config.reverse = args.reverse;
+    if let Some(ref output) = args.output {
+        config.output_file = Some(output.clone());
+    }
+    if let Some(ref error_output) = args.error_output {
+        config.error_output_file = Some(error_output.clone());
+    }
+    config.summary_json = args.summary_json.clone();
+    config.expect_rows = args.expect_rows;
+    config.expect_rows_tolerance = args.expect_rows_tolerance;
+    config.limit = args.limit;
+    config.skip = args.skip;
+    config.tail = args.tail;
+    config.stats = args.stats;
+    config.stats_top = args.stats_top;
+    config.timing_stats = args.timing_stats || args.timing_stats_by.is_some();
+    config.timing_stats_by = args.timing_stats_by.clone();
+    config.count = args.count;
+    config.group_by = args.group_by.clone();
+    config.group_missing = args.group_missing;
+    config.gzip_output = args.gzip_output;
+    config.gzip_errors = args.gzip_errors;
+    config.mmap = args.mmap;
+    config.low_memory = args.low_memory;
+    config.open = args.open;
+    config.max_output_size = args.max_output_size;
+    config.only_errors = args.only_errors;
+    // The pretty format is for reading at a terminal, so it is always written to stdout.
+    config.stdout = args.stdout || config.output_format == OutputFormat::Pretty;
+    // There is only one stdout, so per-file output makes no sense there.
+    config.per_file = args.per_file && !config.stdout;
+    config.follow = args.follow;
+    config.split_by_day = args.split_by_day;
+    config.skip_binary = args.skip_binary || !args.no_skip_binary;
+    config.binary_threshold = args.binary_threshold;
+    config.parse_options.lenient_dates = args.lenient_dates;
+    config.set_field_separator(&args.field_separator);
+    config.parse_options.allow_duplicate_kvps = args.allow_duplicate_kvps;
+    config.duplicate_kvp_separator = args.duplicate_kvp_separator.clone();
+    if !args.columns.is_empty() {
+        config.set_columns(&args.columns);
+    }
+    for column_name in &args.url_decode {
+        config.add_url_decode_column(column_name.clone());
+    }
+    if args.split_date {
+        config.split_log_date_column();
+    }
+    if args.classify {
+        config.add_column(kvp::CATEGORY);
+    }
+    config.stdin = args.stdin;
     for pat in &args.files {
-        config.add_file_pattern(pat.to_string());
+        if pat == STDIN_FILE_PATTERN {
+            config.stdin = true;
+        } else {
+            config.add_file_pattern(pat.to_string());
+        }
     }
 
     config.set_from_and_to(&args.from, &args.to);
 
     config.sysrefs.extend(args.sysrefs.iter().map(|sr| sr.bytes().collect()));
+    config.case_insensitive_sysref = args.case_insensitive_sysref;
+
+    config.where_clauses.extend(args.where_clauses.iter().map(|(k, v)| (k.clone(), v.bytes().collect())));
+    config.match_regexes.extend(args.match_clauses.iter()
+        .map(|(k, pattern)| (k.clone(), make_case_insensitive_bytes_regex_for_pattern(pattern).expect("The match clauses are checked when the arguments are parsed"))));
+    config.pids.extend(args.pids.iter().map(|pid| pid.trim().bytes().collect()));
+    config.tids.extend(args.tids.iter().map(|tid| tid.trim().bytes().collect()));
+    // Checked here rather than when the arguments are parsed because the profile can add levels.
+    for level in &args.levels {
+        let level = config.find_log_level(level)?;
+        config.log_levels.push(level);
+    }
+
+    config.invert_match = args.invert_match;
+    for column_name in &args.required_columns {
+        vec_add_entry(column_name.clone(), &mut config.required_columns);
+    }
 
     // Default if no profile or command line specifies a file pattern.
-    // Means we will process everything in the current directory.
+    // Means we will process everything in the current directory, or below it.
     if config.file_patterns.is_empty() {
-        config.add_file_pattern("*.log".to_string());
+        for pattern in &default_file_patterns(args.recursive) {
+            config.add_file_pattern(pattern.to_string());
+        }
     }
 
-    config
+    Ok(config)
+}
+
+/// Adds the settings of a profile to the configuration. Lists and maps are combined
+/// with what is already there, and scalar settings replace it. Returns an error if the
+/// profile has a regex or timestamp format which cannot be used.
+fn apply_profile(config: &mut Configuration, override_profile: &Profile) -> Result<(), String> {
+    config.name = override_profile.name.clone();
+    if let Some(quiet) = override_profile.quiet {
+        config.quiet = quiet;
+    }
+
+    for column_name in &override_profile.columns {
+        config.add_column(column_name.clone());
+    }
+
+    for (main_column_name, alternate_names_for_column) in &override_profile.alternate_column_names {
+        for alt_name in alternate_names_for_column {
+            config.add_alternate_column(main_column_name, alt_name.to_string());
+        }
+    }
+
+    for (column_name, header) in &override_profile.column_headers {
+        config.column_headers.insert(column_name.clone(), header.clone());
+    }
+
+    for pat in &override_profile.file_patterns {
+        config.add_file_pattern(pat.to_string());
+    }
+
+    for column_name in &override_profile.url_decode {
+        config.add_url_decode_column(column_name.clone());
+    }
+
+    for column_name in &override_profile.numeric_columns {
+        config.add_numeric_column(column_name.clone());
+    }
+
+    for (column_name, pattern) in &override_profile.column_regexes {
+        config.add_column_regex(column_name.clone(), pattern)
+            .map_err(|e| format!("Profile '{}': the regex for column '{}' is invalid: {}", override_profile.name, column_name, e))?;
+    }
+
+    for (category, pattern) in &override_profile.category_regexes {
+        config.add_category_regex(category.clone(), pattern)
+            .map_err(|e| format!("Profile '{}': the regex for category '{}' is invalid: {}", override_profile.name, category, e))?;
+    }
+
+    config.set_timestamp_format(&override_profile.timestamp_format)
+        .map_err(|e| format!("Profile '{}': {}", override_profile.name, e))?;
+    config.set_field_separator(&override_profile.field_separator);
+    for level in &override_profile.log_levels {
+        config.add_log_level(level);
+    }
+    config.set_from_and_to(&override_profile.from, &override_profile.to);
+    Ok(())
 }
 
 #[cfg(test)]
 mod get_config_tests {
     use super::*;
+    use crate::parsed_line::ParsedLine;
 
     fn make_profiles_with_override() -> ProfileSet {
         let mut profiles = ProfileSet::default();
@@ -250,35 +863,91 @@ mod get_config_tests {
         let mut args = Arguments::default();
 
         args.quiet = Some(true);
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
         assert!(config.quiet);
 
         args.quiet = Some(false);
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
         assert!(!config.quiet);
     }
 
+    #[test]
+    pub fn timing_stats_by_implies_timing_stats() {
+        let args = Arguments { timing_stats_by: Some("Action".to_string()), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        assert!(config.timing_stats);
+        assert_eq!(config.timing_stats_by, Some("Action".to_string()));
+    }
+
     #[test]
     pub fn sets_command_line_arguments_max_message_length_correctly() {
         let profiles = ProfileSet::default();
         let mut args = Arguments::default();
 
         args.max_message_length = Some(20);
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
         assert_eq!(config.max_message_length, 20);
 
         args.max_message_length = None;
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
         assert_eq!(config.max_message_length, DEFAULT_MAX_MESSAGE_LENGTH);
     }
 
+    #[test]
+    pub fn sets_command_line_arguments_output_format_correctly() {
+        let profiles = ProfileSet::default();
+        let mut args = Arguments::default();
+
+        let config = get_config(&profiles, &args).unwrap();
+        assert_eq!(config.output_format, OutputFormat::Csv);
+
+        args.output_format = Some(OutputFormat::Json);
+        let config = get_config(&profiles, &args).unwrap();
+        assert_eq!(config.output_format, OutputFormat::Json);
+    }
+
+    #[test]
+    pub fn sets_command_line_arguments_delimiter_correctly() {
+        let profiles = ProfileSet::default();
+        let mut args = Arguments::default();
+
+        let config = get_config(&profiles, &args).unwrap();
+        assert_eq!(config.delimiter, b',');
+
+        args.delimiter = Some(b';');
+        let config = get_config(&profiles, &args).unwrap();
+        assert_eq!(config.delimiter, b';');
+
+        args.tsv = true;
+        let config = get_config(&profiles, &args).unwrap();
+        assert_eq!(config.delimiter, b'\t');
+    }
+
+    #[test]
+    pub fn output_format_parses_case_insensitively() {
+        assert_eq!("csv".parse::<OutputFormat>(), Ok(OutputFormat::Csv));
+        assert_eq!("JSON".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+        assert_eq!("ndjson".parse::<OutputFormat>(), Ok(OutputFormat::Ndjson));
+        assert_eq!("Pretty".parse::<OutputFormat>(), Ok(OutputFormat::Pretty));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    pub fn for_highlight_output_is_pretty_and_goes_to_stdout() {
+        let args = Arguments { highlight: true, per_file: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        assert_eq!(config.output_format, OutputFormat::Pretty);
+        assert!(config.stdout);
+        assert!(!config.per_file);
+    }
+
     #[test]
     pub fn for_no_default_profile_returns_blank() {
         let profiles = ProfileSet::default();
         let mut args = Arguments::default();
         args.no_default_profile = true;
 
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
         assert_eq!(config.name, "blank");
         assert!(config.columns.is_empty());
     }
@@ -289,7 +958,7 @@ mod get_config_tests {
         let mut args = Arguments::default();
         args.profile = "over".to_string();
 
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
         assert_eq!(config.name, "over");
         assert_eq!(config.quiet, true);
     }
@@ -300,7 +969,7 @@ mod get_config_tests {
         let mut args = Arguments::default();
         args.profile = "over".to_string();
 
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
 
         assert!(config.columns.contains(&"col1".to_string()));
         assert!(config.columns.contains(&"col2".to_string()));
@@ -313,7 +982,7 @@ mod get_config_tests {
         let mut args = Arguments::default();
         args.profile = "over".to_string();
 
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
 
         assert!(config.alternate_column_names["PID"].contains(&"ProcessId".to_string()));
         assert!(config.alternate_column_names["PID"].contains(&"ProcId".to_string()));
@@ -326,7 +995,7 @@ mod get_config_tests {
         let mut args = Arguments::default();
         args.profile = "over".to_string();
 
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
 
         assert_eq!(config.file_patterns, vec!["case*.log"]);
     }
@@ -342,8 +1011,312 @@ mod get_config_tests {
         args.profile = "over".to_string();
         args.files.clear();
 
-        let config = get_config(&profiles, &args);
+        let config = get_config(&profiles, &args).unwrap();
+
+        assert_eq!(config.file_patterns, vec!["*.log", "*.log.gz"]);
+    }
+
+    #[test]
+    pub fn for_no_file_patterns_and_recursive_adds_recursive_default() {
+        let args = Arguments { recursive: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        assert_eq!(config.file_patterns, vec!["**/*.log", "**/*.log.gz"]);
+    }
+
+    #[test]
+    pub fn for_file_patterns_and_recursive_keeps_file_patterns() {
+        let args = Arguments { recursive: true, files: vec!["app*.log".to_string()], .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        assert_eq!(config.file_patterns, vec!["app*.log"]);
+    }
+
+    #[test]
+    pub fn for_no_output_args_uses_default_file_names() {
+        let args = Arguments { output_format: Some(OutputFormat::Json), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert_eq!(config.success_file(), PathBuf::from("consolidated.json"));
+        assert_eq!(config.error_file(), PathBuf::from("errors.json"));
+    }
+
+    #[test]
+    pub fn for_output_arg_derives_error_file_from_it() {
+        let args = Arguments { output: Some(PathBuf::from("out/run1.csv")), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert_eq!(config.success_file(), PathBuf::from("out/run1.csv"));
+        assert_eq!(config.error_file(), PathBuf::from("out/run1.errors.csv"));
+    }
+
+    #[test]
+    pub fn for_gzip_args_adds_gz_extensions() {
+        let args = Arguments { gzip_output: true, gzip_errors: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert_eq!(config.success_file(), PathBuf::from("consolidated.csv.gz"));
+        assert_eq!(config.error_file(), PathBuf::from("errors.csv.gz"));
+    }
+
+    #[test]
+    pub fn for_gzip_output_with_gz_output_arg_does_not_add_another() {
+        let args = Arguments { output: Some(PathBuf::from("run1.csv.gz")), gzip_output: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert_eq!(config.success_file(), PathBuf::from("run1.csv.gz"));
+        assert_eq!(config.error_file(), PathBuf::from("run1.errors.csv"));
+    }
+
+    #[test]
+    pub fn for_error_output_arg_uses_it() {
+        let args = Arguments {
+            output: Some(PathBuf::from("out/run1.csv")),
+            error_output: Some(PathBuf::from("bad.csv")),
+            .. Arguments::default()
+        };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert_eq!(config.error_file(), PathBuf::from("bad.csv"));
+    }
+
+    #[test]
+    pub fn for_dash_file_reads_stdin() {
+        let args = Arguments { files: vec!["-".to_string()], .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert!(config.stdin);
+        assert!(!config.file_patterns.contains(&"-".to_string()));
+    }
+
+    #[test]
+    pub fn override_profile_adds_log_levels_to_defaults() {
+        let mut p = make_override_profile();
+        p.log_levels = vec!["INFO".to_string(), "[CRITICAL]".to_string(), "[ERROR]".to_string()];
+        let mut profiles = ProfileSet::default();
+        profiles.insert(p);
+        let args = Arguments { profile: "over".to_string(), .. Arguments::default() };
+
+        let config = get_config(&profiles, &args).unwrap();
+
+        let levels = &config.parse_options.log_levels;
+        assert_eq!(levels.len(), kvp::LOG_LEVELS.len() + 2);
+        assert_eq!(levels[0], b"[INFO_]");
+        assert_eq!(&levels[levels.len() - 2..], &[b"[INFO]".to_vec(), b"[CRITICAL]".to_vec()]);
+    }
+
+    #[test]
+    pub fn without_default_profile_uses_only_the_profile_log_levels() {
+        let mut p = make_override_profile();
+        p.log_levels = vec!["TRACE".to_string(), "[NOTICE]".to_string()];
+        let mut profiles = ProfileSet::default();
+        profiles.insert(p);
+        let args = Arguments { profile: "over".to_string(), no_default_profile: true, .. Arguments::default() };
+
+        let config = get_config(&profiles, &args).unwrap();
+        assert_eq!(config.parse_options.log_levels, vec![b"[TRACE]".to_vec(), b"[NOTICE]".to_vec()]);
+
+        let parse = |line: &'static [u8]| ParsedLine::parse_with_options(line, &config.parse_options).unwrap();
+        assert_eq!(parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [NOTICE] | Message").log_level, b"[NOTICE]");
+        assert!(parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | Message").log_level.is_empty());
+    }
+
+    #[test]
+    pub fn without_any_log_levels_uses_the_built_in_ones() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        assert_eq!(config.parse_options.log_levels.len(), kvp::LOG_LEVELS.len());
+    }
+
+    #[test]
+    pub fn for_level_args_in_any_case_with_or_without_brackets_uses_the_known_levels() {
+        let args = Arguments { levels: vec!["error".to_string(), "[debg1]".to_string(), "Warng".to_string()], .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        assert_eq!(config.log_levels, vec![b"[ERROR]".to_vec(), b"[DEBG1]".to_vec(), b"[WARNG]".to_vec()]);
+    }
+
+    #[test]
+    pub fn for_unknown_level_arg_returns_error() {
+        let args = Arguments { levels: vec!["warning".to_string()], .. Arguments::default() };
+        let msg = get_config(&ProfileSet::default(), &args).unwrap_err();
+        assert!(msg.contains("Unknown log level 'warning'"));
+        assert!(msg.contains("[WARNG]"));
+    }
+
+    #[test]
+    pub fn for_level_arg_from_override_profile_uses_it() {
+        let mut p = make_override_profile();
+        p.log_levels = vec!["[CRITICAL]".to_string()];
+        let mut profiles = ProfileSet::default();
+        profiles.insert(p);
+        let make_args = |profile: &str| Arguments { profile: profile.to_string(), levels: vec!["critical".to_string()], .. Arguments::default() };
+
+        let config = get_config(&profiles, &make_args("over")).unwrap();
+        assert_eq!(config.log_levels, vec![b"[CRITICAL]".to_vec()]);
+
+        let msg = get_config(&profiles, &make_args(DEFAULT_PROFILE_NAME)).unwrap_err();
+        assert!(msg.contains("Unknown log level 'critical'"));
+    }
+
+    #[test]
+    pub fn field_separator_comes_from_profile_unless_overridden() {
+        let mut p = make_override_profile();
+        p.field_separator = Some(" :: ".to_string());
+        let mut profiles = ProfileSet::default();
+        profiles.insert(p);
+
+        let args = Arguments { profile: "over".to_string(), .. Arguments::default() };
+        assert_eq!(get_config(&profiles, &args).unwrap().parse_options.field_separator, b"::");
+
+        let args = Arguments { profile: "over".to_string(), field_separator: Some(";".to_string()), .. Arguments::default() };
+        assert_eq!(get_config(&profiles, &args).unwrap().parse_options.field_separator, b";");
+
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        assert_eq!(config.parse_options.field_separator, b"|");
+    }
+
+    #[test]
+    pub fn override_profile_sets_timestamp_format() {
+        let mut p = make_override_profile();
+        p.timestamp_format = Some("%d/%m/%Y %H:%M:%S".to_string());
+        let mut profiles = ProfileSet::default();
+        profiles.insert(p);
+        let args = Arguments { profile: "over".to_string(), .. Arguments::default() };
+
+        let config = get_config(&profiles, &args).unwrap();
+
+        assert_eq!(config.parse_options.timestamp_format, Some("%d/%m/%Y %H:%M:%S".parse().unwrap()));
+    }
+
+    #[test]
+    pub fn set_timestamp_format_for_invalid_format_returns_error_and_keeps_format() {
+        let mut config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        config.set_timestamp_format(&Some("%d/%m/%Y %H:%M:%S".to_string())).unwrap();
 
-        assert_eq!(config.file_patterns, vec!["*.log"]);
+        let result = config.set_timestamp_format(&Some("%d/%m/%Y %q".to_string()));
+
+        assert!(result.unwrap_err().contains("Unsupported specifier '%q'"));
+        assert_eq!(config.parse_options.timestamp_format, Some("%d/%m/%Y %H:%M:%S".parse().unwrap()));
+    }
+
+    #[test]
+    pub fn override_profile_with_two_levels_of_extends_merges_the_chain() {
+        let mut base = Profile::blank();
+        base.name = "base".to_string();
+        base.quiet = Some(true);
+        base.add_column("BaseCol");
+        base.add_alternate_column("PID", "ProcessId");
+        base.column_headers.insert("BaseCol".to_string(), "Base".to_string());
+
+        let mut mid = Profile::blank();
+        mid.name = "mid".to_string();
+        mid.extends = Some("base".to_string());
+        mid.add_column("MidCol");
+        mid.column_headers.insert("BaseCol".to_string(), "Mid".to_string());
+
+        let mut svc = Profile::blank();
+        svc.name = "svc".to_string();
+        svc.extends = Some("mid".to_string());
+        svc.quiet = Some(false);
+        svc.add_column("SvcCol");
+        svc.add_alternate_column("PID", "ProcId");
+
+        let mut profiles = ProfileSet::default();
+        profiles.insert(base);
+        profiles.insert(mid);
+        profiles.insert(svc);
+        let args = Arguments { profile: "svc".to_string(), no_default_profile: true, .. Arguments::default() };
+
+        let config = get_config(&profiles, &args).unwrap();
+
+        assert_eq!(config.name, "svc");
+        assert!(!config.quiet);
+        assert_eq!(config.columns, vec!["BaseCol", "MidCol", "SvcCol"]);
+        assert_eq!(config.alternate_column_names["PID"], vec!["ProcessId", "ProcId"]);
+        assert_eq!(config.column_headers["BaseCol"], "Mid");
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn override_profile_with_extends_cycle_returns_error() {
+        let mut a = Profile::blank();
+        a.name = "a".to_string();
+        a.extends = Some("b".to_string());
+        let mut b = Profile::blank();
+        b.name = "b".to_string();
+        b.extends = Some("a".to_string());
+
+        let mut profiles = ProfileSet::default();
+        profiles.insert(a);
+        profiles.insert(b);
+        let args = Arguments { profile: "a".to_string(), .. Arguments::default() };
+
+        assert_eq!(get_config(&profiles, &args).unwrap_err(), "Profile 'a' extends itself: a -> b -> a");
+    }
+
+    #[test]
+    pub fn override_profile_with_invalid_regex_returns_error() {
+        let mut p = make_override_profile();
+        p.column_regexes.insert("Action".to_string(), "(".to_string());
+        let mut profiles = ProfileSet::default();
+        profiles.insert(p);
+        let args = Arguments { profile: "over".to_string(), .. Arguments::default() };
+
+        let msg = get_config(&profiles, &args).unwrap_err();
+
+        assert!(msg.starts_with("Profile 'over': the regex for column 'Action' is invalid"), "{}", msg);
+    }
+
+    #[test]
+    pub fn override_profile_with_invalid_timestamp_format_returns_error() {
+        let mut p = make_override_profile();
+        p.timestamp_format = Some("%d/%m/%Y %q".to_string());
+        let mut profiles = ProfileSet::default();
+        profiles.insert(p);
+        let args = Arguments { profile: "over".to_string(), .. Arguments::default() };
+
+        let msg = get_config(&profiles, &args).unwrap_err();
+
+        assert!(msg.starts_with("Profile 'over': ") && msg.contains("Unsupported specifier '%q'"), "{}", msg);
+    }
+
+    #[test]
+    pub fn default_profile_has_no_timestamp_format() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        assert!(config.parse_options.timestamp_format.is_none());
+    }
+
+    #[test]
+    pub fn columns_arg_replaces_profile_columns_in_order() {
+        let args = Arguments {
+            profile: "over".to_string(),
+            columns: vec!["SysRef".to_string(), "LogDate".to_string(), "NewCol".to_string()],
+            .. Arguments::default()
+        };
+        let config = get_config(&make_profiles_with_override(), &args).unwrap();
+
+        assert_eq!(config.columns, vec!["SysRef", "LogDate", "NewCol"]);
+        assert!(config.column_regexes.contains_key("NewCol"));
+    }
+
+    #[test]
+    pub fn split_date_arg_replaces_log_date_column() {
+        let args = Arguments { columns: vec!["Message".to_string(), "LogDate".to_string(), "pid".to_string()], split_date: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert_eq!(config.columns, vec!["Message", kvp::LOG_DATE_ONLY, kvp::LOG_TIME_ONLY, "pid"]);
+    }
+
+    #[test]
+    pub fn split_date_arg_without_log_date_column_adds_columns() {
+        let args = Arguments { columns: vec!["Message".to_string()], split_date: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert_eq!(config.columns, vec!["Message", kvp::LOG_DATE_ONLY, kvp::LOG_TIME_ONLY]);
+    }
+
+    #[test]
+    pub fn columns_arg_keeps_classify_column() {
+        let args = Arguments { columns: vec!["Message".to_string()], classify: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert_eq!(config.columns, vec!["Message", kvp::CATEGORY]);
+    }
+}
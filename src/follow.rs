@@ -0,0 +1,194 @@
+//! This module implements `--follow`, which works like `tail -f`: the lines appended to
+//! a file are parsed and written to stdout as they arrive. There is no sorting or
+//! merging, since the lines are written as soon as they are complete.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use crate::configuration::Configuration;
+use crate::inputs::InputFile;
+use crate::output::FollowWriter;
+use crate::parsed_file::parse_line_at;
+use crate::parsed_line::find_lines;
+use crate::should_output_line;
+
+/// How long to wait before checking the file for new data again.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Follows a file as it grows, writing each new line to stdout as CSV. The line numbers
+/// count from the point at which following started. This only returns on error, e.g.
+/// when stdout is closed.
+pub fn follow(config: &Configuration, input_file: &InputFile) -> Result<(), io::Error> {
+    let mut follower = Follower::new(&input_file.path)?;
+    let mut writer = FollowWriter::new(config, Box::new(io::stdout()))?;
+    let mut line_num = 0;
+
+    loop {
+        let bytes = follower.read_complete_lines()?;
+        if bytes.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        for line in find_lines(&bytes) {
+            let result = parse_line_at(line, &input_file.filename_only_as_string, &input_file.path_as_string, line_num, &config.parse_options);
+            line_num += 1;
+
+            if !should_output_line(config, &result) {
+                continue;
+            }
+
+            match result {
+                Ok(parsed_line) => writer.write_line(&parsed_line)?,
+                Err(e) => eprintln!("Error on line {}: {}", e.line_num, e.error),
+            }
+        }
+    }
+}
+
+/// Reads the data appended to a file. If the file is truncated, or replaced by a new
+/// file as happens when logs are rotated, it is read again from the start.
+struct Follower {
+    path: PathBuf,
+    file: File,
+    position: u64,
+    pending: Vec<u8>,
+}
+
+impl Follower {
+    /// Opens the file, positioned at its end so that only new data is read.
+    fn new(path: &Path) -> Result<Self, io::Error> {
+        let file = File::open(path)?;
+        let position = file.metadata()?.len();
+        Ok(Follower { path: path.to_owned(), file, position, pending: Vec::new() })
+    }
+
+    /// Reads any new data and returns the lines that are complete, i.e. have a line
+    /// ending, which may be none. An incomplete line is kept until the rest of it arrives.
+    fn read_complete_lines(&mut self) -> Result<Vec<u8>, io::Error> {
+        if self.is_replaced() {
+            self.file = File::open(&self.path)?;
+            self.position = 0;
+            self.pending.clear();
+        } else if self.file.metadata()?.len() < self.position {
+            self.position = 0;
+            self.pending.clear();
+        }
+
+        self.file.seek(SeekFrom::Start(self.position))?;
+        let count = self.file.read_to_end(&mut self.pending)?;
+        self.position += count as u64;
+
+        // With Windows line endings a message may contain a bare \n, so only a \r\n ends a line.
+        let end = if self.pending.windows(2).any(|w| w == b"\r\n") {
+            self.pending.windows(2).rposition(|w| w == b"\r\n").map(|idx| idx + 2)
+        } else {
+            self.pending.iter().rposition(|&c| c == b'\n').map(|idx| idx + 1)
+        };
+
+        match end {
+            Some(end) => {
+                let rest = self.pending.split_off(end);
+                Ok(std::mem::replace(&mut self.pending, rest))
+            },
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Checks whether the path now refers to a different file from the one that is open.
+    /// While a log is being rotated the path may briefly not exist, in which case we
+    /// carry on with the open file.
+    #[cfg(unix)]
+    fn is_replaced(&self) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        match (std::fs::metadata(&self.path), self.file.metadata()) {
+            (Ok(current), Ok(open)) => current.ino() != open.ino() || current.dev() != open.dev(),
+            _ => false,
+        }
+    }
+
+    /// Files have no inode number here, so a replaced file is only detected if it is
+    /// shorter than the one that was being followed, which looks like a truncation.
+    #[cfg(not(unix))]
+    fn is_replaced(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod follower_tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use crate::output::test_utils::make_temp_dir;
+
+    fn append(path: &Path, text: &str) {
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    pub fn reads_only_data_appended_after_opening() {
+        let path = make_temp_dir("follow_appended").join("app.log");
+        fs::write(&path, "2018-09-26 12:34:56.0000000 | Old\n").unwrap();
+
+        let mut follower = Follower::new(&path).unwrap();
+        assert!(follower.read_complete_lines().unwrap().is_empty());
+
+        append(&path, "2018-09-26 12:34:57.0000000 | New\n");
+        assert_eq!(follower.read_complete_lines().unwrap(), b"2018-09-26 12:34:57.0000000 | New\n");
+        assert!(follower.read_complete_lines().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn keeps_incomplete_line_until_it_is_finished() {
+        let path = make_temp_dir("follow_incomplete").join("app.log");
+        fs::write(&path, "").unwrap();
+
+        let mut follower = Follower::new(&path).unwrap();
+        append(&path, "2018-09-26 12:34:56.0000000 | One\n2018-09-26 12:34:57.0000000 | Tw");
+        assert_eq!(follower.read_complete_lines().unwrap(), b"2018-09-26 12:34:56.0000000 | One\n");
+
+        append(&path, "o\n");
+        assert_eq!(follower.read_complete_lines().unwrap(), b"2018-09-26 12:34:57.0000000 | Two\n");
+    }
+
+    #[test]
+    pub fn for_windows_endings_waits_for_crlf() {
+        let path = make_temp_dir("follow_windows").join("app.log");
+        fs::write(&path, "").unwrap();
+
+        let mut follower = Follower::new(&path).unwrap();
+        append(&path, "2018-09-26 12:34:56.0000000 | One\r\n2018-09-26 12:34:57.0000000 | Two\nstill two");
+        assert_eq!(follower.read_complete_lines().unwrap(), b"2018-09-26 12:34:56.0000000 | One\r\n");
+
+        append(&path, "\r\n");
+        assert_eq!(follower.read_complete_lines().unwrap(), b"2018-09-26 12:34:57.0000000 | Two\nstill two\r\n");
+    }
+
+    #[test]
+    pub fn for_truncated_file_reads_from_start() {
+        let path = make_temp_dir("follow_truncated").join("app.log");
+        fs::write(&path, "2018-09-26 12:34:56.0000000 | A long line before truncation\n").unwrap();
+
+        let mut follower = Follower::new(&path).unwrap();
+        fs::write(&path, "2018-09-26 12:34:57.0000000 | After\n").unwrap();
+        assert_eq!(follower.read_complete_lines().unwrap(), b"2018-09-26 12:34:57.0000000 | After\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn for_rotated_file_reads_new_file_from_start() {
+        let dir = make_temp_dir("follow_rotated");
+        let path = dir.join("app.log");
+        fs::write(&path, "2018-09-26 12:34:56.0000000 | Before\n").unwrap();
+
+        let mut follower = Follower::new(&path).unwrap();
+        fs::rename(&path, dir.join("app.log.1")).unwrap();
+        fs::write(&path, "2018-09-26 12:34:57.0000000 | After rotation, which is a longer line\n").unwrap();
+        assert_eq!(follower.read_complete_lines().unwrap(), b"2018-09-26 12:34:57.0000000 | After rotation, which is a longer line\n");
+    }
+}
@@ -0,0 +1,183 @@
+//! This module implements `--group-by`, which summarises the lines that share a value
+//! in a column, such as all the lines for one CorrelationKey, as a single row.
+
+use std::collections::HashMap;
+use crate::configuration::Configuration;
+use crate::output::get_column_value;
+use crate::parsed_line::ParseLineResult;
+
+/// The key of the group for lines which have no value in the group-by column.
+pub const MISSING_KEY: &str = "(none)";
+
+/// The separator written between the messages of the lines in a group.
+const MESSAGE_SEPARATOR: &[u8] = b"; ";
+
+/// A summary of all the lines which have the same value in the group-by column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub key: Vec<u8>,
+    pub first_log_date: Vec<u8>,
+    pub last_log_date: Vec<u8>,
+    pub count: usize,
+
+    /// The messages of the lines, in line order, truncated to the maximum message length.
+    pub messages: Vec<u8>,
+}
+
+impl Group {
+    fn new(key: &[u8], log_date: &[u8]) -> Self {
+        Group {
+            key: key.to_vec(),
+            first_log_date: log_date.to_vec(),
+            last_log_date: log_date.to_vec(),
+            count: 0,
+            messages: vec![],
+        }
+    }
+
+    fn add(&mut self, log_date: &[u8], message: &[u8], max_message_length: usize) {
+        // The lines are not necessarily sorted by date, e.g. with `--no-sort` or `--reverse`.
+        if log_date < self.first_log_date.as_slice() {
+            self.first_log_date = log_date.to_vec();
+        }
+        if log_date > self.last_log_date.as_slice() {
+            self.last_log_date = log_date.to_vec();
+        }
+
+        if self.messages.len() < max_message_length {
+            if self.count > 0 {
+                self.messages.extend_from_slice(MESSAGE_SEPARATOR);
+            }
+            self.messages.extend_from_slice(message);
+            self.messages.truncate(max_message_length);
+        }
+
+        self.count += 1;
+    }
+}
+
+/// Groups the successfully parsed lines by their value in the `group_by` column. The
+/// groups are returned in the order in which their first line appears. Lines which
+/// have no value are skipped, unless `group_missing` is set.
+pub fn group_lines(config: &Configuration, results: &[ParseLineResult]) -> Vec<Group> {
+    let column = match config.group_by {
+        Some(ref column) => column,
+        None => return vec![],
+    };
+
+    let mut groups: Vec<Group> = vec![];
+    let mut indexes: HashMap<&[u8], usize> = HashMap::new();
+
+    for line in results.iter().filter_map(|r| r.as_ref().ok()) {
+        let key = match get_column_value(config, line, column) {
+            Some(value) if !value.is_empty() => value.as_ref(),
+            _ if config.group_missing => MISSING_KEY.as_bytes(),
+            _ => continue,
+        };
+
+        let index = *indexes.entry(key).or_insert_with(|| {
+            groups.push(Group::new(key, &line.log_date));
+            groups.len() - 1
+        });
+
+        groups[index].add(&line.log_date, &line.message, config.max_message_length);
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod group_lines_tests {
+    use super::*;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::parsed_line::ParsedLine;
+    use crate::profiles::ProfileSet;
+
+    fn make_config(group_missing: bool, max_message_length: usize) -> Configuration {
+        let args = Arguments {
+            no_default_profile: true,
+            group_by: Some("CorrelationKey".to_string()),
+            group_missing,
+            max_message_length: Some(max_message_length),
+            .. Arguments::default()
+        };
+        get_config(&ProfileSet::default(), &args).unwrap()
+    }
+
+    fn parse(lines: &[&'static str]) -> Vec<ParseLineResult<'static>> {
+        lines.iter().map(|line| ParsedLine::parse(line.as_bytes())).collect()
+    }
+
+    #[test]
+    pub fn groups_lines_by_key_in_order_of_first_appearance() {
+        let config = make_config(false, 1000);
+        let results = parse(&[
+            "2018-09-26 12:34:56.1000000 | Start CorrelationKey=B",
+            "2018-09-26 12:34:57.1000000 | Begin CorrelationKey=A",
+            "not a log line",
+            "2018-09-26 12:34:58.1000000 | End CorrelationKey=B",
+        ]);
+
+        let groups = group_lines(&config, &results);
+        assert_eq!(groups.len(), 2);
+
+        assert_eq!(groups[0].key, b"B");
+        assert_eq!(groups[0].first_log_date, b"2018-09-26 12:34:56.1000000");
+        assert_eq!(groups[0].last_log_date, b"2018-09-26 12:34:58.1000000");
+        assert_eq!(groups[0].count, 2);
+        assert_eq!(groups[0].messages, b"Start CorrelationKey=B; End CorrelationKey=B".to_vec());
+
+        assert_eq!(groups[1].key, b"A");
+        assert_eq!(groups[1].count, 1);
+    }
+
+    #[test]
+    pub fn for_unsorted_lines_finds_earliest_and_latest_dates() {
+        let config = make_config(false, 1000);
+        let results = parse(&[
+            "2018-09-26 12:34:57.1000000 | B CorrelationKey=A",
+            "2018-09-26 12:34:56.1000000 | A CorrelationKey=A",
+            "2018-09-26 12:34:58.1000000 | C CorrelationKey=A",
+        ]);
+
+        let groups = group_lines(&config, &results);
+        assert_eq!(groups[0].first_log_date, b"2018-09-26 12:34:56.1000000");
+        assert_eq!(groups[0].last_log_date, b"2018-09-26 12:34:58.1000000");
+    }
+
+    #[test]
+    pub fn for_lines_without_key_skips_them_by_default() {
+        let config = make_config(false, 1000);
+        let results = parse(&["2018-09-26 12:34:56.1000000 | No key here"]);
+        assert!(group_lines(&config, &results).is_empty());
+    }
+
+    #[test]
+    pub fn for_lines_without_key_and_group_missing_groups_them_under_none() {
+        let config = make_config(true, 1000);
+        let results = parse(&[
+            "2018-09-26 12:34:56.1000000 | No key here",
+            "2018-09-26 12:34:57.1000000 | Nor here CorrelationKey=",
+        ]);
+
+        let groups = group_lines(&config, &results);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].key, MISSING_KEY.as_bytes());
+        assert_eq!(groups[0].count, 2);
+    }
+
+    #[test]
+    pub fn truncates_messages_to_max_message_length() {
+        let config = make_config(false, 12);
+        let results = parse(&[
+            "2018-09-26 12:34:56.1000000 | Hello CorrelationKey=A",
+            "2018-09-26 12:34:57.1000000 | World CorrelationKey=A",
+            "2018-09-26 12:34:58.1000000 | Again CorrelationKey=A",
+        ]);
+
+        let groups = group_lines(&config, &results);
+        assert_eq!(groups[0].messages, b"Hello Correl".to_vec());
+        assert_eq!(groups[0].count, 3);
+    }
+}
@@ -1,6 +1,11 @@
-use std::fs;
-use std::path::{Path, PathBuf};
-use crate::configuration::Configuration;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::PathBuf;
+use flate2::read::GzDecoder;
+use memmap2::Mmap;
+use crate::configuration::{has_gz_extension, Configuration};
 
 /// The inputs module represents the set of files to be processed by the program.
 /// The top-level struct is 'Inputs'. This is constructed based on the 'Configuration'.
@@ -22,7 +27,8 @@ pub struct InputFile {
     pub length: usize,
     pub path_as_string: String,
     pub filename_only_as_string: String,
-    pub output_path: String
+    pub output_path: String,
+    pub is_stdin: bool,
 }
 
 impl InputFile {
@@ -41,21 +47,190 @@ impl InputFile {
             path_as_string,
             filename_only_as_string: filename_as_string,
             output_path,
+            is_stdin: false,
+        }
+    }
+
+    /// Construct an InputFile which represents stdin. The length is not known in advance.
+    pub fn stdin() -> Self {
+        InputFile {
+            path: PathBuf::from(STDIN_NAME),
+            path_as_string: STDIN_NAME.to_string(),
+            filename_only_as_string: STDIN_NAME.to_string(),
+            is_stdin: true,
+            .. InputFile::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl InputFile {
+    /// Construct an InputFile without touching the file system, for tests which
+    /// only need the names.
+    pub fn with_path(path: &str) -> Self {
+        let path = PathBuf::from(path);
+        InputFile {
+            path_as_string: path.to_str().unwrap().to_owned(),
+            filename_only_as_string: path.file_name().unwrap().to_str().unwrap().to_owned(),
+            path,
+            .. InputFile::default()
+        }
+    }
+}
+
+impl InputFile {
+    /// Reads the entire file into memory. Files which are gzipped - either because they
+    /// have a ".gz" extension or because they start with the gzip magic bytes - are
+    /// transparently decompressed. Files which start with a UTF-16 byte order mark are
+    /// transcoded to UTF-8.
+    pub fn read(&self) -> Result<Vec<u8>, io::Error> {
+        let bytes = if self.is_stdin {
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes)?;
+            bytes
+        } else {
+            fs::read(&self.path)?
+        };
+
+        let bytes = if self.has_gz_extension() || is_gzipped(&bytes) {
+            decompress(&bytes)?
+        } else {
+            bytes
+        };
+
+        if is_utf16(&bytes) {
+            utf16_to_utf8(&bytes)
+        } else {
+            Ok(bytes)
         }
     }
 }
 
+impl InputFile {
+    /// Memory-maps the file rather than reading it, which avoids a large allocation and
+    /// lets the OS page the data in as needed. Stdin, empty files (which cannot be mapped
+    /// on some platforms), gzipped files and UTF-16 files are read into memory instead.
+    pub fn map(&self) -> Result<FileBytes, io::Error> {
+        if self.is_stdin || self.has_gz_extension() {
+            return self.read().map(FileBytes::Read);
+        }
+
+        let file = File::open(&self.path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(FileBytes::Read(Vec::new()));
+        }
+
+        // This is unsafe because the file could be modified while it is mapped. Log files
+        // are normally only appended to, and we never look beyond the original length.
+        let map = unsafe { Mmap::map(&file)? };
+        if is_gzipped(&map) || is_utf16(&map) {
+            self.read().map(FileBytes::Read)
+        } else {
+            Ok(FileBytes::Mapped(map))
+        }
+    }
+
+    fn has_gz_extension(&self) -> bool {
+        has_gz_extension(&self.path)
+    }
+}
+
+/// The bytes of an input file, either read into memory or memory-mapped.
+pub enum FileBytes {
+    Read(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Deref for FileBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileBytes::Read(bytes) => bytes,
+            FileBytes::Mapped(map) => map,
+        }
+    }
+}
+
+/// The name used as the source of lines read from stdin.
+pub const STDIN_NAME: &str = "<stdin>";
+
+const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+
+fn is_gzipped(bytes: &[u8]) -> bool {
+    bytes.starts_with(&GZIP_MAGIC_BYTES)
+}
+
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut decompressed = Vec::with_capacity(bytes.len() * 4);
+    GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+/// The number of bytes at the start of a file which `looks_binary` examines.
+const BINARY_SAMPLE_LEN: usize = 8192;
+
+/// Checks whether the start of a file looks like binary data rather than text, i.e. more
+/// than `threshold` percent of it are NULs or other control characters. Tabs, line breaks,
+/// form feeds and escapes (which colour the output of some loggers) are normal in text.
+pub fn looks_binary(bytes: &[u8], threshold: usize) -> bool {
+    let sample = &bytes[..bytes.len().min(BINARY_SAMPLE_LEN)];
+    let control_count = sample.iter()
+        .filter(|&&c| c.is_ascii_control() && !matches!(c, b'\t' | b'\n' | b'\r' | b'\x0c' | b'\x1b'))
+        .count();
+
+    control_count * 100 > sample.len() * threshold
+}
+
+const UTF16_LE_BOM: [u8; 2] = [0xff, 0xfe];
+const UTF16_BE_BOM: [u8; 2] = [0xfe, 0xff];
+
+fn is_utf16(bytes: &[u8]) -> bool {
+    bytes.starts_with(&UTF16_LE_BOM) || bytes.starts_with(&UTF16_BE_BOM)
+}
+
+/// Transcodes UTF-16 with a byte order mark to UTF-8, so that the rest of the program only
+/// ever has to deal with UTF-8 (or ASCII). The byte order mark is dropped. Invalid characters,
+/// such as unpaired surrogates, are replaced with U+FFFD.
+fn utf16_to_utf8(bytes: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let data = &bytes[UTF16_LE_BOM.len()..];
+    if !data.len().is_multiple_of(2) {
+        let msg = "The file starts with a UTF-16 byte order mark but has an odd number of bytes";
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    let little_endian = bytes.starts_with(&UTF16_LE_BOM);
+    let units = data.chunks(2).map(|pair| {
+        let pair = [pair[0], pair[1]];
+        if little_endian { u16::from_le_bytes(pair) } else { u16::from_be_bytes(pair) }
+    });
+
+    let text: String = std::char::decode_utf16(units)
+        .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER))
+        .collect();
+    Ok(text.into_bytes())
+}
+
 impl Inputs {
     pub fn new_from_config(config: &Configuration) -> Self {
         use glob::glob;
 
         let mut i = Inputs::default();
 
-        // Determine available input files.
+        // In stdin mode there is nothing to glob.
+        if config.stdin {
+            i.files.push(InputFile::stdin());
+            return i;
+        }
+
+        // Determine available input files. The same file can be matched by more than one
+        // pattern, possibly via different paths such as "./a.log" and "a.log", so compare
+        // canonical paths to avoid processing it twice.
+        let mut seen = HashSet::new();
         for path in &config.file_patterns {
             for entry in glob(&path).expect("Failed to read glob pattern.") {
                 match entry {
-                    Ok(path) => if !i.contains_file(&path) {
+                    Ok(path) => if seen.insert(fs::canonicalize(&path).unwrap_or_else(|_| path.clone())) {
                         i.files.push(InputFile::new(path))
                     },
                     Err(e) => {
@@ -79,15 +254,240 @@ impl Inputs {
         self.files.is_empty()
     }
 
+    /// The total size of the input files on disk. Stdin counts as nothing, because its
+    /// length is not known in advance.
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.length as u64).sum()
+    }
+
     // pub fn longest_input_name_len(&self) -> usize {
     //     self.files.iter().map(|f| f.filename_only_as_string.len()).max().unwrap()
     // }
+}
 
-    fn contains_file(&self, path: &Path) -> bool {
-        self.files.iter().any(|f| f.path == path)
+#[cfg(test)]
+mod read_tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use crate::output::test_utils::make_temp_dir;
+
+    fn make_temp_file(name: &str, contents: &[u8]) -> InputFile {
+        let path = make_temp_dir(&format!("read-{}", name)).join(name);
+        fs::write(&path, contents).unwrap();
+        InputFile::new(path)
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    pub fn for_plain_file_returns_bytes() {
+        let f = make_temp_file("plain.log", b"Hello\r\n");
+        assert_eq!(f.read().unwrap(), b"Hello\r\n");
+    }
+
+    #[test]
+    pub fn for_gz_extension_decompresses() {
+        let f = make_temp_file("app.log.gz", &gzip(b"Hello\r\n"));
+        assert_eq!(f.read().unwrap(), b"Hello\r\n");
+    }
+
+    #[test]
+    pub fn for_gzip_magic_bytes_decompresses() {
+        let f = make_temp_file("compressed.log", &gzip(b"Hello\r\n"));
+        assert_eq!(f.read().unwrap(), b"Hello\r\n");
+    }
+
+    #[test]
+    pub fn map_for_plain_file_returns_bytes() {
+        let f = make_temp_file("mapped.log", b"Hello\r\n");
+        assert_eq!(&f.map().unwrap()[..], b"Hello\r\n");
+    }
+
+    #[test]
+    pub fn map_for_empty_file_returns_no_bytes() {
+        let f = make_temp_file("empty.log", b"");
+        assert!(f.map().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn map_for_gzipped_file_decompresses() {
+        let f = make_temp_file("mapped.log.gz", &gzip(b"Hello\r\n"));
+        assert_eq!(&f.map().unwrap()[..], b"Hello\r\n");
+        let f = make_temp_file("mapped_compressed.log", &gzip(b"Hello\r\n"));
+        assert_eq!(&f.map().unwrap()[..], b"Hello\r\n");
+    }
+
+    fn utf16(text: &str, little_endian: bool) -> Vec<u8> {
+        let mut bytes = if little_endian { UTF16_LE_BOM.to_vec() } else { UTF16_BE_BOM.to_vec() };
+        for unit in text.encode_utf16() {
+            let pair = if little_endian { unit.to_le_bytes() } else { unit.to_be_bytes() };
+            bytes.extend_from_slice(&pair);
+        }
+        bytes
+    }
+
+    #[test]
+    pub fn for_utf16_le_file_transcodes_to_utf8() {
+        let f = make_temp_file("utf16le.log", &utf16("2018-09-26 12:34:56.1146655 | Café\r\n", true));
+        assert_eq!(f.read().unwrap(), "2018-09-26 12:34:56.1146655 | Café\r\n".as_bytes());
+        assert_eq!(&f.map().unwrap()[..], "2018-09-26 12:34:56.1146655 | Café\r\n".as_bytes());
+    }
+
+    #[test]
+    pub fn for_utf16_be_file_transcodes_to_utf8() {
+        let f = make_temp_file("utf16be.log", &utf16("Hello\r\n", false));
+        assert_eq!(f.read().unwrap(), b"Hello\r\n");
+    }
+
+    #[test]
+    pub fn for_gzipped_utf16_file_decompresses_and_transcodes() {
+        let f = make_temp_file("utf16.log.gz", &gzip(&utf16("Hello\r\n", true)));
+        assert_eq!(f.read().unwrap(), b"Hello\r\n");
+    }
+
+    #[test]
+    pub fn for_utf16_file_with_odd_length_returns_error() {
+        let mut bytes = utf16("Hello", true);
+        bytes.push(b'!');
+        let f = make_temp_file("utf16odd.log", &bytes);
+        assert_eq!(f.read().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    pub fn for_corrupt_gz_file_returns_error() {
+        let f = make_temp_file("corrupt.log.gz", b"\x1f\x8bThis is not really gzipped");
+        assert!(f.read().is_err());
+    }
+}
+
+#[cfg(test)]
+mod looks_binary_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_log_text_returns_false() {
+        assert!(!looks_binary(b"2018-09-26 12:34:56.7654321 | [INFO_] | Hello\tthere\r\n\x1b[31mRed\x1b[0m\r\n", 10));
+    }
+
+    #[test]
+    pub fn for_empty_file_returns_false() {
+        assert!(!looks_binary(b"", 10));
+    }
+
+    #[test]
+    pub fn for_mostly_nuls_returns_true() {
+        let mut bytes = b"PK\x03\x04".to_vec();
+        bytes.extend_from_slice(&[0; 100]);
+        assert!(looks_binary(&bytes, 10));
     }
 
-    pub fn total_bytes(&self) -> usize {
-        self.files.iter().map(|f| f.length).sum()
+    #[test]
+    pub fn compares_proportion_of_control_bytes_with_threshold() {
+        let mut bytes = vec![b'a'; 80];
+        bytes.extend_from_slice(&[1; 20]);
+        assert!(looks_binary(&bytes, 19));
+        assert!(!looks_binary(&bytes, 20));
+    }
+
+    #[test]
+    pub fn only_examines_the_start_of_the_file() {
+        let mut bytes = vec![b'a'; BINARY_SAMPLE_LEN];
+        bytes.extend_from_slice(&[0; BINARY_SAMPLE_LEN]);
+        assert!(!looks_binary(&bytes, 10));
+    }
+}
+
+#[cfg(test)]
+mod new_from_config_tests {
+    use super::*;
+    use crate::arguments::Arguments;
+    use crate::configuration::{default_file_patterns, get_config};
+    use crate::output::test_utils::make_temp_dir;
+    use crate::profiles::ProfileSet;
+
+    #[test]
+    pub fn for_stdin_returns_only_stdin() {
+        let args = Arguments { stdin: true, files: vec!["*.log".to_string()], .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        let inputs = Inputs::new_from_config(&config);
+
+        assert_eq!(inputs.len(), 1);
+        assert!(inputs.files[0].is_stdin);
+        assert_eq!(inputs.files[0].filename_only_as_string, "<stdin>");
+    }
+
+    #[test]
+    pub fn for_double_star_pattern_finds_files_in_subdirectories_once() {
+        let dir = make_temp_dir("recursive");
+        fs::create_dir_all(dir.join("sub").join("deeper")).unwrap();
+        fs::write(dir.join("a.log"), "a").unwrap();
+        fs::write(dir.join("sub").join("b.log"), "b").unwrap();
+        fs::write(dir.join("sub").join("deeper").join("c.log"), "c").unwrap();
+        fs::write(dir.join("sub").join("notes.txt"), "x").unwrap();
+
+        let dir_str = dir.to_str().unwrap();
+        let args = Arguments {
+            files: vec![format!("{}/**/*.log", dir_str), format!("{}/./a.log", dir_str), format!("{}/sub/../a.log", dir_str)],
+            .. Arguments::default()
+        };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        let inputs = Inputs::new_from_config(&config);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut names: Vec<_> = inputs.files.iter().map(|f| f.filename_only_as_string.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["a.log", "b.log", "c.log"]);
+    }
+
+    #[test]
+    pub fn default_file_patterns_find_plain_and_gzipped_logs() {
+        let dir = make_temp_dir("default_patterns");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.log"), "a").unwrap();
+        fs::write(dir.join("a.log.1"), "x").unwrap();
+        fs::write(dir.join("b.log.gz"), "b").unwrap();
+        fs::write(dir.join("sub").join("c.log.gz"), "c").unwrap();
+
+        let dir_str = dir.to_str().unwrap();
+        let find = |recursive| {
+            let files = default_file_patterns(recursive).iter().map(|p| format!("{}/{}", dir_str, p)).collect();
+            let config = get_config(&ProfileSet::default(), &Arguments { files, .. Arguments::default() }).unwrap();
+            let mut names: Vec<_> = Inputs::new_from_config(&config).files.into_iter().map(|f| f.filename_only_as_string).collect();
+            names.sort();
+            names
+        };
+        let names = find(false);
+        let recursive_names = find(true);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(names, vec!["a.log", "b.log.gz"]);
+        assert_eq!(recursive_names, vec!["a.log", "b.log.gz", "c.log.gz"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn for_file_matched_directly_and_via_symlink_adds_it_once_with_path_as_given() {
+        let dir = make_temp_dir("symlink");
+        fs::write(dir.join("app.log"), "a").unwrap();
+        std::os::unix::fs::symlink(dir.join("app.log"), dir.join("link.log")).unwrap();
+
+        let dir_str = dir.to_str().unwrap();
+        let args = Arguments { files: vec![format!("{}/./app.log", dir_str), format!("{}/*.log", dir_str)], .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        let inputs = Inputs::new_from_config(&config);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs.files[0].path_as_string, format!("{}/./app.log", dir_str));
+        assert_eq!(inputs.files[0].filename_only_as_string, "app.log");
     }
 }
@@ -1,10 +1,19 @@
 use std::borrow::Cow;
-use crate::byte_extensions::{ByteExtensions, ByteSliceExtensions};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use crate::byte_extensions::ByteExtensions;
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_till, take_till1};
+use nom::combinator::{map, opt, recognize, verify};
+use nom::sequence::{delimited, pair, terminated};
+use nom::IResult;
+use serde::ser::{Serialize, SerializeMap, Serializer};
 
 /// This module contains the representation of a Key-Value pair as parsed from the original line,
 /// and some utility methods for doing that parsing.
 
-/// The set of possible log level emitted by the logging framework.
+/// The set of possible log level emitted by the logging framework. These are the default
+/// levels, more can be added in the profile.
 /// They are ordered by frequency of occurence, as this should give a (very small!)
 /// performance boost when checking for them.
 pub const LOG_LEVELS: [&[u8]; 9] =
@@ -23,15 +32,47 @@ pub const LOG_LEVELS: [&[u8]; 9] =
 /// The name of the built-in LogDate column.
 pub const LOG_DATE: &str = "LogDate";
 
+/// The names of the built-in columns which contain just the date part, e.g. "2018-09-26",
+/// and just the time part, e.g. "12:34:56.1146655", of the LogDate.
+pub const LOG_DATE_ONLY: &str = "LogDateOnly";
+pub const LOG_TIME_ONLY: &str = "LogTimeOnly";
+
 /// The name of the built-in LogLevel column.
 pub const LOG_LEVEL: &str = "LogLevel";
 
-/// The name of the built-in LogSource column.
+/// The name of the built-in LogSource column, which is the component that wrote the
+/// line, taken from the Source KVP.
 pub const LOG_SOURCE: &str = "LogSource";
 
+/// The name of the KVP which the LogSource column is taken from.
+pub const SOURCE: &str = "Source";
+
+/// The name of the built-in FileName column, which is the name of the file the line came from.
+pub const FILE_NAME: &str = "FileName";
+
+/// The names of the built-in columns which trace a line back to where it came from: the
+/// full path of its file, and its zero-based line number within that file.
+pub const SOURCE_FILE: &str = "SourceFile";
+pub const LINE_NUM: &str = "LineNum";
+
 /// The name of the built-in Message column.
 pub const MESSAGE: &str = "Message";
 
+/// The name of the built-in RawLine column, which is the entire original line.
+pub const RAW_LINE: &str = "RawLine";
+
+/// The name of the built-in DeltaMs column, which is the number of milliseconds
+/// since the previous line in the output.
+pub const DELTA_MS: &str = "DeltaMs";
+
+/// The name of the special column which contains all the KVPs that are not
+/// written to any other column, as "key=value; key=value".
+pub const OTHER_KVPS: &str = "__OtherKvps__";
+
+/// The name of the built-in Category column, which classifies each line
+/// as a request, error, heartbeat etc.
+pub const CATEGORY: &str = "Category";
+
 /// Represents a single Key-Value pair as parsed from the log line.
 //#[derive(Debug, Default)]
 #[derive(Debug, Default)]
@@ -51,48 +92,146 @@ impl<'f> KVP<'f> {
     fn new(key: &'f [u8], value: &'f [u8]) -> Self {
         KVP {
             key,
-            value: value.make_safe(),
+            value: Cow::Borrowed(value),
             is_log_level: false
         }
     }
 }
 
+/// A key which hashes and compares case-insensitively, so that the index can be keyed
+/// on the ASCII-lowercased key without allocating a lowercased copy of it.
+#[derive(Debug, Clone, Copy)]
+struct CaseInsensitiveKey<'k>(&'k [u8]);
+
+impl<'k> PartialEq for CaseInsensitiveKey<'k> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(other.0)
+    }
+}
+
+impl<'k> Eq for CaseInsensitiveKey<'k> {}
+
+impl<'k> Hash for CaseInsensitiveKey<'k> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for c in self.0 {
+            state.write_u8(c.to_ascii_lowercase());
+        }
+    }
+}
+
+/// The FNV-1a hash, which is much quicker than the default SipHash for short keys such as
+/// KVP names. The keys come from log files, not from an adversary, so SipHash's
+/// resistance to collision attacks is not needed.
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &c in bytes {
+            self.0 = (self.0 ^ u64::from(c)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+type KeyIndex<'f> = HashMap<CaseInsensitiveKey<'f>, usize, BuildHasherDefault<FnvHasher>>;
+
+/// Once a collection holds this many KVPs it builds an index for its lookups. Below
+/// this a linear scan is cheap enough, and saves hashing every key.
+const INDEX_THRESHOLD: usize = 16;
+
 /// A Vec is probably as fast as a HashMap for the small number of KVPs we expect to see.
+/// Some lines have a lot of KVPs though, and with a wide column set every column is looked
+/// up in every line, so past `INDEX_THRESHOLD` KVPs an index is built as well.
 #[derive(Debug, Default)]
 pub struct KVPCollection<'f> {
-    kvps: Vec<KVP<'f>>
+    kvps: Vec<KVP<'f>>,
+
+    /// Maps each distinct key to the position of its first KVP. It is only built once
+    /// there are enough KVPs to make it worthwhile.
+    index: Option<KeyIndex<'f>>,
+
+    /// If true, a KVP whose key already exists is kept rather than discarded.
+    allow_duplicates: bool,
 }
 
 impl<'f> KVPCollection<'f> {
-    /// Insert a new KVP, but only if it does not already exist.
+    /// Creates a new, empty collection. If `allow_duplicates` is true, then `insert`
+    /// keeps KVPs with repeated keys, otherwise only the first one is kept.
+    pub fn new(allow_duplicates: bool) -> Self {
+        KVPCollection {
+            kvps: Vec::new(),
+            index: None,
+            allow_duplicates
+        }
+    }
+
+    /// Insert a new KVP, but only if it does not already exist (unless duplicates are allowed).
     pub fn insert(&mut self, new_kvp: KVP<'f>) {
-        for kvp in &self.kvps {
-            if kvp.key.eq_ignore_ascii_case(new_kvp.key) {
-                return;
-            }
+        if !self.allow_duplicates && self.position(new_kvp.key).is_some() {
+            return;
         }
 
+        let key = new_kvp.key;
         self.kvps.push(new_kvp);
+
+        match self.index {
+            // The first KVP with a key wins, so a duplicate does not replace it.
+            Some(ref mut index) => { index.entry(CaseInsensitiveKey(key)).or_insert(self.kvps.len() - 1); },
+            None if self.kvps.len() >= INDEX_THRESHOLD => self.build_index(),
+            None => {},
+        }
+    }
+
+    /// Builds the index from the KVPs inserted so far, keeping the first position of each key.
+    fn build_index(&mut self) {
+        let mut index = KeyIndex::with_capacity_and_hasher(self.kvps.len() * 2, Default::default());
+        for (idx, kvp) in self.kvps.iter().enumerate() {
+            index.entry(CaseInsensitiveKey(kvp.key)).or_insert(idx);
+        }
+        self.index = Some(index);
+    }
+
+    /// Finds the position of the first KVP with the key, looking it up case-insensitively.
+    fn position(&self, key: &[u8]) -> Option<usize> {
+        match self.index {
+            Some(ref index) => index.get(&CaseInsensitiveKey(key)).cloned(),
+            None => self.kvps.iter().position(|kvp| kvp.key.eq_ignore_ascii_case(key)),
+        }
     }
 
     /// Gets a value, looking it up case-insensitively by the specified key.
     /// Returns None if there is no value for that key.
     pub fn get_value(&self, key: &[u8]) -> Option<&Cow<'f, [u8]>> {
-        for kvp in &self.kvps {
-            if kvp.key.eq_ignore_ascii_case(key) {
-                return Some(&kvp.value);
-            }
-        }
+        self.position(key).map(|idx| &self.kvps[idx].value)
+    }
 
-        None
+    /// Gets all the values for a key, looking it up case-insensitively, in the order they
+    /// were inserted. There is only ever more than one value if duplicates are allowed.
+    pub fn get_all_values(&self, key: &[u8]) -> Vec<&[u8]> {
+        self.kvps.iter()
+            .filter(|kvp| kvp.key.eq_ignore_ascii_case(key))
+            .map(|kvp| kvp.value.as_ref())
+            .collect()
+    }
+
+    /// Returns an iterator over all the KVPs in the collection, in the order they were inserted.
+    pub fn iter(&self) -> impl Iterator<Item = &KVP<'f>> {
+        self.kvps.iter()
     }
 
-    #[cfg(test)]
     pub fn len(&self) -> usize {
         self.kvps.len()
     }
 
-    #[cfg(test)]
     pub fn is_empty(&self) -> bool {
         self.kvps.is_empty()
     }
@@ -111,8 +250,66 @@ impl<'f> KVPCollection<'f> {
     }
 }
 
+/// Serializes as a map from key to value, in the order the keys were first inserted.
+/// If duplicates are allowed and a key has more than one value, the values are serialized
+/// as an array. Keys and values which are not valid UTF-8 are converted lossily.
+impl<'f> Serialize for KVPCollection<'f> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (idx, kvp) in self.kvps.iter().enumerate() {
+            let seen_before = self.kvps[..idx].iter().any(|other| other.key.eq_ignore_ascii_case(kvp.key));
+            if seen_before {
+                continue;
+            }
+
+            let key = String::from_utf8_lossy(kvp.key);
+            let values = self.get_all_values(kvp.key);
+            if values.len() == 1 {
+                map.serialize_entry(&key, &String::from_utf8_lossy(values[0]))?;
+            } else {
+                let values: Vec<_> = values.into_iter().map(String::from_utf8_lossy).collect();
+                map.serialize_entry(&key, &values)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// Parses a KVP in any of the forms described for `next_kvp`. The key runs up to the '=',
+/// and may not be empty or contain whitespace.
+fn key_value(input: &[u8]) -> IResult<&[u8], KVP<'_>> {
+    let key = terminated(take_till1(|c: u8| c == b'=' || c.is_whitespace()), tag("="));
+    map(pair(key, alt((quoted_value, unquoted_value))), |(key, value)| KVP::new(key, value))(input)
+}
+
+/// Parses a value in double quotes. Values are not allowed to span lines, so the value
+/// also ends at a line break or the end of the input, in which case there is no closing
+/// quote to consume.
+fn quoted_value(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let value = take_till(|c: u8| c == b'"' || c == b'\r' || c == b'\n');
+    delimited(tag("\""), value, opt(tag("\"")))(input)
+}
+
+/// Parses a value without quotes, which runs up to the next whitespace character. The
+/// value is empty for expressions like "Key= ", and the whitespace is not consumed.
+fn unquoted_value(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    take_till(|c: u8| c.is_whitespace())(input)
+}
+
+/// Parses a log level such as "[INFO_]", which is any bracketed word that is one of the
+/// `levels`. It is handy to treat this as a special case of a KVP with an empty value,
+/// which makes parsing the prologue easier.
+fn log_level<'l, L: AsRef<[u8]>>(levels: &'l [L]) -> impl Fn(&[u8]) -> IResult<&[u8], KVP<'_>> + 'l {
+    move |input| {
+        let bracketed = recognize(delimited(tag("["), take_till1(|c: u8| c == b']' || c.is_whitespace()), tag("]")));
+        let level = verify(bracketed, |level: &[u8]| levels.iter().any(|l| l.as_ref() == level));
+        map(level, |level| KVP { is_log_level: true, .. KVP::new(level, b"") })(input)
+    }
+}
+
 pub trait ByteSliceKvpExtensions<'s> {
     fn next_kvp(self) -> KVPParseResult<'s>;
+    fn next_kvp_with_levels<L: AsRef<[u8]>>(self, levels: &[L]) -> KVPParseResult<'s>;
     fn prev_kvp(self) -> KVPParseResult<'s>;
 }
 
@@ -129,9 +326,11 @@ impl<'s> ByteSliceKvpExtensions<'s> for &'s [u8] {
     /// Attempts to extract a Key-Value pair from a slice, starting at the beginning of the slice
     /// and reading forward. There are several possible forms of a KVP:
     ///
-    ///     Key=
-    ///     Key=Value
-    ///     Key="Value with space"
+    /// ```text
+    /// Key=
+    /// Key=Value
+    /// Key="Value with space"
+    /// ```
     ///
     /// These forms are guaranteed by the logging framework. In particular, there is guaranteed
     /// to be no space around the '=', and the value will be wrapped in double quotes if it has
@@ -140,109 +339,26 @@ impl<'s> ByteSliceKvpExtensions<'s> for &'s [u8] {
     /// Pre: The first character of the slice is the first character of the key - 'K' in the
     /// above examples.
     fn next_kvp(self) -> KVPParseResult<'s> {
-        let no_kvp = KVPParseResult {
-            remaining_slice: self,
-            kvp: None
-        };
-
-        if self.is_empty() { return no_kvp; };
-
-        // Scan forward looking for the equals sign. If we hit a whitespace character instead,
-        // then we don't actually have a KVP. It MAY be the log-level in the prologue or we
-        // might just be looking at some random text in the message.
-        const LOG_LEVEL_LENGTH: usize = 7;
-        let idx = self.iter().position(|&c| c == b'=' || c.is_whitespace());
-        if self.len() >= LOG_LEVEL_LENGTH && (idx.is_none() || self[*idx.as_ref().unwrap()] != b'=') {
-            let possible_log_level = &self[0..LOG_LEVEL_LENGTH];
-            if LOG_LEVELS.contains(&possible_log_level) {
-                //println!("  >> Returning Log Level {:?}", possible_log_level.to_string());
-                let mut kvp = KVP::new(possible_log_level, b"");
-                kvp.is_log_level = true;
-                return KVPParseResult {
-                    remaining_slice: &self[LOG_LEVEL_LENGTH..],
-                    kvp: Some(kvp)
-                };
-            }
-        }
-
-        if idx.is_none() { return no_kvp };
-        let idx = idx.unwrap_or(0);
-
-        let key_slice = &self[0..idx];
-        if key_slice.is_empty() || self[idx] != b'=' { return no_kvp };
-
-        // The value should start immediately after the '=' with no intervening whitespace.
-        let value_slice = &self[idx..].trim_left();
-        //println!("  >> Case2, key_slice = {:?}, value_slice = {:?}", key_slice.to_string(), value_slice.to_string());
-
-        if value_slice.is_empty() {
-            // This is the pathological case where we reached the end of the input such as: "....Key="
-            // In practice we should never reach here except with badly formed lines because such trailing
-            // KVPs should be consumed by `prev_kvp`.
-            return KVPParseResult {
-                remaining_slice: value_slice,
-                kvp: Some(KVP::new(key_slice, value_slice))
-            };
-        }
-
-        //println!("  >> Case3, key_slice = {:?}, value_slice = {:?}", key_slice.to_string(), value_slice.to_string());
-
-        // Now extract the value.
-        if value_slice[0] == b'"' {
-            // We have a value in double quotes. Strip off the leading quote.
-            let value_slice = value_slice.trim_left();
-            if value_slice.is_empty() {
-                // We reached the end of input with the very last bytes being: 'Key="'
-                return KVPParseResult {
-                    remaining_slice: b"",
-                    kvp: Some(KVP::new(key_slice, b""))
-                };
-            };
-
-            // Find the closing quote. Don't allow values to span lines.
-            let idx = value_slice.iter().position(|&c| c == b'"' || c == b'\r' || c == b'\n');
-            if idx.is_none() {
-                // We reached the end of input with the vert last bytes being: 'Key="unfinished'
-                return KVPParseResult {
-                    remaining_slice: b"",
-                    kvp: Some(KVP::new(key_slice, value_slice))
-                };
-            }
+        self.next_kvp_with_levels(&LOG_LEVELS)
+    }
 
-            let idx = idx.unwrap();
-            let hit_ws = value_slice[idx] != b'"';
-            // 1 for the equals sign and 2 for the double quotes.
-            let extra = if hit_ws { 2 } else {3 };
-            let value_slice = &value_slice[0..idx];
-            KVPParseResult {
-                remaining_slice: &self[key_slice.len() + value_slice.len() + extra..],
-                kvp: Some(KVP::new(key_slice, value_slice))
-            }
-        } else if value_slice[0].is_whitespace() {
-            // We have an empty value ("Key= "). Make an empty slice.
-            // 1 for the equals sign.
-            KVPParseResult {
-                remaining_slice: &self[key_slice.len() + 1..],
-                kvp: Some(KVP::new(key_slice, b""))
-            }
-        } else {
-            // We have a KVP of the form "Key=Value". Find the next whitespace character.
-            let idx = value_slice.iter().position(|&c| c.is_whitespace()).unwrap_or_else(|| value_slice.len());
-            let value_slice = &value_slice[0..idx];
-            // 1 for the equals sign.
-            KVPParseResult {
-                remaining_slice: &self[key_slice.len() + 1 + value_slice.len()..],
-                kvp: Some(KVP::new(key_slice, value_slice))
-            }
+    /// Like `next_kvp`, but recognizes any of `levels` as the log level instead of the
+    /// default `LOG_LEVELS`, for logs which use levels such as "[INFO]" or "[CRITICAL]".
+    fn next_kvp_with_levels<L: AsRef<[u8]>>(self, levels: &[L]) -> KVPParseResult<'s> {
+        match alt((key_value, log_level(levels)))(self) {
+            Ok((remaining_slice, kvp)) => KVPParseResult { remaining_slice, kvp: Some(kvp) },
+            Err(_) => KVPParseResult { remaining_slice: self, kvp: None },
         }
     }
 
     /// Attempts to extract a Key-Value pair from a slice, starting at the end of the slice
     /// and reading backwards. There are several possible forms of a KVP:
     ///
-    ///     Key=
-    ///     Key=Value
-    ///     Key="Value with space"
+    /// ```text
+    /// Key=
+    /// Key=Value
+    /// Key="Value with space"
+    /// ```
     ///
     /// These forms are guaranteed by the logging framework. In particular, there is guaranteed
     /// to be no space around the '=', and the value will be wrapped in double quotes if it has
@@ -289,12 +405,6 @@ impl<'s> ByteSliceKvpExtensions<'s> for &'s [u8] {
         };
 
         match last_char {
-            b'=' => {
-                // We possibly have an empty KVP of the form 'Key='.
-                let value_slice = b"";
-                let index_of_equals = self.len() - 1;
-                extract_key(value_slice, index_of_equals)
-            },
             b'"' => {
                 // We possibly a KVP of the form 'Key="some value with spaces"'.
                 // Find the previous double quote (trim off the trailing double quote to make it easier).
@@ -320,18 +430,21 @@ impl<'s> ByteSliceKvpExtensions<'s> for &'s [u8] {
                 extract_key(value_slice, index_of_equals)
             },
             _ => {
-                // We possibly a KVP of the form 'Key=Value'. But we may also just be looking at
-                // some random word. We expect to find an '=' before we hit any whitespace or a double quote.
-                // Hitting a double quote first indicates a badly formed line - such as the 'unclosed_quote'
-                // test cases - and we terminate with no_kvp in that case.
-                let index_of_equals = self.iter().rposition(|&c| c == b'=' || c == b'"' || c.is_whitespace());
-                if index_of_equals.is_none() { return no_kvp };
-
-                // Check to see if we hit an '=' first.
-                let index_of_equals = index_of_equals.unwrap();
-                if self[index_of_equals] != b'=' { return no_kvp; }
+                // We possibly have a KVP of the form 'Key=Value' or 'Key='. But we may also just be
+                // looking at some random word. Unquoted values cannot contain whitespace, so the KVP
+                // is the last whitespace-delimited token, and the key ends at its first '='. The value
+                // may contain further '=' characters, as in 'QueryString=a=1&b=2'.
+                let token_start = self.iter().rposition(|&c| c.is_whitespace()).map_or(0, |idx| idx + 1);
+                let index_of_equals = match self[token_start..].iter().position(|&c| c == b'=') {
+                    Some(idx) => token_start + idx,
+                    None => return no_kvp,
+                };
 
+                // A double quote in the value indicates a badly formed line - such as the
+                // 'unclosed_quote' test cases - and we terminate with no_kvp in that case.
                 let value_slice = &self[index_of_equals + 1..];
+                if value_slice.contains(&b'"') { return no_kvp; }
+
                 extract_key(value_slice, index_of_equals)
             },
         }
@@ -374,6 +487,101 @@ mod kvp_collection_tests {
         assert_eq!(sut.get_value(b"Car").unwrap().as_ref(), b"ford");
         assert_eq!(sut.get_value(b"XYZ"), None);
     }
+
+    #[test]
+    pub fn serializes_as_map_in_insertion_order() {
+        let mut sut = KVPCollection::default();
+        sut.insert(KVP::new(b"truck", b"volvo"));
+        sut.insert(KVP::new(b"car", b"\xffford"));
+
+        let json = serde_json::to_string(&sut).unwrap();
+        assert_eq!(json, "{\"truck\":\"volvo\",\"car\":\"\u{fffd}ford\"}");
+    }
+
+    #[test]
+    pub fn serializes_duplicate_values_as_array() {
+        let mut sut = KVPCollection::new(true);
+        sut.insert(KVP::new(b"Tag", b"a"));
+        sut.insert(KVP::new(b"car", b"ford"));
+        sut.insert(KVP::new(b"tag", b"b"));
+
+        let json = serde_json::to_string(&sut).unwrap();
+        assert_eq!(json, r#"{"Tag":["a","b"],"car":"ford"}"#);
+    }
+
+    #[test]
+    pub fn get_all_values_when_not_allowing_duplicates_returns_first_value() {
+        let mut sut = KVPCollection::default();
+        sut.insert(KVP::new(b"Tag", b"a"));
+        sut.insert(KVP::new(b"tag", b"b"));
+
+        assert_eq!(sut.len(), 1);
+        assert_eq!(sut.get_all_values(b"TAG"), vec![b"a" as &[u8]]);
+    }
+
+    #[test]
+    pub fn get_all_values_when_allowing_duplicates_returns_all_values_in_order() {
+        let mut sut = KVPCollection::new(true);
+        sut.insert(KVP::new(b"Tag", b"a"));
+        sut.insert(KVP::new(b"car", b"ford"));
+        sut.insert(KVP::new(b"tag", b"b"));
+
+        assert_eq!(sut.len(), 3);
+        assert_eq!(sut.value(b"tag"), b"a");
+        assert_eq!(sut.get_all_values(b"TAG"), vec![b"a" as &[u8], b"b"]);
+        assert!(sut.get_all_values(b"XYZ").is_empty());
+    }
+
+    /// Enough keys for a collection to build its index.
+    fn many_keys() -> Vec<Vec<u8>> {
+        (0..INDEX_THRESHOLD + 4).map(|i| format!("k{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    pub fn with_many_kvps_builds_index_and_looks_up_case_insensitively() {
+        let keys = many_keys();
+        let mut sut = KVPCollection::default();
+        for key in &keys {
+            sut.insert(KVP::new(key, key));
+        }
+
+        assert!(sut.index.is_some());
+        assert_eq!(sut.len(), keys.len());
+        for key in &keys {
+            assert_eq!(sut.get_value(&key.to_ascii_uppercase()).unwrap().as_ref(), key.as_slice());
+        }
+        assert!(sut.get_value(b"k999").is_none());
+    }
+
+    #[test]
+    pub fn with_many_kvps_insert_still_keeps_first_value() {
+        let keys = many_keys();
+        let mut sut = KVPCollection::default();
+        sut.insert(KVP::new(b"Car", b"ford"));
+        for key in &keys {
+            sut.insert(KVP::new(key, b"x"));
+        }
+        sut.insert(KVP::new(b"CAR", b"volvo"));
+
+        assert_eq!(sut.len(), keys.len() + 1);
+        assert_eq!(sut.value(b"car"), b"ford");
+        assert_eq!(sut.get_value(b"car").unwrap().as_ref(), b"ford");
+    }
+
+    #[test]
+    pub fn with_many_kvps_and_duplicates_allowed_gets_first_value() {
+        let keys = many_keys();
+        let mut sut = KVPCollection::new(true);
+        sut.insert(KVP::new(b"Tag", b"a"));
+        for key in &keys {
+            sut.insert(KVP::new(key, b"x"));
+        }
+        sut.insert(KVP::new(b"tag", b"b"));
+
+        assert_eq!(sut.len(), keys.len() + 2);
+        assert_eq!(sut.get_value(b"TAG").unwrap().as_ref(), b"a");
+        assert_eq!(sut.get_all_values(b"TAG"), vec![b"a" as &[u8], b"b"]);
+    }
 }
 
 #[cfg(test)]
@@ -432,6 +640,25 @@ mod next_kvp_tests {
         assert_eq!(result.remaining_slice, b" | ");
     }
 
+    #[test]
+    pub fn for_levels_of_other_lengths_returns_log_level() {
+        let levels: [&[u8]; 2] = [b"[INFO]", b"[CRITICAL]"];
+
+        let result = (&b"[INFO] | "[..]).next_kvp_with_levels(&levels);
+        assert_eq!(result.kvp.unwrap().key, b"[INFO]");
+        assert_eq!(result.remaining_slice, b" | ");
+
+        let result = (&b"[CRITICAL]"[..]).next_kvp_with_levels(&levels);
+        assert!(result.kvp.unwrap().is_log_level);
+    }
+
+    #[test]
+    pub fn for_bracketed_word_not_in_levels_returns_none() {
+        let result = (&b"[INFO] | "[..]).next_kvp();
+        assert!(result.kvp.is_none());
+        assert_eq!(result.remaining_slice, b"[INFO] | ");
+    }
+
     #[test]
     pub fn for_non_kvp_word_only() {
         let slice = &b"Car";
@@ -470,6 +697,37 @@ mod next_kvp_tests {
         assert!(result.remaining_slice.is_empty());
     }
 
+    #[test]
+    pub fn for_value_containing_equals() {
+        let slice = &b"Message Owin.Request.QueryString=foo=bar&baz=qux";
+        let result = slice.prev_kvp();
+
+        let kvp = result.kvp.unwrap();
+        assert_eq!(kvp.key, b"Owin.Request.QueryString");
+        assert_eq!(kvp.value.as_ref(), b"foo=bar&baz=qux");
+        assert_eq!(result.remaining_slice, b"Message ");
+    }
+
+    #[test]
+    pub fn for_value_ending_with_equals() {
+        let slice = &b"Message QueryString=foo=";
+        let result = slice.prev_kvp();
+
+        let kvp = result.kvp.unwrap();
+        assert_eq!(kvp.key, b"QueryString");
+        assert_eq!(kvp.value.as_ref(), b"foo=");
+        assert_eq!(result.remaining_slice, b"Message ");
+    }
+
+    #[test]
+    pub fn for_leading_equals_only() {
+        let slice = &b"Message =foo=bar";
+        let result = slice.prev_kvp();
+
+        assert!(result.kvp.is_none());
+        assert_eq!(result.remaining_slice, b"Message =foo=bar");
+    }
+
     #[test]
     pub fn for_key_only_and_whitespce() {
         let slice = &b"Car= ";
@@ -0,0 +1,19 @@
+//! The log file parser, which splits each line into its timestamp, log level, KVPs and
+//! message. It is used by the log-file-processor binary, and can also be embedded directly
+//! in other programs.
+
+// The byte extensions are needed by the binary, but they are not part of the library's API.
+#[doc(hidden)]
+pub mod byte_extensions;
+pub mod kvp;
+pub mod parsed_file;
+pub mod parsed_line;
+pub mod timestamp;
+// Shared by the tests of the library and of the binary, so it cannot be cfg(test).
+#[doc(hidden)]
+pub mod test_utils;
+
+pub use crate::kvp::{ByteSliceKvpExtensions, KVPCollection, KVPParseResult, KVP};
+pub use crate::parsed_file::{parse_file, parse_lines, ParsedFile};
+pub use crate::parsed_line::{ErrorKind, ParseError, ParseLineResult, ParseOptions, ParsedLine, ParsedLineError, ParsedLines};
+pub use crate::timestamp::TimestampFormat;
@@ -0,0 +1,392 @@
+//! This module implements the low-memory mode. Rather than reading all the files into memory
+//! at once and sorting all their lines together, each file is read, parsed and sorted in turn,
+//! and a record of what will be written for each line is copied out of it. The file's bytes
+//! can then be freed. Finally, the sorted records of all the files are merged together and
+//! written to the output as they are merged.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+use rayon::prelude::*;
+use crate::configuration::{Configuration, OutputFormat};
+use crate::inputs::{InputFile, Inputs};
+use crate::kvp;
+use crate::output::{format_output_values, write_output_rows, OutputLine, OutputRow, OutputValue};
+use crate::parsed_line::{ParseError, ParseLineResult, ParsedLine, ParsedLineError};
+use crate::progress::Progress;
+use crate::{compare_lines, compare_sort_keys, parse_input_file, read_file, LineStats, SortKey};
+
+/// Gets the option which stops low-memory mode from being used, if there is one. Each
+/// line is written as soon as it has been merged, so the output cannot depend on the
+/// lines which come after it, or on the parsed lines themselves.
+pub fn unsupported_option(config: &Configuration) -> Option<&'static str> {
+    if config.group_by.is_some() {
+        Some("--group-by")
+    } else if config.tail.is_some() {
+        Some("--tail")
+    } else if config.skip > 0 {
+        Some("--skip")
+    } else if config.limit.is_some() {
+        Some("--limit")
+    } else if config.output_format == OutputFormat::Pretty {
+        Some("the pretty output format")
+    } else {
+        None
+    }
+}
+
+/// Reads, parses and sorts each file in turn, keeping a record of each line which will be
+/// written, then merges the records of all the files and writes them to the output (or
+/// in input order if sorting is turned off). Returns the total number of bytes read, the
+/// number of error lines written and the figures about the lines for the summary.
+pub fn write_files(config: &Configuration, progress: &Progress, inputs: &Inputs) -> Result<(u64, usize, LineStats), io::Error> {
+    let mut total_bytes = 0;
+    let mut error_lines = 0;
+    let mut stats = LineStats::default();
+    let mut sorted_files = Vec::with_capacity(inputs.len());
+
+    for f in &inputs.files {
+        if let Some(bytes) = read_file(config, progress, f) {
+            total_bytes += bytes.len() as u64;
+            let mut results = parse_input_file(config, progress, f, &bytes);
+            error_lines += results.iter().filter(|r| r.is_err()).count();
+            stats.add(LineStats::new(config, &results));
+
+            // Nothing is written when counting, so there is no need to keep anything.
+            if config.count {
+                continue;
+            }
+
+            if !config.no_sort {
+                results.par_sort_by(|a, b| compare_lines(config, a, b));
+            }
+            sorted_files.push(results.par_iter().map(|r| Record::new(config, r, f)).collect::<Vec<_>>());
+        }
+    }
+
+    let error_count = if config.count {
+        error_lines
+    } else if config.no_sort {
+        write_output_rows(config, sorted_files.into_iter().flatten())?
+    } else {
+        write_output_rows(config, MergeSorted::new(config, sorted_files))?
+    };
+
+    Ok((total_bytes, error_count, stats))
+}
+
+/// A line which will be written to the output, kept in place of the parsed line so that
+/// the file's bytes can be freed.
+pub enum Record<'i> {
+    Line(LineRecord<'i>),
+    Error(ErrorRecord<'i>),
+}
+
+impl<'i> Record<'i> {
+    fn new(config: &Configuration, result: &ParseLineResult, file: &'i InputFile) -> Self {
+        match result {
+            Ok(line) => Record::Line(LineRecord::new(config, line, file)),
+            Err(e) => Record::Error(ErrorRecord { file, line_num: e.line_num, line: e.line.into(), error: e.error.clone() }),
+        }
+    }
+}
+
+impl<'i> OutputRow for Record<'i> {
+    type Line = LineRecord<'i>;
+
+    fn as_output(&self) -> Result<&LineRecord<'i>, ParsedLineError<'_>> {
+        match self {
+            Record::Line(line) => Ok(line),
+            Record::Error(e) => Err(ParsedLineError {
+                source: &e.file.filename_only_as_string,
+                path: &e.file.path_as_string,
+                line_num: e.line_num,
+                line: &e.line,
+                error: e.error.clone(),
+            }),
+        }
+    }
+}
+
+/// The parts of a successfully parsed line which are needed to write it: the values which
+/// determine its place in the output, and the value of each column exactly as it will be
+/// written. The values are kept one after the other in a single buffer.
+pub struct LineRecord<'i> {
+    file: &'i InputFile,
+    line_num: usize,
+    /// The log date, the 'sort by' value, the 'then by' value, then the value of each column.
+    data: Box<[u8]>,
+    /// The end of each value in `data`.
+    ends: Box<[u32]>,
+}
+
+const LOG_DATE_INDEX: usize = 0;
+const SORT_BY_INDEX: usize = 1;
+const THEN_BY_INDEX: usize = 2;
+const FIRST_COLUMN_INDEX: usize = 3;
+
+impl<'i> LineRecord<'i> {
+    fn new(config: &Configuration, line: &ParsedLine, file: &'i InputFile) -> Self {
+        let csv = !matches!(config.output_format, OutputFormat::Json | OutputFormat::Ndjson);
+        let key = SortKey::new(config, line);
+        let mut data = Vec::new();
+        let mut ends = Vec::with_capacity(FIRST_COLUMN_INDEX + config.columns.len());
+        let mut add_value = |value: &[u8]| {
+            data.extend_from_slice(value);
+            ends.push(data.len() as u32);
+        };
+
+        add_value(key.log_date);
+        add_value(key.sort_by);
+        add_value(key.then_by);
+        format_output_values(config, line, csv, &mut add_value);
+
+        LineRecord { file, line_num: line.line_num, data: data.into_boxed_slice(), ends: ends.into_boxed_slice() }
+    }
+
+    fn value(&self, index: usize) -> &[u8] {
+        let start = if index == 0 { 0 } else { self.ends[index - 1] as usize };
+        &self.data[start..self.ends[index] as usize]
+    }
+
+    fn sort_key(&self) -> SortKey<'_> {
+        SortKey {
+            sort_by: self.value(SORT_BY_INDEX),
+            log_date: self.value(LOG_DATE_INDEX),
+            then_by: self.value(THEN_BY_INDEX),
+            source: &self.file.filename_only_as_string,
+            path: &self.file.path_as_string,
+            line_num: self.line_num,
+            // The line is not kept, but the line number already tells the lines of a file apart.
+            line: b"",
+        }
+    }
+}
+
+impl OutputLine for LineRecord<'_> {
+    fn log_date(&self) -> &[u8] {
+        self.value(LOG_DATE_INDEX)
+    }
+
+    /// The values were made for the configured output format when the record was made.
+    fn output_value<'a>(&'a self, _config: &'a Configuration, index: usize, column: &str, _csv: bool) -> OutputValue<'a> {
+        if column == kvp::DELTA_MS {
+            OutputValue::DeltaMs
+        } else {
+            OutputValue::Verbatim(Cow::Borrowed(self.value(FIRST_COLUMN_INDEX + index)))
+        }
+    }
+}
+
+/// A line which could not be parsed.
+pub struct ErrorRecord<'i> {
+    file: &'i InputFile,
+    line_num: usize,
+    line: Box<[u8]>,
+    error: ParseError,
+}
+
+/// Compares two records in the same way as `compare_lines` compares the lines they were made from.
+fn compare_records(config: &Configuration, a: &Record, b: &Record) -> Ordering {
+    match (a, b) {
+        (Record::Error(a), Record::Error(b)) => {
+            (&a.file.filename_only_as_string, &a.file.path_as_string, a.line_num, &a.line)
+                .cmp(&(&b.file.filename_only_as_string, &b.file.path_as_string, b.line_num, &b.line))
+        },
+        (Record::Error(_), Record::Line(_)) => Ordering::Less,
+        (Record::Line(_), Record::Error(_)) => Ordering::Greater,
+        (Record::Line(a), Record::Line(b)) => compare_sort_keys(config, &a.sort_key(), &b.sort_key()),
+    }
+}
+
+/// Merges the records of several files, each of which is already sorted, into one sorted
+/// sequence. A heap holds the next record of each file, so only those records are compared,
+/// and each record is produced as soon as it is known to be next.
+struct MergeSorted<'c, 'i> {
+    heap: BinaryHeap<MergeHead<'c, 'i>>,
+    files: Vec<std::vec::IntoIter<Record<'i>>>,
+}
+
+impl<'c, 'i> MergeSorted<'c, 'i> {
+    fn new(config: &'c Configuration, sorted_files: Vec<Vec<Record<'i>>>) -> Self {
+        let mut files: Vec<_> = sorted_files.into_iter().map(Vec::into_iter).collect();
+        let heap = files.iter_mut()
+            .enumerate()
+            .filter_map(|(file_index, records)| records.next().map(|record| MergeHead { config, record, file_index }))
+            .collect();
+
+        MergeSorted { heap, files }
+    }
+}
+
+impl<'c, 'i> Iterator for MergeSorted<'c, 'i> {
+    type Item = Record<'i>;
+
+    fn next(&mut self) -> Option<Record<'i>> {
+        let head = self.heap.pop()?;
+        if let Some(record) = self.files[head.file_index].next() {
+            self.heap.push(MergeHead { config: head.config, record, file_index: head.file_index });
+        }
+        Some(head.record)
+    }
+}
+
+/// The next record of one of the files being merged. `BinaryHeap` is a max-heap, so the
+/// order is reversed to make the smallest record come out first. Records which compare as
+/// equal come out in the order of their files.
+struct MergeHead<'c, 'i> {
+    config: &'c Configuration,
+    record: Record<'i>,
+    file_index: usize,
+}
+
+impl Ord for MergeHead<'_, '_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_records(self.config, &other.record, &self.record)
+            .then_with(|| other.file_index.cmp(&self.file_index))
+    }
+}
+
+impl PartialOrd for MergeHead<'_, '_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeHead<'_, '_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeHead<'_, '_> {}
+
+#[cfg(test)]
+mod low_memory_tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::output::test_utils::make_temp_dir;
+    use crate::output::write_output_files;
+    use crate::profiles::ProfileSet;
+
+    fn make_input_file(dir: &Path, name: &str, contents: &[u8]) -> InputFile {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        InputFile::new(path)
+    }
+
+    fn make_config(dir: &Path, name: &str, args: Arguments, columns: &[&str]) -> Configuration {
+        let args = Arguments { output: Some(dir.join(name)), .. args };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.columns = columns.iter().map(|c| c.to_string()).collect();
+        config
+    }
+
+    #[test]
+    pub fn write_files_merges_lines_from_all_files_in_date_order() {
+        let dir = make_temp_dir("low_memory_merge");
+        let config = make_config(&dir, "out.csv", Arguments::default(), &[kvp::MESSAGE]);
+        let inputs = Inputs { files: vec![
+            make_input_file(&dir, "a.log", b"2018-09-26 12:34:51.0000000 | [INFO_] | A1\r\n2018-09-26 12:34:54.0000000 | [INFO_] | A2\r\n"),
+            make_input_file(&dir, "b.log", b"2018-09-26 12:34:52.0000000 | [INFO_] | B1\r\nNot a log line\r\n2018-09-26 12:34:53.0000000 | [INFO_] | B2\r\n"),
+        ]};
+
+        let (total_bytes, error_count, stats) = write_files(&config, &Progress::new(&config, &inputs), &inputs).unwrap();
+
+        assert_eq!(total_bytes, inputs.files.iter().map(|f| f.length as u64).sum::<u64>());
+        assert_eq!(error_count, 1);
+        assert_eq!(stats.line_count, 5);
+        assert_eq!(fs::read_to_string(config.success_file()).unwrap(), "Message\nA1\nB1\nB2\nA2\n");
+        assert!(fs::read_to_string(config.error_file()).unwrap().contains("Not a log line"));
+    }
+
+    #[test]
+    pub fn write_files_with_no_sort_keeps_input_order() {
+        let dir = make_temp_dir("low_memory_no_sort");
+        let config = make_config(&dir, "out.csv", Arguments { no_sort: true, .. Arguments::default() }, &[kvp::MESSAGE]);
+        let inputs = Inputs { files: vec![
+            make_input_file(&dir, "a.log", b"2018-09-26 12:34:54.0000000 | [INFO_] | A1\r\n2018-09-26 12:34:51.0000000 | [INFO_] | A2\r\n"),
+            make_input_file(&dir, "b.log", b"2018-09-26 12:34:50.0000000 | [INFO_] | B1\r\n"),
+        ]};
+
+        write_files(&config, &Progress::new(&config, &inputs), &inputs).unwrap();
+
+        assert_eq!(fs::read_to_string(config.success_file()).unwrap(), "Message\nA1\nA2\nB1\n");
+    }
+
+    #[test]
+    pub fn write_files_when_counting_writes_nothing() {
+        let dir = make_temp_dir("low_memory_count");
+        let config = make_config(&dir, "out.csv", Arguments { count: true, .. Arguments::default() }, &[kvp::MESSAGE]);
+        let inputs = Inputs { files: vec![
+            make_input_file(&dir, "a.log", b"2018-09-26 12:34:54.0000000 | [INFO_] | A1\r\nNot a log line\r\n"),
+        ]};
+
+        let (_, error_count, stats) = write_files(&config, &Progress::new(&config, &inputs), &inputs).unwrap();
+
+        assert_eq!((error_count, stats.line_count), (1, 2));
+        assert!(!config.success_file().exists());
+    }
+
+    #[test]
+    pub fn merge_sorted_handles_empty_files() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        assert_eq!(MergeSorted::new(&config, vec![vec![], vec![]]).count(), 0);
+    }
+
+    /// Writes the same files in low-memory mode and in the normal way, and returns both outputs.
+    fn write_both_ways(name: &str, make_args: impl Fn() -> Arguments, columns: &[&str]) -> (String, String) {
+        let dir = make_temp_dir(name);
+        let inputs = Inputs { files: vec![
+            make_input_file(&dir, "a.log", b"2018-09-26 12:34:51.5000000 | pid=1 | [INFO_] | =1+1 Sysref=A\r\n2018-09-26 12:34:54.0000000 | [ERROR] | Line one\r\nline two\r\n"),
+            make_input_file(&dir, "b.log", b"2018-09-26 12:34:52.0000000 | pid=2 | [INFO_] | B1 Sysref=B\r\nNot a log line\r\n2018-09-26 12:34:53.2500000 | [WARN_] | B2\r\n"),
+        ]};
+
+        let config = make_config(&dir, "low.out", make_args(), columns);
+        write_files(&config, &Progress::new(&config, &inputs), &inputs).unwrap();
+        let low_memory = fs::read_to_string(config.success_file()).unwrap();
+
+        let config = make_config(&dir, "normal.out", make_args(), columns);
+        let bytes: Vec<_> = inputs.files.iter().map(|f| f.read().unwrap()).collect();
+        let mut results: Vec<_> = inputs.files.iter().zip(&bytes)
+            .flat_map(|(f, bytes)| parse_input_file(&config, &Progress::new(&config, &inputs), f, bytes))
+            .collect();
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+        write_output_files(&config, &results).unwrap();
+        let normal = fs::read_to_string(config.success_file()).unwrap();
+
+        (low_memory, normal)
+    }
+
+    #[test]
+    pub fn for_csv_writes_the_same_as_the_normal_mode() {
+        let columns = [kvp::LOG_DATE, kvp::DELTA_MS, kvp::LOG_LEVEL, "pid", "Sysref", kvp::LINE_NUM, kvp::MESSAGE];
+
+        let (low_memory, normal) = write_both_ways("low_memory_same_csv", || Arguments { reverse: true, sanitize_formulas: true, .. Arguments::default() }, &columns);
+
+        assert_eq!(low_memory, normal);
+        assert!(low_memory.contains("'=1+1"), "Formulas should be sanitized");
+    }
+
+    #[test]
+    pub fn for_json_writes_the_same_as_the_normal_mode() {
+        let columns = [kvp::LOG_DATE, kvp::DELTA_MS, "Sysref", kvp::MESSAGE];
+
+        let (low_memory, normal) = write_both_ways("low_memory_same_json", || Arguments { output_format: Some(OutputFormat::Json), .. Arguments::default() }, &columns);
+
+        assert_eq!(low_memory, normal);
+        assert!(low_memory.contains("\"DeltaMs\":\"500\""));
+    }
+
+    #[test]
+    pub fn unsupported_option_is_found_for_options_which_need_all_the_lines() {
+        let config_for = |args| get_config(&ProfileSet::default(), &args).unwrap();
+        assert_eq!(unsupported_option(&config_for(Arguments::default())), None);
+        assert_eq!(unsupported_option(&config_for(Arguments { tail: Some(5), .. Arguments::default() })), Some("--tail"));
+        assert_eq!(unsupported_option(&config_for(Arguments { limit: Some(5), .. Arguments::default() })), Some("--limit"));
+    }
+}
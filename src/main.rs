@@ -1,25 +1,36 @@
-use csv::WriterBuilder;
+use chrono::{DateTime, Utc};
 use indicatif::HumanBytes;
 use itertools::Itertools;
 use rayon::prelude::*;
-use std::fs::{self, File};
+use std::collections::HashMap;
+use std::cmp::Ordering;
 use std::io;
 use std::time::Instant;
 use structopt::StructOpt;
 
 mod arguments;
-mod byte_extensions;
 mod configuration;
+mod follow;
+mod grouping;
 mod inputs;
-mod kvp;
+mod low_memory;
 mod output;
-mod parsed_line;
+mod pretty;
 mod profiles;
-use crate::arguments::Arguments;
-use crate::configuration::{get_config, Configuration};
-use crate::inputs::{InputFile, Inputs};
-use crate::parsed_line::{ParseLineResult, ParsedLine, string_to_utc_datetime_and_panic};
-use crate::profiles::ProfileSet;
+mod progress;
+mod timing_stats;
+use log_file_processor::{byte_extensions, kvp, parsed_file, parsed_line, timestamp};
+use crate::arguments::{write_completions, Arguments};
+use crate::byte_extensions::{ByteExtensions, ByteSliceExtensions};
+use crate::configuration::{get_config, Configuration, DEFAULT_PROFILE_NAME};
+use crate::inputs::{looks_binary, FileBytes, InputFile, Inputs};
+use crate::grouping::group_lines;
+use crate::output::{get_column_value, get_output_value, open_success_file, per_file_output_paths, write_grouped_output_files, write_output_files, write_output_files_to, write_summary_json, RunSummary};
+use crate::parsed_file::parse_lines;
+use crate::parsed_line::{ParseLineResult, ParsedLine, string_to_utc_datetime};
+use crate::profiles::{config_file_path, explicit_config_path, is_default_column, ProfileSet, CONFIG_ENV_VAR};
+use crate::progress::Progress;
+use crate::timing_stats::{collect_execution_times, format_timing_stats, summarise_execution_times, TimingStats};
 
 
 
@@ -28,20 +39,53 @@ fn main() -> Result<(), io::Error> {
     //println!("Args = {:#?}", args);
     //std::process::exit(0);
 
+    // This comes first so that nothing else, such as loading the configuration, happens.
+    if let Some(shell) = args.completions {
+        write_completions(shell, &mut io::stdout());
+        return Ok(());
+    }
+
+    // All the parallel work uses rayon's global pool, so limiting it limits everything.
+    if let Some(threads) = args.threads {
+        if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+            eprintln!("Cannot create a thread pool with {} threads: {}", threads, e);
+            std::process::exit(1);
+        }
+    }
+
     if args.dump_config {
         let profiles = ProfileSet::default();
-        let json = serde_json::to_string_pretty(&profiles)?;
-        println!("{}", json);
+        let text = profiles.to_text(args.format).map_err(io::Error::other)?;
+        println!("{}", text);
         return Ok(());
     }
 
-    let profiles = match dirs::home_dir() {
-        Some(mut path) => {
-            path.push(".lpf.json");
-            match File::open(path) {
-                Ok(f) => serde_json::from_reader(f)?,
-                Err(ref e) if e.kind() == io::ErrorKind::NotFound => ProfileSet::default(),
-                Err(e) => panic!("Error opening ~/.lpf.json: {:?}", e),
+    // A configuration file named by `--config` or LPF_CONFIG must exist, whereas the one
+    // in the home directory is optional.
+    let config_path = match explicit_config_path(&args.config, std::env::var_os(CONFIG_ENV_VAR)) {
+        Some(path) => {
+            if !path.is_file() {
+                eprintln!("The configuration file {} does not exist.", path.display());
+                std::process::exit(1);
+            }
+            Some(path)
+        }
+        None => dirs::home_dir().map(|home_dir| config_file_path(&home_dir)),
+    };
+
+    let profiles = match config_path {
+        Some(path) => {
+            match ProfileSet::load(&path) {
+                Ok(profiles) => profiles,
+                Err(msg) if args.ignore_bad_config => {
+                    eprintln!("{}", msg);
+                    eprintln!("Ignoring the configuration file, using default configuration.");
+                    ProfileSet::default()
+                }
+                Err(msg) => {
+                    eprintln!("{}", msg);
+                    std::process::exit(1);
+                }
             }
         }
         None => {
@@ -50,7 +94,54 @@ fn main() -> Result<(), io::Error> {
         }
     };
 
-    let configuration = get_config(&profiles, &args);
+    if args.validate_config {
+        let problems = profiles.validate();
+        if problems.is_empty() {
+            println!("The configuration is valid.");
+            return Ok(());
+        }
+
+        for problem in problems {
+            eprintln!("{}", problem);
+        }
+        std::process::exit(1);
+    }
+
+    // Check the profile and the profiles it extends now, so that every bad regex is
+    // reported rather than just the first one found while building the configuration.
+    let mut used_profiles = Vec::new();
+    if !args.no_default_profile {
+        used_profiles.extend(profiles.get(DEFAULT_PROFILE_NAME));
+    }
+    if args.profile != DEFAULT_PROFILE_NAME {
+        match profiles.get_with_ancestors(&args.profile) {
+            Ok(chain) => used_profiles.extend(chain),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let regex_problems: Vec<String> = used_profiles.iter()
+        .unique_by(|profile| &profile.name)
+        .flat_map(|profile| profile.regex_problems())
+        .collect();
+
+    if !regex_problems.is_empty() {
+        for problem in regex_problems {
+            eprintln!("{}", problem);
+        }
+        std::process::exit(1);
+    }
+
+    let configuration = match get_config(&profiles, &args) {
+        Ok(configuration) => configuration,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+    };
     let inputs = Inputs::new_from_config(&configuration);
 
     if inputs.is_empty() {
@@ -58,6 +149,21 @@ fn main() -> Result<(), io::Error> {
         return Ok(());
     }
 
+    if args.dry_run {
+        for line in format_dry_run(&inputs) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    if configuration.follow {
+        if inputs.len() != 1 || inputs.files[0].is_stdin {
+            eprintln!("--follow needs exactly one input file, and cannot follow stdin.");
+            std::process::exit(1);
+        }
+        return follow::follow(&configuration, &inputs.files[0]);
+    }
+
     //println!("profiles = {:#?}", profiles);
     //println!("configuration = {:#?}", configuration);
 
@@ -89,69 +195,78 @@ fn main() -> Result<(), io::Error> {
     // 2.506    ...include entire line in the message
 
     let start_time = Instant::now();
-    let total_bytes = inputs.total_bytes() as u64;
     let input_count = inputs.len();
+    let progress = Progress::new(&configuration, &inputs);
 
-    // We need to get all the files into memory at the same time because we
-    // want to collect a consolidated set of parsed line (over all the files).
-    // The bytes of the files must therefore outlive all the parsed lines.
-    let all_files: Vec<(&InputFile, Vec<u8>)> = inputs
-        .files
-        .par_iter()
-        .map(|f| (f, fs::read(&f.path).expect("Can read file")))
-        .collect();
+    // Per-file mode needs all the files in memory to fan out the writes, so it takes
+    // precedence over low memory mode. Nothing is written when counting, so there is
+    // nothing to fan out, and grouping is done over all the files together.
+    let per_file = configuration.per_file && !configuration.count && configuration.group_by.is_none();
+    let mut low_memory = configuration.low_memory && !per_file;
+    if let Some(option) = low_memory::unsupported_option(&configuration).filter(|_| low_memory) {
+        print_status(&configuration, &format!("Warning: --low-memory cannot be used with {}, so all the files are read into memory.", option));
+        low_memory = false;
+    }
 
-    // Process all files in parallel. Accumulate the lines written for each file so
-    // that they can be merged and written to a single, sorted, consolidated file.
+    let (total_bytes, error_count, stats) = if low_memory {
+        // Files are parsed one at a time and only the values which will be written
+        // are kept, so the bytes of each file can be freed before the next is read.
+        low_memory::write_files(&configuration, &progress, &inputs)?
+    } else {
+        // We need to get all the files into memory at the same time because we
+        // want to collect a consolidated set of parsed line (over all the files).
+        // The bytes of the files must therefore outlive all the parsed lines.
+        // If the files are memory-mapped, the maps must likewise outlive the parsed lines.
+        let all_files: Vec<(&InputFile, FileBytes)> = inputs
+            .files
+            .par_iter()
+            .filter_map(|f| read_file(&configuration, &progress, f).map(|bytes| (f, bytes)))
+            .collect();
 
-    let mut all_lines_and_errors: Vec<_> = all_files
-        .par_iter()
-        .map(|(f, bytes)| {
-            let lines = find_lines(bytes);
-            println!("Found {} lines", lines.len());
+        // Measure what was actually read, which differs from the size on disk for
+        // compressed files and cannot be known in advance for stdin.
+        let total_bytes = all_files.iter().map(|(_, bytes)| bytes.len() as u64).sum::<u64>();
 
-            let parsing_results: Vec<_> = lines
+        let (all_lines_and_errors, error_count) = if per_file {
+            write_per_file(&configuration, &progress, &all_files)?
+        } else {
+            // Process all files in parallel. Accumulate the lines written for each file so
+            // that they can be merged and written to a single, sorted, consolidated file.
+            let mut all_lines_and_errors: Vec<_> = all_files
                 .par_iter()
-                .enumerate()
-                .map(|(line_num, &line)| {
-                    let mut parsed_line_result = ParsedLine::parse(line);
-
-                    // Attach line number and original source.
-                    match parsed_line_result {
-                        Ok(ref mut pl) => {
-                            pl.line_num = line_num;
-                            pl.source = &f.filename_only_as_string
-                        }
-                        Err(ref mut e) => {
-                            e.line_num = line_num;
-                            e.source = &f.filename_only_as_string
-                        }
-                    };
-
-                    parsed_line_result
-                })
-                .filter(|parsed_line_result| should_output_line(&configuration, parsed_line_result))
+                .map(|(f, bytes)| parse_input_file(&configuration, &progress, f, bytes))
+                .flatten()
                 .collect();
 
-            parsing_results
-        })
-        .flatten()
-        .collect();
+            // The lines are collected in input order, so there is nothing to do if we are not sorting.
+            // The order doesn't affect the counts unless only some of the rows are selected.
+            if !configuration.no_sort && (!configuration.count || selects_rows(&configuration)) {
+                all_lines_and_errors.par_sort_by(|a, b| compare_lines(&configuration, a, b));
+            }
+            select_rows(&configuration, &mut all_lines_and_errors);
 
-    // Rust cannot always coerce a reference to an array such as:
-    //      &b""
-    // to a slice. We can force it to by [..]
-    // This should put the errors at the front.
-    all_lines_and_errors.par_sort_by_key(|r| match r {
-        Ok(ref v) => (v.log_date, v.source, v.line_num),
-        Err(ref e) => (&b""[..], e.source, e.line_num),
-    });
+            let error_count = write_or_count(&configuration, &all_lines_and_errors)?;
+            (all_lines_and_errors, error_count)
+        };
 
-    let total = all_lines_and_errors.len();
-    let error_count = write_output_files(&configuration, &all_lines_and_errors)?;
+        (total_bytes, error_count, LineStats::new(&configuration, &all_lines_and_errors))
+    };
+
+    let total = stats.line_count;
+    let level_counts = stats.level_counts(&configuration);
+    let app_counts = stats.app_counts();
+    let timing_stats = stats.timing_stats();
+    let empty_columns = stats.empty_columns(&configuration);
+
+    progress.finish();
+
+    // There is nothing worth looking at if no lines were written.
+    if configuration.open && !configuration.count && !configuration.stdout && !per_file && !configuration.split_by_day && !configuration.only_errors && total > error_count {
+        open_success_file(&configuration);
+    }
 
     let elapsed = start_time.elapsed();
-    println!(
+    print_status(&configuration, &format!(
         "Processed {} in {} files in {}.{:03} seconds, ok lines = {}, error lines = {}",
         HumanBytes(total_bytes),
         input_count,
@@ -159,11 +274,428 @@ fn main() -> Result<(), io::Error> {
         elapsed.subsec_millis(),
         total - error_count,
         error_count
-    );
+    ));
+
+    let skipped_files = progress.skipped_files();
+    if !skipped_files.is_empty() {
+        print_status(&configuration, &format!("Skipped binary files: {}", skipped_files.join(", ")));
+    }
+
+    if !configuration.quiet && !level_counts.is_empty() {
+        print_status(&configuration, &format_log_level_counts(&level_counts));
+    }
+
+    if !empty_columns.is_empty() {
+        print_status(&configuration, &format!(
+            "Warning: these columns were empty in every row, check the KVP names in the profile: {}",
+            empty_columns.join(", ")
+        ));
+    }
+
+    if configuration.stats {
+        print_status(&configuration, "Lines per application:");
+        for (app, count) in app_counts.iter().take(configuration.stats_top) {
+            print_status(&configuration, &format!("{:>10}  {}", count, app));
+        }
+    }
+
+    // Like `--stats`, the timings were asked for explicitly, so they are printed even when quiet.
+    if configuration.timing_stats {
+        if timing_stats.is_empty() {
+            print_status(&configuration, "No CallRecorderExecutionTime values found.");
+        } else {
+            print_status(&configuration, "CallRecorderExecutionTime (ms):");
+            for line in format_timing_stats(&configuration, &timing_stats) {
+                print_status(&configuration, &line);
+            }
+        }
+    }
+
+    if let Some(ref path) = configuration.summary_json {
+        let summary = RunSummary {
+            total_bytes,
+            file_count: input_count,
+            ok_lines: total - error_count,
+            error_lines: error_count,
+            elapsed_ms: elapsed.as_millis(),
+            level_counts: &level_counts,
+            app_counts: &app_counts,
+        };
+        write_summary_json(&configuration, path, &summary)?;
+    }
+
+    if let Err(message) = check_expected_rows(&configuration, total - error_count) {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
 
     Ok(())
 }
 
+/// Lists the input files, in the order in which they would be processed, with their sizes,
+/// followed by the total. The size of stdin is not known, so it is shown as "-".
+fn format_dry_run(inputs: &Inputs) -> Vec<String> {
+    let mut lines: Vec<_> = inputs.files.iter()
+        .map(|f| {
+            let size = if f.is_stdin { "-".to_string() } else { HumanBytes(f.length as u64).to_string() };
+            format!("{:>10}  {}", size, f.path_as_string)
+        })
+        .collect();
+
+    lines.push(format!("{:>10}  Total of {} files", HumanBytes(inputs.total_bytes()).to_string(), inputs.len()));
+    lines
+}
+
+/// Writes the output files, returning the number of error lines written. When only
+/// counting, nothing is written and the number of error lines is returned. When grouping,
+/// one row is written per group rather than per line.
+fn write_or_count(config: &Configuration, results: &[ParseLineResult]) -> Result<usize, io::Error> {
+    if config.count {
+        Ok(results.iter().filter(|r| r.is_err()).count())
+    } else if config.group_by.is_some() {
+        let groups = group_lines(config, results);
+        write_grouped_output_files(config, &groups, results)
+    } else {
+        write_output_files(config, results)
+    }
+}
+
+/// Reads a file into memory, or memory-maps it. A file which cannot be read, e.g. a
+/// corrupt .gz file, is reported and skipped. So is a file which looks binary, unless
+/// binary files are not being skipped.
+fn read_file(config: &Configuration, progress: &Progress, f: &InputFile) -> Option<FileBytes> {
+    let result = if config.mmap { f.map() } else { f.read().map(FileBytes::Read) };
+    match result {
+        Ok(ref bytes) if config.skip_binary && looks_binary(bytes, config.binary_threshold) => {
+            progress.file_skipped(f, "it looks like a binary file");
+            None
+        }
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            eprintln!("Could not read {}, ignoring. Error is {}", f.path_as_string, e);
+            None
+        }
+    }
+}
+
+/// Parses each file and writes its lines to its own output files, sorted within the file.
+/// The files are processed in parallel. Returns all the lines, for the summary, and the
+/// total number of error lines that were written.
+fn write_per_file<'f>(
+    config: &Configuration,
+    progress: &Progress,
+    all_files: &'f [(&'f InputFile, FileBytes)]
+    ) -> Result<(Vec<ParseLineResult<'f>>, usize), io::Error>
+{
+    let per_file_results = all_files
+        .par_iter()
+        .map(|(f, bytes)| {
+            let mut lines_and_errors = parse_input_file(config, progress, f, bytes);
+            if !config.no_sort {
+                lines_and_errors.sort_by(|a, b| compare_lines(config, a, b));
+            }
+            select_rows(config, &mut lines_and_errors);
+            let (success_file, error_file) = per_file_output_paths(config, f);
+            let error_count = write_output_files_to(config, &lines_and_errors, &success_file, &error_file)?;
+            Ok((lines_and_errors, error_count))
+        })
+        .collect::<Result<Vec<_>, io::Error>>()?;
+
+    let error_count = per_file_results.iter().map(|(_, error_count)| error_count).sum();
+    let all_lines_and_errors = per_file_results.into_iter().flat_map(|(lines, _)| lines).collect();
+    Ok((all_lines_and_errors, error_count))
+}
+
+/// Returns true if `select_rows` might remove some of the rows.
+fn selects_rows(config: &Configuration) -> bool {
+    config.tail.is_some() || config.skip > 0 || config.limit.is_some()
+}
+
+/// Keeps only the latest `tail` rows if there is a tail, then skips the first `skip` lines and
+/// errors, then keeps only the first `limit` of the rest if there is a limit. This must be
+/// called after sorting, and since errors sort first they are the first to be skipped and
+/// they count towards the limit.
+fn select_rows(config: &Configuration, lines_and_errors: &mut Vec<ParseLineResult>) {
+    if let Some(tail) = config.tail {
+        keep_tail(config, lines_and_errors, tail);
+    }
+
+    let skip = config.skip.min(lines_and_errors.len());
+    lines_and_errors.drain(..skip);
+
+    if let Some(limit) = config.limit {
+        lines_and_errors.truncate(limit);
+    }
+}
+
+/// Keeps only the latest `tail` rows. Errors have no timestamp, so they are treated as
+/// the oldest and are only kept if there are fewer than `tail` lines. Sorted results are
+/// errors followed by the lines, oldest first (newest first in reverse mode), so the
+/// latest lines are at the end or just after the errors respectively. If the results
+/// were not sorted, this simply keeps the last `tail` of them.
+fn keep_tail(config: &Configuration, lines_and_errors: &mut Vec<ParseLineResult>, tail: usize) {
+    let len = lines_and_errors.len();
+
+    if config.no_sort {
+        lines_and_errors.drain(..len - tail.min(len));
+        return;
+    }
+
+    let first_line = lines_and_errors.iter().position(|r| r.is_ok()).unwrap_or(len);
+    let kept_lines = tail.min(len - first_line);
+    let kept_errors = (tail - kept_lines).min(first_line);
+
+    // Remove the unwanted lines first, so that the positions of the errors do not change.
+    if config.reverse {
+        lines_and_errors.truncate(first_line + kept_lines);
+    } else {
+        lines_and_errors.drain(first_line..len - kept_lines);
+    }
+    lines_and_errors.drain(..first_line - kept_errors);
+}
+
+/// Parses all the lines in a file, returning those which should be written to the output.
+/// The lines are parsed in parallel and are not sorted.
+fn parse_input_file<'f>(config: &Configuration, progress: &Progress, f: &'f InputFile, bytes: &'f [u8]) -> Vec<ParseLineResult<'f>> {
+    let results = parse_lines(bytes, &f.filename_only_as_string, &f.path_as_string, &config.parse_options);
+    let num_lines = results.len();
+
+    let results = results
+        .into_par_iter()
+        .filter(|parsed_line_result| should_output_line(config, parsed_line_result))
+        .collect();
+
+    progress.file_parsed(config, f, &format!("Found {} lines", num_lines));
+    results
+}
+
+/// Parses a single line and attaches its line number and original source.
+#[cfg(test)]
+fn parse_line<'f>(config: &Configuration, line: &'f [u8], f: &'f InputFile, line_num: usize) -> ParseLineResult<'f> {
+    parsed_file::parse_line_at(line, &f.filename_only_as_string, &f.path_as_string, line_num, &config.parse_options)
+}
+
+/// The name used in the log level summary for lines with no recognized log level.
+const NO_LOG_LEVEL: &str = "(none)";
+
+/// The figures about the lines in the output which are reported at the end of a run. The
+/// figures for different sets of lines can be added together, which is how low-memory
+/// mode gathers them from each file in turn.
+#[derive(Default)]
+pub struct LineStats {
+    /// The number of lines and errors.
+    pub line_count: usize,
+    /// The number of successfully parsed lines at each configured log level, followed by
+    /// the number with no recognized log level.
+    level_counts: Vec<usize>,
+    app_counts: HashMap<String, usize>,
+    execution_times: HashMap<String, Vec<u64>>,
+    /// Whether each of the columns checked by `empty_columns` has a value in any line,
+    /// or None if there are no lines.
+    populated_columns: Option<Vec<bool>>,
+}
+
+impl LineStats {
+    pub fn new(config: &Configuration, results: &[ParseLineResult]) -> Self {
+        LineStats {
+            line_count: results.len(),
+            level_counts: count_log_levels(config, results),
+            app_counts: count_lines_by_app(config, results),
+            execution_times: collect_execution_times(config, results),
+            populated_columns: find_populated_columns(config, results),
+        }
+    }
+
+    pub fn add(&mut self, other: LineStats) {
+        self.line_count += other.line_count;
+
+        if self.level_counts.is_empty() {
+            self.level_counts = other.level_counts;
+        } else {
+            for (count, other_count) in self.level_counts.iter_mut().zip(other.level_counts) {
+                *count += other_count;
+            }
+        }
+
+        for (app, count) in other.app_counts {
+            *self.app_counts.entry(app).or_insert(0) += count;
+        }
+
+        for (group, times) in other.execution_times {
+            self.execution_times.entry(group).or_default().extend(times);
+        }
+
+        self.populated_columns = combine_populated_columns(self.populated_columns.take(), other.populated_columns);
+    }
+
+    /// Gets the number of successfully parsed lines at each log level, in the same order as
+    /// the configured levels, i.e. `kvp::LOG_LEVELS` then any from the profile. Levels which
+    /// have no lines are omitted.
+    fn level_counts(&self, config: &Configuration) -> Vec<(String, usize)> {
+        name_log_level_counts(config, &self.level_counts)
+    }
+
+    /// Gets the number of successfully parsed lines for each application, noisiest first.
+    fn app_counts(&self) -> Vec<(String, usize)> {
+        self.app_counts.iter()
+            .map(|(app, &count)| (app.clone(), count))
+            .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+            .collect()
+    }
+
+    fn timing_stats(&self) -> Vec<TimingStats> {
+        summarise_execution_times(&self.execution_times)
+    }
+
+    /// Gets the columns which are empty in every line, see `find_populated_columns`.
+    fn empty_columns(&self, config: &Configuration) -> Vec<String> {
+        match self.populated_columns {
+            Some(ref flags) => checked_columns(config).into_iter()
+                .zip(flags)
+                .filter(|&(_, &populated)| !populated)
+                .map(|(column, _)| column.clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+/// Counts the successfully parsed lines at each of the configured log levels, with the
+/// count of lines which have no recognized log level last.
+fn count_log_levels(config: &Configuration, results: &[ParseLineResult]) -> Vec<usize> {
+    let levels = &config.parse_options.log_levels;
+    let mut counts = vec![0; levels.len() + 1];
+    for line in results.iter().filter_map(|r| r.as_ref().ok()) {
+        let idx = levels.iter().position(|level| level.as_slice() == line.log_level);
+        counts[idx.unwrap_or(levels.len())] += 1;
+    }
+    counts
+}
+
+/// Pairs the counts from `count_log_levels` with the names of their levels, omitting
+/// the levels which have no lines.
+fn name_log_level_counts(config: &Configuration, counts: &[usize]) -> Vec<(String, usize)> {
+    let levels = &config.parse_options.log_levels;
+
+    // The levels look like "[INFO_]", but the brackets are just noise in the summary.
+    let names = levels.iter()
+        .map(|level| String::from_utf8_lossy(&level[1..level.len() - 1]).into_owned())
+        .chain(std::iter::once(NO_LOG_LEVEL.to_string()));
+
+    names.zip(counts.iter())
+        .filter(|&(_, &count)| count > 0)
+        .map(|(name, &count)| (name, count))
+        .collect()
+}
+
+/// Formats the log level counts as a single line, e.g. "INFO_: 12000, ERROR: 34".
+fn format_log_level_counts(level_counts: &[(String, usize)]) -> String {
+    level_counts.iter().map(|(name, count)| format!("{}: {}", name, count)).join(", ")
+}
+
+/// The column which identifies the application that wrote a line, for `--stats`.
+const APP_NAME_COLUMN: &str = "AppName";
+
+/// Counts the successfully parsed lines for each application. Lines with no application
+/// name (including alternate names) are counted under their file. Nothing is counted
+/// unless stats were asked for, so normal runs don't pay for it.
+fn count_lines_by_app(config: &Configuration, results: &[ParseLineResult]) -> HashMap<String, usize> {
+    if !config.stats {
+        return HashMap::new();
+    }
+
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    for line in results.iter().filter_map(|r| r.as_ref().ok()) {
+        let app = match get_column_value(config, line, APP_NAME_COLUMN) {
+            Some(value) if !value.is_empty() => value.as_ref(),
+            _ => line.source.as_bytes(),
+        };
+        *counts.entry(app).or_insert(0) += 1;
+    }
+
+    counts.into_iter()
+        .map(|(app, count)| (String::from_utf8_lossy(app).into_owned(), count))
+        .collect()
+}
+
+/// The columns which are built in rather than looked up in the KVPs. They are calculated
+/// from the line, so they cannot be empty because their name is misspelled.
+const NON_KVP_COLUMNS: [&str; 13] = [
+    kvp::LOG_DATE, kvp::LOG_DATE_ONLY, kvp::LOG_TIME_ONLY, kvp::LOG_LEVEL, kvp::LOG_SOURCE,
+    kvp::FILE_NAME, kvp::SOURCE_FILE, kvp::LINE_NUM, kvp::MESSAGE, kvp::RAW_LINE,
+    kvp::DELTA_MS, kvp::OTHER_KVPS, kvp::CATEGORY,
+];
+
+/// Gets the columns which are checked for being empty in every line, which usually means
+/// that the KVP name is misspelled in the profile. The columns of the built-in default
+/// profile are not checked, since they are spelled correctly and many logs do not have
+/// all of them. Nothing is checked when quiet.
+fn checked_columns(config: &Configuration) -> Vec<&String> {
+    if config.quiet {
+        return vec![];
+    }
+
+    config.columns.iter()
+        .filter(|column| !NON_KVP_COLUMNS.contains(&column.as_str()) && !is_default_column(column))
+        .collect()
+}
+
+/// Finds whether each of the checked columns, see `checked_columns`, has a value in any
+/// successfully parsed line. Each column has an "ever populated" flag, and the flags of
+/// the lines are combined in parallel. The result is None when there are no such lines,
+/// since then every column is empty, or nothing is checked.
+fn find_populated_columns(config: &Configuration, results: &[ParseLineResult]) -> Option<Vec<bool>> {
+    let columns = checked_columns(config);
+    if columns.is_empty() {
+        return None;
+    }
+
+    results.par_iter()
+        .filter_map(|r| r.as_ref().ok())
+        .fold(|| None, |flags: Option<Vec<bool>>, line| {
+            let mut flags = flags.unwrap_or_else(|| vec![false; columns.len()]);
+            for (flag, column) in flags.iter_mut().zip(&columns) {
+                *flag = *flag || get_column_value(config, line, column).is_some_and(|value| !value.is_empty());
+            }
+            Some(flags)
+        })
+        .reduce(|| None, combine_populated_columns)
+}
+
+/// Combines the flags from `find_populated_columns` for two sets of lines.
+fn combine_populated_columns(a: Option<Vec<bool>>, b: Option<Vec<bool>>) -> Option<Vec<bool>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.iter().zip(&b).map(|(x, y)| *x || *y).collect()),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Checks the number of successfully parsed rows against the expected number, if any.
+fn check_expected_rows(config: &Configuration, ok_rows: usize) -> Result<(), String> {
+    match config.expect_rows {
+        Some(expected) if ok_rows.max(expected) - ok_rows.min(expected) > config.expect_rows_tolerance => {
+            Err(format!(
+                "Expected {} rows (with a tolerance of {}) but {} were written",
+                expected,
+                config.expect_rows_tolerance,
+                ok_rows
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Prints a status message. When the output is going to stdout the message
+/// goes to stderr instead, so that it does not get mixed up with the output.
+fn print_status(config: &Configuration, message: &str) {
+    if config.stdout {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
 /// Applies the appropriate filtering to parsed line results.
 /// Errors are always passed through so that they can be written to the errors file,
 /// but successfully parsed lines may have a filter applied, for example to match a sysref
@@ -174,9 +706,12 @@ fn should_output_line(config: &Configuration, parsed_line_result: &ParseLineResu
     let line = parsed_line_result.as_ref().unwrap();
 
     // Do any date filters first, they are the most likely.
+    // A line whose date cannot be understood cannot be in range, so it is dropped.
     if config.from.is_some() || config.to.is_some() {
-        let date_slice = unsafe { std::str::from_utf8_unchecked(line.log_date) };
-        let date_in_line = string_to_utc_datetime_and_panic(date_slice);
+        let date_in_line = match line_datetime(&line.log_date) {
+            Some(dt) => dt,
+            None => return false,
+        };
 
         if let Some(from_date) = config.from {
             if date_in_line < from_date { return false; }
@@ -187,114 +722,1200 @@ fn should_output_line(config: &Configuration, parsed_line_result: &ParseLineResu
         }
     }
 
+    // Inverting applies to the outcome of all the content filters taken together, so
+    // an inverted line is one which fails at least one of them.
+    matches_content_filters(config, line) != config.invert_match
+}
+
+/// Converts the date of a line for comparison with `--from` and `--to`. With
+/// `--lenient-dates` the fractional seconds need not be digits, in which case only the
+/// whole seconds are used.
+fn line_datetime(log_date: &[u8]) -> Option<DateTime<Utc>> {
+    let parse = |bytes: &[u8]| std::str::from_utf8(bytes).ok().and_then(|s| string_to_utc_datetime(s).ok());
+
+    parse(log_date).or_else(|| {
+        let whole_seconds = log_date.split(|&c| c == b'.').next()?;
+        parse(whole_seconds)
+    })
+}
+
+/// Applies the filters on the content of a line, i.e. everything except the dates.
+fn matches_content_filters(config: &Configuration, line: &ParsedLine) -> bool {
+    // Where clauses. Every KVP the user named must have the value they gave.
+    for (column, value) in &config.where_clauses {
+        match get_column_value(config, line, column) {
+            Some(line_value) if line_value.eq_ignore_ascii_case(value) => {}
+            _ => return false,
+        }
+    }
+
+    // Match clauses. Every column the user named must match their regex.
+    for (column, regex) in &config.match_regexes {
+        match get_column_value(config, line, column) {
+            Some(line_value) if regex.is_match(line_value) => {}
+            _ => return false,
+        }
+    }
+
+    // Pid and tid filters. User can specify lists of pids and tids to pass through.
+    if !config.pids.is_empty() && !kvp_value_is_one_of(line, b"pid", &config.pids) {
+        return false;
+    }
+
+    if !config.tids.is_empty() && !kvp_value_is_one_of(line, b"tid", &config.tids) {
+        return false;
+    }
+
+    // Log level filter. User can specify list of levels to pass through.
+    if !config.log_levels.is_empty() && !config.log_levels.iter().any(|level| level.as_slice() == line.log_level) {
+        return false;
+    }
+
+    // Required columns filter. Every column the user named must have a non-blank value.
+    // Note that a KVP like 'SysRef=' has a blank value and so counts as missing.
+    for column in &config.required_columns {
+        match get_column_value(config, line, column) {
+            Some(value) if !value.is_empty() => {}
+            _ => return false,
+        }
+    }
+
     // SysRef filter. User can specify list of sysrefs to pass through.
     if !config.sysrefs.is_empty() {
         let sr_from_line = line.kvps.get_value(b"sysref");
         if sr_from_line.is_none() { return false; }
         let sr_value = sr_from_line.unwrap();
-        return config.sysrefs.iter().any(|sr| sr == &sr_value.as_ref());
+        return if config.case_insensitive_sysref {
+            config.sysrefs.iter().any(|sr| sr.eq_ignore_ascii_case(sr_value))
+        } else {
+            config.sysrefs.iter().any(|sr| sr == &sr_value.as_ref())
+        };
     }
 
     true
 }
 
-/// Look for the \r\n line endings in the file and return a vector of
-/// slices, each slice being one line in the log file. Be careful not to be confused
-/// by any stray '\r's in the log file.
-fn find_lines(bytes: &[u8]) -> Vec<&[u8]> {
-    let mut cr_indexes: Vec<_> = bytes.iter().positions(|&c| c == b'\r').collect();
-    cr_indexes.retain(|&idx| idx == bytes.len() - 1 || bytes[idx + 1] == b'\n');
-    cr_indexes.insert(0, 0);
-    let last_idx = cr_indexes[cr_indexes.len() - 1];
-    if last_idx == bytes.len() - 2 || last_idx == bytes.len() - 1 {
-        // The last idx is at the end of the file (accounting for "\r\n").
-    } else {
-        // It isn't. Be sure to include the trailing data in a slice.
-        cr_indexes.push(bytes.len() - 1);
+/// Checks whether the line has a KVP with one of the allowed values. Surrounding
+/// whitespace in the value is ignored. Lines without the KVP never match.
+fn kvp_value_is_one_of(line: &ParsedLine, key: &[u8], allowed: &[Vec<u8>]) -> bool {
+    match line.kvps.get_value(key) {
+        Some(value) => {
+            let value = value.trim_while(|c| c.is_whitespace());
+            allowed.iter().any(|a| a.as_slice() == value)
+        },
+        None => false,
     }
+}
 
-    cr_indexes
-        .windows(2)
-        .map(|window| &bytes[window[0]..window[1]])
-        .collect()
+/// Determines the order of lines in the output. Errors go at the front, then lines are
+/// sorted by the 'sort by' column (if any), then by date, then by the 'then by' column (if any),
+/// then by source, path and line number, and finally by the text of the line itself. This makes
+/// the order fully deterministic, so that the output of different runs can be compared.
+/// In reverse mode the order of the lines is reversed, but the errors are left at the front
+/// in the order they were found, so that the output is still deterministic.
+fn compare_lines(config: &Configuration, a: &ParseLineResult, b: &ParseLineResult) -> Ordering {
+    match (a, b) {
+        (Err(a), Err(b)) => (a.source, a.path, a.line_num, a.line).cmp(&(b.source, b.path, b.line_num, b.line)),
+        (Err(_), Ok(_)) => Ordering::Less,
+        (Ok(_), Err(_)) => Ordering::Greater,
+        (Ok(a), Ok(b)) => compare_sort_keys(config, &SortKey::new(config, a), &SortKey::new(config, b)),
+    }
 }
 
-const EMPTY: [&[u8]; 0] = [];
-
-fn write_output_files(config: &Configuration, results: &[ParseLineResult]) -> Result<usize, io::Error> {
-    const SUCCESS_FILE: &str = "consolidated.csv";
-    const ERROR_FILE: &str = "errors.csv";
-
-    let mut error_count = 0;
-    let mut success_writer = WriterBuilder::new()
-        .flexible(true)
-        .from_path(SUCCESS_FILE)?;
-    let mut error_writer = WriterBuilder::new().flexible(true).from_path(ERROR_FILE)?;
-
-    success_writer.write_record(config.columns.iter())?;
-    error_writer.write_field("Source")?;
-    error_writer.write_field("LineNum")?;
-    error_writer.write_field("Message")?;
-    error_writer.write_field("Line")?;
-    error_writer.write_record(&EMPTY)?;
-
-    for result in results {
-        match result {
-            Ok(parsed_line) => write_line(config, &mut success_writer, parsed_line)?,
-            Err(parsed_line_error) => {
-                error_writer.write_field(parsed_line_error.source)?;
-                error_writer.write_field(parsed_line_error.line_num.to_string())?;
-                error_writer.write_field(&parsed_line_error.message)?;
-                error_writer.write_field(parsed_line_error.line)?;
-                error_writer.write_record(&EMPTY)?;
-                error_count += 1;
-            }
+/// The values of a successfully parsed line which determine its place in the output.
+/// They are kept separately by low-memory mode, which does not keep the lines.
+struct SortKey<'a> {
+    sort_by: &'a [u8],
+    log_date: &'a [u8],
+    then_by: &'a [u8],
+    source: &'a str,
+    path: &'a str,
+    line_num: usize,
+    line: &'a [u8],
+}
+
+impl<'a> SortKey<'a> {
+    fn new(config: &'a Configuration, line: &'a ParsedLine) -> Self {
+        let column_value = |column: &Option<String>| column.as_ref().map_or(&b""[..], |column| get_output_value(config, line, column));
+
+        SortKey {
+            sort_by: column_value(&config.sort_by),
+            log_date: &line.log_date,
+            then_by: column_value(&config.then_by),
+            source: line.source,
+            path: line.path,
+            line_num: line.line_num,
+            line: line.line,
         }
     }
+}
 
-    success_writer.flush()?;
-    error_writer.flush()?;
-
-    // Did we need this file?
-    if error_count == 0 {
-        fs::remove_file(ERROR_FILE)?;
-    }
-
-    Ok(error_count)
-}
-
-fn write_line(config: &Configuration, writer: &mut csv::Writer<std::fs::File>, line: &ParsedLine) -> Result<(), io::Error> {
-    for column in &config.columns {
-        match column.as_str() {
-            kvp::LOG_DATE => writer.write_field(line.log_date)?,
-            kvp::LOG_LEVEL => writer.write_field(line.log_level)?,
-            kvp::LOG_SOURCE => writer.write_field(line.source)?,
-            kvp::MESSAGE => writer.write_field(&line.message)?,
-            _ => {
-                if let Some(kvp_value) = line.kvps.get_value(column.as_bytes()) {
-                    writer.write_field(&kvp_value)?;
-                } else {
-                    // Check for the column under any alternative names.
-                    let mut did_write = false;
-                    if let Some(alternate_names) = config.alternate_column_names.get(column) {
-                        for alt_name in alternate_names {
-                            if let Some(kvp_value) = line.kvps.get_value(alt_name.as_bytes()) {
-                                writer.write_field(&kvp_value)?;
-                                did_write = true;
-                                break;
-                            }
-                        }
-                    }
-
-                    if !did_write {
-                        writer.write_field(b"")?;
-                    }
-                }
-            }
+/// Compares two successfully parsed lines by their sort keys, see `compare_lines`.
+fn compare_sort_keys(config: &Configuration, a: &SortKey, b: &SortKey) -> Ordering {
+    let compare_column = |column: &Option<String>, a: &[u8], b: &[u8]| match column {
+        Some(ref column) => compare_values(a, b, config.is_numeric_column(column)),
+        None => Ordering::Equal,
+    };
+
+    let ordering = compare_column(&config.sort_by, a.sort_by, b.sort_by)
+        .then_with(|| a.log_date.cmp(b.log_date))
+        .then_with(|| compare_column(&config.then_by, a.then_by, b.then_by))
+        .then_with(|| (a.source, a.path, a.line_num, a.line).cmp(&(b.source, b.path, b.line_num, b.line)));
+
+    if config.reverse { ordering.reverse() } else { ordering }
+}
+
+/// Compares two column values. Numbers are compared numerically, so that "9" comes before
+/// "10". In a `numeric` column, such as PID, only whole numbers count as numbers. Values which
+/// are not numbers sort before all the numbers, and among themselves as bytes, so that the
+/// order is consistent however the numbers and other values are mixed.
+fn compare_values(a: &[u8], b: &[u8], numeric: bool) -> Ordering {
+    let parse = |v: &[u8]| {
+        let s = std::str::from_utf8(v).ok()?.trim();
+        if numeric { s.parse::<i64>().ok().map(|n| n as f64) } else { s.parse::<f64>().ok() }
+    };
+
+    match (parse(a), parse(b)) {
+        (Some(x), Some(y)) => x.total_cmp(&y),
+        (None, None) => a.cmp(b),
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+    }
+}
+
+#[cfg(test)]
+mod count_tests {
+    use super::*;
+    use crate::output::test_utils::make_temp_dir;
+
+    #[test]
+    pub fn count_matches_what_would_be_written() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+
+        let f = InputFile::with_path("a.log");
+        let results = vec![
+            parse_line(&config, b"2018-09-26 12:34:56.0000000 | [INFO_] | A", &f, 0),
+            parse_line(&config, b"garbage", &f, 1),
+            parse_line(&config, b"2018-09-26 12:34:57.0000000 | [INFO_] | B", &f, 2),
+        ];
+
+        let dir = make_temp_dir("count");
+        let success_file = dir.join("consolidated.csv");
+        let written_errors = write_output_files_to(&config, &results, &success_file, &dir.join("errors.csv")).unwrap();
+        let written_lines = std::fs::read_to_string(&success_file).unwrap().lines().count() - 1;
+
+        config.count = true;
+        let counted_errors = write_or_count(&config, &results).unwrap();
+        assert_eq!(counted_errors, written_errors);
+        assert_eq!(results.len() - counted_errors, written_lines);
+    }
+
+    #[test]
+    pub fn selects_rows_only_with_tail_skip_or_limit() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        assert!(!selects_rows(&config));
+
+        let args = Arguments { skip: 1, .. Arguments::default() };
+        assert!(selects_rows(&get_config(&ProfileSet::default(), &args).unwrap()));
+
+        let args = Arguments { tail: Some(5), .. Arguments::default() };
+        assert!(selects_rows(&get_config(&ProfileSet::default(), &args).unwrap()));
+    }
+}
+
+#[cfg(test)]
+mod should_output_line_tests {
+    use super::*;
+
+    fn make_config(args: &Arguments) -> Configuration {
+        get_config(&ProfileSet::default(), args).unwrap()
+    }
+
+    #[test]
+    pub fn for_level_keeps_only_lines_at_that_level_and_errors() {
+        let args = Arguments { levels: vec!["error".to_string()], .. Arguments::default() };
+        let config = make_config(&args);
+
+        let lines: Vec<&[u8]> = vec![
+            b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | Started",
+            b"2018-09-26 12:34:57.7654321 | pid=1 | [ERROR] | Failed",
+            b"2018-09-26 12:34:58.7654321 | pid=1 | [WARNG] | Retrying",
+            b"garbage",
+            b"2018-09-26 12:34:59.7654321 | pid=1 | [ERROR] | Failed again",
+        ];
+
+        let survivors: Vec<_> = lines.into_iter()
+            .map(ParsedLine::parse)
+            .filter(|r| should_output_line(&config, r))
+            .collect();
+
+        assert_eq!(survivors.len(), 3);
+        assert!(survivors[1].is_err(), "Errors should always be output");
+        assert_eq!(survivors[0].as_ref().unwrap().message, &b"Failed"[..]);
+        assert_eq!(survivors[2].as_ref().unwrap().message, &b"Failed again"[..]);
+    }
+
+    #[test]
+    pub fn for_sysref_matches_case_only_when_case_insensitive() {
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Message sysref=qu090700");
+
+        let args = Arguments { sysrefs: vec!["QU090700".to_string()], .. Arguments::default() };
+        assert!(!should_output_line(&make_config(&args), &line));
+
+        let args = Arguments { sysrefs: vec!["QU090700".to_string()], case_insensitive_sysref: true, .. Arguments::default() };
+        assert!(should_output_line(&make_config(&args), &line));
+    }
+
+    #[test]
+    pub fn for_inverted_sysref_keeps_only_lines_without_that_sysref_and_errors() {
+        let args = Arguments { sysrefs: vec!["QU090700".to_string()], invert_match: true, .. Arguments::default() };
+        let config = make_config(&args);
+
+        let lines: Vec<&[u8]> = vec![
+            b"2018-09-26 12:34:56.7654321 | [INFO_] | Wanted sysref=QU090700",
+            b"2018-09-26 12:34:57.7654321 | [INFO_] | Other sysref=QU123456",
+            b"2018-09-26 12:34:58.7654321 | [INFO_] | None",
+            b"garbage",
+        ];
+
+        let survivors: Vec<_> = lines.into_iter()
+            .map(ParsedLine::parse)
+            .filter(|r| should_output_line(&config, r))
+            .collect();
+
+        assert_eq!(survivors.len(), 3);
+        assert_eq!(survivors[0].as_ref().unwrap().message, &b"Other sysref=QU123456"[..]);
+        assert_eq!(survivors[1].as_ref().unwrap().message, &b"None"[..]);
+        assert!(survivors[2].is_err(), "Errors should always be output");
+    }
+
+    #[test]
+    pub fn for_inverted_filters_drops_only_lines_passing_all_of_them() {
+        let args = Arguments {
+            levels: vec!["[ERROR]".to_string()],
+            sysrefs: vec!["QU090700".to_string()],
+            invert_match: true,
+            .. Arguments::default()
+        };
+        let config = make_config(&args);
+
+        let both = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [ERROR] | Both sysref=QU090700");
+        let level_only = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [ERROR] | Level only");
+        let sysref_only = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Sysref only sysref=QU090700");
+
+        assert!(!should_output_line(&config, &both));
+        assert!(should_output_line(&config, &level_only));
+        assert!(should_output_line(&config, &sysref_only));
+    }
+
+    #[test]
+    pub fn for_inverted_match_still_applies_date_filters() {
+        let args = Arguments { from: Some("2018-09-27".to_string()), sysrefs: vec!["QU090700".to_string()], invert_match: true, .. Arguments::default() };
+        let config = make_config(&args);
+
+        let too_early = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Early");
+        let in_range = ParsedLine::parse(b"2018-09-27 12:34:56.7654321 | [INFO_] | In range");
+
+        assert!(!should_output_line(&config, &too_early));
+        assert!(should_output_line(&config, &in_range));
+    }
+
+    #[test]
+    pub fn for_lenient_dates_applies_date_filters_to_whole_seconds() {
+        let args = Arguments { from: Some("2018-09-27".to_string()), lenient_dates: true, .. Arguments::default() };
+        let config = make_config(&args);
+
+        let too_early = ParsedLine::parse_with_options(b"2018-09-26 12:34:56.76x4321 | [INFO_] | Early", &config.parse_options);
+        let in_range = ParsedLine::parse_with_options(b"2018-09-27 12:34:56.76x4321 | [INFO_] | In range", &config.parse_options);
+        let not_utf8 = ParsedLine::parse_with_options(b"2018-09-27 12:34:56.76\xff4321 | [INFO_] | Not UTF-8", &config.parse_options);
+
+        assert!(in_range.is_ok() && too_early.is_ok() && not_utf8.is_ok());
+        assert!(!should_output_line(&config, &too_early));
+        assert!(should_output_line(&config, &in_range));
+        assert!(should_output_line(&config, &not_utf8));
+    }
+
+    #[test]
+    pub fn line_datetime_for_unparseable_date_returns_none() {
+        assert!(line_datetime(b"2018-09-26 12:34:56.7654321").is_some());
+        assert!(line_datetime(b"2018-13-26 12:34:56.7654321").is_none());
+    }
+
+    #[test]
+    pub fn for_where_clauses_keeps_only_lines_matching_all() {
+        let args = Arguments {
+            where_clauses: vec![
+                ("AppName".to_string(), "web".to_string()),
+                ("Action".to_string(), "Save".to_string()),
+            ],
+            .. Arguments::default()
+        };
+        let config = make_config(&args);
+
+        let lines: Vec<&[u8]> = vec![
+            b"2018-09-26 12:34:56.7654321 | AppName=Web | [INFO_] | First Action=save",
+            b"2018-09-26 12:34:56.7654321 | ApplicationName=WEB | [INFO_] | Second Action=Save",
+            b"2018-09-26 12:34:56.7654321 | AppName=Web | [INFO_] | Wrong action Action=Load",
+            b"2018-09-26 12:34:56.7654321 | AppName=Web | [INFO_] | No action",
+        ];
+
+        let survivors: Vec<_> = lines.into_iter()
+            .map(ParsedLine::parse)
+            .filter(|r| should_output_line(&config, r))
+            .collect();
+
+        assert_eq!(survivors.len(), 2);
+        assert!(survivors[0].as_ref().unwrap().message.starts_with(b"First"));
+        assert!(survivors[1].as_ref().unwrap().message.starts_with(b"Second"));
+    }
+
+    #[test]
+    pub fn for_match_clauses_keeps_only_lines_matching_all_regexes() {
+        let args = Arguments {
+            match_clauses: vec![("Action".to_string(), "^Get".to_string())],
+            levels: vec!["info_".to_string()],
+            .. Arguments::default()
+        };
+        let config = make_config(&args);
+
+        let lines: Vec<&[u8]> = vec![
+            b"2018-09-26 12:34:56.7654321 | [INFO_] | First Action=GetQuote",
+            b"2018-09-26 12:34:56.7654321 | [INFO_] | Second Action=getPolicy",
+            b"2018-09-26 12:34:56.7654321 | [INFO_] | Wrong action Action=ForgetQuote",
+            b"2018-09-26 12:34:56.7654321 | [ERROR] | Wrong level Action=GetQuote",
+            b"2018-09-26 12:34:56.7654321 | [INFO_] | No action",
+        ];
+
+        let survivors: Vec<_> = lines.into_iter()
+            .map(ParsedLine::parse)
+            .filter(|r| should_output_line(&config, r))
+            .collect();
+
+        assert_eq!(survivors.len(), 2);
+        assert!(survivors[0].as_ref().unwrap().message.starts_with(b"First"));
+        assert!(survivors[1].as_ref().unwrap().message.starts_with(b"Second"));
+    }
+
+    #[test]
+    pub fn for_where_clause_with_empty_value_keeps_lines_with_blank_kvp() {
+        let args = Arguments { where_clauses: vec![("SysRef".to_string(), "".to_string())], .. Arguments::default() };
+        let config = make_config(&args);
+
+        let lines: Vec<&[u8]> = vec![
+            b"2018-09-26 12:34:56.7654321 | [INFO_] | Blank SysRef=",
+            b"2018-09-26 12:34:56.7654321 | [INFO_] | Set SysRef=QU1",
+            b"2018-09-26 12:34:56.7654321 | [INFO_] | Missing",
+        ];
+
+        let survivors: Vec<_> = lines.into_iter()
+            .map(ParsedLine::parse)
+            .filter(|r| should_output_line(&config, r))
+            .collect();
+
+        assert_eq!(survivors.len(), 1);
+        assert!(survivors[0].as_ref().unwrap().message.starts_with(b"Blank"));
+    }
+
+    #[test]
+    pub fn for_pid_keeps_only_lines_from_that_process() {
+        let args = Arguments { pids: vec!["12".to_string(), " 7 ".to_string()], .. Arguments::default() };
+        let config = make_config(&args);
+
+        let lines: Vec<&[u8]> = vec![
+            b"2018-09-26 12:34:56.7654321 | pid=12 | tid=1 | [INFO_] | First",
+            b"2018-09-26 12:34:56.7654321 | pid=123 | tid=1 | [INFO_] | Second",
+            b"2018-09-26 12:34:56.7654321 | PID=\" 7\" | tid=1 | [INFO_] | Third",
+            b"2018-09-26 12:34:56.7654321 | tid=1 | [INFO_] | No pid at all",
+        ];
+
+        let survivors: Vec<_> = lines.into_iter()
+            .map(ParsedLine::parse)
+            .filter(|r| should_output_line(&config, r))
+            .collect();
+
+        assert_eq!(survivors.len(), 2);
+        assert_eq!(survivors[0].as_ref().unwrap().message, &b"First"[..]);
+        assert_eq!(survivors[1].as_ref().unwrap().message, &b"Third"[..]);
+    }
+
+    #[test]
+    pub fn for_pid_and_tid_and_sysref_all_must_match() {
+        let args = Arguments {
+            pids: vec!["12".to_string()],
+            tids: vec!["2".to_string()],
+            sysrefs: vec!["QU1".to_string()],
+            .. Arguments::default()
+        };
+        let config = make_config(&args);
+
+        let lines: Vec<&[u8]> = vec![
+            b"2018-09-26 12:34:56.7654321 | pid=12 | tid=2 | [INFO_] | All match SysRef=QU1",
+            b"2018-09-26 12:34:56.7654321 | pid=12 | tid=3 | [INFO_] | Wrong tid SysRef=QU1",
+            b"2018-09-26 12:34:56.7654321 | pid=12 | tid=2 | [INFO_] | Wrong sysref SysRef=QU2",
+        ];
+
+        let survivors: Vec<_> = lines.into_iter()
+            .map(ParsedLine::parse)
+            .filter(|r| should_output_line(&config, r))
+            .collect();
+
+        assert_eq!(survivors.len(), 1);
+        assert!(survivors[0].as_ref().unwrap().message.starts_with(b"All match"));
+    }
+
+    #[test]
+    pub fn for_required_column_keeps_only_lines_with_a_value() {
+        let args = Arguments { required_columns: vec!["CallRecorderExecutionTime".to_string()], .. Arguments::default() };
+        let config = make_config(&args);
+
+        let lines: Vec<&[u8]> = vec![
+            b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | Call made CallRecorderExecutionTime=134",
+            b"2018-09-26 12:34:57.7654321 | pid=1 | [INFO_] | Call made",
+        ];
+
+        let survivors: Vec<_> = lines.into_iter()
+            .map(ParsedLine::parse)
+            .filter(|r| should_output_line(&config, r))
+            .collect();
+
+        assert_eq!(survivors.len(), 1);
+        let line = survivors[0].as_ref().unwrap();
+        assert_eq!(line.kvps.get_value(b"CallRecorderExecutionTime").unwrap().as_ref(), b"134");
+    }
+
+    #[test]
+    pub fn for_required_column_treats_blank_value_as_missing() {
+        let args = Arguments { required_columns: vec!["SysRef".to_string()], .. Arguments::default() };
+        let config = make_config(&args);
+
+        let result = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | Message SysRef=");
+        assert!(!should_output_line(&config, &result));
+    }
+
+    #[test]
+    pub fn for_required_column_checks_alternate_names() {
+        let args = Arguments { required_columns: vec!["AppName".to_string()], .. Arguments::default() };
+        let config = make_config(&args);
+
+        let result = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | ApplicationName=Foo | [INFO_] | Message");
+        assert!(should_output_line(&config, &result));
+    }
+}
+
+#[cfg(test)]
+mod check_expected_rows_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_no_expected_rows_passes() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        assert!(check_expected_rows(&config, 42).is_ok());
+    }
+
+    #[test]
+    pub fn for_matching_rows_passes() {
+        let args = Arguments { expect_rows: Some(42), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        assert!(check_expected_rows(&config, 42).is_ok());
+    }
+
+    #[test]
+    pub fn for_mismatched_rows_fails() {
+        let args = Arguments { expect_rows: Some(42), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let message = check_expected_rows(&config, 41).expect_err("Should fail");
+        assert!(message.contains("Expected 42 rows"));
+        assert!(message.contains("41 were written"));
+    }
+
+    #[test]
+    pub fn for_rows_within_tolerance_passes() {
+        let args = Arguments { expect_rows: Some(42), expect_rows_tolerance: 2, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        assert!(check_expected_rows(&config, 40).is_ok());
+        assert!(check_expected_rows(&config, 44).is_ok());
+        assert!(check_expected_rows(&config, 45).is_err());
+    }
+}
+
+#[cfg(test)]
+mod compare_lines_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_then_by_orders_equal_timestamps_numerically() {
+        let args = Arguments { then_by: Some("SourceInstance".to_string()), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        let mut results = [
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | SourceInstance=10 | [INFO_] | Third"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | SourceInstance=9 | [INFO_] | Second"),
+            ParsedLine::parse(b"2018-09-26 12:34:57.7654321 | SourceInstance=1 | [INFO_] | Fourth"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | SourceInstance=2 | [INFO_] | First"),
+        ];
+
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+
+        let messages: Vec<_> = results.iter().map(|r| r.as_ref().unwrap().message.as_ref()).collect();
+        assert_eq!(messages, vec![&b"First"[..], b"Second", b"Third", b"Fourth"]);
+    }
+
+    #[test]
+    pub fn for_sort_by_numeric_column_orders_numerically_with_non_numbers_first() {
+        let args = Arguments { sort_by: Some("PID".to_string()), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        let mut results = [
+            ParsedLine::parse(b"2018-09-26 12:34:56.1000000 | pid=100 | [INFO_] | Hundred"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.2000000 | pid=9 | [INFO_] | Nine"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.3000000 | pid=1a | [INFO_] | NotANumber"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.4000000 | pid=10 | [INFO_] | Ten"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.5000000 | [INFO_] | Missing"),
+        ];
+
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+
+        let messages: Vec<_> = results.iter().map(|r| r.as_ref().unwrap().message.as_ref()).collect();
+        assert_eq!(messages, vec![&b"Missing"[..], b"NotANumber", b"Nine", b"Ten", b"Hundred"]);
+    }
+
+    #[test]
+    pub fn compare_values_for_numeric_column_is_consistent_for_mixed_values() {
+        assert_eq!(compare_values(b"9", b"10", true), Ordering::Less);
+        assert_eq!(compare_values(b"10", b"1a", true), Ordering::Greater);
+        assert_eq!(compare_values(b"1a", b"9", true), Ordering::Less);
+        assert_eq!(compare_values(b"", b"1a", true), Ordering::Less);
+        assert_eq!(compare_values(b" 7 ", b"7", true), Ordering::Equal);
+        assert_eq!(compare_values(b"1.5", b"1", true), Ordering::Less);
+        assert_eq!(compare_values(b"1.5", b"1", false), Ordering::Greater);
+    }
+
+    #[test]
+    pub fn for_equal_timestamps_and_names_orders_by_path_then_line() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let a = InputFile::with_path("/logs/a/app.log");
+        let b = InputFile::with_path("/logs/b/app.log");
+        let line_a = b"2018-09-26 12:34:56.7654321 | [INFO_] | From a";
+        let line_b = b"2018-09-26 12:34:56.7654321 | [INFO_] | From b";
+        let line_b2 = b"2018-09-26 12:34:56.7654321 | [INFO_] | From b again";
+
+        let expected = vec![&b"From a"[..], b"From b", b"From b again"];
+        for order in &[[0, 1, 2], [2, 1, 0], [1, 2, 0]] {
+            let lines = [(&line_a[..], &a), (&line_b[..], &b), (&line_b2[..], &b)];
+            let mut results: Vec<_> = order.iter()
+                .map(|&i| parse_line(&config, lines[i].0, lines[i].1, 7))
+                .collect();
+
+            results.sort_by(|x, y| compare_lines(&config, x, y));
+
+            let messages: Vec<_> = results.iter().map(|r| r.as_ref().unwrap().message.as_ref()).collect();
+            assert_eq!(messages, expected);
         }
     }
 
-    writer.write_record(&EMPTY)?;
+    #[test]
+    pub fn puts_errors_first() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
 
-    Ok(())
+        let mut results = [
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Good"),
+            ParsedLine::parse(b"Bad"),
+        ];
+
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+    }
+
+    #[test]
+    pub fn for_sort_by_groups_by_column_then_date() {
+        let args = Arguments { sort_by: Some("CorrelationKey".to_string()), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        let mut results = [
+            ParsedLine::parse(b"2018-09-26 12:34:59.7654321 | CorrelationKey=B | [INFO_] | Fourth"),
+            ParsedLine::parse(b"2018-09-26 12:34:58.7654321 | CorrelationKey=A | [INFO_] | Third"),
+            ParsedLine::parse(b"2018-09-26 12:34:57.7654321 | CorrelationKey=B | [INFO_] | Fifth"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | CorrelationKey=A | [INFO_] | Second"),
+            ParsedLine::parse(b"2018-09-26 12:34:59.7654321 | [INFO_] | First"),
+        ];
+
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+
+        let messages: Vec<_> = results.iter().map(|r| r.as_ref().unwrap().message.as_ref()).collect();
+        assert_eq!(messages, vec![&b"First"[..], b"Second", b"Third", b"Fifth", b"Fourth"]);
+    }
+
+    #[test]
+    pub fn for_sort_by_log_level_uses_the_level() {
+        let args = Arguments { sort_by: Some(kvp::LOG_LEVEL.to_string()), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        let mut results = [
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Second"),
+            ParsedLine::parse(b"2018-09-26 12:34:57.7654321 | [ERROR] | First"),
+        ];
+
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+
+        let messages: Vec<_> = results.iter().map(|r| r.as_ref().unwrap().message.as_ref()).collect();
+        assert_eq!(messages, vec![&b"First"[..], b"Second"]);
+    }
+
+    #[test]
+    pub fn for_reverse_puts_newest_first_and_errors_still_first() {
+        let args = Arguments { reverse: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        let mut results = [
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Older"),
+            ParsedLine::parse(b"Bad"),
+            ParsedLine::parse(b"2018-09-26 12:34:57.7654321 | [INFO_] | Newer"),
+        ];
+
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap().message, &b"Newer"[..]);
+        assert_eq!(results[2].as_ref().unwrap().message, &b"Older"[..]);
+    }
+
+    #[test]
+    pub fn compare_values_falls_back_to_bytes_for_non_numbers() {
+        assert_eq!(compare_values(b"9", b"10", false), Ordering::Less);
+        assert_eq!(compare_values(b"abc", b"abd", false), Ordering::Less);
+        assert_eq!(compare_values(b"9", b"abc", false), Ordering::Greater);
+        assert_eq!(compare_values(b"", b"1", false), Ordering::Less);
+    }
+
+    #[test]
+    pub fn compare_values_is_consistent_for_mixed_values() {
+        assert_eq!(compare_values(b"10", b"1a", false), Ordering::Greater);
+        assert_eq!(compare_values(b"1a", b"9", false), Ordering::Less);
+        assert_eq!(compare_values(b"NaN", b"NaN", false), Ordering::Equal);
+        assert_ne!(compare_values(b"NaN", b"1", false), Ordering::Equal);
+
+        let expected = vec!["", "1a", "abc", "-1.5", "9", "10", "NaN"];
+        for start in 0..expected.len() {
+            let mut values = expected.clone();
+            values.rotate_left(start);
+            values.reverse();
+            values.sort_by(|a, b| compare_values(a.as_bytes(), b.as_bytes(), false));
+            assert_eq!(values, expected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod log_level_summary_tests {
+    use super::*;
+
+    #[test]
+    pub fn counts_ok_lines_by_level_in_log_levels_order() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let results = [
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [ERROR] | Failed"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | One"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Two"),
+            ParsedLine::parse(b"garbage"),
+        ];
+
+        let counts = LineStats::new(&config, &results).level_counts(&config);
+        assert_eq!(counts, vec![("INFO_".to_string(), 2), ("ERROR".to_string(), 1)]);
+        assert_eq!(format_log_level_counts(&counts), "INFO_: 2, ERROR: 1");
+    }
+
+    #[test]
+    pub fn for_line_without_log_level_counts_it_as_none() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let results = [ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | No level here")];
+        assert_eq!(LineStats::new(&config, &results).level_counts(&config), vec![(NO_LOG_LEVEL.to_string(), 1)]);
+    }
+
+    #[test]
+    pub fn for_no_ok_lines_returns_empty() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let results = [ParsedLine::parse(b"garbage")];
+        assert!(LineStats::new(&config, &results).level_counts(&config).is_empty());
+    }
+
+    #[test]
+    pub fn counts_configured_levels_after_the_defaults() {
+        let mut config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        config.add_log_level("CRITICAL");
+        let results = [
+            ParsedLine::parse_with_options(b"2018-09-26 12:34:56.7654321 | [CRITICAL] | Down", &config.parse_options),
+            ParsedLine::parse_with_options(b"2018-09-26 12:34:56.7654321 | [INFO_] | One", &config.parse_options),
+        ];
+
+        assert_eq!(LineStats::new(&config, &results).level_counts(&config), vec![("INFO_".to_string(), 1), ("CRITICAL".to_string(), 1)]);
+    }
+}
+
+#[cfg(test)]
+mod app_stats_tests {
+    use super::*;
+
+    fn parse<'f>(line: &'f [u8], source: &'f str) -> ParseLineResult<'f> {
+        let mut result = ParsedLine::parse(line);
+        if let Ok(ref mut parsed_line) = result {
+            parsed_line.source = source;
+        }
+        result
+    }
+
+    #[test]
+    pub fn counts_by_app_name_noisiest_first() {
+        let args = Arguments { stats: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let results = [
+            parse(b"2018-09-26 12:34:56.7654321 | AppName=Quiet | [INFO_] | One", "a.log"),
+            parse(b"2018-09-26 12:34:56.7654321 | AppName=Noisy | [INFO_] | Two", "a.log"),
+            parse(b"2018-09-26 12:34:56.7654321 | ApplicationName=Noisy | [INFO_] | Three", "b.log"),
+            parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Four", "c.log"),
+            parse(b"garbage", "c.log"),
+        ];
+
+        let counts = LineStats::new(&config, &results).app_counts();
+        assert_eq!(counts, vec![
+            ("Noisy".to_string(), 2),
+            ("Quiet".to_string(), 1),
+            ("c.log".to_string(), 1),
+        ]);
+    }
+
+    #[test]
+    pub fn without_stats_counts_nothing() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let results = [parse(b"2018-09-26 12:34:56.7654321 | AppName=Quiet | [INFO_] | One", "a.log")];
+        assert!(LineStats::new(&config, &results).app_counts().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod empty_columns_tests {
+    use super::*;
+
+    fn make_config(quiet: bool) -> Configuration {
+        let args = Arguments { no_default_profile: true, quiet: Some(quiet), .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_DATE);
+        config.add_column("Action");
+        config.add_column("SysReff");
+        config.add_column("Http.Request.Path");
+        config.add_column("Result");
+        config.add_column(kvp::DELTA_MS);
+        config.add_column(kvp::MESSAGE);
+        config.add_alternate_column("Result", "Outcome".to_string());
+        config
+    }
+
+    fn find_empty_columns(quiet: bool, results: &[ParseLineResult]) -> Vec<String> {
+        let config = make_config(quiet);
+        LineStats::new(&config, results).empty_columns(&config)
+    }
+
+    #[test]
+    pub fn finds_columns_which_are_empty_in_every_line() {
+        let config = make_config(false);
+        let results = [
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Saved Action=Save SysRef=QU1"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | Outcome=Ok | [INFO_] | Blank SysReff="),
+            ParsedLine::parse(b"garbage SysReff=QU2"),
+        ];
+
+        assert_eq!(LineStats::new(&config, &results).empty_columns(&config), vec!["SysReff".to_string()]);
+    }
+
+    #[test]
+    pub fn finds_columns_which_are_empty_in_every_file_when_the_files_are_added_together() {
+        let config = make_config(false);
+        let mut stats = LineStats::new(&config, &[ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Saved Action=Save SysRef=QU1")]);
+        stats.add(LineStats::new(&config, &[]));
+        stats.add(LineStats::new(&config, &[ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | Outcome=Ok | [INFO_] | Blank SysReff=")]));
+
+        assert_eq!(stats.line_count, 2);
+        assert_eq!(stats.empty_columns(&config), vec!["SysReff".to_string()]);
+    }
+
+    #[test]
+    pub fn for_no_ok_lines_or_quiet_finds_nothing() {
+        let results = [ParsedLine::parse(b"garbage")];
+        assert!(find_empty_columns(false, &results).is_empty());
+
+        let results = [ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Saved Action=Save")];
+        assert!(find_empty_columns(true, &results).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod per_file_tests {
+    use super::*;
+    use std::fs;
+    use crate::output::test_utils::make_temp_dir;
+
+    #[test]
+    pub fn writes_each_file_to_its_own_sorted_output() {
+        let args = Arguments { no_default_profile: true, per_file: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_DATE);
+        config.add_column(kvp::MESSAGE);
+
+        let dir = make_temp_dir("per_file");
+        fs::write(dir.join("a.log"), "2018-09-26 12:34:57.0000000 | [INFO_] | A2\n2018-09-26 12:34:56.0000000 | [INFO_] | A1\n").unwrap();
+        fs::write(dir.join("b.log"), "2018-09-26 12:34:55.0000000 | [INFO_] | B1\ngarbage\n").unwrap();
+        let a = InputFile::new(dir.join("a.log"));
+        let b = InputFile::new(dir.join("b.log"));
+        let all_files: Vec<(&InputFile, FileBytes)> = [&a, &b].iter()
+            .map(|&f| (f, FileBytes::Read(f.read().unwrap())))
+            .collect();
+
+        let progress = Progress::new(&config, &Inputs::default());
+        let (all_lines_and_errors, error_count) = write_per_file(&config, &progress, &all_files).unwrap();
+
+        assert_eq!(all_lines_and_errors.len(), 4);
+        assert_eq!(error_count, 1);
+        assert_eq!(
+            fs::read_to_string(dir.join("a.log.csv")).unwrap(),
+            "LogDate,Message\n2018-09-26 12:34:56.0000000,A1\n2018-09-26 12:34:57.0000000,A2\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("b.log.csv")).unwrap(),
+            "LogDate,Message\n2018-09-26 12:34:55.0000000,B1\n"
+        );
+        assert!(!dir.join("a.log.errors.csv").exists());
+        assert!(dir.join("b.log.errors.csv").exists());
+    }
+
+    #[test]
+    pub fn line_num_and_source_file_columns_trace_lines_to_their_origin() {
+        let args = Arguments { no_default_profile: true, per_file: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::SOURCE_FILE);
+        config.add_column(kvp::LINE_NUM);
+        config.add_column(kvp::MESSAGE);
+
+        let dir = make_temp_dir("line_num_column");
+        let path = dir.join("a.log");
+        fs::write(&path, "2018-09-26 12:34:57.0000000 | [INFO_] | A2\ngarbage\n2018-09-26 12:34:56.0000000 | [INFO_] | A1\n").unwrap();
+        let a = InputFile::new(path.clone());
+        let all_files = vec![(&a, FileBytes::Read(a.read().unwrap()))];
+
+        let progress = Progress::new(&config, &Inputs::default());
+        write_per_file(&config, &progress, &all_files).unwrap();
+
+        // The lines are sorted, but keep the zero-based line numbers they had in the file.
+        let path = path.display();
+        assert_eq!(
+            fs::read_to_string(dir.join("a.log.csv")).unwrap(),
+            format!("SourceFile,LineNum,Message\n{},2,A1\n{},0,A2\n", path, path)
+        );
+    }
+}
+
+#[cfg(test)]
+mod select_rows_tests {
+    use super::*;
+    use std::fs;
+    use crate::output::test_utils::make_temp_dir;
+
+    #[test]
+    pub fn with_limit_writes_only_that_many_rows() {
+        let args = Arguments { no_default_profile: true, limit: Some(10), .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+
+        let lines = numbered_lines(1000);
+        let f = InputFile::with_path("a.log");
+        let mut results = parse_all(&config, &f, &lines);
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+        select_rows(&config, &mut results);
+
+        let dir = make_temp_dir("limit");
+        let success_file = dir.join("consolidated.csv");
+        write_output_files_to(&config, &results, &success_file, &dir.join("errors.csv")).unwrap();
+
+        let output = fs::read_to_string(&success_file).unwrap();
+        assert_eq!(output.lines().count(), 11);
+        assert_eq!(output.lines().nth(1), Some("0"));
+        assert_eq!(output.lines().last(), Some("9"));
+    }
+
+    #[test]
+    pub fn with_limit_counts_errors() {
+        let args = Arguments { no_default_profile: true, limit: Some(2), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let f = InputFile::with_path("a.log");
+        let mut results = vec![
+            parse_line(&config, b"2018-09-26 12:34:56.0000000 | [INFO_] | A", &f, 0),
+            parse_line(&config, b"garbage", &f, 1),
+            parse_line(&config, b"2018-09-26 12:34:57.0000000 | [INFO_] | B", &f, 2),
+        ];
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+        select_rows(&config, &mut results);
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap().message.as_ref(), b"A");
+    }
+
+    fn numbered_lines(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("2018-09-26 12:34:56.{:07} | [INFO_] | {}", i, i))
+            .collect()
+    }
+
+    fn parse_all<'l>(config: &Configuration, f: &'l InputFile, lines: &'l [String]) -> Vec<ParseLineResult<'l>> {
+        lines.iter()
+            .enumerate()
+            .map(|(line_num, line)| parse_line(config, line.as_bytes(), f, line_num))
+            .collect()
+    }
+
+    fn messages(results: &[ParseLineResult]) -> Vec<String> {
+        results.iter().map(|r| String::from_utf8_lossy(&r.as_ref().unwrap().message).to_string()).collect()
+    }
+
+    #[test]
+    pub fn with_skip_drops_first_rows() {
+        let args = Arguments { no_default_profile: true, skip: 3, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let lines = numbered_lines(5);
+        let f = InputFile::with_path("a.log");
+        let mut results = parse_all(&config, &f, &lines);
+        select_rows(&config, &mut results);
+
+        assert_eq!(messages(&results), vec!["3", "4"]);
+    }
+
+    #[test]
+    pub fn with_skip_and_limit_returns_a_page() {
+        let args = Arguments { no_default_profile: true, skip: 2, limit: Some(2), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let lines = numbered_lines(10);
+        let f = InputFile::with_path("a.log");
+        let mut results = parse_all(&config, &f, &lines);
+        select_rows(&config, &mut results);
+
+        assert_eq!(messages(&results), vec!["2", "3"]);
+    }
+
+    #[test]
+    pub fn with_skip_more_than_total_returns_nothing() {
+        let args = Arguments { no_default_profile: true, skip: 20, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let lines = numbered_lines(10);
+        let f = InputFile::with_path("a.log");
+        let mut results = parse_all(&config, &f, &lines);
+        select_rows(&config, &mut results);
+
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    pub fn with_skip_skips_errors_first() {
+        let args = Arguments { no_default_profile: true, skip: 1, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let f = InputFile::with_path("a.log");
+        let mut results = vec![
+            parse_line(&config, b"2018-09-26 12:34:56.0000000 | [INFO_] | A", &f, 0),
+            parse_line(&config, b"garbage", &f, 1),
+        ];
+        results.sort_by(|a, b| compare_lines(&config, a, b));
+        select_rows(&config, &mut results);
+
+        assert_eq!(messages(&results), vec!["A"]);
+    }
+
+    fn sorted_lines_and_errors<'l>(config: &Configuration, f: &'l InputFile, lines: &'l [String]) -> Vec<ParseLineResult<'l>> {
+        let mut results = parse_all(config, f, lines);
+        results.push(parse_line(config, b"garbage 1", f, lines.len()));
+        results.push(parse_line(config, b"garbage 2", f, lines.len() + 1));
+        results.sort_by(|a, b| compare_lines(config, a, b));
+        results
+    }
+
+    fn error_lines(results: &[ParseLineResult]) -> Vec<String> {
+        results.iter().filter_map(|r| r.as_ref().err()).map(|e| String::from_utf8_lossy(e.line).to_string()).collect()
+    }
+
+    #[test]
+    pub fn with_tail_keeps_latest_lines_and_drops_errors() {
+        let args = Arguments { no_default_profile: true, tail: Some(2), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let lines = numbered_lines(5);
+        let f = InputFile::with_path("a.log");
+        let mut results = sorted_lines_and_errors(&config, &f, &lines);
+        select_rows(&config, &mut results);
+
+        assert_eq!(messages(&results), vec!["3", "4"]);
+    }
+
+    #[test]
+    pub fn with_tail_and_reverse_keeps_latest_lines_newest_first() {
+        let args = Arguments { no_default_profile: true, tail: Some(2), reverse: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let lines = numbered_lines(5);
+        let f = InputFile::with_path("a.log");
+        let mut results = sorted_lines_and_errors(&config, &f, &lines);
+        select_rows(&config, &mut results);
+
+        assert_eq!(messages(&results), vec!["4", "3"]);
+    }
+
+    #[test]
+    pub fn with_tail_more_than_lines_keeps_latest_errors() {
+        for &reverse in &[false, true] {
+            let args = Arguments { no_default_profile: true, tail: Some(3), reverse, .. Arguments::default() };
+            let config = get_config(&ProfileSet::default(), &args).unwrap();
+            let lines = numbered_lines(2);
+            let f = InputFile::with_path("a.log");
+            let mut results = sorted_lines_and_errors(&config, &f, &lines);
+            select_rows(&config, &mut results);
+
+            assert_eq!(results.len(), 3);
+            assert_eq!(error_lines(&results), vec!["garbage 2"]);
+        }
+    }
+
+    #[test]
+    pub fn with_tail_and_limit_applies_limit_to_the_tail() {
+        let args = Arguments { no_default_profile: true, tail: Some(3), limit: Some(1), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let lines = numbered_lines(5);
+        let f = InputFile::with_path("a.log");
+        let mut results = sorted_lines_and_errors(&config, &f, &lines);
+        select_rows(&config, &mut results);
+
+        assert_eq!(messages(&results), vec!["2"]);
+    }
+}
+
+#[cfg(test)]
+mod read_file_tests {
+    use super::*;
+    use std::fs;
+    use crate::output::test_utils::make_temp_dir;
+
+    fn make_binary_file(name: &str) -> Inputs {
+        let path = make_temp_dir(name).join("image.png");
+        fs::write(&path, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x10\0\0\0\x10\x08\x06\0\0\0").unwrap();
+        Inputs { files: vec![InputFile::new(path)] }
+    }
+
+    #[test]
+    pub fn for_binary_file_skips_it_and_records_it() {
+        let inputs = make_binary_file("read_file_binary");
+        let config = get_config(&ProfileSet::default(), &Arguments { quiet: Some(true), .. Arguments::default() }).unwrap();
+        let progress = Progress::new(&config, &inputs);
+
+        assert!(read_file(&config, &progress, &inputs.files[0]).is_none());
+        assert_eq!(progress.skipped_files(), vec![inputs.files[0].path_as_string.clone()]);
+    }
+
+    #[test]
+    pub fn for_binary_file_with_no_skip_binary_reads_it() {
+        let inputs = make_binary_file("read_file_no_skip_binary");
+        let config = get_config(&ProfileSet::default(), &Arguments { quiet: Some(true), no_skip_binary: true, .. Arguments::default() }).unwrap();
+        let progress = Progress::new(&config, &inputs);
+
+        assert!(read_file(&config, &progress, &inputs.files[0]).is_some());
+        assert!(progress.skipped_files().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod dry_run_tests {
+    use super::*;
+
+    fn make_input_file(path: &str, length: usize) -> InputFile {
+        InputFile { length, .. InputFile::with_path(path) }
+    }
+
+    #[test]
+    pub fn lists_each_file_with_its_size_then_the_total() {
+        let inputs = Inputs { files: vec![make_input_file("logs/big.log", 3 * 1024 * 1024), make_input_file("small.log", 100)] };
+        assert_eq!(format_dry_run(&inputs), vec![
+            "    3.00MB  logs/big.log",
+            "      100B  small.log",
+            "    3.00MB  Total of 2 files",
+        ]);
+    }
+
+    #[test]
+    pub fn for_stdin_size_is_unknown() {
+        let inputs = Inputs { files: vec![InputFile::stdin()] };
+        assert_eq!(format_dry_run(&inputs), vec![
+            "         -  <stdin>",
+            "        0B  Total of 1 files",
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod summary_json_tests {
+    use super::*;
+    use std::fs;
+    use crate::output::test_utils::make_temp_dir;
+
+    fn write_and_read(stats: bool, results: &[ParseLineResult], name: &str) -> serde_json::Value {
+        let args = Arguments { stats, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let stats = LineStats::new(&config, results);
+        let level_counts = stats.level_counts(&config);
+        let app_counts = stats.app_counts();
+        let error_lines = results.iter().filter(|r| r.is_err()).count();
+
+        let path = make_temp_dir(name).join("sub").join("summary.json");
+        let summary = RunSummary {
+            total_bytes: 1234,
+            file_count: 2,
+            ok_lines: results.len() - error_lines,
+            error_lines,
+            elapsed_ms: 56,
+            level_counts: &level_counts,
+            app_counts: &app_counts,
+        };
+        write_summary_json(&config, &path, &summary).unwrap();
+
+        serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap()
+    }
+
+    #[test]
+    pub fn writes_counts_and_breakdowns_with_stats() {
+        let results = [
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | AppName=Web | [INFO_] | One"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | AppName=Web | [ERROR] | Two"),
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | AppName=Db | [INFO_] | Three"),
+            ParsedLine::parse(b"garbage"),
+        ];
+
+        let value = write_and_read(true, &results, "summary_json_stats");
+        assert_eq!(value, serde_json::json!({
+            "totalBytes": 1234,
+            "fileCount": 2,
+            "okLines": 3,
+            "errorLines": 1,
+            "elapsedMs": 56,
+            "levels": { "INFO_": 2, "ERROR": 1 },
+            "applications": { "Web": 2, "Db": 1 },
+        }));
+    }
+
+    #[test]
+    pub fn without_stats_or_errors_writes_only_totals() {
+        let results = [ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | AppName=Web | [INFO_] | One")];
+
+        let value = write_and_read(false, &results, "summary_json_no_stats");
+        assert_eq!(value, serde_json::json!({
+            "totalBytes": 1234,
+            "fileCount": 2,
+            "okLines": 1,
+            "errorLines": 0,
+            "elapsedMs": 56,
+        }));
+    }
 }
@@ -1,8 +1,2274 @@
-// This module is responsible for preparing an output record from a ParsedLine.
-// use regex::Captures;
+//! This module is responsible for writing the output files from the parsed lines.
+//! Successfully parsed lines go to the consolidated file, lines that could not be
+//! parsed go to a separate errors file.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+use std::cell::Cell;
+use csv::WriterBuilder;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::json;
+use crate::byte_extensions::ByteSliceExtensions;
+use crate::configuration::{has_gz_extension, Configuration, OutputFormat};
+use crate::grouping::Group;
+use crate::inputs::InputFile;
+use crate::kvp;
+use crate::parsed_line::{log_date_to_utc_datetime, ParseLineResult, ParsedLine, ParsedLineError};
+use crate::pretty::write_pretty_output;
+
+const EMPTY: [&[u8]; 0] = [];
+
+/// The default capacity of a csv writer's buffer.
+const CSV_BUFFER_CAPACITY: usize = 8 * (1 << 10);
+
+/// The UTF-8 byte order mark, which tells Excel that a CSV file is UTF-8.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Writes the results to the output files, in the format specified by the configuration.
+/// Returns the number of error lines that were written.
+pub fn write_output_files(config: &Configuration, results: &[ParseLineResult]) -> Result<usize, io::Error> {
+    if config.output_format == OutputFormat::Pretty {
+        return write_pretty_output(config, results);
+    }
+
+    write_output_rows(config, results)
+}
+
+/// Writes the rows to the output files, or to stdout and stderr, in the format specified by
+/// the configuration. Each row is written as soon as it is produced, so they do not all have
+/// to be in memory at once. The pretty format needs the parsed lines to highlight the
+/// matches, so it is written as CSV, as for groups. Returns the number of error lines written.
+pub fn write_output_rows<R: OutputRow>(config: &Configuration, rows: impl IntoIterator<Item = R>) -> Result<usize, io::Error> {
+    if config.stdout {
+        return write_output_streams(config, rows, OutputStream::Plain(Box::new(io::stdout())), OutputStream::Plain(Box::new(io::stderr())));
+    }
+
+    let (success_file, error_file) = (config.success_file(), config.error_file());
+    match config.output_format {
+        OutputFormat::Json | OutputFormat::Ndjson => write_json_output_files(config, rows, &success_file, &error_file),
+        OutputFormat::Csv | OutputFormat::Pretty => write_csv_output_files(config, rows, &success_file, &error_file),
+    }
+}
+
+/// Writes the results to the specified files, in the format specified by the configuration.
+/// Returns the number of error lines that were written.
+pub fn write_output_files_to(config: &Configuration, results: &[ParseLineResult], success_file: &Path, error_file: &Path) -> Result<usize, io::Error> {
+    match config.output_format {
+        OutputFormat::Csv => write_csv_output_files(config, results, success_file, error_file),
+        OutputFormat::Json | OutputFormat::Ndjson => write_json_output_files(config, results, success_file, error_file),
+        // There are no files in this format, it is only ever written to the terminal.
+        OutputFormat::Pretty => write_pretty_output(config, results),
+    }
+}
+
+/// Writes one row per group to the success file, instead of one row per line, and the
+/// errors to the error file as usual. Returns the number of error lines that were written.
+pub fn write_grouped_output_files(config: &Configuration, groups: &[Group], results: &[ParseLineResult]) -> Result<usize, io::Error> {
+    if config.stdout {
+        return write_grouped_output_streams(config, groups, results, OutputStream::Plain(Box::new(io::stdout())), OutputStream::Plain(Box::new(io::stderr())));
+    }
+
+    let success_file = config.success_file();
+    let error_file = config.error_file();
+    let result = create_file(&success_file)
+        .and_then(|success_stream| Ok((success_stream, create_file(&error_file)?)))
+        .and_then(|(success_stream, error_stream)| write_grouped_output_streams(config, groups, results, success_stream, error_stream));
+
+    finish_output_files(result, &[success_file], &error_file)
+}
+
+/// Gets the success and error files for an input file in per-file mode. The success
+/// file is the input file's `output_path`, with the extension for the output format,
+/// e.g. "app.log.csv", and the errors go alongside it in "app.log.errors.csv". Stdin
+/// has no path of its own, so it uses the normal output files.
+pub fn per_file_output_paths(config: &Configuration, input_file: &InputFile) -> (PathBuf, PathBuf) {
+    if input_file.is_stdin {
+        return (config.success_file(), config.error_file());
+    }
+
+    let extension = config.output_format.extension();
+    let success_file = Path::new(&input_file.output_path).with_extension(extension);
+    let error_file = success_file.with_extension(format!("errors.{}", extension));
+    (config.gzip_path(success_file, config.gzip_output), config.gzip_path(error_file, config.gzip_errors))
+}
+
+/// Opens the consolidated file in the default application for its type. If the output
+/// was split into parts, the first part is opened. Failure to launch the application is
+/// only a warning, because by this point the output has been written successfully.
+pub fn open_success_file(config: &Configuration) {
+    let path = match config.max_output_size {
+        // Grouped output is never split into parts.
+        Some(_) if config.group_by.is_none() => make_part_path(&config.success_file(), 0),
+        _ => config.success_file(),
+    };
+
+    let mut command = if cfg!(target_os = "windows") {
+        // The empty argument is the window title, without it 'start' treats a quoted path as the title.
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else {
+        Command::new("xdg-open")
+    };
+
+    if let Err(e) = command.arg(&path).spawn() {
+        eprintln!("Warning: could not launch an application to open {}, error is {}", path.display(), e);
+    }
+}
+
+/// The figures from a run which are written to the `--summary-json` file. The counts per
+/// log level and per application are pairs of name and count.
+pub struct RunSummary<'a> {
+    pub total_bytes: u64,
+    pub file_count: usize,
+    pub ok_lines: usize,
+    pub error_lines: usize,
+    pub elapsed_ms: u128,
+    pub level_counts: &'a [(String, usize)],
+    pub app_counts: &'a [(String, usize)],
+}
+
+/// Writes the summary of a run to a JSON file. The breakdowns by log level and by
+/// application are only included with `--stats`, like the breakdown by application
+/// which is printed.
+pub fn write_summary_json(config: &Configuration, path: &Path, summary: &RunSummary) -> Result<(), io::Error> {
+    let mut value = json!({
+        "totalBytes": summary.total_bytes,
+        "fileCount": summary.file_count,
+        "okLines": summary.ok_lines,
+        "errorLines": summary.error_lines,
+        "elapsedMs": summary.elapsed_ms as u64,
+    });
+
+    if config.stats {
+        let to_map = |counts: &[(String, usize)]| counts.iter()
+            .map(|(name, count)| (name.clone(), json!(count)))
+            .collect::<serde_json::Map<_, _>>();
+        value["levels"] = to_map(summary.level_counts).into();
+        value["applications"] = to_map(summary.app_counts).into();
+    }
+
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    let text = serde_json::to_string_pretty(&value).map_err(io::Error::other)?;
+    fs::write(path, text + "\n")
+}
+
+/// Writes the output to streams rather than files, e.g. stdout and stderr, which
+/// is useful when running in a pipeline. Output is never split into parts. The pretty
+/// format is written as CSV, see `write_output_rows`.
+fn write_output_streams<R: OutputRow>(
+    config: &Configuration,
+    rows: impl IntoIterator<Item = R>,
+    success_stream: OutputStream,
+    error_stream: OutputStream
+    ) -> Result<usize, io::Error>
+{
+    match config.output_format {
+        OutputFormat::Csv | OutputFormat::Pretty => {
+            let success_writer = if config.only_errors {
+                None
+            } else {
+                Some(RollingCsvWriter::from_writer(config, success_stream)?)
+            };
+
+            let error_writer = make_csv_error_writer(config, error_stream)?;
+            write_csv(rows, success_writer, error_writer)
+        },
+        OutputFormat::Json | OutputFormat::Ndjson => {
+            let ndjson = config.output_format == OutputFormat::Ndjson;
+            let success_writer = if config.only_errors {
+                None
+            } else {
+                Some(JsonWriter::new(success_stream, ndjson)?)
+            };
+
+            let error_writer = JsonWriter::new(error_stream, ndjson)?;
+            write_json(config, rows, success_writer, error_writer)
+        },
+    }
+}
+
+fn write_grouped_output_streams(
+    config: &Configuration,
+    groups: &[Group],
+    results: &[ParseLineResult],
+    mut success_stream: OutputStream,
+    error_stream: OutputStream
+    ) -> Result<usize, io::Error>
+{
+    let key_header = config.group_by.as_ref().map_or("Key", |column| config.column_header(column));
+
+    // The groups have no matches to highlight, so the pretty format is written as CSV.
+    match config.output_format {
+        OutputFormat::Csv | OutputFormat::Pretty => {
+            write_bom(config, &mut success_stream)?;
+            let mut success_writer = make_csv_writer_builder(config).from_writer(success_stream);
+            if !config.no_header {
+                success_writer.write_record([key_header, "FirstLogDate", "LastLogDate", "Count", "Messages"])?;
+            }
+            for group in groups {
+                success_writer.write_field(make_csv_field(config, &group.key))?;
+                success_writer.write_field(&group.first_log_date)?;
+                success_writer.write_field(&group.last_log_date)?;
+                success_writer.write_field(group.count.to_string())?;
+                success_writer.write_field(make_csv_field(config, &group.messages))?;
+                success_writer.write_record(EMPTY)?;
+            }
+            into_stream(success_writer)?.finish()?;
+
+            let error_writer = make_csv_error_writer(config, error_stream)?;
+            write_csv(results, None, error_writer)
+        },
+        OutputFormat::Json | OutputFormat::Ndjson => {
+            let ndjson = config.output_format == OutputFormat::Ndjson;
+            let mut success_writer = JsonWriter::new(success_stream, ndjson)?;
+            for group in groups {
+                let mut value = serde_json::Map::new();
+                value.insert(key_header.to_string(), String::from_utf8_lossy(&make_output_safe(config, &group.key)).into());
+                value.insert("FirstLogDate".to_string(), String::from_utf8_lossy(&group.first_log_date).into());
+                value.insert("LastLogDate".to_string(), String::from_utf8_lossy(&group.last_log_date).into());
+                value.insert("Count".to_string(), group.count.into());
+                value.insert("Messages".to_string(), String::from_utf8_lossy(&make_output_safe(config, &group.messages)).into());
+                success_writer.write(&value)?;
+            }
+            success_writer.finish()?.finish()?;
+
+            let error_writer = JsonWriter::new(error_stream, ndjson)?;
+            write_json(config, results, None, error_writer)
+        }
+    }
+}
+
+fn write_csv_output_files<R: OutputRow>(config: &Configuration, rows: impl IntoIterator<Item = R>, success_file: &Path, error_file: &Path) -> Result<usize, io::Error> {
+    if config.split_by_day {
+        return write_csv_day_files(config, rows, success_file, error_file);
+    }
+
+    let result = write_csv_temp_files(config, rows, success_file, error_file);
+
+    let success_files = match config.max_output_size {
+        _ if config.only_errors => vec![],
+        Some(_) => (0..).map(|part| make_part_path(success_file, part)).take_while(|p| temp_path(p).exists()).collect(),
+        None => vec![success_file.to_owned()],
+    };
+
+    finish_output_files(result, &success_files, error_file)
+}
+
+fn write_csv_temp_files<R: OutputRow>(config: &Configuration, rows: impl IntoIterator<Item = R>, success_file: &Path, error_file: &Path) -> Result<usize, io::Error> {
+    // In only-errors mode we do not create the success file at all.
+    let success_writer = if config.only_errors {
+        None
+    } else {
+        Some(RollingCsvWriter::new(config, success_file)?)
+    };
+
+    let error_writer = make_csv_error_writer(config, create_file(error_file)?)?;
+    write_csv(rows, success_writer, error_writer)
+}
+
+/// Writes the rows as CSV, returning the number of errors written.
+fn write_csv<R: OutputRow>(
+    rows: impl IntoIterator<Item = R>,
+    mut success_writer: Option<RollingCsvWriter>,
+    mut error_writer: csv::Writer<OutputStream>
+    ) -> Result<usize, io::Error>
+{
+    let mut error_count = 0;
+    let mut previous_log_date = PreviousLogDate::default();
+
+    for row in rows {
+        match row.as_output() {
+            Ok(line) => {
+                if let Some(ref mut writer) = success_writer {
+                    writer.write_line(line, previous_log_date.get())?;
+                }
+                previous_log_date.set(line.log_date());
+            }
+            Err(parsed_line_error) => {
+                write_csv_error(&mut error_writer, &parsed_line_error)?;
+                error_count += 1;
+            }
+        }
+    }
+
+    if let Some(writer) = success_writer {
+        writer.finish()?;
+    }
+    into_stream(error_writer)?.finish()?;
+
+    Ok(error_count)
+}
+
+/// Writes a line which could not be parsed as a row of the errors file.
+fn write_csv_error(error_writer: &mut csv::Writer<OutputStream>, parsed_line_error: &ParsedLineError) -> Result<(), io::Error> {
+    error_writer.write_field(parsed_line_error.source)?;
+    error_writer.write_field(parsed_line_error.line_num.to_string())?;
+    error_writer.write_field(parsed_line_error.error.kind().to_string())?;
+    error_writer.write_field(parsed_line_error.error.to_string())?;
+    error_writer.write_field(parsed_line_error.line)?;
+    error_writer.write_record(EMPTY)?;
+    Ok(())
+}
+
+/// Writes the lines for each day to their own file, see `make_day_path`, and the errors
+/// to the error file as usual. Returns the number of errors written.
+fn write_csv_day_files<R: OutputRow>(config: &Configuration, rows: impl IntoIterator<Item = R>, success_file: &Path, error_file: &Path) -> Result<usize, io::Error> {
+    let mut day_files = Vec::new();
+    let result = write_csv_day_temp_files(config, rows, success_file, error_file, &mut day_files);
+    finish_output_files(result, &day_files, error_file)
+}
+
+/// The writer for one day's file, and the date of the last line written to it, for the DeltaMs column.
+struct DayWriter {
+    writer: csv::Writer<CountingWriter<OutputStream>>,
+    previous_log_date: PreviousLogDate,
+}
+
+fn write_csv_day_temp_files<R: OutputRow>(
+    config: &Configuration,
+    rows: impl IntoIterator<Item = R>,
+    success_file: &Path,
+    error_file: &Path,
+    day_files: &mut Vec<PathBuf>
+    ) -> Result<usize, io::Error>
+{
+    let mut error_writer = make_csv_error_writer(config, create_file(error_file)?)?;
+    let mut day_writers: HashMap<Vec<u8>, DayWriter> = HashMap::new();
+    let mut scratch = Vec::new();
+    let mut error_count = 0;
+
+    for row in rows {
+        match row.as_output() {
+            Ok(_) if config.only_errors => {},
+            Ok(line) => {
+                // Log dates are normalized when they are parsed, so the day is always the first 10 bytes.
+                let log_date = line.log_date();
+                let day = log_date.get(..10).unwrap_or(log_date);
+                if !day_writers.contains_key(day) {
+                    let day_file = make_day_path(success_file, &String::from_utf8_lossy(day));
+                    day_files.push(day_file.clone());
+                    let writer = RollingCsvWriter::make_writer(config, create_file(&day_file)?, &Rc::new(Cell::new(0)))?;
+                    day_writers.insert(day.to_vec(), DayWriter { writer, previous_log_date: PreviousLogDate::default() });
+                }
+
+                let day_writer = day_writers.get_mut(day).expect("The writer for the day has been added");
+                write_line(config, &mut day_writer.writer, line, day_writer.previous_log_date.get(), &mut scratch)?;
+                day_writer.previous_log_date.set(log_date);
+            }
+            Err(parsed_line_error) => {
+                write_csv_error(&mut error_writer, &parsed_line_error)?;
+                error_count += 1;
+            }
+        }
+    }
+
+    for (_, day_writer) in day_writers.drain() {
+        into_stream(day_writer.writer)?.inner.finish()?;
+    }
+    into_stream(error_writer)?.finish()?;
+
+    Ok(error_count)
+}
+
+/// Writes the byte order mark to the start of a CSV success stream, if one is configured.
+/// It has to go to the underlying stream because the CSV writer has no way of writing it.
+fn write_bom(config: &Configuration, stream: &mut OutputStream) -> Result<(), io::Error> {
+    if config.bom {
+        stream.write_all(UTF8_BOM)?;
+    }
+    Ok(())
+}
+
+/// Makes a CSV writer for the errors and writes the header row to it, unless headers are turned off.
+fn make_csv_error_writer(config: &Configuration, stream: OutputStream) -> Result<csv::Writer<OutputStream>, io::Error> {
+    let mut error_writer = make_csv_writer_builder(config).from_writer(stream);
+    if !config.no_header {
+        error_writer.write_field("Source")?;
+        error_writer.write_field("LineNum")?;
+        error_writer.write_field("Kind")?;
+        error_writer.write_field("Message")?;
+        error_writer.write_field("Line")?;
+        error_writer.write_record(EMPTY)?;
+    }
+    Ok(error_writer)
+}
+
+/// Flushes the csv writer's buffer and gets back the stream it was writing to, so that
+/// the stream can be finished.
+fn into_stream<W: Write>(writer: csv::Writer<W>) -> Result<W, io::Error> {
+    writer.into_inner().map_err(|e| io::Error::new(e.error().kind(), e.to_string()))
+}
+
+/// Creates a file for output, first creating its directory if it does not exist.
+/// Files with a ".gz" extension are gzip-compressed. The stream must be completed
+/// with `OutputStream::finish`. The output is actually written to the temporary
+/// file for `path`, see `finish_output_files`.
+fn create_file(path: &Path) -> Result<OutputStream, io::Error> {
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    let file = BufWriter::new(File::create(temp_path(path))?);
+    if has_gz_extension(path) {
+        Ok(OutputStream::Gzip(GzEncoder::new(file, Compression::default())))
+    } else {
+        Ok(OutputStream::Plain(Box::new(file)))
+    }
+}
+
+/// A stream which output is written to. Dropping a gzip stream would complete it but
+/// discard any error, leaving a truncated file which looks fine until it is read, so
+/// every stream is completed explicitly by `finish` before its file is moved into place.
+pub enum OutputStream {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Write for OutputStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputStream::Plain(writer) => writer.write(buf),
+            OutputStream::Gzip(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputStream::Plain(writer) => writer.flush(),
+            OutputStream::Gzip(writer) => writer.flush(),
+        }
+    }
+}
+
+impl OutputStream {
+    /// Flushes the stream, first writing the end of the compressed data for a gzip stream.
+    pub fn finish(self) -> Result<(), io::Error> {
+        match self {
+            OutputStream::Plain(mut writer) => writer.flush(),
+            OutputStream::Gzip(writer) => writer.finish()?.flush(),
+        }
+    }
+}
+
+/// Gets the temporary file which the output for `path` is written to, e.g. "consolidated.csv.tmp".
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+/// Moves the temporary files into place once all the output has been written
+/// successfully. A rename within a directory is atomic, so if we are killed part way
+/// through writing, the output files are left as they were rather than truncated. If
+/// writing failed, the temporary files are removed. The error file is only kept if there
+/// were errors, and an unrelated errors file which is already there is left alone if not.
+fn finish_output_files(result: Result<usize, io::Error>, success_files: &[PathBuf], error_file: &Path) -> Result<usize, io::Error> {
+    let error_count = match result {
+        Ok(error_count) => error_count,
+        Err(e) => {
+            for path in success_files.iter().map(PathBuf::as_path).chain(std::iter::once(error_file)) {
+                let _ = fs::remove_file(temp_path(path));
+            }
+            return Err(e);
+        }
+    };
+
+    for path in success_files {
+        fs::rename(temp_path(path), path)?;
+    }
+
+    if error_count == 0 {
+        fs::remove_file(temp_path(error_file))?;
+    } else {
+        fs::rename(temp_path(error_file), error_file)?;
+    }
+
+    Ok(error_count)
+}
+
+/// Makes a builder for CSV writers, configured as specified by the configuration.
+fn make_csv_writer_builder(config: &Configuration) -> WriterBuilder {
+    let mut builder = WriterBuilder::new();
+    builder.flexible(true).delimiter(config.delimiter);
+    builder
+}
+
+/// Writes successfully parsed lines as CSV. If a maximum output size is configured, the output
+/// is split across numbered parts, such as "consolidated.000.csv", "consolidated.001.csv" etc.
+/// A new part is started once the current one exceeds the maximum size, and each part has
+/// its own header row. The size is measured before compression, so compressed parts are
+/// smaller than the maximum. Output to a stream rather than a file is never split.
+struct RollingCsvWriter<'c> {
+    config: &'c Configuration,
+    path: Option<PathBuf>,
+    part: usize,
+    writer: csv::Writer<CountingWriter<OutputStream>>,
+    bytes_written: Rc<Cell<u64>>,
+    /// Reused for the fields of every line, see `write_line`.
+    scratch: Vec<u8>,
+}
+
+impl<'c> RollingCsvWriter<'c> {
+    fn new(config: &'c Configuration, path: &Path) -> Result<Self, io::Error> {
+        let part = 0;
+        let bytes_written = Rc::new(Cell::new(0));
+        let writer = RollingCsvWriter::open_part(config, path, part, &bytes_written)?;
+        Ok(RollingCsvWriter { config, path: Some(path.to_owned()), part, writer, bytes_written, scratch: Vec::new() })
+    }
+
+    fn from_writer(config: &'c Configuration, stream: OutputStream) -> Result<Self, io::Error> {
+        let bytes_written = Rc::new(Cell::new(0));
+        let writer = RollingCsvWriter::make_writer(config, stream, &bytes_written)?;
+        Ok(RollingCsvWriter { config, path: None, part: 0, writer, bytes_written, scratch: Vec::new() })
+    }
+
+    /// Opens a new part.
+    fn open_part(
+        config: &Configuration,
+        path: &Path,
+        part: usize,
+        bytes_written: &Rc<Cell<u64>>
+        ) -> Result<csv::Writer<CountingWriter<OutputStream>>, io::Error>
+    {
+        let path = match config.max_output_size {
+            Some(_) => make_part_path(path, part),
+            None => path.to_owned(),
+        };
+
+        RollingCsvWriter::make_writer(config, create_file(&path)?, bytes_written)
+    }
+
+    /// Wraps the stream in a CSV writer and writes the header row to it, if wanted, preceded by a
+    /// byte order mark if one is configured. Every part gets its own byte order mark.
+    fn make_writer(
+        config: &Configuration,
+        mut stream: OutputStream,
+        bytes_written: &Rc<Cell<u64>>
+        ) -> Result<csv::Writer<CountingWriter<OutputStream>>, io::Error>
+    {
+        write_bom(config, &mut stream)?;
+        bytes_written.set(0);
+        let counter = CountingWriter { inner: stream, count: Rc::clone(bytes_written) };
+        let mut builder = make_csv_writer_builder(config);
+        if let Some(max_output_size) = config.max_output_size {
+            // Only the bytes which have left the csv writer's buffer are counted, so the
+            // count can lag behind by up to the buffer's capacity. Keeping the buffer small
+            // relative to the maximum size keeps the parts close to it.
+            let capacity = (max_output_size / 4).clamp(1, CSV_BUFFER_CAPACITY as u64);
+            builder.buffer_capacity(capacity as usize);
+        }
+        let mut writer = builder.from_writer(counter);
+        if !config.no_header {
+            writer.write_record(config.columns.iter().map(|c| config.column_header(c)))?;
+        }
+        Ok(writer)
+    }
+
+    fn write_line<L: OutputLine>(&mut self, line: &L, previous_log_date: Option<&[u8]>) -> Result<(), io::Error> {
+        if let (Some(max_output_size), Some(path)) = (self.config.max_output_size, &self.path) {
+            // The count does not include what is still in the csv writer's buffer, but
+            // flushing it for every row would be slow, and would bloat compressed output.
+            // Only roll over once the current part actually exceeds the size, so that we
+            // never create a part which has no rows in it.
+            if self.bytes_written.get() > max_output_size {
+                self.part += 1;
+                let writer = RollingCsvWriter::open_part(self.config, path, self.part, &self.bytes_written)?;
+                into_stream(mem::replace(&mut self.writer, writer))?.inner.finish()?;
+            }
+        }
+
+        write_line(self.config, &mut self.writer, line, previous_log_date, &mut self.scratch)
+    }
+
+    fn finish(self) -> Result<(), io::Error> {
+        into_stream(self.writer)?.inner.finish()
+    }
+}
+
+/// Writes lines to a stream as CSV one at a time, as they are read by `--follow`. Each
+/// line is flushed as soon as it is written so that it can be seen immediately.
+pub struct FollowWriter<'c> {
+    writer: RollingCsvWriter<'c>,
+}
+
+impl<'c> FollowWriter<'c> {
+    pub fn new(config: &'c Configuration, stream: Box<dyn Write>) -> Result<Self, io::Error> {
+        let mut writer = RollingCsvWriter::from_writer(config, OutputStream::Plain(stream))?;
+        writer.writer.flush()?;
+        Ok(FollowWriter { writer })
+    }
+
+    /// Writes a line. There is no previous line to measure a DeltaMs against.
+    pub fn write_line(&mut self, line: &ParsedLine) -> Result<(), io::Error> {
+        self.writer.write_line(line, None)?;
+        self.writer.writer.flush()
+    }
+}
+
+/// Makes the path for a part of the output by inserting the part number before
+/// the extension, e.g. "consolidated.csv" becomes "consolidated.001.csv". A ".gz"
+/// extension stays on the end, so "consolidated.csv.gz" becomes "consolidated.001.csv.gz".
+fn make_part_path(path: &Path, part: usize) -> PathBuf {
+    add_to_file_stem(path, &format!(".{:03}", part))
+}
+
+/// Makes the path for the output for one day by appending the day to the file name,
+/// before the extension, e.g. "consolidated.csv" becomes "consolidated-2018-09-26.csv".
+fn make_day_path(path: &Path, day: &str) -> PathBuf {
+    add_to_file_stem(path, &format!("-{}", day))
+}
+
+/// Inserts a suffix into a file name before its extension. A ".gz" extension is
+/// skipped over, so the suffix goes before the extension of the compressed file.
+fn add_to_file_stem(path: &Path, suffix: &str) -> PathBuf {
+    if has_gz_extension(path) {
+        let mut new_path = add_to_file_stem(&path.with_extension(""), suffix).into_os_string();
+        new_path.push(".gz");
+        return PathBuf::from(new_path);
+    }
+
+    let stem = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}{}", stem, suffix),
+    };
+
+    path.with_file_name(file_name)
+}
+
+/// A writer which counts the number of bytes written through it. The count is shared
+/// because the csv writer does not give us access to the underlying writer.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: Rc<Cell<u64>>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_json_output_files<R: OutputRow>(config: &Configuration, rows: impl IntoIterator<Item = R>, success_file: &Path, error_file: &Path) -> Result<usize, io::Error> {
+    let result = write_json_temp_files(config, rows, success_file, error_file);
+    let success_files = if config.only_errors { vec![] } else { vec![success_file.to_owned()] };
+    finish_output_files(result, &success_files, error_file)
+}
+
+fn write_json_temp_files<R: OutputRow>(config: &Configuration, rows: impl IntoIterator<Item = R>, success_file: &Path, error_file: &Path) -> Result<usize, io::Error> {
+    let ndjson = config.output_format == OutputFormat::Ndjson;
+
+    // In only-errors mode we do not create the success file at all.
+    let success_writer = if config.only_errors {
+        None
+    } else {
+        Some(JsonWriter::new(create_file(success_file)?, ndjson)?)
+    };
+
+    let error_writer = JsonWriter::new(create_file(error_file)?, ndjson)?;
+    write_json(config, rows, success_writer, error_writer)
+}
+
+/// Writes the rows as JSON, returning the number of errors written.
+fn write_json<R: OutputRow>(
+    config: &Configuration,
+    rows: impl IntoIterator<Item = R>,
+    mut success_writer: Option<JsonWriter<OutputStream>>,
+    mut error_writer: JsonWriter<OutputStream>
+    ) -> Result<usize, io::Error>
+{
+    let mut error_count = 0;
+    let mut previous_log_date = PreviousLogDate::default();
+
+    for row in rows {
+        match row.as_output() {
+            Ok(line) => {
+                if let Some(ref mut writer) = success_writer {
+                    writer.write(&JsonLine { config, line, previous_log_date: previous_log_date.get() })?;
+                }
+                previous_log_date.set(line.log_date());
+            }
+            Err(parsed_line_error) => {
+                error_writer.write(&json!({
+                    "source": parsed_line_error.source,
+                    "line_num": parsed_line_error.line_num,
+                    "kind": parsed_line_error.error.kind().to_string(),
+                    "message": parsed_line_error.error.to_string(),
+                    "line": String::from_utf8_lossy(&parsed_line_error.line.make_safe()),
+                }))?;
+                error_count += 1;
+            }
+        }
+    }
+
+    if let Some(writer) = success_writer {
+        writer.finish()?.finish()?;
+    }
+    error_writer.finish()?.finish()?;
+
+    Ok(error_count)
+}
+
+/// Writes a line as a CSV record. Multi-line values such as stack traces are common, so
+/// rather than allocate a safe copy of each of them, the caller's `scratch` buffer is
+/// reused for all the fields of all the lines.
+fn write_line<W: Write, L: OutputLine>(
+    config: &Configuration,
+    writer: &mut csv::Writer<W>,
+    line: &L,
+    previous_log_date: Option<&[u8]>,
+    scratch: &mut Vec<u8>
+    ) -> Result<(), io::Error>
+{
+    for (index, column) in config.columns.iter().enumerate() {
+        match line.output_value(config, index, column, true) {
+            OutputValue::Text(value) => write_csv_field(config, writer, &value, scratch)?,
+            OutputValue::Verbatim(value) => writer.write_field(value)?,
+            // Negative deltas are numbers, not formulas, so this is not sanitized.
+            OutputValue::DeltaMs => writer.write_field(make_delta_ms(line.log_date(), previous_log_date))?,
+        }
+    }
+
+    writer.write_record(&EMPTY)?;
+
+    Ok(())
+}
+
+/// A row of the output: either a line, which goes to the success output, or a line which
+/// could not be parsed, which goes to the errors output. The rows are usually the results
+/// of parsing, but in low-memory mode they are the records which are kept in their place.
+pub trait OutputRow {
+    type Line: OutputLine;
+
+    fn as_output(&self) -> Result<&Self::Line, ParsedLineError<'_>>;
+}
+
+impl<'f> OutputRow for &ParseLineResult<'f> {
+    type Line = ParsedLine<'f>;
+
+    fn as_output(&self) -> Result<&ParsedLine<'f>, ParsedLineError<'_>> {
+        self.as_ref().map_err(|e| e.clone())
+    }
+}
+
+/// A line to be written to the success output.
+pub trait OutputLine {
+    fn log_date(&self) -> &[u8];
+
+    /// Gets the value of a column, `index` being its position in the configured columns,
+    /// for CSV output or otherwise for JSON.
+    fn output_value<'a>(&'a self, config: &'a Configuration, index: usize, column: &str, csv: bool) -> OutputValue<'a>;
+}
+
+impl OutputLine for ParsedLine<'_> {
+    fn log_date(&self) -> &[u8] {
+        &self.log_date
+    }
+
+    fn output_value<'a>(&'a self, config: &'a Configuration, _index: usize, column: &str, csv: bool) -> OutputValue<'a> {
+        column_value(config, self, column, csv)
+    }
+}
+
+/// The value of a column of a line, as it is to be written to the output.
+pub enum OutputValue<'a> {
+    /// A value from the line, which still has to be made safe for the output format.
+    Text(Cow<'a, [u8]>),
+    /// A value which is written exactly as it is.
+    Verbatim(Cow<'a, [u8]>),
+    /// The DeltaMs column, which depends on the previous line written, see `make_delta_ms`.
+    DeltaMs,
+}
+
+/// Gets the value of a column of a line, for CSV output or otherwise for JSON. Only CSV has
+/// Excel dates and a text for missing values.
+fn column_value<'a>(config: &'a Configuration, line: &'a ParsedLine, column: &str, csv: bool) -> OutputValue<'a> {
+    if csv && config.excel_dates && column == kvp::LOG_DATE {
+        OutputValue::Verbatim(Cow::Owned(make_excel_text(&line.log_date)))
+    } else if column == kvp::RAW_LINE {
+        OutputValue::Text(Cow::Borrowed(line.line))
+    } else if column == kvp::DELTA_MS {
+        OutputValue::DeltaMs
+    } else if column == kvp::LINE_NUM {
+        OutputValue::Verbatim(Cow::Owned(line.line_num.to_string().into_bytes()))
+    } else if column == kvp::OTHER_KVPS {
+        OutputValue::Text(Cow::Owned(make_other_kvps(config, line)))
+    } else if let Some(value) = get_joined_column_value(config, line, column) {
+        OutputValue::Text(Cow::Owned(url_decode_column(config, column, &value).into_owned()))
+    } else if csv && is_missing_column(config, line, column) {
+        OutputValue::Verbatim(Cow::Borrowed(config.null_text.as_bytes()))
+    } else {
+        OutputValue::Text(url_decode_column(config, column, get_output_value(config, line, column)))
+    }
+}
+
+/// Gets the value of each of the configured columns of a line exactly as it will be written,
+/// as CSV or otherwise as JSON, and passes them to `add_value` in order. The DeltaMs column
+/// depends on the previous line written, so its value is left empty.
+pub fn format_output_values(config: &Configuration, line: &ParsedLine, csv: bool, mut add_value: impl FnMut(&[u8])) {
+    for column in &config.columns {
+        match column_value(config, line, column, csv) {
+            OutputValue::Text(value) if csv => add_value(&make_csv_field(config, &value)),
+            OutputValue::Text(value) => add_value(&make_output_safe(config, &value)),
+            OutputValue::Verbatim(value) => add_value(&value),
+            OutputValue::DeltaMs => add_value(b""),
+        }
+    }
+}
+
+/// The log date of the last line written, for the DeltaMs column. It is copied so that
+/// the line itself does not have to be kept.
+#[derive(Default)]
+struct PreviousLogDate(Option<Vec<u8>>);
+
+impl PreviousLogDate {
+    fn get(&self) -> Option<&[u8]> {
+        self.0.as_deref()
+    }
+
+    fn set(&mut self, log_date: &[u8]) {
+        let previous = self.0.get_or_insert_with(Vec::new);
+        previous.clear();
+        previous.extend_from_slice(log_date);
+    }
+}
+
+/// Makes the value for the DeltaMs column, which is the number of milliseconds between
+/// the previous line in the output and this one. It is blank for the first line, or if
+/// either timestamp cannot be parsed, which is possible with lenient dates.
+fn make_delta_ms(log_date: &[u8], previous_log_date: Option<&[u8]>) -> String {
+    match previous_log_date {
+        Some(previous_log_date) => match (log_date_to_utc_datetime(previous_log_date), log_date_to_utc_datetime(log_date)) {
+            (Some(previous), Some(current)) => (current - previous).num_milliseconds().to_string(),
+            _ => String::new(),
+        },
+        None => String::new(),
+    }
+}
+
+/// Percent-decodes the value if the column is one of the ones to be decoded.
+fn url_decode_column<'v>(config: &Configuration, column: &str, value: &'v [u8]) -> Cow<'v, [u8]> {
+    if config.is_url_decode_column(column) {
+        url_decode(value)
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Decodes a percent-encoded value, so "%40" becomes "@" and "+" becomes a space. A '%'
+/// which is not followed by two hex digits is left as it is.
+fn url_decode(value: &[u8]) -> Cow<'_, [u8]> {
+    if !value.contains(&b'%') && !value.contains(&b'+') {
+        return Cow::Borrowed(value);
+    }
+
+    let hex_value = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut idx = 0;
+    while idx < value.len() {
+        match value[idx] {
+            b'+' => decoded.push(b' '),
+            b'%' => match (value.get(idx + 1).and_then(|&c| hex_value(c)), value.get(idx + 2).and_then(|&c| hex_value(c))) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high * 16 + low);
+                    idx += 2;
+                },
+                _ => decoded.push(b'%'),
+            },
+            c => decoded.push(c),
+        }
+        idx += 1;
+    }
+
+    Cow::Owned(decoded)
+}
+
+/// Makes a value safe to write, by replacing any line breaks in it with spaces or,
+/// if `escape_newlines` is configured, with "\r" and "\n" so that they can be recovered,
+/// or with the `join_char` text. Line breaks are only dealt with here, when writing, so
+/// parsed values still contain them.
+fn make_output_safe<'v>(config: &Configuration, value: &'v [u8]) -> Cow<'v, [u8]> {
+    if config.escape_newlines {
+        return value.escape_newlines();
+    }
+
+    let mut safe = Vec::new();
+    if make_output_safe_into(config, value, &mut safe) { Cow::Owned(safe) } else { Cow::Borrowed(value) }
+}
+
+/// Appends the value made safe by `make_output_safe` to `buf`, unless the value is already
+/// safe, in which case nothing is appended and false is returned.
+fn make_output_safe_into(config: &Configuration, value: &[u8], buf: &mut Vec<u8>) -> bool {
+    if config.escape_newlines {
+        return match value.escape_newlines() {
+            Cow::Borrowed(_) => false,
+            Cow::Owned(escaped) => {
+                buf.extend_from_slice(&escaped);
+                true
+            }
+        };
+    }
+
+    if !value.iter().any(|&c| c == b'\r' || c == b'\n') {
+        return false;
+    }
+
+    match config.join_char {
+        Some(ref separator) => value.join_lines_into(separator, buf),
+        None => value.make_safe_into(buf),
+    }
+    true
+}
+
+/// The characters which make a spreadsheet application treat a field as a formula.
+const FORMULA_PREFIXES: &[u8] = b"=+-@";
+
+/// Makes a value safe to write as a CSV field. As well as dealing with line breaks, if
+/// `sanitize_formulas` is configured, a value which would be treated as a formula when
+/// the file is opened in Excel or LibreOffice is prefixed with a single quote. The
+/// messages and KVPs come from the logs, so this prevents CSV injection.
+fn make_csv_field<'v>(config: &Configuration, value: &'v [u8]) -> Cow<'v, [u8]> {
+    let mut field = Vec::new();
+    if make_csv_field_into(config, value, &mut field) { Cow::Owned(field) } else { Cow::Borrowed(value) }
+}
+
+/// Replaces the contents of `buf` with the field made by `make_csv_field`, unless the value
+/// can be written as it is, in which case false is returned.
+fn make_csv_field_into(config: &Configuration, value: &[u8], buf: &mut Vec<u8>) -> bool {
+    buf.clear();
+    let made_safe = make_output_safe_into(config, value, buf);
+    let first = if made_safe { buf.first() } else { value.first() };
+    if !config.sanitize_formulas || !first.is_some_and(|c| FORMULA_PREFIXES.contains(c)) {
+        return made_safe;
+    }
+
+    if !made_safe {
+        buf.extend_from_slice(value);
+    }
+    buf.insert(0, b'\'');
+    true
+}
+
+/// Writes a value as a CSV field, see `make_csv_field`. A value which has to be changed
+/// is built in `scratch` rather than a new buffer.
+fn write_csv_field<W: Write>(config: &Configuration, writer: &mut csv::Writer<W>, value: &[u8], scratch: &mut Vec<u8>) -> Result<(), io::Error> {
+    if make_csv_field_into(config, value, scratch) {
+        writer.write_field(&scratch)?;
+    } else {
+        writer.write_field(value)?;
+    }
+    Ok(())
+}
+
+/// Makes the value for the __OtherKvps__ column, which lists all the KVPs that are not
+/// written to a column, either under their own name or as an alternate name, as
+/// "key=value; key=value". This makes it easy to discover KVPs that might be worth
+/// adding as columns. To keep within the maximum message length, the KVPs which do not
+/// fit are left out, rather than cutting a KVP (or a multi-byte character) in half.
+fn make_other_kvps(config: &Configuration, line: &ParsedLine) -> Vec<u8> {
+    let is_column = |key: &[u8]| config.columns.iter().any(|column|
+        column.as_bytes().eq_ignore_ascii_case(key) ||
+        config.alternate_column_names.get(column).is_some_and(|alternate_names|
+            alternate_names.iter().any(|alt_name| alt_name.as_bytes().eq_ignore_ascii_case(key)))
+    );
+
+    let mut result = Vec::new();
+    for kvp in line.kvps.iter().filter(|kvp| !is_column(kvp.key)) {
+        let separator: &[u8] = if result.is_empty() { b"" } else { b"; " };
+        if result.len() + separator.len() + kvp.key.len() + 1 + kvp.value.len() > config.max_message_length {
+            break;
+        }
+        result.extend_from_slice(separator);
+        result.extend_from_slice(kvp.key);
+        result.push(b'=');
+        result.extend_from_slice(&kvp.value);
+    }
+
+    result
+}
+
+/// Wraps a value in an Excel formula, e.g. ="2018-09-26 12:34:56.1146655", which makes Excel
+/// treat it as text. Otherwise Excel converts timestamps to dates, losing the fractional seconds.
+fn make_excel_text(value: &[u8]) -> Vec<u8> {
+    let mut text = Vec::with_capacity(value.len() + 3);
+    text.extend_from_slice(b"=\"");
+    text.extend_from_slice(value);
+    text.push(b'"');
+    text
+}
+
+/// Gets the value to be written to the output for a column. This is either one of the
+/// built-in columns or a KVP. Columns which have no value are written as an empty slice.
+pub fn get_output_value<'a>(config: &'a Configuration, line: &'a ParsedLine, column: &str) -> &'a [u8] {
+    match column {
+        kvp::LOG_DATE => &line.log_date,
+        kvp::LOG_DATE_ONLY => split_log_date(&line.log_date).0,
+        kvp::LOG_TIME_ONLY => split_log_date(&line.log_date).1,
+        kvp::LOG_LEVEL => line.log_level,
+        kvp::LOG_SOURCE => get_column_value(config, line, kvp::SOURCE).map_or(b"", |v| v.as_ref()),
+        kvp::FILE_NAME => line.source.as_bytes(),
+        kvp::SOURCE_FILE => line.path.as_bytes(),
+        kvp::MESSAGE => &line.message,
+        kvp::RAW_LINE => line.line,
+        kvp::CATEGORY => classify_line(config, line).as_bytes(),
+        _ => get_column_value(config, line, column).map_or(b"", |v| v.as_ref()),
+    }
+}
+
+/// Checks whether a line has no value at all for a column, as opposed to an empty one.
+/// The built-in columns always have a value, a KVP column only has one if the line has
+/// the KVP, under its own name or an alternate name, even if the KVP's value is empty.
+fn is_missing_column(config: &Configuration, line: &ParsedLine, column: &str) -> bool {
+    match column {
+        kvp::LOG_DATE | kvp::LOG_DATE_ONLY | kvp::LOG_TIME_ONLY | kvp::LOG_LEVEL | kvp::FILE_NAME |
+        kvp::SOURCE_FILE | kvp::LINE_NUM | kvp::MESSAGE | kvp::RAW_LINE | kvp::CATEGORY => false,
+        kvp::LOG_SOURCE => get_column_value(config, line, kvp::SOURCE).is_none(),
+        _ => get_column_value(config, line, column).is_none(),
+    }
+}
+
+/// Splits a log date into its date and time parts at the separator, which is always
+/// at index 10 because log dates are normalized when the line is parsed. The time part
+/// includes the fractional seconds, which are zero if the original timestamp had none.
+fn split_log_date(log_date: &[u8]) -> (&[u8], &[u8]) {
+    const SEPARATOR_INDEX: usize = 10;
+
+    if log_date.len() > SEPARATOR_INDEX {
+        (&log_date[..SEPARATOR_INDEX], &log_date[SEPARATOR_INDEX + 1..])
+    } else {
+        (log_date, b"")
+    }
+}
+
+/// Classifies a line for the Category column. Any custom rules from the configuration
+/// are checked first, then some simple built-in heuristics are applied.
+fn classify_line<'a>(config: &'a Configuration, line: &ParsedLine) -> &'a str {
+    const OWIN_REQUEST_PREFIX: &[u8] = b"Owin.Request.";
+
+    if let Some((category, _)) = config.category_regexes.iter().find(|(_, regex)| regex.is_match(line.line)) {
+        return category;
+    }
+
+    if line.log_level == b"[ERROR]" || line.log_level == b"[FATAL]" {
+        return "error";
+    }
+
+    let is_request = line.kvps.iter().any(|kvp|
+        kvp.key.len() > OWIN_REQUEST_PREFIX.len() &&
+        kvp.key[..OWIN_REQUEST_PREFIX.len()].eq_ignore_ascii_case(OWIN_REQUEST_PREFIX)
+    );
+    if is_request {
+        return "request";
+    }
+
+    if line.message.windows(9).any(|w| w.eq_ignore_ascii_case(b"heartbeat")) {
+        return "heartbeat";
+    }
+
+    "info"
+}
+
+/// Looks up the value of a column in the KVPs of a line. The column is checked for under
+/// its main name first, then under any alternative names.
+pub fn get_column_value<'l, 'f>(config: &Configuration, line: &'l ParsedLine<'f>, column: &str) -> Option<&'l Cow<'f, [u8]>> {
+    if let Some(kvp_value) = line.kvps.get_value(column.as_bytes()) {
+        return Some(kvp_value);
+    }
+
+    if let Some(alternate_names) = config.alternate_column_names.get(column) {
+        for alt_name in alternate_names {
+            if let Some(kvp_value) = line.kvps.get_value(alt_name.as_bytes()) {
+                return Some(kvp_value);
+            }
+        }
+    }
+
+    None
+}
+
+/// When duplicate KVPs are allowed, gets all the values of a column joined by the configured
+/// separator. The column is checked for under its main name first, then under any alternative
+/// names, as for `get_column_value`. Returns None if the column has less than two values,
+/// in which case the normal single value should be used.
+fn get_joined_column_value(config: &Configuration, line: &ParsedLine, column: &str) -> Option<Vec<u8>> {
+    if !config.parse_options.allow_duplicate_kvps {
+        return None;
+    }
+
+    let alternate_names = config.alternate_column_names.get(column).into_iter().flatten();
+    let values = std::iter::once(column)
+        .chain(alternate_names.map(String::as_str))
+        .map(|name| line.kvps.get_all_values(name.as_bytes()))
+        .find(|values| !values.is_empty())?;
+
+    if values.len() < 2 {
+        return None;
+    }
+
+    Some(values.join(config.duplicate_kvp_separator.as_bytes()))
+}
+
+/// Writes a sequence of values as JSON. Normally the values are written as the elements of
+/// a single JSON array, one element per line. In NDJSON mode there is no enclosing array and
+/// each value is flushed as soon as it is written, for the benefit of streaming consumers.
+/// Either way, we avoid having to build the entire document in memory before writing it.
+struct JsonWriter<W: Write> {
+    writer: W,
+    ndjson: bool,
+    count: usize,
+}
+
+impl<W: Write> JsonWriter<W> {
+    fn new(mut writer: W, ndjson: bool) -> Result<Self, io::Error> {
+        if !ndjson {
+            writer.write_all(b"[")?;
+        }
+        Ok(JsonWriter { writer, ndjson, count: 0 })
+    }
+
+    fn write<T: Serialize>(&mut self, value: &T) -> Result<(), io::Error> {
+        if self.ndjson {
+            serde_json::to_writer(&mut self.writer, value)?;
+            self.writer.write_all(b"\n")?;
+            self.writer.flush()?;
+        } else {
+            let separator: &[u8] = if self.count == 0 { b"\n" } else { b",\n" };
+            self.writer.write_all(separator)?;
+            serde_json::to_writer(&mut self.writer, value)?;
+        }
+
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W, io::Error> {
+        if !self.ndjson {
+            self.writer.write_all(b"\n]\n")?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// A successfully parsed line, serialized as a JSON object keyed by the configured column
+/// headers. The keys are written in the same order as the columns.
+struct JsonLine<'c, 'l, L> {
+    config: &'c Configuration,
+    line: &'l L,
+    previous_log_date: Option<&'l [u8]>,
+}
+
+impl<'c, 'l, L: OutputLine> Serialize for JsonLine<'c, 'l, L> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.config.columns.len()))?;
+        for (index, column) in self.config.columns.iter().enumerate() {
+            let header = self.config.column_header(column);
+            match self.line.output_value(self.config, index, column, false) {
+                OutputValue::Text(value) =>
+                    map.serialize_entry(header, &String::from_utf8_lossy(&make_output_safe(self.config, &value)))?,
+                OutputValue::Verbatim(value) => map.serialize_entry(header, &String::from_utf8_lossy(&value))?,
+                OutputValue::DeltaMs => map.serialize_entry(header, &make_delta_ms(self.line.log_date(), self.previous_log_date))?,
+            }
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    pub use log_file_processor::test_utils::make_temp_dir;
+}
+
+#[cfg(test)]
+mod json_output_tests {
+    use super::*;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::profiles::ProfileSet;
+
+    #[test]
+    pub fn json_line_is_keyed_by_column_in_column_order() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_DATE);
+        config.add_column("pid");
+        config.add_column("Missing");
+        config.add_column(kvp::MESSAGE);
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | Line one\nLine two").unwrap();
+        let json = serde_json::to_string(&JsonLine { config: &config, line: &line, previous_log_date: None }).unwrap();
+
+        assert_eq!(json, r#"{"LogDate":"2018-09-26 12:34:56.7654321","pid":"1","Missing":"","Message":"Line one Line two"}"#);
+    }
+
+    #[test]
+    pub fn json_writer_writes_valid_array() {
+        let mut writer = JsonWriter::new(Vec::new(), false).unwrap();
+        writer.write(&1).unwrap();
+        writer.write(&2).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let values: Vec<u32> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(values, vec![1, 2]);
+    }
+
+    #[test]
+    pub fn json_writer_writes_valid_empty_array() {
+        let bytes = JsonWriter::new(Vec::new(), false).unwrap().finish().unwrap();
+        let values: Vec<u32> = serde_json::from_slice(&bytes).unwrap();
+        assert!(values.is_empty());
+    }
+
+    #[test]
+    pub fn json_writer_in_ndjson_mode_writes_one_object_per_line() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let lines = vec![
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | First").unwrap(),
+            ParsedLine::parse(b"2018-09-26 12:34:57.7654321 | pid=2 | [INFO_] | Second\nline").unwrap(),
+            ParsedLine::parse(b"2018-09-26 12:34:58.7654321 | pid=3 | [INFO_] | Third").unwrap(),
+        ];
+
+        let mut writer = JsonWriter::new(Vec::new(), true).unwrap();
+        for line in &lines {
+            writer.write(&JsonLine { config: &config, line, previous_log_date: None }).unwrap();
+        }
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+
+        assert!(output.ends_with('\n'));
+        let objects: Vec<serde_json::Value> = output.lines()
+            .map(|l| serde_json::from_str(l).expect("Each line should be a valid JSON object"))
+            .collect();
+        assert_eq!(objects.len(), 3);
+        assert_eq!(objects[0]["PID"], "1");
+        assert_eq!(objects[1]["Message"], "Second line");
+        assert_eq!(objects[2]["PID"], "3");
+        assert_eq!(objects[2]["SysRef"], "", "Missing columns should be empty strings, not null");
+    }
+}
+
+#[cfg(test)]
+mod csv_output_tests {
+    use super::*;
+    use super::test_utils::make_temp_dir;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::profiles::ProfileSet;
+
+    #[test]
+    pub fn for_tab_delimiter_writes_tsv_and_quotes_embedded_tabs() {
+        let args = Arguments { no_default_profile: true, tsv: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_DATE);
+        config.add_column("pid");
+        config.add_column(kvp::MESSAGE);
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | A\ttabbed, message").unwrap();
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        writer.write_record(config.columns.iter()).unwrap();
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
 
-//use crate::configuration::Configuration;
-//use crate::parse_utils;
+        assert_eq!(output, "LogDate\tpid\tMessage\n2018-09-26 12:34:56.7654321\t1\t\"A\ttabbed, message\"\n");
+    }
+
+    #[test]
+    pub fn log_source_is_source_kvp_and_file_name_is_input_file() {
+        // The same line as parsed_line::real_log_line_tests::capacity_service_test.
+        let mut text = "2018-09-26 12:34:56.1146655 | MachineName=Some.machine.net | AppName=Some.Service-Z63JHGJKK23 | pid=4964 | tid=22 | [INFO_] | Running aggregate capacity generator.".to_string();
+        text.push_str("\n Source=AggregateCapacityGenerator Action=Run");
+        text.push_str("\n SourceInfo=\"Something.Something.DarkSide.Aggregation.AggregateCapacityGenerator, Something.Something.DarkSide v1.0.0\"");
+        text.push_str("\n SourceInstance=38449385");
+
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::FILE_NAME);
+        config.add_column(kvp::LOG_SOURCE);
+        config.add_column(kvp::SOURCE);
+
+        let mut line = ParsedLine::parse(text.as_bytes()).unwrap();
+        line.source = "capacity.log";
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        assert_eq!(output, "capacity.log,AggregateCapacityGenerator,AggregateCapacityGenerator\n");
+    }
+
+    fn write_null_text_line(null_text: Option<&str>) -> String {
+        let args = Arguments { no_default_profile: true, null_text: null_text.map(|s| s.to_string()), .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column("SysRef");
+        config.add_column("Missing");
+        config.add_column(kvp::MESSAGE);
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | SysRef= | Hello").unwrap();
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    pub fn for_null_text_writes_it_for_missing_columns_only() {
+        assert_eq!(write_null_text_line(Some("\\N")), ",\\N,Hello\n");
+    }
+
+    #[test]
+    pub fn without_null_text_writes_missing_columns_as_empty() {
+        assert_eq!(write_null_text_line(None), ",,Hello\n");
+    }
+
+    fn write_formula_line(sanitize_formulas: bool) -> String {
+        let args = Arguments { no_default_profile: true, sanitize_formulas, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column("Cmd");
+        config.add_column("Amount");
+        config.add_column("User");
+        config.add_column(kvp::MESSAGE);
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | Cmd==cmd|calc!A0 | Amount=+1 | User=@bob | Fine message").unwrap();
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    pub fn for_sanitize_formulas_prefixes_formula_fields_with_quote() {
+        assert_eq!(write_formula_line(true), "'=cmd|calc!A0,'+1,'@bob,Fine message\n");
+    }
+
+    #[test]
+    pub fn without_sanitize_formulas_writes_formula_fields_unchanged() {
+        assert_eq!(write_formula_line(false), "=cmd|calc!A0,+1,@bob,Fine message\n");
+    }
+
+    #[test]
+    pub fn for_sanitize_formulas_prefixes_message_starting_with_minus() {
+        let args = Arguments { no_default_profile: true, sanitize_formulas: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | -2+3+cmd").unwrap();
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "'-2+3+cmd\n");
+    }
+
+    #[test]
+    pub fn for_multi_line_values_writes_same_fields_as_make_csv_field() {
+        let args = Arguments { no_default_profile: true, sanitize_formulas: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+        config.add_column("Trace");
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | -Failed\r\nagain Trace=\"at Foo()\r\nat Bar()\"").unwrap();
+        assert!(line.message.contains(&b'\n'));
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+
+        let mut expected = make_csv_writer_builder(&config).from_writer(Vec::new());
+        expected.write_field(make_csv_field(&config, &line.message)).unwrap();
+        expected.write_field(make_csv_field(&config, get_output_value(&config, &line, "Trace"))).unwrap();
+        expected.write_record(EMPTY).unwrap();
+
+        assert_eq!(writer.into_inner().unwrap(), expected.into_inner().unwrap());
+    }
+
+    #[test]
+    pub fn reuses_the_scratch_buffer_across_lines() {
+        let args = Arguments { no_default_profile: true, sanitize_formulas: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        let mut scratch = Vec::new();
+
+        for line in &[&b"2018-09-26 12:34:56.7654321 | Long\nmessage"[..], b"2018-09-26 12:34:56.7654321 | =1+1", b"2018-09-26 12:34:56.7654321 | Short"] {
+            write_line(&config, &mut writer, &ParsedLine::parse(line).unwrap(), None, &mut scratch).unwrap();
+        }
+
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "Long message\n'=1+1\nShort\n");
+    }
+
+    #[test]
+    pub fn for_url_decode_columns_decodes_only_those_columns() {
+        let args = Arguments { no_default_profile: true, url_decode: vec!["useremail".to_string(), "UserName".to_string()], .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column("UserEmail");
+        config.add_column("UserName");
+        config.add_column("Other");
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Hi UserEmail=philip.daniels%40ex.com UserName=Philip+Daniels Other=a%40b+c").unwrap();
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        assert_eq!(output, "philip.daniels@ex.com,Philip Daniels,a%40b+c\n");
+    }
+
+    #[test]
+    pub fn url_decode_leaves_invalid_escapes_alone() {
+        assert_eq!(url_decode(b"100% sure").as_ref(), b"100% sure");
+        assert_eq!(url_decode(b"50%").as_ref(), b"50%");
+        assert_eq!(url_decode(b"%4").as_ref(), b"%4");
+        assert_eq!(url_decode(b"%zz%41").as_ref(), b"%zzA");
+    }
+
+    #[test]
+    pub fn for_url_decode_column_with_encoded_line_break_writes_it_safely() {
+        let args = Arguments { no_default_profile: true, url_decode: vec!["Foo".to_string()], .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column("Foo");
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Hi Foo=a%0D%0Ab").unwrap();
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        assert_eq!(output, "a  b\n");
+    }
+
+    fn write_message(config: &Configuration) -> String {
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Exception\n   at Foo()\r\n   at Bar()").unwrap();
+        let mut writer = make_csv_writer_builder(config).from_writer(Vec::new());
+        write_line(config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    pub fn for_message_with_newlines_replaces_them_with_spaces() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+
+        assert_eq!(write_message(&config), "Exception    at Foo()     at Bar()\n");
+    }
+
+    #[test]
+    pub fn for_message_with_newlines_and_escape_newlines_escapes_them() {
+        let args = Arguments { no_default_profile: true, escape_newlines: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+
+        assert_eq!(write_message(&config), "Exception\\n   at Foo()\\r\\n   at Bar()\n");
+    }
+
+    /// The multi-line "template" KVP from parsed_line::real_log_line_tests::notification_template_test.
+    fn write_template(config: &Configuration) -> String {
+        let mut text = "2018-06-27 12:32:00.6811879 | pid=7900 | tid=21 | [INFO_] | Notification Template".to_string();
+        text.push_str("\n Source=NotificationTemplater");
+        text.push_str("\n template=\"Invoice Authorisation Code => {InvoiceAuthorisationCode}");
+        text.push_str("\nInvoice Customer Payment Type => {InvoiceCustomerPaymentType}");
+        text.push_str("\n some words \"");
+        text.push_str("\n SysRef=QU076868");
+
+        let line = ParsedLine::parse(text.as_bytes()).unwrap();
+        let mut writer = make_csv_writer_builder(config).from_writer(Vec::new());
+        write_line(config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    pub fn for_join_char_joins_lines_of_multi_line_values_with_it() {
+        let args = Arguments { no_default_profile: true, join_char: Some(" | ".to_string()), .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column("Source");
+        config.add_column("template");
+        config.add_column("SysRef");
+
+        assert_eq!(write_template(&config),
+            "NotificationTemplater,Invoice Authorisation Code => {InvoiceAuthorisationCode} | Invoice Customer Payment Type => {InvoiceCustomerPaymentType} |  some words ,QU076868\n");
+    }
+
+    #[test]
+    pub fn for_join_char_treats_crlf_as_one_line_break() {
+        let args = Arguments { no_default_profile: true, join_char: Some(" | ".to_string()), .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+
+        assert_eq!(write_message(&config), "Exception |    at Foo() |    at Bar()\n");
+    }
+
+    #[test]
+    pub fn for_join_char_json_output_joins_lines_too() {
+        let args = Arguments { no_default_profile: true, join_char: Some(" | ".to_string()), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        assert_eq!(make_output_safe(&config, b"a\nb").as_ref(), b"a | b");
+        assert!(matches!(make_output_safe(&config, b"ab"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    pub fn for_split_date_columns_writes_date_and_time_alongside_log_date() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_DATE_ONLY);
+        config.add_column(kvp::LOG_TIME_ONLY);
+        config.add_column(kvp::LOG_DATE);
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.1146655 | [INFO_] | Hello").unwrap();
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        assert_eq!(output, "2018-09-26,12:34:56.1146655,2018-09-26 12:34:56.1146655\n");
+    }
+
+    #[test]
+    pub fn for_split_date_columns_and_timestamp_without_fraction_writes_zero_fraction() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let line = ParsedLine::parse(b"2018-09-26T12:34:56 | [INFO_] | Hello").unwrap();
+
+        assert_eq!(get_output_value(&config, &line, kvp::LOG_DATE_ONLY), b"2018-09-26");
+        assert_eq!(get_output_value(&config, &line, kvp::LOG_TIME_ONLY), b"12:34:56.0000000");
+    }
+
+    #[test]
+    pub fn for_raw_line_column_writes_trimmed_input_line() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_LEVEL);
+        config.add_column(kvp::RAW_LINE);
+        let line = ParsedLine::parse(b"  2018-09-26 12:34:56.1146655 | pid=1 | [INFO_] | Msg Foo=Bar \r\n").unwrap();
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "[INFO_],2018-09-26 12:34:56.1146655 | pid=1 | [INFO_] | Msg Foo=Bar\n"
+        );
+    }
+
+    #[test]
+    pub fn for_raw_line_column_replaces_embedded_newlines() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::RAW_LINE);
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.1146655 | [INFO_] | First\r\nSecond").unwrap();
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "2018-09-26 12:34:56.1146655 | [INFO_] | First  Second\n"
+        );
+    }
+
+    #[test]
+    pub fn for_other_kvps_column_writes_kvps_not_in_other_columns() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column("pid");
+        config.add_column("AppName");
+        config.alternate_column_names.insert("AppName".to_string(), vec!["ApplicationName".to_string()]);
+        config.add_column(kvp::OTHER_KVPS);
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.1146655 | ApplicationName=App | PID=1 | tid=2 | [INFO_] | Msg Foo=\"a b\" Bar=").unwrap();
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "1,App,tid=2; Bar=; Foo=a b\n");
+    }
+
+    #[test]
+    pub fn for_other_kvps_column_truncates_to_max_message_length() {
+        let args = Arguments { no_default_profile: true, max_message_length: Some(8), .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::OTHER_KVPS);
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.1146655 | pid=1 | tid=2 | [INFO_] | Msg").unwrap();
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "pid=1\n");
+    }
+
+    #[test]
+    pub fn for_other_kvps_column_with_non_ascii_values_truncates_to_whole_kvps() {
+        let args = Arguments { no_default_profile: true, max_message_length: Some(16), .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::OTHER_KVPS);
+        // Cutting at 16 bytes would split the 'è' in half.
+        let line = ParsedLine::parse("2018-09-26 12:34:56.1146655 | [INFO_] | Msg A=Crème B=Ñandú".as_bytes()).unwrap();
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "B=Ñandú\n");
+    }
+
+    #[test]
+    pub fn for_duplicate_kvps_writes_single_value_by_default() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column("Tag");
+        let line = ParsedLine::parse_with_options(b"2018-09-26 12:34:56.1146655 | [INFO_] | Msg Tag=a Tag=b", &config.parse_options).unwrap();
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+
+        // Trailing KVPs are found from the end of the line, so the last one is the first seen.
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "b\n");
+    }
+
+    #[test]
+    pub fn for_duplicate_kvps_and_allow_duplicates_writes_joined_values() {
+        let args = Arguments {
+            no_default_profile: true,
+            allow_duplicate_kvps: true,
+            duplicate_kvp_separator: "|".to_string(),
+            .. Arguments::default()
+        };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column("Tag");
+        config.add_column("Foo");
+        let line = ParsedLine::parse_with_options(b"2018-09-26 12:34:56.1146655 | [INFO_] | Msg Tag=a Foo=x Tag=b", &config.parse_options).unwrap();
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "a|b,x\n");
+    }
+
+    #[test]
+    pub fn for_delta_ms_column_writes_milliseconds_since_previous_line() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::DELTA_MS);
+        config.add_column(kvp::MESSAGE);
+        let dir = make_temp_dir("delta_ms");
+        let success_file = dir.join("consolidated.csv");
+        let error_file = dir.join("errors.csv");
+
+        let results = vec![
+            ParsedLine::parse(b"2018-09-26 12:34:56.1146655 | [INFO_] | First"),
+            ParsedLine::parse(b"2018-09-26 12:34:57.1146655 | [INFO_] | Second"),
+            ParsedLine::parse(b"Not a log line"),
+            ParsedLine::parse(b"2018-09-26 12:34:57.1198655 | [INFO_] | Third"),
+            ParsedLine::parse(b"2018-09-26 12:35:00 | [INFO_] | Fourth"),
+        ];
+        write_csv_output_files(&config, &results, &success_file, &error_file).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&success_file).unwrap(),
+            "DeltaMs,Message\n,First\n1000,Second\n5,Third\n2880,Fourth\n"
+        );
+    }
+
+    #[test]
+    pub fn for_excel_dates_writes_log_date_as_formula() {
+        let args = Arguments { no_default_profile: true, excel_dates: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_DATE);
+        config.add_column(kvp::MESSAGE);
+
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.1146655 | [INFO_] | Hello").unwrap();
+        let mut writer = make_csv_writer_builder(&config).from_writer(Vec::new());
+        write_line(&config, &mut writer, &line, None, &mut Vec::new()).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        assert_eq!(output, "\"=\"\"2018-09-26 12:34:56.1146655\"\"\",Hello\n");
+    }
+
+    #[test]
+    pub fn for_gzip_output_writes_compressed_csv() {
+        let dir = make_temp_dir("gzip_output");
+        let args = Arguments { output: Some(dir.join("run1.csv")), gzip_output: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.columns = vec![kvp::LOG_DATE.to_string(), kvp::MESSAGE.to_string()];
+        let success_file = config.success_file();
+        let error_file = config.error_file();
+        assert_eq!(success_file, dir.join("run1.csv.gz"));
+        assert_eq!(error_file, dir.join("run1.errors.csv"), "Errors should not be compressed by default");
+
+        let results = vec![
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Hello"),
+            ParsedLine::parse(b"A bad line"),
+        ];
+        write_csv_output_files(&config, &results, &success_file, &error_file).unwrap();
+
+        let decoder = flate2::read::GzDecoder::new(File::open(&success_file).unwrap());
+        let mut reader = csv::Reader::from_reader(decoder);
+        assert_eq!(reader.headers().unwrap(), vec!["LogDate", "Message"]);
+        let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(&rows[0][1], "Hello");
+        assert!(fs::read_to_string(&error_file).unwrap().contains("A bad line"));
+    }
+
+    #[test]
+    pub fn for_renamed_column_writes_header_but_same_values() {
+        let args = Arguments { no_default_profile: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_DATE);
+        config.add_column(kvp::MESSAGE);
+        config.column_headers.insert(kvp::LOG_DATE.to_string(), "When".to_string());
+        let dir = make_temp_dir("renamed_column");
+        let success_file = dir.join("consolidated.csv");
+        let error_file = dir.join("errors.csv");
+
+        let results = vec![ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | Hello")];
+        write_csv_output_files(&config, &results, &success_file, &error_file).unwrap();
+
+        let mut reader = csv::Reader::from_path(&success_file).unwrap();
+        assert_eq!(reader.headers().unwrap(), vec!["When", "Message"]);
+        let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(&rows[0][0], "2018-09-26 12:34:56.7654321");
+        assert_eq!(&rows[0][1], "Hello");
+    }
+}
+
+#[cfg(test)]
+mod rolling_output_tests {
+    use super::*;
+    use super::test_utils::make_temp_dir;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::profiles::ProfileSet;
+
+    #[test]
+    pub fn make_part_path_inserts_part_number_before_extension() {
+        assert_eq!(make_part_path(Path::new("consolidated.csv"), 0), PathBuf::from("consolidated.000.csv"));
+        assert_eq!(make_part_path(Path::new("out/consolidated.csv"), 12), PathBuf::from("out/consolidated.012.csv"));
+        assert_eq!(make_part_path(Path::new("consolidated"), 1), PathBuf::from("consolidated.001"));
+        assert_eq!(make_part_path(Path::new("consolidated.csv.gz"), 2), PathBuf::from("consolidated.002.csv.gz"));
+    }
+
+    #[test]
+    pub fn make_day_path_appends_day_before_extension() {
+        assert_eq!(make_day_path(Path::new("consolidated.csv"), "2018-09-26"), PathBuf::from("consolidated-2018-09-26.csv"));
+        assert_eq!(make_day_path(Path::new("out/run1.csv.gz"), "2018-09-26"), PathBuf::from("out/run1-2018-09-26.csv.gz"));
+    }
+
+    #[test]
+    pub fn for_split_by_day_writes_file_per_day() {
+        let args = Arguments { no_default_profile: true, split_by_day: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_DATE);
+        config.add_column(kvp::MESSAGE);
+        let dir = make_temp_dir("split_by_day");
+        let success_file = dir.join("consolidated.csv");
+        let error_file = dir.join("errors.csv");
+
+        let results = vec![
+            ParsedLine::parse(b"2018-09-26 23:59:59.0000000 | [INFO_] | Late"),
+            ParsedLine::parse(b"2018-09-27 00:00:01.0000000 | [INFO_] | Early"),
+            ParsedLine::parse(b"Not a line"),
+            ParsedLine::parse(b"2018-09-27 00:00:02.0000000 | [INFO_] | Later"),
+        ];
+
+        let error_count = write_csv_output_files(&config, &results, &success_file, &error_file).unwrap();
+
+        assert_eq!(error_count, 1);
+        assert!(!success_file.exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("consolidated-2018-09-26.csv")).unwrap(),
+            "LogDate,Message\n2018-09-26 23:59:59.0000000,Late\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("consolidated-2018-09-27.csv")).unwrap(),
+            "LogDate,Message\n2018-09-27 00:00:01.0000000,Early\n2018-09-27 00:00:02.0000000,Later\n"
+        );
+        assert!(fs::read_to_string(&error_file).unwrap().contains("Not a line"));
+        assert!(!temp_path(&dir.join("consolidated-2018-09-27.csv")).exists());
+    }
+
+    #[test]
+    pub fn for_tiny_max_output_size_splits_rows_across_parts() {
+        let args = Arguments { no_default_profile: true, max_output_size: Some(60), .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::LOG_DATE);
+        config.add_column(kvp::MESSAGE);
+        let dir = make_temp_dir("rolling_output");
+        let success_file = dir.join("consolidated.csv");
+        let error_file = dir.join("errors.csv");
+
+        let results = vec![
+            ParsedLine::parse(b"2018-09-26 12:34:51.7654321 | [INFO_] | Line 1"),
+            ParsedLine::parse(b"2018-09-26 12:34:52.7654321 | [INFO_] | Line 2"),
+            ParsedLine::parse(b"2018-09-26 12:34:53.7654321 | [INFO_] | Line 3"),
+            ParsedLine::parse(b"2018-09-26 12:34:54.7654321 | [INFO_] | Line 4"),
+        ];
+
+        write_csv_output_files(&config, &results, &success_file, &error_file).unwrap();
+
+        assert!(!success_file.exists());
+        let mut rows = vec![];
+        let mut part = 0;
+        while make_part_path(&success_file, part).exists() {
+            let contents = fs::read_to_string(make_part_path(&success_file, part)).unwrap();
+            let mut lines = contents.lines();
+            assert_eq!(lines.next(), Some("LogDate,Message"), "Each part should have a header");
+            rows.extend(lines.map(|l| l.to_string()));
+            part += 1;
+        }
+
+        assert!(part > 1, "Expected multiple parts");
+        assert_eq!(rows, vec![
+            "2018-09-26 12:34:51.7654321,Line 1",
+            "2018-09-26 12:34:52.7654321,Line 2",
+            "2018-09-26 12:34:53.7654321,Line 3",
+            "2018-09-26 12:34:54.7654321,Line 4",
+        ]);
+    }
+
+    #[test]
+    pub fn for_gzip_output_split_into_parts_completes_each_part() {
+        let args = Arguments { no_default_profile: true, max_output_size: Some(60), .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+        let dir = make_temp_dir("rolling_gzip_output");
+        let success_file = dir.join("consolidated.csv.gz");
+        let error_file = dir.join("errors.csv");
+
+        let results: Vec<_> = (1..=20)
+            .map(|n| format!("2018-09-26 12:34:56.7654321 | [INFO_] | A line which is long enough to fill a part {}", n))
+            .collect();
+        let results: Vec<_> = results.iter().map(|line| ParsedLine::parse(line.as_bytes())).collect();
+        write_csv_output_files(&config, &results, &success_file, &error_file).unwrap();
+
+        // The maximum size applies to the uncompressed output, and each part must be a
+        // complete gzip stream of its own.
+        let mut row_count = 0;
+        let mut part = 0;
+        while make_part_path(&success_file, part).exists() {
+            let decoder = flate2::read::GzDecoder::new(File::open(make_part_path(&success_file, part)).unwrap());
+            for record in csv::Reader::from_reader(decoder).records() {
+                record.unwrap();
+                row_count += 1;
+            }
+            part += 1;
+        }
+
+        assert!(part > 1, "Expected multiple parts");
+        assert_eq!(row_count, 20);
+    }
+}
+
+#[cfg(test)]
+mod delta_ms_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_first_line_returns_blank() {
+        assert_eq!(make_delta_ms(b"2018-09-26 12:34:56.0000000", None), "");
+    }
+
+    #[test]
+    pub fn for_log_dates_returns_milliseconds_between_them() {
+        assert_eq!(make_delta_ms(b"2018-09-26 12:34:57.2500000", Some(b"2018-09-26 12:34:56.0000000")), "1250");
+    }
+
+    #[test]
+    pub fn for_log_dates_with_offsets_applies_them() {
+        assert_eq!(make_delta_ms(b"2018-09-26 12:34:57.0000000Z", Some(b"2018-09-26 12:34:56.0000000")), "1000");
+        assert_eq!(make_delta_ms(b"2018-09-26 13:35:00.0000000+01:00", Some(b"2018-09-26 12:34:56.0000000Z")), "4000");
+        assert_eq!(make_delta_ms(b"2018-09-26 12:34:56.0000000", Some(b"2018-09-26 07:34:56.0000000-05:30")), "-1800000");
+    }
+
+    #[test]
+    pub fn for_unparseable_log_date_returns_blank() {
+        assert_eq!(make_delta_ms(b"2018-09-26 12:34:57.00000xx", Some(b"2018-09-26 12:34:56.0000000")), "");
+    }
+}
+
+#[cfg(test)]
+mod classify_tests {
+    use super::*;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::profiles::ProfileSet;
+
+    fn classify(config: &Configuration, line: &[u8]) -> String {
+        let line = ParsedLine::parse(line).unwrap();
+        String::from_utf8(get_output_value(config, &line, kvp::CATEGORY).to_vec()).unwrap()
+    }
+
+    fn make_config() -> Configuration {
+        let args = Arguments { classify: true, .. Arguments::default() };
+        get_config(&ProfileSet::default(), &args).unwrap()
+    }
+
+    #[test]
+    pub fn classify_adds_category_column() {
+        let config = make_config();
+        assert!(config.columns.contains(&kvp::CATEGORY.to_string()));
+    }
+
+    #[test]
+    pub fn for_error_and_fatal_levels_returns_error() {
+        let config = make_config();
+        assert_eq!(classify(&config, b"2018-09-26 12:34:56.7654321 | pid=1 | [ERROR] | Boom Owin.Request.Id=1"), "error");
+        assert_eq!(classify(&config, b"2018-09-26 12:34:56.7654321 | pid=1 | [FATAL] | Boom"), "error");
+    }
+
+    #[test]
+    pub fn for_owin_request_kvp_returns_request() {
+        let config = make_config();
+        let line = b"2018-06-27 12:40:02.8554336 | pid=7900 | [INFO_] | Retrieved items Owin.Request.Path=/api/foo Owin.Request.QueryString=";
+        assert_eq!(classify(&config, line), "request");
+    }
+
+    #[test]
+    pub fn for_heartbeat_message_returns_heartbeat() {
+        let config = make_config();
+        assert_eq!(classify(&config, b"2018-09-26 12:34:56.7654321 | pid=1 | [VRBSE] | Sending HeartBeat to monitor"), "heartbeat");
+    }
+
+    #[test]
+    pub fn for_other_lines_returns_info() {
+        let config = make_config();
+        assert_eq!(classify(&config, b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | Running aggregate capacity generator."), "info");
+    }
+
+    #[test]
+    pub fn custom_rules_are_checked_before_built_in_rules() {
+        let mut config = make_config();
+        config.add_category_regex("capacity", r"capacity\s+generator").unwrap();
+        assert_eq!(classify(&config, b"2018-09-26 12:34:56.7654321 | pid=1 | [ERROR] | Running aggregate capacity generator."), "capacity");
+        assert_eq!(classify(&config, b"2018-09-26 12:34:56.7654321 | pid=1 | [ERROR] | Something else"), "error");
+    }
+}
+
+#[cfg(test)]
+mod only_errors_tests {
+    use super::*;
+    use super::test_utils::make_temp_dir;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::profiles::ProfileSet;
+
+    #[test]
+    pub fn only_errors_does_not_create_success_file() {
+        let args = Arguments { only_errors: true, .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let dir = make_temp_dir("only_errors");
+        let success_file = dir.join("consolidated.csv");
+        let error_file = dir.join("errors.csv");
+
+        let results = vec![
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | A good line"),
+            ParsedLine::parse(b"A bad line"),
+        ];
+
+        let error_count = write_csv_output_files(&config, &results, &success_file, &error_file).unwrap();
+
+        assert_eq!(error_count, 1);
+        assert!(!success_file.exists());
+        let errors = fs::read_to_string(&error_file).unwrap();
+        assert!(errors.starts_with("Source,LineNum,Kind,Message,Line"));
+        assert!(errors.contains("A bad line"));
+        assert!(!errors.contains("A good line"));
+    }
+}
+
+#[cfg(test)]
+mod atomic_output_tests {
+    use super::*;
+    use super::test_utils::make_temp_dir;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::profiles::ProfileSet;
+
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    pub fn successful_write_leaves_no_temp_files() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let dir = make_temp_dir("atomic_success");
+        let success_file = dir.join("consolidated.csv");
+        let error_file = dir.join("errors.csv");
+
+        let results = vec![
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | A good line"),
+            ParsedLine::parse(b"A bad line"),
+        ];
+        write_csv_output_files(&config, &results, &success_file, &error_file).unwrap();
+
+        assert_eq!(dir_entries(&dir), vec!["consolidated.csv", "errors.csv"]);
+    }
+
+    #[test]
+    pub fn zero_error_write_keeps_existing_error_file() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let dir = make_temp_dir("atomic_keep_errors");
+        let success_file = dir.join("consolidated.csv");
+        let error_file = dir.join("errors.csv");
+        fs::write(&error_file, "errors from an earlier run").unwrap();
+
+        let results = vec![ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | A good line")];
+        let error_count = write_csv_output_files(&config, &results, &success_file, &error_file).unwrap();
+
+        assert_eq!(error_count, 0);
+        assert_eq!(dir_entries(&dir), vec!["consolidated.csv", "errors.csv"]);
+        assert_eq!(fs::read_to_string(&error_file).unwrap(), "errors from an earlier run");
+    }
+
+    #[test]
+    pub fn failed_write_removes_temp_files_and_keeps_existing_output() {
+        let dir = make_temp_dir("atomic_failure");
+        let success_file = dir.join("consolidated.csv");
+        let error_file = dir.join("errors.csv");
+        fs::write(&success_file, "complete output from an earlier run").unwrap();
+        fs::write(temp_path(&success_file), "half written").unwrap();
+        fs::write(temp_path(&error_file), "half written").unwrap();
+
+        let result = finish_output_files(Err(io::Error::other("disk full")), std::slice::from_ref(&success_file), &error_file);
+
+        assert!(result.is_err());
+        assert_eq!(dir_entries(&dir), vec!["consolidated.csv"]);
+        assert_eq!(fs::read_to_string(&success_file).unwrap(), "complete output from an earlier run");
+    }
+
+    #[test]
+    pub fn temp_path_appends_tmp_extension() {
+        assert_eq!(temp_path(Path::new("out/consolidated.csv.gz")), PathBuf::from("out/consolidated.csv.gz.tmp"));
+    }
+}
+
+#[cfg(test)]
+mod output_path_tests {
+    use super::*;
+    use super::test_utils::make_temp_dir;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::profiles::ProfileSet;
+
+    #[test]
+    pub fn creates_missing_directories_for_output_and_errors() {
+        let dir = make_temp_dir("output_paths");
+        let success_file = dir.join("a").join("b").join("run1.csv");
+        let args = Arguments { output: Some(success_file.clone()), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+
+        let results = vec![
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | A good line"),
+            ParsedLine::parse(b"A bad line"),
+        ];
+
+        write_csv_output_files(&config, &results, &config.success_file(), &config.error_file()).unwrap();
+
+        assert!(success_file.exists());
+        assert!(dir.join("a").join("b").join("run1.errors.csv").exists());
+    }
+
+    #[test]
+    pub fn per_file_paths_are_next_to_input_file() {
+        let args = Arguments { output_format: Some(OutputFormat::Json), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let input_file = InputFile { output_path: "logs/app.log.csv".to_string(), .. InputFile::default() };
+
+        let (success_file, error_file) = per_file_output_paths(&config, &input_file);
+        assert_eq!(success_file, PathBuf::from("logs/app.log.json"));
+        assert_eq!(error_file, PathBuf::from("logs/app.log.errors.json"));
+    }
+
+    #[test]
+    pub fn per_file_paths_for_stdin_are_the_normal_output_files() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+
+        let (success_file, error_file) = per_file_output_paths(&config, &InputFile::stdin());
+        assert_eq!(success_file, config.success_file());
+        assert_eq!(error_file, config.error_file());
+    }
+}
+
+#[cfg(test)]
+mod stream_output_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::profiles::ProfileSet;
+
+    /// A writer which can still be read after it has been boxed up and handed to the output code.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn make_results() -> Vec<ParseLineResult<'static>> {
+        vec![
+            ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [INFO_] | A good line"),
+            ParsedLine::parse(b"A bad line"),
+        ]
+    }
+
+    #[test]
+    pub fn for_csv_writes_lines_and_errors_to_separate_streams() {
+        let args = Arguments { no_default_profile: true, stdout: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+        let success = SharedBuffer::default();
+        let errors = SharedBuffer::default();
+
+        let error_count = write_output_streams(&config, &make_results(), OutputStream::Plain(Box::new(success.clone())), OutputStream::Plain(Box::new(errors.clone()))).unwrap();
+
+        assert_eq!(error_count, 1);
+        assert_eq!(success.contents(), "Message\nA good line\n");
+        let errors = errors.contents();
+        assert!(errors.starts_with("Source,LineNum,Kind,Message,Line\n"));
+        assert!(errors.contains("A bad line"));
+    }
+
+    #[test]
+    pub fn for_ndjson_writes_lines_and_errors_to_separate_streams() {
+        let args = Arguments {
+            no_default_profile: true,
+            stdout: true,
+            output_format: Some(OutputFormat::Ndjson),
+            .. Arguments::default()
+        };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+        let success = SharedBuffer::default();
+        let errors = SharedBuffer::default();
+
+        let error_count = write_output_streams(&config, &make_results(), OutputStream::Plain(Box::new(success.clone())), OutputStream::Plain(Box::new(errors.clone()))).unwrap();
+
+        assert_eq!(error_count, 1);
+        assert_eq!(success.contents(), "{\"Message\":\"A good line\"}\n");
+        assert!(errors.contents().contains("A bad line"));
+    }
+
+    #[test]
+    pub fn for_bom_writes_bom_before_header_of_success_stream_only() {
+        let args = Arguments { no_default_profile: true, stdout: true, bom: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+        let success = SharedBuffer::default();
+        let errors = SharedBuffer::default();
+
+        write_output_streams(&config, &make_results(), OutputStream::Plain(Box::new(success.clone())), OutputStream::Plain(Box::new(errors.clone()))).unwrap();
+
+        assert_eq!(success.contents(), "\u{feff}Message\nA good line\n");
+        assert!(errors.contents().starts_with("Source,"));
+    }
+
+    #[test]
+    pub fn for_no_header_writes_no_header_rows() {
+        let args = Arguments { no_default_profile: true, stdout: true, no_header: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+        let success = SharedBuffer::default();
+        let errors = SharedBuffer::default();
+
+        write_output_streams(&config, &make_results(), OutputStream::Plain(Box::new(success.clone())), OutputStream::Plain(Box::new(errors.clone()))).unwrap();
+
+        assert_eq!(success.contents(), "A good line\n");
+        assert!(errors.contents().starts_with(",0,"));
+        assert!(errors.contents().contains("A bad line"));
+    }
+
+    #[test]
+    pub fn without_bom_writes_no_bom() {
+        let args = Arguments { no_default_profile: true, stdout: true, .. Arguments::default() };
+        let mut config = get_config(&ProfileSet::default(), &args).unwrap();
+        config.add_column(kvp::MESSAGE);
+        let success = SharedBuffer::default();
+
+        write_output_streams(&config, &make_results(), OutputStream::Plain(Box::new(success.clone())), OutputStream::Plain(Box::new(SharedBuffer::default()))).unwrap();
+
+        assert!(success.contents().starts_with("Message"));
+    }
+
+    fn make_groups() -> Vec<Group> {
+        vec![Group {
+            key: b"abc".to_vec(),
+            first_log_date: b"2018-09-26 12:34:56.7654321".to_vec(),
+            last_log_date: b"2018-09-26 12:34:58.7654321".to_vec(),
+            count: 2,
+            messages: b"Start\r\nmore; End".to_vec(),
+        }]
+    }
+
+    #[test]
+    pub fn for_grouped_csv_writes_one_row_per_group() {
+        let args = Arguments { no_default_profile: true, stdout: true, group_by: Some("CorrelationKey".to_string()), .. Arguments::default() };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let success = SharedBuffer::default();
+        let errors = SharedBuffer::default();
+
+        let error_count = write_grouped_output_streams(&config, &make_groups(), &make_results(), OutputStream::Plain(Box::new(success.clone())), OutputStream::Plain(Box::new(errors.clone()))).unwrap();
+
+        assert_eq!(error_count, 1);
+        assert_eq!(success.contents(), "CorrelationKey,FirstLogDate,LastLogDate,Count,Messages\n\
+            abc,2018-09-26 12:34:56.7654321,2018-09-26 12:34:58.7654321,2,Start  more; End\n");
+        assert!(errors.contents().contains("A bad line"));
+    }
+
+    #[test]
+    pub fn for_grouped_ndjson_writes_one_object_per_group() {
+        let args = Arguments {
+            no_default_profile: true,
+            stdout: true,
+            output_format: Some(OutputFormat::Ndjson),
+            group_by: Some("CorrelationKey".to_string()),
+            .. Arguments::default()
+        };
+        let config = get_config(&ProfileSet::default(), &args).unwrap();
+        let success = SharedBuffer::default();
+        let errors = SharedBuffer::default();
+
+        let error_count = write_grouped_output_streams(&config, &make_groups(), &make_results(), OutputStream::Plain(Box::new(success.clone())), OutputStream::Plain(Box::new(errors.clone()))).unwrap();
+
+        assert_eq!(error_count, 1);
+        let value: serde_json::Value = serde_json::from_str(&success.contents()).unwrap();
+        assert_eq!(value["CorrelationKey"], "abc");
+        assert_eq!(value["FirstLogDate"], "2018-09-26 12:34:56.7654321");
+        assert_eq!(value["LastLogDate"], "2018-09-26 12:34:58.7654321");
+        assert_eq!(value["Count"], 2);
+        assert_eq!(value["Messages"], "Start  more; End");
+    }
+}
+
+// use regex::Captures;
 
 // pub fn make_output_record(config: &Configuration, parsed_line: &ParsedLine) -> Vec<String> {
 //     let mut data = Vec::new();
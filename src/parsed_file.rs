@@ -0,0 +1,129 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use rayon::prelude::*;
+use crate::parsed_line::{find_lines, ParseLineResult, ParseOptions, ParsedLine};
+
+// The parsed lines borrow from the bytes of the file they came from, so a function which
+// reads a file cannot simply return them. Instead `parse_file` returns a `ParsedFile`,
+// which owns the bytes, and the lines are parsed from it on demand.
+
+/// A log file which has been read into memory, ready to be parsed.
+#[derive(Debug, Clone)]
+pub struct ParsedFile {
+    source: String,
+    path: String,
+    bytes: Vec<u8>,
+}
+
+/// Reads the file at `path` into memory. The file is read as-is; it is not decompressed.
+pub fn parse_file(path: &Path) -> io::Result<ParsedFile> {
+    let bytes = fs::read(path)?;
+    Ok(ParsedFile::new(path, bytes))
+}
+
+impl ParsedFile {
+    /// Wraps bytes which have already been read from `path`.
+    pub fn new(path: &Path, bytes: Vec<u8>) -> Self {
+        ParsedFile {
+            source: path.file_name().map_or(String::new(), |f| f.to_string_lossy().into_owned()),
+            path: path.to_string_lossy().into_owned(),
+            bytes,
+        }
+    }
+
+    /// The file name, without the directory, which is used as the source of each line.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Parses all the lines in the file.
+    pub fn lines(&self) -> Vec<ParseLineResult<'_>> {
+        self.lines_with_options(&ParseOptions::default())
+    }
+
+    /// Parses all the lines in the file using the specified options.
+    pub fn lines_with_options(&self, options: &ParseOptions) -> Vec<ParseLineResult<'_>> {
+        parse_lines(&self.bytes, &self.source, &self.path, options)
+    }
+}
+
+/// Splits the bytes of a file into lines and parses them in parallel, returning the results
+/// in line order with the source, path and (zero-based) line number of each one set.
+pub fn parse_lines<'f>(bytes: &'f [u8], source: &'f str, path: &'f str, options: &ParseOptions) -> Vec<ParseLineResult<'f>> {
+    find_lines(bytes)
+        .par_iter()
+        .enumerate()
+        .map(|(line_num, &line)| parse_line_at(line, source, path, line_num, options))
+        .collect()
+}
+
+/// Parses a single line and attaches its line number and original source.
+pub fn parse_line_at<'f>(line: &'f [u8], source: &'f str, path: &'f str, line_num: usize, options: &ParseOptions) -> ParseLineResult<'f> {
+    let mut parsed_line_result = ParsedLine::parse_with_options(line, options);
+
+    match parsed_line_result {
+        Ok(ref mut pl) => {
+            pl.line_num = line_num;
+            pl.source = source;
+            pl.path = path;
+        }
+        Err(ref mut e) => {
+            e.line_num = line_num;
+            e.source = source;
+            e.path = path;
+        }
+    };
+
+    parsed_line_result
+}
+
+#[cfg(test)]
+mod parsed_file_tests {
+    use super::*;
+    use crate::test_utils::make_temp_dir;
+
+    #[test]
+    pub fn lines_have_source_path_and_line_num() {
+        let path = Path::new("logs").join("input.log");
+        let file = ParsedFile::new(&path, b"2018-09-26 12:34:56.7654321 | A\r\ngarbage\r\n2018-09-26 12:34:57.7654321 | B\r\n".to_vec());
+        let results = file.lines();
+        assert_eq!(results.len(), 3);
+
+        let first = results[0].as_ref().unwrap();
+        assert_eq!(first.source, "input.log");
+        assert_eq!(first.path, path.to_str().unwrap());
+        assert_eq!(first.line_num, 0);
+
+        let error = results[1].as_ref().unwrap_err();
+        assert_eq!(error.source, "input.log");
+        assert_eq!(error.line_num, 1);
+
+        assert_eq!(results[2].as_ref().unwrap().message.as_ref(), b"B");
+    }
+
+    #[test]
+    pub fn parse_file_for_missing_file_returns_error() {
+        let error = parse_file(Path::new("no-such-dir/no-such-file.log")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    pub fn parse_file_reads_and_parses_the_file() {
+        let path = make_temp_dir("parse_file").join("input.log");
+        fs::write(&path, b"2018-09-26 12:34:56.7654321 | [INFO_] | Hello\n").unwrap();
+
+        let file = parse_file(&path).unwrap();
+        let results = file.lines();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].as_ref().unwrap().log_level, b"[INFO_]");
+    }
+}
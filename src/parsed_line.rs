@@ -1,7 +1,12 @@
 use std::borrow::Cow;
+use std::fmt;
 use chrono::prelude::*;
+use chrono::Duration;
+use memchr::{memchr, memchr_iter};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 use crate::byte_extensions::{ByteExtensions, ByteSliceExtensions};
-use crate::kvp::{KVPCollection, ByteSliceKvpExtensions};
+use crate::timestamp::TimestampFormat;
+use crate::kvp::{KVPCollection, ByteSliceKvpExtensions, LOG_LEVELS};
 
 /*
 Notes
@@ -50,23 +55,91 @@ Some message formats
     messageFormat="{timestamp} | AppName={appName} | pid={processId} | tid={threadId} | {level} | {message}"  (Case Service)
 */
 
-#[derive(Debug, Default)]
+/// The broad category of a parse error, which allows errors to be grouped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The line is empty, or only contains whitespace.
+    #[default]
+    EmptyLine,
+    /// The line is too short to contain a timestamp.
+    ShortLine,
+    /// A character in the timestamp which should be a digit is not.
+    BadDateChar,
+    /// A separator in the timestamp is not the expected character.
+    BadSeparator,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// The reason a line could not be parsed. Errors are common in some logs and many of them
+/// are filtered out again, so the message is only built when it is displayed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line is empty, or only contains whitespace.
+    #[default]
+    EmptyLine,
+    /// The line is shorter than the timestamp, which is `min_length` characters.
+    ShortLine { min_length: usize },
+    /// The character at `index` should be a decimal digit but was `found`.
+    ExpectedDigit { index: usize, found: u8 },
+    /// The character at `index` should be the `expected` separator but was `found`.
+    ExpectedChar { index: usize, expected: u8, found: u8 },
+    /// The character between the date and the time should be ' ' or 'T' but was `found`.
+    ExpectedDateTimeSeparator { index: usize, found: u8 },
+}
+
+impl ParseError {
+    /// Gets the broad category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ParseError::EmptyLine => ErrorKind::EmptyLine,
+            ParseError::ShortLine { .. } => ErrorKind::ShortLine,
+            ParseError::ExpectedDigit { .. } => ErrorKind::BadDateChar,
+            ParseError::ExpectedChar { .. } | ParseError::ExpectedDateTimeSeparator { .. } => ErrorKind::BadSeparator,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::EmptyLine =>
+                write!(f, "Line is empty"),
+            ParseError::ShortLine { min_length } =>
+                write!(f, "The input line is less than {} characters, which indicates it does not even contain a logging timestamp", min_length),
+            ParseError::ExpectedDigit { index, found } =>
+                write!(f, "Character {} was expected to be a decimal digit, but was '{}'", index, found as char),
+            ParseError::ExpectedChar { index, expected, found } =>
+                write!(f, "Character {} was expected to be '{}', but was '{}'", index, expected as char, found as char),
+            ParseError::ExpectedDateTimeSeparator { index, found } =>
+                write!(f, "Character {} was expected to be ' ' or 'T', but was '{}'", index, found as char),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct ParsedLineError<'f> {
     // It makes sorting easier if we also include a reference to the original file or HTTP source.
     pub source: &'f str,
+    /// The full path of the source, which distinguishes files with the same name in different directories.
+    pub path: &'f str,
     pub line_num: usize,
 
     /// The entire original line with whitespace trimmed from the ends.
     pub line: &'f [u8],
 
-    /// A message describing the error.
-    pub message: String,
+    /// Why the line could not be parsed.
+    pub error: ParseError,
 }
 
 impl<'f> ParsedLineError<'f> {
-    pub fn new(message: &str, line: &'f [u8]) -> Self {
+    pub fn new(error: ParseError, line: &'f [u8]) -> Self {
         ParsedLineError {
-            message: message.to_string(),
+            error,
             line,
             .. ParsedLineError::default()
         }
@@ -80,8 +153,13 @@ impl<'f> ParsedLineError<'f> {
 #[derive(Debug, Default)]
 pub struct ParsedLine<'f> {
     // It makes sorting easier if we also include a reference to the original file or HTTP source.
-    pub log_date: &'f [u8],
+    /// The log date. ISO-8601 style dates with a 'T' separator or a UTC offset are
+    /// normalized to the usual form in UTC, so that all dates sort consistently, hence
+    /// this is usually borrowed but occasionally owned.
+    pub log_date: Cow<'f, [u8]>,
     pub source: &'f str,
+    /// The full path of the source, which distinguishes files with the same name in different directories.
+    pub path: &'f str,
     pub line_num: usize,
 
     /// The entire original line with whitespace trimmed from the ends.
@@ -89,41 +167,121 @@ pub struct ParsedLine<'f> {
 
     pub log_level: &'f [u8],
     pub kvps: KVPCollection<'f>,
+
+    /// The message. Like the KVP values, it still contains any line breaks from the original line.
     pub message: Cow<'f, [u8]>,
 }
 
+/// Serializes the main parts of the line, with the byte fields as (lossily converted) UTF-8 strings.
+impl<'f> Serialize for ParsedLine<'f> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ParsedLine", 6)?;
+        state.serialize_field("log_date", &String::from_utf8_lossy(&self.log_date))?;
+        state.serialize_field("log_level", &String::from_utf8_lossy(self.log_level))?;
+        state.serialize_field("source", self.source)?;
+        state.serialize_field("line_num", &self.line_num)?;
+        state.serialize_field("message", &String::from_utf8_lossy(&self.message))?;
+        state.serialize_field("kvps", &self.kvps)?;
+        state.end()
+    }
+}
+
 /// The result of parsing a line is one of these types.
 pub type ParseLineResult<'f> = Result<ParsedLine<'f>, ParsedLineError<'f>>;
 
+/// The separator between the fields of the prologue, e.g. "pid=1 | tid=2 | [INFO_]".
+pub const DEFAULT_FIELD_SEPARATOR: &[u8] = b"|";
+
+/// Options which control how strictly lines are parsed.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// If true, the fractional seconds of the log date are not required to be decimal
+    /// digits, only the separators are checked. This allows lines from loggers which
+    /// occasionally garble a character in the fraction to be treated as valid.
+    pub lenient_dates: bool,
+
+    /// The layout of the timestamp at the start of each line, if it is not our standard
+    /// "YYYY-MM-DD HH:MM:SS.FFFFFFF" form.
+    pub timestamp_format: Option<TimestampFormat>,
+
+    /// If true, KVPs with the same key are all kept, instead of only the first one.
+    pub allow_duplicate_kvps: bool,
+
+    /// The characters which separate the fields of the prologue. Any run of these and
+    /// whitespace between the fields is skipped, so "::" works as well as "|".
+    pub field_separator: Vec<u8>,
+
+    /// The bracketed words which are recognized as the log level in the prologue.
+    /// Defaults to `kvp::LOG_LEVELS`.
+    pub log_levels: Vec<Vec<u8>>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            lenient_dates: false,
+            timestamp_format: None,
+            allow_duplicate_kvps: false,
+            field_separator: DEFAULT_FIELD_SEPARATOR.to_vec(),
+            log_levels: LOG_LEVELS.iter().map(|level| level.to_vec()).collect(),
+        }
+    }
+}
+
 impl<'f> ParsedLine<'f> {
     const LENGTH_OF_LOGGING_TIMESTAMP: usize = 27;
+    const LENGTH_OF_TIMESTAMP_WITHOUT_FRACTION: usize = 19;
 
     /// Parses a line, returning a struct with all the individual pieces of information.
     pub fn parse(line: &[u8]) -> ParseLineResult {
+        ParsedLine::parse_with_options(line, &ParseOptions::default())
+    }
+
+    /// Parses a line using the specified options, returning a struct with all the
+    /// individual pieces of information.
+    pub fn parse_with_options(line: &'f [u8], options: &ParseOptions) -> ParseLineResult<'f> {
+        // Files saved by some editors start with a UTF-8 byte order mark, which would
+        // otherwise end up at the start of the first line's timestamp.
+        const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+        let line = line.strip_prefix(UTF8_BOM).unwrap_or(line);
+
         let mut line = line.trim_while(ByteExtensions::is_whitespace);
         if line.is_empty() {
-            return Err(ParsedLineError::new("Line is empty", line));
+            return Err(ParsedLineError::new(ParseError::EmptyLine, line));
         }
 
-        let mut parsed_line = ParsedLine { line, .. ParsedLine::default() };
+        let mut parsed_line = ParsedLine {
+            line,
+            kvps: KVPCollection::new(options.allow_duplicate_kvps),
+            .. ParsedLine::default()
+        };
 
         // Extract the log date, splitting the line into two slices - the log date and the remainder.
-        match ParsedLine::extract_log_date(&line) {
-            Ok((log_date_slice, remainder)) => {
-                parsed_line.log_date = log_date_slice;
+        // A custom timestamp format produces the log date in our standard form.
+        let extract_result = match options.timestamp_format {
+            Some(ref format) => format.extract(line, options.lenient_dates),
+            None => ParsedLine::extract_log_date_with_options(line, options)
+                .map(|(log_date_slice, remainder)| (ParsedLine::normalize_log_date(log_date_slice), remainder)),
+        };
+
+        match extract_result {
+            Ok((log_date, remainder)) => {
+                parsed_line.log_date = log_date;
                 line = remainder;
             },
-            Err(message) => return Err(ParsedLineError::new(&message, line))
+            Err(error) => return Err(ParsedLineError::new(error, line))
         }
 
         // Now, in the remainder of the line (if there is any), extract KVPs/prologue items until we reach the message.
         // First skip to the usual beginning of the first item in the prologue.
-        let mut line = line.trim_left_while(ByteExtensions::is_whitespace_or_pipe);
+        let separator = options.field_separator.as_slice();
+        let is_whitespace_or_separator = |c: u8| c.is_whitespace() || separator.contains(&c);
+        let mut line = line.trim_left_while(is_whitespace_or_separator);
         if line.is_empty() { return Ok(parsed_line); }
 
         loop {
-            let kvp_parse_result = line.next_kvp();
-            line = kvp_parse_result.remaining_slice.trim_left_while(ByteExtensions::is_whitespace_or_pipe);
+            let kvp_parse_result = line.next_kvp_with_levels(&options.log_levels);
+            line = kvp_parse_result.remaining_slice.trim_left_while(is_whitespace_or_separator);
             if let Some(kvp) = kvp_parse_result.kvp {
                 if kvp.is_log_level {
                     parsed_line.log_level = kvp.key;
@@ -143,19 +301,30 @@ impl<'f> ParsedLine<'f> {
         // This is important, as without it, context can be lost. For example, if a KVP is not
         // named as a column and we do this after trimming the trailing KVPs we will never see
         // what that KVP's value was. This may hinder debugging.
-        parsed_line.message = line.make_safe();
+        parsed_line.message = Cow::Borrowed(line);
 
         // Now find trailing KVPs. There are usually more of these than leading ones.
+        // When duplicates are kept they are collected first, because they are found from the
+        // end of the line backwards and we want repeated keys to keep their original order.
+        let mut trailing_kvps = Vec::new();
         loop {
             let kvp_parse_result = line.prev_kvp();
             line = kvp_parse_result.remaining_slice.trim_right_while(ByteExtensions::is_whitespace);
             if let Some(kvp) = kvp_parse_result.kvp {
-                parsed_line.kvps.insert(kvp);
+                if options.allow_duplicate_kvps {
+                    trailing_kvps.push(kvp);
+                } else {
+                    parsed_line.kvps.insert(kvp);
+                }
             } else {
                 break;
             }
         }
 
+        for kvp in trailing_kvps.into_iter().rev() {
+            parsed_line.kvps.insert(kvp);
+        }
+
         Ok(parsed_line)
     }
 
@@ -171,19 +340,24 @@ impl<'f> ParsedLine<'f> {
     */
 
     /// Extracts the log date from the message. We expect this to occur at the beginning of the message
-    /// and to have a specific number of characters.
+    /// and to have a specific number of characters. Only the message is returned on error, which
+    /// keeps the tests short.
+    #[cfg(test)]
     fn extract_log_date(line: &[u8]) -> Result<(&[u8],&[u8]), String> {
-        if line.len() < ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP {
-            let msg = format!("The input line is less than {} characters, which indicates it does not even contain a logging timestamp", ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP);
-            return Err(msg);
+        ParsedLine::extract_log_date_with_options(line, &ParseOptions::default()).map_err(|e| e.to_string())
+    }
+
+    /// Extracts the log date from the message using the specified options.
+    fn extract_log_date_with_options<'l>(line: &'l [u8], options: &ParseOptions) -> Result<(&'l [u8], &'l [u8]), ParseError> {
+        if line.len() < ParsedLine::LENGTH_OF_TIMESTAMP_WITHOUT_FRACTION {
+            return Err(ParseError::ShortLine { min_length: ParsedLine::LENGTH_OF_TIMESTAMP_WITHOUT_FRACTION });
         }
 
-        // The numbers.
-        const DECIMAL_INDEXES: [usize; 21] = [0,1,2,3,5,6,8,9,11,12,14,15,17,18,20,21,22,23,24,25,26];
+        // The numbers in the date and time.
+        const DECIMAL_INDEXES: [usize; 14] = [0,1,2,3,5,6,8,9,11,12,14,15,17,18];
         for &idx in &DECIMAL_INDEXES {
             if !line[idx].is_decimal_digit() {
-                let msg = format!("Character {} was expected to be a decimal digit, but was '{}'", idx, line[idx] as char);
-                return Err(msg);
+                return Err(ParseError::ExpectedDigit { index: idx, found: line[idx] });
             }
         }
 
@@ -191,8 +365,7 @@ impl<'f> ParsedLine<'f> {
         const DATE_SEP_INDEXES: [usize; 2] = [4,7];
         for &idx in &DATE_SEP_INDEXES {
             if line[idx] != b'-' {
-                let msg = format!("Character {} was expected to be '-', but was '{}'", idx, line[idx] as char);
-                return Err(msg);
+                return Err(ParseError::ExpectedChar { index: idx, expected: b'-', found: line[idx] });
             }
         }
 
@@ -200,22 +373,39 @@ impl<'f> ParsedLine<'f> {
         const TIME_SEP_INDEXES: [usize; 2] = [13,16];
         for &idx in &TIME_SEP_INDEXES {
             if line[idx] != b':' {
-                let msg = format!("Character {} was expected to be '-', but was '{}'", idx, line[idx] as char);
-                return Err(msg);
+                return Err(ParseError::ExpectedChar { index: idx, expected: b':', found: line[idx] });
             }
         }
 
-        // YYYY-MM-DD_
-        if line[10] != b' ' {
-            let msg = format!("Character {} was expected to be ' ', but was '{}'", 10, line[10] as char);
-            return Err(msg);
+        // YYYY-MM-DD_ where _ may be 'T' as in ISO-8601.
+        if line[10] != b' ' && line[10] != b'T' {
+            return Err(ParseError::ExpectedDateTimeSeparator { index: 10, found: line[10] });
         }
 
-        // YYYY-MM-DD_HH:MM:SS.
-        if line[19] != b'.' {
-            let msg = format!("Character {} was expected to be '.', but was '{}'", 19, line[19] as char);
-            return Err(msg);
-        }
+        // YYYY-MM-DD_HH:MM:SS.FFFFFFF
+        // Some loggers omit the fractional seconds entirely, in which case the timestamp must be
+        // followed by the end of the line, whitespace, a pipe or a UTC offset.
+        let length = if line.len() > 19 && line[19] == b'.' {
+            if line.len() < ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP {
+                return Err(ParseError::ShortLine { min_length: ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP });
+            }
+
+            // The numbers in the fractional seconds, which are not checked in lenient mode.
+            const FRACTION_INDEXES: [usize; 7] = [20,21,22,23,24,25,26];
+            if !options.lenient_dates {
+                for &idx in &FRACTION_INDEXES {
+                    if !line[idx].is_decimal_digit() {
+                        return Err(ParseError::ExpectedDigit { index: idx, found: line[idx] });
+                    }
+                }
+            }
+
+            ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP
+        } else if line.len() == 19 || line[19].is_whitespace() || line[19] == b'|' || ParsedLine::utc_offset_length(&line[19..]) > 0 {
+            ParsedLine::LENGTH_OF_TIMESTAMP_WITHOUT_FRACTION
+        } else {
+            return Err(ParseError::ExpectedChar { index: 19, expected: b'.', found: line[19] });
+        };
 
         // For reference: the code from the old date parsing function.
         // // YYYY-MM-DD_HH:MM:SS.FFFFFFF
@@ -235,8 +425,252 @@ impl<'f> ParsedLine<'f> {
         //     Ok(fraction_end)
         // }
 
-        Ok(line.split_at(ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP))
+        // The timestamp may be followed by a 'Z' or '+HH:MM' offset as in ISO-8601.
+        // This is included in the log date.
+        let suffix_length = ParsedLine::utc_offset_length(&line[length..]);
+        Ok(line.split_at(length + suffix_length))
+    }
+
+    /// Returns the length of the UTC offset at the start of the slice, which
+    /// is either 'Z', '+HH:MM' or '-HH:MM', or 0 if there isn't one.
+    fn utc_offset_length(slice: &[u8]) -> usize {
+        match slice.first() {
+            Some(b'Z') => 1,
+            Some(b'+') | Some(b'-') if slice.len() >= 6
+                && slice[1].is_decimal_digit() && slice[2].is_decimal_digit()
+                && slice[3] == b':'
+                && slice[4].is_decimal_digit() && slice[5].is_decimal_digit() => 6,
+            _ => 0,
+        }
+    }
+
+    /// Normalizes the log date so that all dates sort correctly as strings. The 'T' separator
+    /// of an ISO-8601 date is replaced with a space, and if the fractional seconds are missing
+    /// they are filled in with zeros. This means "12:34:56" sorts as "12:34:56.0000000", i.e.
+    /// before every other timestamp in the same second, and with ties broken by source and
+    /// line number as usual.
+    ///
+    /// Dates without an offset are treated as UTC, so a 'Z' is simply dropped and a '+HH:MM'
+    /// offset is applied. This means lines written in different timezones are sorted, filtered
+    /// and split by day by when they happened rather than by their wall-clock times.
+    fn normalize_log_date(log_date: &[u8]) -> Cow<'_, [u8]> {
+        let has_fraction = log_date.len() > 19 && log_date[19] == b'.';
+        if log_date[10] != b'T' && log_date.len() == ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP {
+            return Cow::Borrowed(log_date);
+        }
+
+        let mut normalized = Vec::with_capacity(ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP);
+        normalized.extend_from_slice(&log_date[..10]);
+        normalized.push(b' ');
+        normalized.extend_from_slice(&log_date[11..19]);
+        let suffix = if has_fraction {
+            normalized.extend_from_slice(&log_date[19..ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP]);
+            &log_date[ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP..]
+        } else {
+            normalized.extend_from_slice(b".0000000");
+            &log_date[19..]
+        };
+
+        // The offset is a whole number of minutes, so the fractional seconds (which need
+        // not be digits with lenient dates) are left alone. A date which chrono does not
+        // accept, such as one in month 13, keeps its offset.
+        let offset = utc_offset_seconds(suffix).unwrap_or(0);
+        if offset != 0 {
+            let shifted = std::str::from_utf8(&normalized[..19]).ok()
+                .and_then(|s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").ok())
+                .map(|dt| (dt - Duration::seconds(offset)).format("%Y-%m-%d %H:%M:%S").to_string())
+                .filter(|s| s.len() == 19);
+
+            match shifted {
+                Some(shifted) => normalized[..19].copy_from_slice(shifted.as_bytes()),
+                None => normalized.extend_from_slice(suffix),
+            }
+        }
+
+        Cow::Owned(normalized)
+    }
+}
+
+/// Converts the suffix of a log date, which is either nothing, 'Z', '+HH:MM' or '-HH:MM',
+/// to the offset from UTC in seconds. Returns None for anything else.
+pub fn utc_offset_seconds(suffix: &[u8]) -> Option<i64> {
+    let digit = |c: u8| i64::from(c - b'0');
+
+    match *suffix {
+        [] | [b'Z'] => Some(0),
+        [sign, h1, h2, b':', m1, m2] if (sign == b'+' || sign == b'-')
+            && [h1, h2, m1, m2].iter().all(|c| c.is_decimal_digit()) => {
+            let seconds = (digit(h1) * 10 + digit(h2)) * 3600 + (digit(m1) * 10 + digit(m2)) * 60;
+            Some(if sign == b'-' { -seconds } else { seconds })
+        },
+        _ => None,
+    }
+}
+
+/// Converts a log date in the usual form, "YYYY-MM-DD HH:MM:SS.FFFFFFF", optionally followed
+/// by a 'Z' or '+HH:MM' offset, to a UTC datetime. Returns None if it is not in that form, which
+/// is possible with lenient dates.
+pub fn log_date_to_utc_datetime(log_date: &[u8]) -> Option<DateTime<Utc>> {
+    if log_date.len() < ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP {
+        return None;
     }
+
+    let (date_time, suffix) = log_date.split_at(ParsedLine::LENGTH_OF_LOGGING_TIMESTAMP);
+    let offset = utc_offset_seconds(suffix)?;
+    let date_time = NaiveDateTime::parse_from_str(std::str::from_utf8(date_time).ok()?, "%Y-%m-%d %H:%M:%S%.f").ok()?;
+    Some(Utc.from_utc_datetime(&(date_time - Duration::seconds(offset))))
+}
+
+/// An iterator which splits a buffer into lines and parses each one, setting the
+/// (zero-based) line number of each result. The source and path are left empty.
+pub struct ParsedLines<'f> {
+    lines: LineSlices<'f>,
+    options: ParseOptions,
+    line_num: usize,
+}
+
+impl<'f> ParsedLines<'f> {
+    pub fn new(bytes: &'f [u8]) -> Self {
+        ParsedLines::with_options(bytes, ParseOptions::default())
+    }
+
+    pub fn with_options(bytes: &'f [u8], options: ParseOptions) -> Self {
+        ParsedLines { lines: LineSlices::new(bytes), options, line_num: 0 }
+    }
+}
+
+impl<'f> Iterator for ParsedLines<'f> {
+    type Item = ParseLineResult<'f>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next()?;
+        let line_num = self.line_num;
+        self.line_num += 1;
+
+        let mut parsed_line_result = ParsedLine::parse_with_options(line, &self.options);
+        match parsed_line_result {
+            Ok(ref mut pl) => pl.line_num = line_num,
+            Err(ref mut e) => e.line_num = line_num,
+        };
+
+        Some(parsed_line_result)
+    }
+}
+
+/// Look for the \r\n line endings in the file and return a vector of
+/// slices, each slice being one line in the log file. Be careful not to be confused
+/// by any stray '\r's in the log file. Files with Unix line endings (no \r\n at all)
+/// are split on the \n instead.
+pub fn find_lines(bytes: &[u8]) -> Vec<&[u8]> {
+    LineSlices::new(bytes).collect()
+}
+
+/// The iterator behind `find_lines`. In a file with Windows line endings each line
+/// after the first starts with the "\r\n" that terminated the previous one, and the
+/// parser trims it off.
+struct LineSlices<'f> {
+    bytes: &'f [u8],
+    start: usize,
+    search_from: usize,
+    windows_endings: bool,
+    done: bool,
+}
+
+impl<'f> LineSlices<'f> {
+    fn new(bytes: &'f [u8]) -> Self {
+        let windows_endings = memchr_iter(b'\r', bytes).any(|idx| LineSlices::is_line_end(bytes, idx));
+
+        LineSlices {
+            bytes,
+            start: 0,
+            search_from: 0,
+            windows_endings,
+            // A freshly rotated log can be empty.
+            done: bytes.is_empty(),
+        }
+    }
+
+    /// A '\r' only ends a line if it is followed by a '\n', or is the last byte in the file.
+    fn is_line_end(bytes: &[u8], idx: usize) -> bool {
+        bytes[idx] == b'\r' && (idx == bytes.len() - 1 || bytes[idx + 1] == b'\n')
+    }
+
+    fn next_windows_line(&mut self) -> Option<&'f [u8]> {
+        let bytes = self.bytes;
+
+        // memchr skips quickly to each '\r', then stray ones within a message are passed over.
+        let end = memchr_iter(b'\r', &bytes[self.search_from..])
+            .map(|offset| self.search_from + offset)
+            .find(|&idx| LineSlices::is_line_end(bytes, idx));
+
+        match end {
+            Some(end) => {
+                let line = &bytes[self.start..end];
+                // The next line starts at the '\r' of this line's ending.
+                self.start = end;
+                self.search_from = end + 1;
+                Some(line)
+            },
+            None => {
+                self.done = true;
+                // If the last line ending is at the end of the file (accounting for "\r\n") there
+                // is nothing more. If it isn't, be sure to include the trailing data in a slice.
+                if self.start + 2 >= bytes.len() {
+                    None
+                } else {
+                    Some(&bytes[self.start..])
+                }
+            }
+        }
+    }
+
+    /// Unlike in a file with Windows line endings, a \n cannot be part of a multi-line message here.
+    fn next_unix_line(&mut self) -> Option<&'f [u8]> {
+        let bytes = self.bytes;
+
+        match memchr(b'\n', &bytes[self.start..]) {
+            Some(offset) => {
+                let line = &bytes[self.start..self.start + offset];
+                self.start += offset + 1;
+                // A trailing \n does not start another line.
+                self.done = self.start == bytes.len();
+                Some(line)
+            },
+            None => {
+                self.done = true;
+                Some(&bytes[self.start..])
+            }
+        }
+    }
+}
+
+impl<'f> Iterator for LineSlices<'f> {
+    type Item = &'f [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            None
+        } else if self.windows_endings {
+            self.next_windows_line()
+        } else {
+            self.next_unix_line()
+        }
+    }
+}
+
+/// Normalizes a date given on the command line to the canonical LogDate form,
+/// "YYYY-MM-DD HH:MM:SS.fffffff", which compares directly with the dates in the lines.
+/// It accepts everything `string_to_utc_datetime` does, including "today", "yesterday"
+/// and a bare "HH:MM".
+pub fn normalize_date_string(s: &str) -> Result<String, String> {
+    // chrono has no 7-digit fraction, which is what the log dates use.
+    string_to_utc_datetime(s).map(|dt| format!("{}.{:07}", dt.format("%Y-%m-%d %H:%M:%S"), dt.nanosecond() / 100))
+}
+
+/// Gets today's date by the local clock. The log dates are written in local time but carry
+/// no timezone, so the date is treated as UTC, just like the dates in the lines.
+fn local_today() -> Date<Utc> {
+    Utc.from_utc_date(&Local::today().naive_local())
 }
 
 pub fn string_to_utc_datetime_and_panic(s: &str) -> DateTime<Utc> {
@@ -247,7 +681,7 @@ pub fn string_to_utc_datetime_and_panic(s: &str) -> DateTime<Utc> {
 }
 
 /// Converts a string slice to a DateTime, for comparison purposes.
-fn string_to_utc_datetime(s: &str) -> Result<DateTime<Utc>, String> {
+pub fn string_to_utc_datetime(s: &str) -> Result<DateTime<Utc>, String> {
     // This is the standard LogDate pattern (albeit with up to 9 decimal places rather than 7).
     let dt = Utc.datetime_from_str(s, "%Y-%m-%d %H:%M:%S%.9f");
     if dt.is_ok() { return Ok(dt.unwrap()); }
@@ -256,6 +690,10 @@ fn string_to_utc_datetime(s: &str) -> Result<DateTime<Utc>, String> {
     let dt = Utc.datetime_from_str(s, "%Y-%m-%dT%H:%M:%S%.9f");
     if dt.is_ok() { return Ok(dt.unwrap()); }
 
+    // ISO-8601 style, with a 'Z' or an offset from UTC.
+    if let Ok(dt) = Utc.datetime_from_str(s, "%Y-%m-%d %H:%M:%S%.9fZ") { return Ok(dt); }
+    if let Ok(dt) = DateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.9f%:z") { return Ok(dt.with_timezone(&Utc)); }
+
     let dt = Utc.datetime_from_str(s, "%Y-%m-%d %H:%M:%S");
     if dt.is_ok() { return Ok(dt.unwrap()); }
 
@@ -273,24 +711,23 @@ fn string_to_utc_datetime(s: &str) -> Result<DateTime<Utc>, String> {
     let nt = NaiveTime::parse_from_str(s, "%H:%M:%S");
     if nt.is_ok() {
         let nt = nt.unwrap();
-        return Ok(Utc::today().and_hms(nt.hour(), nt.minute(), nt.second()));
+        return Ok(local_today().and_hms(nt.hour(), nt.minute(), nt.second()));
     }
 
     let nt = NaiveTime::parse_from_str(s, "%H:%M");
     if nt.is_ok() {
         let nt = nt.unwrap();
-        return Ok(Utc::today().and_hms(nt.hour(), nt.minute(), 0));
+        return Ok(local_today().and_hms(nt.hour(), nt.minute(), 0));
     }
 
     // The beginning of yesterday.
     if s == "yesterday" {
-        let dt = Utc::today().pred();
-        return Ok(Utc.ymd(dt.year(), dt.month(), dt.day()).and_hms(0, 0, 0));
+        return Ok(local_today().pred().and_hms(0, 0, 0));
     }
 
     // The beginning of today.
     if s == "today" {
-        return Ok(Utc::today().and_hms(0, 0, 0));
+        return Ok(local_today().and_hms(0, 0, 0));
     }
 
     Err(format!("Cannot convert {} to a DateTime", s))
@@ -299,7 +736,7 @@ fn string_to_utc_datetime(s: &str) -> Result<DateTime<Utc>, String> {
 #[cfg(test)]
 mod string_to_utc_datetime_tests {
     use chrono::prelude::*;
-    use super::string_to_utc_datetime;
+    use super::{local_today, normalize_date_string, string_to_utc_datetime};
 
     #[test]
     pub fn for_standard_log_date() {
@@ -314,6 +751,15 @@ mod string_to_utc_datetime_tests {
         assert_eq!(Utc.ymd(2018, 6, 2).and_hms_nano(3, 4, 5, 678912300), dt);
     }
 
+    #[test]
+    pub fn for_log_date_with_utc_offset() {
+        let dt = string_to_utc_datetime("2018-06-02 03:04:05.6789123Z").unwrap();
+        assert_eq!(Utc.ymd(2018, 6, 2).and_hms_nano(3, 4, 5, 678912300), dt);
+
+        let dt = string_to_utc_datetime("2018-06-02 03:04:05.6789123+01:00").unwrap();
+        assert_eq!(Utc.ymd(2018, 6, 2).and_hms_nano(2, 4, 5, 678912300), dt);
+    }
+
     #[test]
     pub fn for_date_and_time_to_second_accuracy() {
         let dt = string_to_utc_datetime("2018-01-02 03:04:05").unwrap();
@@ -335,25 +781,25 @@ mod string_to_utc_datetime_tests {
     #[test]
     pub fn for_time_with_seconds() {
         let dt = string_to_utc_datetime("03:04:05").unwrap();
-        assert_eq!(Utc::today().and_hms(3, 4, 5), dt);
+        assert_eq!(local_today().and_hms(3, 4, 5), dt);
     }
 
     #[test]
     pub fn for_time_without_seconds() {
         let dt = string_to_utc_datetime("03:04").unwrap();
-        assert_eq!(Utc::today().and_hms(3, 4, 0), dt);
+        assert_eq!(local_today().and_hms(3, 4, 0), dt);
     }
 
     #[test]
     pub fn for_today() {
         let dt = string_to_utc_datetime("today").unwrap();
-        assert_eq!(Utc::today().and_hms(0, 0, 0), dt);
+        assert_eq!(local_today().and_hms(0, 0, 0), dt);
     }
 
     #[test]
     pub fn for_yesterday() {
         let dt = string_to_utc_datetime("yesterday").unwrap();
-        assert_eq!(Utc::today().pred(), dt.date());
+        assert_eq!(local_today().pred(), dt.date());
         assert_eq!(0, dt.hour());
         assert_eq!(0, dt.minute());
         assert_eq!(0, dt.second());
@@ -370,6 +816,98 @@ mod string_to_utc_datetime_tests {
         let dt = string_to_utc_datetime("dslkhg dhg");
         assert!(dt.is_err());
     }
+
+    #[test]
+    pub fn local_today_is_the_local_date() {
+        let today = Local::today();
+        assert_eq!((today.year(), today.month(), today.day()), (local_today().year(), local_today().month(), local_today().day()));
+    }
+
+    #[test]
+    pub fn normalize_full_date_and_time() {
+        assert_eq!(normalize_date_string("2018-01-02 03:04:05").unwrap(), "2018-01-02 03:04:05.0000000");
+        assert_eq!(normalize_date_string("2018-01-02 03:04:05.6789123").unwrap(), "2018-01-02 03:04:05.6789123");
+    }
+
+    #[test]
+    pub fn normalize_date() {
+        assert_eq!(normalize_date_string("2018-01-02").unwrap(), "2018-01-02 00:00:00.0000000");
+    }
+
+    #[test]
+    pub fn normalize_time_is_today() {
+        let expected = format!("{} 03:04:00.0000000", local_today().format("%Y-%m-%d"));
+        assert_eq!(normalize_date_string("03:04").unwrap(), expected);
+    }
+
+    #[test]
+    pub fn normalize_today() {
+        let expected = format!("{} 00:00:00.0000000", local_today().format("%Y-%m-%d"));
+        assert_eq!(normalize_date_string("today").unwrap(), expected);
+    }
+
+    #[test]
+    pub fn normalize_yesterday() {
+        let expected = format!("{} 00:00:00.0000000", local_today().pred().format("%Y-%m-%d"));
+        assert_eq!(normalize_date_string("yesterday").unwrap(), expected);
+    }
+
+    #[test]
+    pub fn normalize_gibberish_returns_error() {
+        assert!(normalize_date_string("last tuesday").is_err());
+    }
+}
+
+#[cfg(test)]
+mod utc_offset_seconds_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_no_offset_or_z_returns_zero() {
+        assert_eq!(utc_offset_seconds(b""), Some(0));
+        assert_eq!(utc_offset_seconds(b"Z"), Some(0));
+    }
+
+    #[test]
+    pub fn for_offset_returns_seconds_east_of_utc() {
+        assert_eq!(utc_offset_seconds(b"+01:00"), Some(3600));
+        assert_eq!(utc_offset_seconds(b"-05:30"), Some(-19800));
+    }
+
+    #[test]
+    pub fn for_anything_else_returns_none() {
+        assert_eq!(utc_offset_seconds(b"z"), None);
+        assert_eq!(utc_offset_seconds(b"+0100"), None);
+        assert_eq!(utc_offset_seconds(b"+01:0x"), None);
+        assert_eq!(utc_offset_seconds(b"Z "), None);
+    }
+}
+
+#[cfg(test)]
+mod log_date_to_utc_datetime_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_log_date_returns_datetime() {
+        let dt = log_date_to_utc_datetime(b"2018-06-02 03:04:05.6789123").unwrap();
+        assert_eq!(dt, Utc.ymd(2018, 6, 2).and_hms_nano(3, 4, 5, 678_912_300));
+    }
+
+    #[test]
+    pub fn for_log_date_with_offset_applies_it() {
+        let dt = log_date_to_utc_datetime(b"2018-06-02 03:04:05.6789123Z").unwrap();
+        assert_eq!(dt, Utc.ymd(2018, 6, 2).and_hms_nano(3, 4, 5, 678_912_300));
+
+        let dt = log_date_to_utc_datetime(b"2018-06-02 03:04:05.6789123+05:30").unwrap();
+        assert_eq!(dt, Utc.ymd(2018, 6, 1).and_hms_nano(21, 34, 5, 678_912_300));
+    }
+
+    #[test]
+    pub fn for_other_forms_returns_none() {
+        assert!(log_date_to_utc_datetime(b"2018-06-02 03:04:05").is_none());
+        assert!(log_date_to_utc_datetime(b"2018-06-02 03:04:05.67891xx").is_none());
+        assert!(log_date_to_utc_datetime(b"2018-06-02 03:04:05.6789123 extra").is_none());
+    }
 }
 
 #[cfg(test)]
@@ -378,20 +916,16 @@ mod white_space_tests {
 
     #[test]
     fn blank_line_returns_error() {
-        let result = ParsedLine::parse(b"");
-        match result {
-            Err(ref e) if e.message == "Line is empty" => assert!(true),
-            _ => assert!(false, "Unexpected result"),
-        }
+        let e = ParsedLine::parse(b"").unwrap_err();
+        assert_eq!(e.error.to_string(), "Line is empty");
+        assert_eq!(e.error.kind(), ErrorKind::EmptyLine);
     }
 
     #[test]
     fn whitespace_line_returns_error() {
-        let result = ParsedLine::parse(b"  \r  ");
-        match result {
-            Err(ref e) if e.message == "Line is empty" => assert!(true),
-            _ => assert!(false, "Unexpected result"),
-        }
+        let e = ParsedLine::parse(b"  \r  ").unwrap_err();
+        assert_eq!(e.error.to_string(), "Line is empty");
+        assert_eq!(e.error.kind(), ErrorKind::EmptyLine);
     }
 
     #[test]
@@ -622,6 +1156,201 @@ mod extract_log_date_tests {
         assert_eq!(result.0, b"2018-09-26 12:34:56.1146655");
     }
 
+    #[test]
+    fn with_t_separator_extracts_log_date() {
+        let result = ParsedLine::extract_log_date(b"2018-12-03T15:10:04.1114295 | Message").expect("Parse should succeed");
+        assert_eq!(result.0, b"2018-12-03T15:10:04.1114295");
+    }
+
+    #[test]
+    fn with_z_suffix_includes_it_in_log_date() {
+        let result = ParsedLine::extract_log_date(b"2018-12-03T15:10:04.1114295Z | Message").expect("Parse should succeed");
+        assert_eq!(result.0, b"2018-12-03T15:10:04.1114295Z");
+        assert_eq!(result.1, b" | Message");
+    }
+
+    #[test]
+    fn with_offset_suffix_includes_it_in_log_date() {
+        let result = ParsedLine::extract_log_date(b"2018-12-03T15:10:04.1114295+01:00 | Message").expect("Parse should succeed");
+        assert_eq!(result.0, b"2018-12-03T15:10:04.1114295+01:00");
+        let result = ParsedLine::extract_log_date(b"2018-12-03 15:10:04.1114295-05:30").expect("Parse should succeed");
+        assert_eq!(result.0, b"2018-12-03 15:10:04.1114295-05:30");
+    }
+
+    #[test]
+    fn with_incomplete_offset_suffix_excludes_it_from_log_date() {
+        let result = ParsedLine::extract_log_date(b"2018-12-03 15:10:04.1114295-05 Message").expect("Parse should succeed");
+        assert_eq!(result.0, b"2018-12-03 15:10:04.1114295");
+    }
+
+    #[test]
+    fn parse_with_t_separator_normalizes_log_date() {
+        let result = ParsedLine::parse(b"2018-12-03T15:10:04.1114295Z | pid=1 | [INFO_] | Message").expect("Parse should succeed");
+        assert_eq!(result.log_date.as_ref(), b"2018-12-03 15:10:04.1114295");
+        assert_eq!(result.log_level, b"[INFO_]");
+        assert_eq!(result.message.as_ref(), b"Message");
+    }
+
+    #[test]
+    fn with_no_fractional_seconds_extracts_log_date() {
+        let result = ParsedLine::extract_log_date(b"2018-09-26 12:34:56 | pid=1 | [INFO_] | msg").expect("Parse should succeed");
+        assert_eq!(result.0, b"2018-09-26 12:34:56");
+        assert_eq!(result.1, b" | pid=1 | [INFO_] | msg");
+
+        let result = ParsedLine::extract_log_date(b"2018-09-26 12:34:56").expect("Parse should succeed");
+        assert_eq!(result.0, b"2018-09-26 12:34:56");
+
+        let result = ParsedLine::extract_log_date(b"2018-09-26T12:34:56Z msg").expect("Parse should succeed");
+        assert_eq!(result.0, b"2018-09-26T12:34:56Z");
+    }
+
+    #[test]
+    fn parse_with_utf8_bom_strips_it() {
+        let line = "\u{FEFF}2018-09-26 12:34:56.1146655 | [INFO_] | msg";
+        let result = ParsedLine::parse(line.as_bytes()).expect("Parse should succeed");
+        assert_eq!(result.log_date.as_ref(), b"2018-09-26 12:34:56.1146655");
+        assert_eq!(result.log_level, b"[INFO_]");
+        assert_eq!(result.line, &line.as_bytes()[3..]);
+    }
+
+    #[test]
+    fn parse_error_has_kind() {
+        let kind = |line: &[u8]| ParsedLine::parse(line).expect_err("Parse should fail").error.kind();
+        assert_eq!(kind(b"2018-09-26"), ErrorKind::ShortLine);
+        assert_eq!(kind(b"2018-09-26 12:34:56.76x4321"), ErrorKind::BadDateChar);
+        assert_eq!(kind(b"2018-09-26 12-34:56.7654321"), ErrorKind::BadSeparator);
+        assert_eq!(kind(b"2018-09-26_12:34:56.7654321"), ErrorKind::BadSeparator);
+    }
+
+    #[test]
+    fn parse_error_displays_message() {
+        assert_eq!(
+            ParseError::ShortLine { min_length: 19 }.to_string(),
+            "The input line is less than 19 characters, which indicates it does not even contain a logging timestamp"
+        );
+        assert_eq!(
+            ParseError::ExpectedDigit { index: 3, found: b'x' }.to_string(),
+            "Character 3 was expected to be a decimal digit, but was 'x'"
+        );
+        assert_eq!(
+            ParseError::ExpectedChar { index: 13, expected: b':', found: b'-' }.to_string(),
+            "Character 13 was expected to be ':', but was '-'"
+        );
+        assert_eq!(
+            ParseError::ExpectedDateTimeSeparator { index: 10, found: b'_' }.to_string(),
+            "Character 10 was expected to be ' ' or 'T', but was '_'"
+        );
+    }
+
+    #[test]
+    fn with_dot_but_short_fraction_returns_error() {
+        let msg = ParsedLine::extract_log_date(b"2018-09-26 12:34:56.12").expect_err("Parse should fail");
+        assert!(msg.contains("less than 27 characters"));
+    }
+
+    #[test]
+    fn parse_with_no_fractional_seconds_pads_log_date() {
+        let result = ParsedLine::parse(b"2018-09-26 12:34:56 | pid=1 | [INFO_] | msg").expect("Parse should succeed");
+        assert_eq!(result.log_date.as_ref(), b"2018-09-26 12:34:56.0000000");
+        assert_eq!(result.kvps.value(b"pid"), b"1");
+        assert_eq!(result.log_level, b"[INFO_]");
+        assert_eq!(result.message.as_ref(), b"msg");
+    }
+
+    #[test]
+    fn parse_with_no_fractional_seconds_sorts_before_fractions_in_the_same_second() {
+        let without = ParsedLine::parse(b"2018-09-26 12:34:56 | [INFO_] | msg").expect("Parse should succeed");
+        let with = ParsedLine::parse(b"2018-09-26 12:34:56.0000001 | [INFO_] | msg").expect("Parse should succeed");
+        let earlier = ParsedLine::parse(b"2018-09-26 12:34:55.9999999 | [INFO_] | msg").expect("Parse should succeed");
+        assert!(without.log_date < with.log_date);
+        assert!(earlier.log_date < without.log_date);
+
+        let zulu = ParsedLine::parse(b"2018-09-26T12:34:56Z | [INFO_] | msg").expect("Parse should succeed");
+        assert_eq!(zulu.log_date.as_ref(), b"2018-09-26 12:34:56.0000000");
+        assert!(zulu.log_date < with.log_date);
+    }
+
+    #[test]
+    fn parse_with_utc_offsets_converts_log_date_to_utc() {
+        let parse = |line: &'static [u8]| ParsedLine::parse(line).expect("Parse should succeed").log_date.into_owned();
+
+        assert_eq!(parse(b"2018-09-26 12:34:56.1234567+01:00 | [INFO_] | msg"), b"2018-09-26 11:34:56.1234567");
+        assert_eq!(parse(b"2018-09-26T12:34:56+05:30 | [INFO_] | msg"), b"2018-09-26 07:04:56.0000000");
+        assert_eq!(parse(b"2018-09-26 12:34:56.1234567+00:00 | [INFO_] | msg"), b"2018-09-26 12:34:56.1234567");
+
+        // Across midnight, which matters for splitting by day.
+        let west = parse(b"2018-09-26T23:30:00.0000000-02:00 | [INFO_] | msg");
+        assert_eq!(west, b"2018-09-27 01:30:00.0000000");
+
+        // The wall-clock times are the other way round.
+        let east = parse(b"2018-09-27T01:00:00.0000000+01:00 | [INFO_] | msg");
+        assert_eq!(east, b"2018-09-27 00:00:00.0000000");
+        assert!(east < west);
+    }
+
+    #[test]
+    fn parse_with_utc_offset_and_lenient_fraction_keeps_the_fraction() {
+        let options = ParseOptions { lenient_dates: true, .. ParseOptions::default() };
+        let result = ParsedLine::parse_with_options(b"2018-09-26 12:34:56.12345xx-01:00 | [INFO_] | msg", &options)
+            .expect("Parse should succeed");
+        assert_eq!(result.log_date.as_ref(), b"2018-09-26 13:34:56.12345xx");
+    }
+
+    #[test]
+    fn parse_with_timestamp_format_converts_log_date() {
+        let options = ParseOptions { timestamp_format: Some("%d/%m/%Y %H:%M:%S".parse().unwrap()), .. ParseOptions::default() };
+        let result = ParsedLine::parse_with_options(b"26/09/2018 12:34:56 | pid=1 | [INFO_] | msg", &options)
+            .expect("Parse should succeed");
+        assert_eq!(result.log_date.as_ref(), b"2018-09-26 12:34:56.0000000");
+        assert_eq!(result.log_level, b"[INFO_]");
+        assert_eq!(result.message.as_ref(), b"msg");
+    }
+
+    #[test]
+    fn parse_with_timestamp_format_rejects_standard_layout() {
+        let options = ParseOptions { timestamp_format: Some("%d/%m/%Y %H:%M:%S".parse().unwrap()), .. ParseOptions::default() };
+        assert!(ParsedLine::parse_with_options(b"2018-09-26 12:34:56.7654321 | [INFO_] | msg", &options).is_err());
+    }
+
+    #[test]
+    fn with_bad_fraction_digit_returns_error_by_default() {
+        let msg = ParsedLine::extract_log_date(b"2018-09-26 12:34:56.76x4321").expect_err("Parse should fail");
+        assert!(msg.contains("Character 22"));
+    }
+
+    #[test]
+    fn with_bad_fraction_digit_and_lenient_dates_extracts_log_date() {
+        let options = ParseOptions { lenient_dates: true, .. ParseOptions::default() };
+        let result = ParsedLine::extract_log_date_with_options(b"2018-09-26 12:34:56.76x4321", &options).expect("Parse should succeed");
+        assert_eq!(result.0, b"2018-09-26 12:34:56.76x4321");
+    }
+
+    #[test]
+    fn with_bad_date_digit_and_lenient_dates_returns_error() {
+        let options = ParseOptions { lenient_dates: true, .. ParseOptions::default() };
+        let error = ParsedLine::extract_log_date_with_options(b"2018-09-2x 12:34:56.7654321", &options).expect_err("Parse should fail");
+        assert_eq!(error, ParseError::ExpectedDigit { index: 9, found: b'x' });
+        assert_eq!(error.kind(), ErrorKind::BadDateChar);
+        assert!(error.to_string().contains("Character 9"));
+    }
+
+    #[test]
+    fn with_bad_separator_and_lenient_dates_returns_error() {
+        let options = ParseOptions { lenient_dates: true, .. ParseOptions::default() };
+        let error = ParsedLine::extract_log_date_with_options(b"2018-09-26 12:34:56x7654321", &options).expect_err("Parse should fail");
+        assert_eq!(error.kind(), ErrorKind::BadSeparator);
+        assert!(error.to_string().contains("Character 19"));
+    }
+
+    #[test]
+    fn parse_with_bad_fraction_digit_and_lenient_dates_succeeds() {
+        let options = ParseOptions { lenient_dates: true, .. ParseOptions::default() };
+        let result = ParsedLine::parse_with_options(b"2018-09-26 12:34:56.765432? | pid=1 | [INFO_] | Message", &options)
+            .expect("Parse should succeed");
+        assert_eq!(result.log_date.as_ref(), b"2018-09-26 12:34:56.765432?");
+        assert_eq!(result.log_level, b"[INFO_]");
+    }
+
     // These were supported under the old parser, but not the new one.
     // #[test]
     // fn with_longer_precision_extracts_log_date() {
@@ -657,6 +1386,35 @@ mod leading_kvps_tests {
         }
     }
 
+    #[test]
+    pub fn with_configured_log_levels_of_other_lengths_returns_them() {
+        let mut options = ParseOptions::default();
+        options.log_levels.push(b"[INFO]".to_vec());
+        options.log_levels.push(b"[CRITICAL]".to_vec());
+
+        let result = ParsedLine::parse_with_options(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO] | Message", &options)
+            .expect("Parse should succeed");
+        assert_eq!(result.log_level, b"[INFO]");
+        assert_eq!(result.message.as_ref(), b"Message");
+
+        let result = ParsedLine::parse_with_options(b"2018-09-26 12:34:56.7654321 | pid=1 | [CRITICAL] | Message", &options)
+            .expect("Parse should succeed");
+        assert_eq!(result.log_level, b"[CRITICAL]");
+        assert_eq!(result.kvps.value(b"pid"), b"1");
+        assert_eq!(result.message.as_ref(), b"Message");
+
+        let result = ParsedLine::parse_with_options(b"2018-09-26 12:34:56.7654321 | [ERROR] | Message", &options)
+            .expect("Parse should succeed");
+        assert_eq!(result.log_level, b"[ERROR]");
+    }
+
+    #[test]
+    pub fn with_unknown_bracketed_level_leaves_it_in_message() {
+        let result = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | [CRITICAL] | Message").expect("Parse should succeed");
+        assert!(result.log_level.is_empty());
+        assert_eq!(result.message.as_ref(), b"[CRITICAL] | Message");
+    }
+
     #[test]
     pub fn with_prologue_containing_kpvs_returns_kvps() {
         let result = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | a=b | pid=123 | [INFO_] | Message")
@@ -702,6 +1460,52 @@ mod leading_kvps_tests {
 mod trailing_kvps_tests {
     use super::*;
 
+    #[test]
+    pub fn with_trailing_kvp_containing_equals_returns_whole_value() {
+        let line = b"2018-09-26 12:34:56.7654321 | [INFO_] | Message Owin.Request.QueryString=foo=bar&baz=qux SysRef=AA123456";
+        let result = ParsedLine::parse(line).expect("Parse should succeed");
+        assert_eq!(result.kvps.len(), 2);
+        assert_eq!(result.kvps.value(b"Owin.Request.QueryString"), b"foo=bar&baz=qux");
+        assert_eq!(result.kvps.value(b"SysRef"), b"AA123456");
+    }
+
+    #[test]
+    pub fn with_field_separator_parses_double_colon_prologue() {
+        let line = b"2018-09-26 12:34:56.7654321 :: pid=1 :: tid=2 :: [INFO_] :: Message Foo=Bar";
+        let options = ParseOptions { field_separator: b"::".to_vec(), .. ParseOptions::default() };
+        let result = ParsedLine::parse_with_options(line, &options).expect("Parse should succeed");
+        assert_eq!(result.log_level, b"[INFO_]");
+        assert_eq!(result.kvps.value(b"pid"), b"1");
+        assert_eq!(result.kvps.value(b"tid"), b"2");
+        assert_eq!(result.kvps.value(b"Foo"), b"Bar");
+        assert_eq!(result.message.as_ref(), b"Message Foo=Bar");
+    }
+
+    #[test]
+    pub fn with_default_field_separator_double_colon_starts_message() {
+        let line = b"2018-09-26 12:34:56.7654321 :: pid=1 :: [INFO_] :: Message";
+        let result = ParsedLine::parse(line).expect("Parse should succeed");
+        assert!(result.kvps.is_empty());
+        assert_eq!(result.message.as_ref(), b":: pid=1 :: [INFO_] :: Message");
+    }
+
+    #[test]
+    pub fn with_repeated_trailing_kvp_keeps_first_value() {
+        let line = b"2018-09-26 12:34:56.7654321 | [INFO_] | Message Tag=a Tag=b";
+        let result = ParsedLine::parse(line).expect("Parse should succeed");
+        assert_eq!(result.kvps.len(), 1);
+    }
+
+    #[test]
+    pub fn with_repeated_trailing_kvp_and_allow_duplicates_keeps_all_values_in_order() {
+        let line = b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | Message Tag=a Foo=Bar Tag=b tag=c";
+        let options = ParseOptions { allow_duplicate_kvps: true, .. ParseOptions::default() };
+        let result = ParsedLine::parse_with_options(line, &options).expect("Parse should succeed");
+        assert_eq!(result.kvps.len(), 5);
+        assert_eq!(result.kvps.get_all_values(b"Tag"), vec![b"a" as &[u8], b"b", b"c"]);
+        assert_eq!(result.kvps.value(b"Foo"), b"Bar");
+    }
+
     #[test]
     pub fn with_trailing_kvp_no_value() {
         let line = b"2018-09-26 12:34:56.7654321 | [INFO_] | Message SysRef=";
@@ -753,6 +1557,41 @@ mod trailing_kvps_tests {
         assert_eq!(result.kvps.value(b"hit"), b"");
         assert_eq!(result.kvps.value(b"Http.Request"), b"http:/www.foo.com");
     }
+
+    #[test]
+    pub fn with_trailing_kvp_empty_quoted_value_returns_empty_kvp() {
+        let line = b"2018-09-26 12:34:56.7654321 | [INFO_] | Message Tags=\"\"";
+        let result = ParsedLine::parse(line).expect("Parse should succeed");
+        assert_eq!(result.kvps.len(), 1);
+        assert_eq!(result.kvps.value(b"Tags"), b"");
+        assert_eq!(result.message.as_ref(), b"Message Tags=\"\"");
+    }
+
+    #[test]
+    pub fn with_trailing_kvp_unclosed_quote_only_returns_no_kvp_and_keeps_message() {
+        let line = b"2018-09-26 12:34:56.7654321 | [INFO_] | Message Tags=\"";
+        let result = ParsedLine::parse(line).expect("Parse should succeed");
+        assert!(result.kvps.is_empty());
+        assert_eq!(result.message.as_ref(), b"Message Tags=\"");
+    }
+
+    #[test]
+    pub fn with_trailing_kvp_unterminated_value_returns_no_kvp_and_keeps_message() {
+        let line = b"2018-09-26 12:34:56.7654321 | [INFO_] | Message Tags=\"unterminated";
+        let result = ParsedLine::parse(line).expect("Parse should succeed");
+        assert!(result.kvps.is_empty());
+        assert_eq!(result.message.as_ref(), b"Message Tags=\"unterminated");
+    }
+
+    #[test]
+    pub fn with_trailing_kvp_unterminated_value_stops_extracting_trailing_kvps() {
+        // The line is badly formed, so we cannot reliably find any KVPs before the
+        // unterminated one. They are still visible in the message.
+        let line = b"2018-09-26 12:34:56.7654321 | [INFO_] | Message Foo=Bar Tags=\"unterminated value";
+        let result = ParsedLine::parse(line).expect("Parse should succeed");
+        assert!(result.kvps.is_empty());
+        assert_eq!(result.message.as_ref(), b"Message Foo=Bar Tags=\"unterminated value");
+    }
 }
 
 
@@ -810,7 +1649,7 @@ mod real_log_line_tests {
         line.push_str("\n SourceInstance=38449385");
 
         let result = ParsedLine::parse(line.as_bytes()).expect("Parse should succeed");
-        assert_eq!(result.log_date, b"2018-09-26 12:34:56.1146655");
+        assert_eq!(result.log_date.as_ref(), b"2018-09-26 12:34:56.1146655");
         assert_eq!(result.log_level, b"[INFO_]");
         assert_eq!(result.message.to_vec(), b"Running aggregate capacity generator.".to_vec());
 
@@ -841,7 +1680,7 @@ mod real_log_line_tests {
         line.push_str("\n SourceInstance=855390 startDate=28/09/2018 endDate=11/10/2018 postcode=\"MK16 8QF\"");
 
         let result = ParsedLine::parse(line.as_bytes()).expect("Parse should succeed");
-        assert_eq!(result.log_date, b"2018-09-27 11:29:51.0680203");
+        assert_eq!(result.log_date.as_ref(), b"2018-09-27 11:29:51.0680203");
         assert_eq!(result.log_level, b"[INFO_]");
         assert_eq!(result.message, message.as_bytes());
 
@@ -887,7 +1726,7 @@ mod real_log_line_tests {
         line.push_str("\n SourceInstance=35519589 startDate=28/11/2018 endDate=04/12/2018 sysref=QU090700");
 
         let result = ParsedLine::parse(line.as_bytes()).expect("Parse should succeed");
-        assert_eq!(result.log_date, b"2018-11-27 10:33:37.2324929");
+        assert_eq!(result.log_date.as_ref(), b"2018-11-27 10:33:37.2324929");
         assert_eq!(result.log_level, b"[ERROR]");
         assert_eq!(result.message, (&message[1..]).as_bytes());  // This message has an extra leading space, we need to trim it for the test.
 
@@ -916,7 +1755,7 @@ mod real_log_line_tests {
         line.push_str("\n Total surveyors matching criteria: 577\"");
 
         let result = ParsedLine::parse(line.as_bytes()).expect("Parse should succeed");
-        assert_eq!(result.log_date, b"2018-11-27 10:33:37.2324929");
+        assert_eq!(result.log_date.as_ref(), b"2018-11-27 10:33:37.2324929");
         assert_eq!(result.log_level, b"[INFO_]");
         assert_eq!(result.message.as_ref(), b"Some irrelevant message");
 
@@ -940,7 +1779,7 @@ mod real_log_line_tests {
         line.push_str("\n AssemblyFile=C:\\Users\\pdaniels\\AppData\\Local\\Temp\\Whatever-201802-03-1434124214.3324\\Something.Database.dll");
 
         let result = ParsedLine::parse(line.as_bytes()).expect("Parse should succeed");
-        assert_eq!(result.log_date, b"2018-12-03 14:42:48.1783541");
+        assert_eq!(result.log_date.as_ref(), b"2018-12-03 14:42:48.1783541");
         assert_eq!(result.log_level, b"[VRBSE]");
         assert_eq!(result.message.to_vec(), b"Attempting to load assembly C:\\Users\\pdaniels\\AppData\\Local\\Temp\\Whatever-201802-03-1434124214.3324\\Something.Database.dll".to_vec());
 
@@ -977,7 +1816,7 @@ mod real_log_line_tests {
         line.push_str("\n targetType=Case");
 
         let result = ParsedLine::parse(line.as_bytes()).expect("Parse should succeed");
-        assert_eq!(result.log_date, b"2018-06-27 12:40:02.8554336");
+        assert_eq!(result.log_date.as_ref(), b"2018-06-27 12:40:02.8554336");
         assert_eq!(result.log_level, b"[INFO_]");
         assert_eq!(result.message.to_vec(), b"Successfully retrived 20 number of audit items for target id PD123456 and targetType Case".to_vec());
 
@@ -1028,7 +1867,7 @@ mod real_log_line_tests {
         line.push_str("\n SysRef=QU076868");
 
         let result = ParsedLine::parse(line.as_bytes()).expect("Parse should succeed");
-        assert_eq!(result.log_date, b"2018-06-27 12:32:00.6811879");
+        assert_eq!(result.log_date.as_ref(), b"2018-06-27 12:32:00.6811879");
         assert_eq!(result.log_level, b"[INFO_]");
         assert_eq!(result.message, message.as_bytes());
 
@@ -1044,3 +1883,140 @@ mod real_log_line_tests {
         assert_eq!(result.kvps.value(b"SysRef"), b"QU076868");
     }
 }
+
+#[cfg(test)]
+mod find_lines_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_unix_line_endings_splits_on_lf() {
+        let lines = find_lines(b"line 1\nline 2\nline 3\n");
+        assert_eq!(lines, vec![&b"line 1"[..], b"line 2", b"line 3"]);
+    }
+
+    #[test]
+    pub fn for_empty_buffer_returns_no_lines() {
+        assert!(find_lines(b"").is_empty());
+    }
+
+    #[test]
+    pub fn for_single_line_without_newline_returns_the_line() {
+        assert_eq!(find_lines(b"single line no newline"), vec![&b"single line no newline"[..]]);
+    }
+
+    #[test]
+    pub fn for_windows_line_endings_without_trailing_newline_keeps_the_last_line() {
+        let lines = find_lines(b"line 1\r\nline 2");
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].trim_while(ByteExtensions::is_whitespace), b"line 2");
+    }
+
+    #[test]
+    pub fn for_lone_carriage_return_does_not_panic() {
+        assert_eq!(find_lines(b"\r").len(), 1);
+    }
+
+    #[test]
+    pub fn for_windows_line_endings_does_not_split_on_lf() {
+        let lines = find_lines(b"line 1\r\nline 2 Foo=\"a\nb\"\r\nline 3\r\n");
+        assert_eq!(lines.len(), 3);
+        let parsed: Vec<_> = lines.iter().map(|l| l.trim_while(ByteExtensions::is_whitespace)).collect();
+        assert_eq!(parsed, vec![&b"line 1"[..], b"line 2 Foo=\"a\nb\"", b"line 3"]);
+    }
+
+    #[test]
+    pub fn for_windows_line_endings_at_start_of_buffer_returns_empty_first_line() {
+        let lines = find_lines(b"\r\nline 1\r\n");
+        assert_eq!(lines, vec![&b""[..], b"\r\nline 1"]);
+    }
+
+    #[test]
+    pub fn for_windows_line_endings_does_not_split_on_stray_cr() {
+        let lines = find_lines(b"line 1 \r stray\r\nline 2\rmore\r\nline 3\r");
+        assert_eq!(lines, vec![&b"line 1 \r stray"[..], b"\r\nline 2\rmore", b"\r\nline 3"]);
+    }
+
+    #[test]
+    pub fn for_windows_line_endings_keeps_trailing_data_after_last_line_end() {
+        assert_eq!(find_lines(b"line 1\r\nab"), vec![&b"line 1"[..], b"\r\nab"]);
+        assert_eq!(find_lines(b"line 1\r\na"), vec![&b"line 1"[..], b"\r\na"]);
+    }
+}
+
+#[cfg(test)]
+mod parsed_lines_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_empty_buffer_returns_nothing() {
+        assert_eq!(ParsedLines::new(b"").count(), 0);
+    }
+
+    #[test]
+    pub fn for_multi_line_buffer_with_trailing_partial_line_returns_all_lines() {
+        let bytes = b"2018-09-26 12:34:56.7654321 | [INFO_] | First\r\n\
+                      2018-09-26 12:34:57.7654321 | [ERROR] | Second Foo=Bar\r\n\
+                      2018-09-26 12:34:58.7654";
+
+        let results: Vec<_> = ParsedLines::new(bytes).collect();
+        assert_eq!(results.len(), 3);
+
+        let first = results[0].as_ref().unwrap();
+        assert_eq!(first.line_num, 0);
+        assert_eq!(first.log_level, b"[INFO_]");
+        assert_eq!(first.message.as_ref(), b"First");
+
+        let second = results[1].as_ref().unwrap();
+        assert_eq!(second.line_num, 1);
+        assert_eq!(second.kvps.value(b"Foo"), b"Bar");
+
+        let error = results[2].as_ref().unwrap_err();
+        assert_eq!(error.line_num, 2);
+        assert_eq!(error.line, b"2018-09-26 12:34:58.7654");
+        assert_eq!(error.error.kind(), ErrorKind::ShortLine);
+    }
+
+    #[test]
+    pub fn for_unix_line_endings_returns_all_lines() {
+        let bytes = b"2018-09-26 12:34:56.7654321 | First\n2018-09-26 12:34:57.7654321 | Second\n";
+        let messages: Vec<_> = ParsedLines::new(bytes).map(|r| r.unwrap().message.into_owned()).collect();
+        assert_eq!(messages, vec![b"First".to_vec(), b"Second".to_vec()]);
+    }
+
+    #[test]
+    pub fn with_options_uses_the_options() {
+        let options = ParseOptions { timestamp_format: Some("%d/%m/%Y %H:%M:%S".parse().unwrap()), .. ParseOptions::default() };
+        let mut lines = ParsedLines::with_options(b"26/09/2018 12:34:56 | Message\n", options);
+        assert_eq!(lines.next().unwrap().unwrap().log_date.as_ref(), b"2018-09-26 12:34:56.0000000");
+        assert!(lines.next().is_none());
+    }
+}
+
+#[cfg(test)]
+mod serialize_tests {
+    use super::*;
+
+    #[test]
+    pub fn serializes_main_fields_and_kvps() {
+        let mut line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | pid=1 | [INFO_] | Hello Foo=Bar").unwrap();
+        line.source = "input.log";
+        line.line_num = 12;
+
+        let json: serde_json::Value = serde_json::to_value(&line).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "log_date": "2018-09-26 12:34:56.7654321",
+            "log_level": "[INFO_]",
+            "source": "input.log",
+            "line_num": 12,
+            "message": "Hello Foo=Bar",
+            "kvps": { "pid": "1", "Foo": "Bar" }
+        }));
+    }
+
+    #[test]
+    pub fn serializes_invalid_utf8_lossily() {
+        let line = ParsedLine::parse(b"2018-09-26 12:34:56.7654321 | Caf\xe9").unwrap();
+        let json: serde_json::Value = serde_json::to_value(&line).unwrap();
+        assert_eq!(json["message"], "Caf\u{fffd}");
+    }
+}
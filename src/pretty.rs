@@ -0,0 +1,231 @@
+//! This module implements the "pretty" output format, which writes the lines to stdout in
+//! a readable form, like grep, rather than as CSV or JSON. The values which made a line
+//! pass the sysref, `--where` and `--match` filters are highlighted, so it is easy to see
+//! why each line was written. It is for reading at a terminal, so there are no columns,
+//! sorting is as usual but the output is never split into files.
+
+use std::ffi::OsString;
+use std::io::{self, IsTerminal, Write};
+use std::ops::Range;
+use crate::byte_extensions::ByteSliceExtensions;
+use crate::configuration::Configuration;
+use crate::output::get_column_value;
+use crate::parsed_line::{ParseLineResult, ParsedLine};
+
+const COLOR_FILE_NAME: &[u8] = b"\x1b[35m";
+const COLOR_SEPARATOR: &[u8] = b"\x1b[36m";
+const COLOR_MATCH: &[u8] = b"\x1b[1;31m";
+const COLOR_RESET: &[u8] = b"\x1b[0m";
+
+/// Decides whether to use ANSI colors: only when writing to a terminal, and never if the
+/// NO_COLOR environment variable is set to anything (see https://no-color.org).
+pub fn use_color(is_terminal: bool, no_color: Option<OsString>) -> bool {
+    is_terminal && no_color.is_none_or(|value| value.is_empty())
+}
+
+/// Writes the lines to stdout and the errors to stderr, returning the number of errors.
+pub fn write_pretty_output(config: &Configuration, results: &[ParseLineResult]) -> Result<usize, io::Error> {
+    let color = use_color(io::stdout().is_terminal(), std::env::var_os("NO_COLOR"));
+    let stdout = io::stdout();
+    let stderr = io::stderr();
+    write_pretty(config, results, color, &mut stdout.lock(), &mut stderr.lock())
+}
+
+/// Writes each line as "FileName: line", and each error as "FileName: error: line".
+/// Line breaks are replaced with spaces so that each line of the log is one line of output.
+pub fn write_pretty<W: Write, E: Write>(
+    config: &Configuration,
+    results: &[ParseLineResult],
+    color: bool,
+    writer: &mut W,
+    error_writer: &mut E
+    ) -> Result<usize, io::Error>
+{
+    let mut error_count = 0;
+
+    for result in results {
+        match result {
+            Ok(line) if !config.only_errors => {
+                write_file_name(writer, line.source, color)?;
+                let ranges = if color { match_ranges(config, line) } else { vec![] };
+                write_highlighted(writer, &line.line.make_safe(), &ranges)?;
+                writer.write_all(b"\n")?;
+            },
+            Ok(_) => {},
+            Err(e) => {
+                write!(error_writer, "{}: {}: ", e.source, e.error)?;
+                error_writer.write_all(&e.line.make_safe())?;
+                error_writer.write_all(b"\n")?;
+                error_count += 1;
+            }
+        }
+    }
+
+    writer.flush()?;
+    error_writer.flush()?;
+    Ok(error_count)
+}
+
+fn write_file_name<W: Write>(writer: &mut W, file_name: &str, color: bool) -> Result<(), io::Error> {
+    if color {
+        writer.write_all(COLOR_FILE_NAME)?;
+        writer.write_all(file_name.as_bytes())?;
+        writer.write_all(COLOR_SEPARATOR)?;
+        writer.write_all(b":")?;
+        writer.write_all(COLOR_RESET)?;
+    } else {
+        writer.write_all(file_name.as_bytes())?;
+        writer.write_all(b":")?;
+    }
+
+    writer.write_all(b" ")
+}
+
+/// Writes the text with each of the sorted, non-overlapping ranges highlighted.
+fn write_highlighted<W: Write>(writer: &mut W, text: &[u8], ranges: &[Range<usize>]) -> Result<(), io::Error> {
+    let mut written = 0;
+    for range in ranges {
+        writer.write_all(&text[written..range.start])?;
+        writer.write_all(COLOR_MATCH)?;
+        writer.write_all(&text[range.clone()])?;
+        writer.write_all(COLOR_RESET)?;
+        written = range.end;
+    }
+
+    writer.write_all(&text[written..])
+}
+
+/// Finds the parts of the line which matched the sysref, `--where` and `--match` filters,
+/// as sorted, non-overlapping ranges. When the filters are inverted the line matched
+/// none of them, so there is nothing to highlight.
+fn match_ranges(config: &Configuration, line: &ParsedLine) -> Vec<Range<usize>> {
+    if config.invert_match {
+        return vec![];
+    }
+
+    let mut ranges = Vec::new();
+
+    for (column, _) in &config.where_clauses {
+        if let Some(range) = get_column_value(config, line, column).and_then(|value| find_value(line.line, value)) {
+            ranges.push(range);
+        }
+    }
+
+    for (column, regex) in &config.match_regexes {
+        if let Some(value) = get_column_value(config, line, column) {
+            if let Some(value_range) = find_value(line.line, value) {
+                ranges.extend(regex.find_iter(value).map(|m| value_range.start + m.start()..value_range.start + m.end()));
+            }
+        }
+    }
+
+    if !config.sysrefs.is_empty() {
+        if let Some(range) = line.kvps.get_value(b"sysref").and_then(|value| find_value(line.line, value)) {
+            ranges.push(range);
+        }
+    }
+
+    merge_ranges(ranges)
+}
+
+/// Finds where a value is in the line. Values are nearly always slices of the line, so
+/// this is usually just a matter of comparing pointers, otherwise the line is searched.
+fn find_value(line: &[u8], value: &[u8]) -> Option<Range<usize>> {
+    if value.is_empty() {
+        return None;
+    }
+
+    let line_start = line.as_ptr() as usize;
+    let value_start = value.as_ptr() as usize;
+    if value_start >= line_start && value_start + value.len() <= line_start + line.len() {
+        let start = value_start - line_start;
+        return Some(start..start + value.len());
+    }
+
+    line.windows(value.len()).position(|w| w == value).map(|start| start..start + value.len())
+}
+
+/// Sorts the ranges and merges any which overlap or touch.
+fn merge_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.retain(|range| !range.is_empty());
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod pretty_tests {
+    use super::*;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::profiles::ProfileSet;
+
+    fn write(args: &Arguments, lines: &[&'static [u8]], color: bool) -> (String, String, usize) {
+        let config = get_config(&ProfileSet::default(), args).unwrap();
+        let results: Vec<_> = lines.iter().map(|line| {
+            let mut result = ParsedLine::parse(line);
+            if let Ok(ref mut parsed_line) = result {
+                parsed_line.source = "app.log";
+            }
+            result
+        }).collect();
+
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let error_count = write_pretty(&config, &results, color, &mut out, &mut err).unwrap();
+        (String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap(), error_count)
+    }
+
+    #[test]
+    pub fn use_color_only_for_terminal_without_no_color() {
+        assert!(use_color(true, None));
+        assert!(use_color(true, Some(OsString::new())));
+        assert!(!use_color(true, Some(OsString::from("1"))));
+        assert!(!use_color(false, None));
+    }
+
+    #[test]
+    pub fn without_color_writes_plain_lines_and_errors() {
+        let (out, err, error_count) = write(&Arguments::default(), &[
+            b"2018-09-26 12:34:56.7654321 | [INFO_] | First\r\nsecond",
+            b"garbage",
+        ], false);
+
+        assert_eq!(out, "app.log: 2018-09-26 12:34:56.7654321 | [INFO_] | First  second\n");
+        assert!(err.ends_with(": garbage\n"));
+        assert_eq!(error_count, 1);
+    }
+
+    #[test]
+    pub fn with_color_highlights_where_match_and_sysref_values() {
+        let args = Arguments {
+            where_clauses: vec![("AppName".to_string(), "web".to_string())],
+            match_clauses: vec![("Action".to_string(), "^Get".to_string())],
+            sysrefs: vec!["QU1".to_string()],
+            .. Arguments::default()
+        };
+        let (out, _, _) = write(&args, &[b"2018-09-26 12:34:56.7654321 | AppName=Web | [INFO_] | Msg Action=GetQuote SysRef=QU1"], true);
+
+        assert_eq!(out, "\x1b[35mapp.log\x1b[36m:\x1b[0m 2018-09-26 12:34:56.7654321 | AppName=\x1b[1;31mWeb\x1b[0m | [INFO_] | \
+            Msg Action=\x1b[1;31mGet\x1b[0mQuote SysRef=\x1b[1;31mQU1\x1b[0m\n");
+    }
+
+    #[test]
+    pub fn with_invert_match_highlights_nothing() {
+        let args = Arguments { sysrefs: vec!["QU1".to_string()], invert_match: true, .. Arguments::default() };
+        let (out, _, _) = write(&args, &[b"2018-09-26 12:34:56.7654321 | Msg SysRef=QU2"], true);
+        assert_eq!(out, "\x1b[35mapp.log\x1b[36m:\x1b[0m 2018-09-26 12:34:56.7654321 | Msg SysRef=QU2\n");
+    }
+
+    #[test]
+    pub fn merge_ranges_sorts_and_joins_overlaps() {
+        assert_eq!(merge_ranges(vec![5..8, 0..2, 1..3, 8..9, 4..4]), vec![0..3, 5..9]);
+    }
+}
@@ -1,8 +1,51 @@
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use itertools::Itertools;
+use regex::RegexBuilder;
+use regex::bytes::RegexBuilder as BytesRegexBuilder;
 use serde_derive::{Serialize, Deserialize};
 
 use crate::configuration::{DEFAULT_PROFILE_NAME, DEFAULT_MAX_MESSAGE_LENGTH};
-use crate::kvp::{LOG_DATE, LOG_LEVEL, MESSAGE, LOG_SOURCE};
+use crate::timestamp::TimestampFormat;
+use crate::kvp::{LOG_DATE, LOG_LEVEL, LOG_LEVELS, MESSAGE, FILE_NAME};
+
+/// The columns of the default profile, in the order they are written. This is the one
+/// place the default columns are defined; the names of the built-in columns are in `kvp`.
+const DEFAULT_COLUMNS: [&str; 18] = [
+    LOG_DATE,
+    LOG_LEVEL,
+    FILE_NAME,
+    "MachineName",
+    "AppName",
+    "PID",
+    "TID",
+    "SysRef",
+    "Action",
+    "Source",
+    "CorrelationKey",
+    "CallRecorderExecutionTime",
+    "Http.RequestId",
+    "Http.RequestQueryString",
+    "Http.Request.Path",
+    "UserName",
+    "UserIdentity",
+    MESSAGE,
+];
+
+/// The alternate names of the default profile's columns, as (column, alternate name) pairs.
+const DEFAULT_ALTERNATE_COLUMNS: [(&str, &str); 4] = [
+    ("AppName", "ApplicationName"),
+    ("Http.RequestId", "Owin.Request.Id"),
+    ("Http.RequestQueryString", "Owin.Request.QueryString"),
+    ("Http.Request.Path", "Owin.Request.Path"),
+];
+
+/// The columns of the default profile which hold whole numbers.
+const DEFAULT_NUMERIC_COLUMNS: [&str; 3] = ["PID", "TID", "CallRecorderExecutionTime"];
 
 /// Represents a profile as defined in the configuration file.
 /// The main difference between this and the final configuration is that
@@ -11,6 +54,13 @@ use crate::kvp::{LOG_DATE, LOG_LEVEL, MESSAGE, LOG_SOURCE};
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Profile {
     pub name: String,
+
+    /// The name of another profile which this one builds on. The parent's settings are
+    /// applied first and then this profile's, so the lists and maps are combined and
+    /// this profile's scalar settings take precedence.
+    #[serde(default)]
+    pub extends: Option<String>,
+
     pub quiet: Option<bool>,
     pub max_message_length: Option<usize>,
 
@@ -25,6 +75,12 @@ pub struct Profile {
     /// a value using "AppName" or "ApplicationName".
     pub alternate_column_names: HashMap<String, Vec<String>>,
 
+    /// A sparse map of ColumnName -> Header, used to give a column a different header
+    /// in the output file, e.g. "CallRecorderExecutionTime" -> "Exec (ms)". Values are
+    /// still located using the column name. Columns with no entry use their own name.
+    #[serde(default)]
+    pub column_headers: HashMap<String, String>,
+
     /// The files to process.
     pub file_patterns: Vec<String>,
 
@@ -33,6 +89,41 @@ pub struct Profile {
     /// extracted KVPs or using a default regex to probe the message text itself.
     pub column_regexes: HashMap<String, String>,
 
+    /// A sparse map of Category -> Regex, used to override the built-in rules which
+    /// classify each line for the Category column. If the regex matches anywhere in
+    /// the line then the line is given that category.
+    #[serde(default)]
+    pub category_regexes: HashMap<String, String>,
+
+    /// Columns whose values are percent-encoded, e.g. "philip.daniels%40ex.com", and
+    /// which are decoded when they are written.
+    #[serde(default)]
+    pub url_decode: Vec<String>,
+
+    /// Columns whose values are whole numbers, such as "PID", which `--sort-by` and
+    /// `--then-by` sort numerically, so that 9 comes before 10 and 100.
+    #[serde(default)]
+    pub numeric_columns: Vec<String>,
+
+    /// The layout of the timestamp at the start of each line, for logs which do not use
+    /// the standard "YYYY-MM-DD HH:MM:SS.FFFFFFF" form. This is a strftime-like pattern
+    /// such as "%d/%m/%Y %H:%M:%S", see `TimestampFormat` for the supported specifiers.
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
+
+    /// The separator between the fields of the prologue, for logs which use something
+    /// other than the standard "|", e.g. "::". Whitespace around it is ignored.
+    #[serde(default)]
+    pub field_separator: Option<String>,
+
+    /// The bracketed words which are recognized as the log level, e.g. "[INFO_]". The
+    /// default profile has the built-in levels, and the levels of other profiles are
+    /// added to them, so "[INFO]" or "[CRITICAL]" can be recognized too. To use a
+    /// different set altogether, list it in a profile used with `--no-default-profile`.
+    /// The brackets are optional, but case matters.
+    #[serde(default)]
+    pub log_levels: Vec<String>,
+
     /// Filtering: Only show records whose LogDate is greater than or equal to this date.
     /// If not specified, all records back to the beginning of time will be shown.
     /// The format is the same as the LogDate: "YYYY-MM-DD HH:MM:SS". It will also accept
@@ -46,6 +137,11 @@ pub struct Profile {
     pub to: Option<String>,
 }
 
+/// Checks whether a column is one of the columns of the built-in default profile.
+pub fn is_default_column(column: &str) -> bool {
+    DEFAULT_COLUMNS.iter().any(|c| c.eq_ignore_ascii_case(column))
+}
+
 fn vec_has_entry(entry: &str, vec: &[String]) -> bool {
     vec.iter().any(|c| c.eq_ignore_ascii_case(entry))
 }
@@ -63,12 +159,20 @@ impl Profile {
     pub fn blank() -> Self {
         Profile {
             name: "blank".to_string(),
+            extends: None,
             quiet: None,
             max_message_length: None,
             columns: Vec::new(),
             alternate_column_names: HashMap::new(),
+            column_headers: HashMap::new(),
             file_patterns: Vec::new(),
             column_regexes: HashMap::new(),
+            category_regexes: HashMap::new(),
+            url_decode: Vec::new(),
+            numeric_columns: Vec::new(),
+            timestamp_format: None,
+            field_separator: None,
+            log_levels: Vec::new(),
             from: None,
             to: None
         }
@@ -99,6 +203,49 @@ impl Profile {
     {
         vec_add_entry(file_pattern, &mut self.file_patterns);
     }
+
+    /// Checks the profile for problems, returning a description of each one.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = self.regex_problems();
+
+        let mut seen = Vec::new();
+        for column_name in &self.columns {
+            if vec_has_entry(column_name, &seen) {
+                problems.push(format!("Profile '{}': column '{}' is specified more than once", self.name, column_name));
+            } else {
+                seen.push(column_name.clone());
+            }
+        }
+
+        problems
+    }
+
+    /// Checks that all the custom regexes and the timestamp format in the profile can be
+    /// compiled. They are compiled the same way as in the `Configuration`, which stops at
+    /// the first bad one, so this finds all of them.
+    pub fn regex_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for (column_name, pattern) in self.column_regexes.iter().sorted() {
+            if let Err(e) = RegexBuilder::new(pattern).case_insensitive(true).build() {
+                problems.push(format!("Profile '{}': the regex for column '{}' is invalid: {}", self.name, column_name, e));
+            }
+        }
+
+        for (category, pattern) in self.category_regexes.iter().sorted() {
+            if let Err(e) = BytesRegexBuilder::new(pattern).case_insensitive(true).build() {
+                problems.push(format!("Profile '{}': the regex for category '{}' is invalid: {}", self.name, category, e));
+            }
+        }
+
+        if let Some(ref pattern) = self.timestamp_format {
+            if let Err(e) = pattern.parse::<TimestampFormat>() {
+                problems.push(format!("Profile '{}': {}", self.name, e));
+            }
+        }
+
+        problems
+    }
 }
 
 impl Default for Profile {
@@ -108,36 +255,77 @@ impl Default for Profile {
         p.quiet = Some(false);
         p.max_message_length = Some(DEFAULT_MAX_MESSAGE_LENGTH);
 
-        p.add_column(LOG_DATE);
-        p.add_column(LOG_LEVEL);
-        p.add_column(LOG_SOURCE);
-        p.add_column("MachineName");
-        p.add_column("AppName");
-        p.add_column("PID");
-        p.add_column("TID");
-        p.add_column("SysRef");
-        p.add_column("Action");
-        p.add_column("Source");
-        p.add_column("CorrelationKey");
-        p.add_column("CallRecorderExecutionTime");
-        p.add_column("Http.RequestId");
-        p.add_column("Http.RequestQueryString");
-        p.add_column("Http.Request.Path");
-        p.add_column("UserName");
-        p.add_column("UserIdentity");
-        p.add_column(MESSAGE);
-
-        p.add_alternate_column("AppName", "ApplicationName");
-        p.add_alternate_column("Http.RequestId", "Owin.Request.Id");
-        p.add_alternate_column("Http.RequestQueryString", "Owin.Request.QueryString");
-        p.add_alternate_column("Http.Request.Path", "Owin.Request.Path");
+        for column in &DEFAULT_COLUMNS {
+            p.add_column(*column);
+        }
+
+        for (column, alternate_name) in &DEFAULT_ALTERNATE_COLUMNS {
+            p.add_alternate_column(column, *alternate_name);
+        }
+
+        p.numeric_columns = DEFAULT_NUMERIC_COLUMNS.iter().map(|c| c.to_string()).collect();
+        p.log_levels = LOG_LEVELS.iter().map(|level| String::from_utf8_lossy(level).into_owned()).collect();
 
         p
     }
 }
 
+/// The format of a configuration file, which is determined by its extension.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    #[default]
+    Json,
+    Toml,
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(ConfigFormat::Json),
+            "toml" => Ok(ConfigFormat::Toml),
+            _ => Err(format!("Unknown configuration format '{}', expected 'json' or 'toml'", s)),
+        }
+    }
+}
+
+impl ConfigFormat {
+    /// Gets the format of a configuration file from its extension. Anything other than
+    /// ".toml" is read as JSON5, as configuration files always have been.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Json,
+        }
+    }
+}
+
+/// The environment variable which names the configuration file, if `--config` is not used.
+pub const CONFIG_ENV_VAR: &str = "LPF_CONFIG";
+
+/// Gets the configuration file named by the `--config` argument or, failing that, by the
+/// LPF_CONFIG environment variable. Returns None if neither is given, in which case the
+/// file in the home directory is used.
+pub fn explicit_config_path(config_arg: &Option<PathBuf>, env_value: Option<OsString>) -> Option<PathBuf> {
+    config_arg.clone().or_else(|| env_value.filter(|value| !value.is_empty()).map(PathBuf::from))
+}
+
+/// Gets the configuration file in the home directory, which is either `~/.lpf.json` or
+/// `~/.lpf.toml`, whichever exists. If they both exist the JSON file is used, and if
+/// neither does then the (missing) JSON file is returned.
+pub fn config_file_path(home_dir: &Path) -> PathBuf {
+    let json_path = home_dir.join(".lpf.json");
+    let toml_path = home_dir.join(".lpf.toml");
+    if !json_path.exists() && toml_path.exists() {
+        toml_path
+    } else {
+        json_path
+    }
+}
+
 /// The `ProfileSet` is just a hash-map of Profile structs as loaded
-/// from the `~/.lpf.json` configuration file.
+/// from the `~/.lpf.json` or `~/.lpf.toml` configuration file.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ProfileSet {
     #[serde(flatten)]
@@ -156,6 +344,45 @@ impl Default for ProfileSet {
 }
 
 impl ProfileSet {
+    /// Parses the contents of a configuration file. The file is read as JSON5, so it may
+    /// contain comments and trailing commas, and any plain JSON file is also valid. The
+    /// error describes the problem and, where it is known, where it is.
+    pub fn from_json5(text: &str) -> Result<ProfileSet, String> {
+        json5::from_str(text).map_err(|e| match e {
+            json5::Error::Message { msg, location: Some(loc) } => format!("line {}, column {}: {}", loc.line, loc.column, msg),
+            json5::Error::Message { msg, location: None } => msg,
+        })
+    }
+
+    /// Parses the contents of a TOML configuration file, in which each profile is a table.
+    pub fn from_toml(text: &str) -> Result<ProfileSet, String> {
+        toml::from_str(text).map_err(|e| e.to_string().trim_end().to_string())
+    }
+
+    /// Formats the profiles as a configuration file in the specified format.
+    pub fn to_text(&self, format: ConfigFormat) -> Result<String, String> {
+        match format {
+            ConfigFormat::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::to_string_pretty(self).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Loads the configuration file, as TOML if it has a ".toml" extension and as JSON5
+    /// otherwise. A missing file is not an error, it just means the default configuration
+    /// is used. The error names the file and describes the problem.
+    pub fn load(path: &Path) -> Result<ProfileSet, String> {
+        let parse = match ConfigFormat::from_path(path) {
+            ConfigFormat::Json => ProfileSet::from_json5,
+            ConfigFormat::Toml => ProfileSet::from_toml,
+        };
+
+        match fs::read_to_string(path) {
+            Ok(text) => parse(&text).map_err(|msg| format!("Error in {}: {}", path.display(), msg)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(ProfileSet::default()),
+            Err(e) => Err(format!("Error reading {}: {}", path.display(), e)),
+        }
+    }
+
     pub fn insert(&mut self, profile: Profile) {
         self.profiles.insert(profile.name.clone(), profile);
     }
@@ -163,6 +390,52 @@ impl ProfileSet {
     pub fn get(&self, profile_name: &str) -> Option<&Profile> {
         self.profiles.get(profile_name)
     }
+
+    /// Gets the named profile together with all the profiles it extends, with the most
+    /// distant ancestor first, i.e. in the order they should be applied. Returns an error
+    /// if any of them does not exist, or if the chain of `extends` goes round in a cycle.
+    pub fn get_with_ancestors(&self, profile_name: &str) -> Result<Vec<&Profile>, String> {
+        let mut names: Vec<&str> = Vec::new();
+        let mut chain = Vec::new();
+        let mut name = profile_name;
+
+        loop {
+            if names.contains(&name) {
+                names.push(name);
+                return Err(format!("Profile '{}' extends itself: {}", profile_name, names.join(" -> ")));
+            }
+
+            let profile = match self.get(name) {
+                Some(profile) => profile,
+                None if name == profile_name => return Err(format!("Profile '{}' does not exist", name)),
+                None => return Err(format!("Profile '{}' extends profile '{}', which does not exist", names[names.len() - 1], name)),
+            };
+
+            names.push(name);
+            chain.push(profile);
+            match profile.extends {
+                Some(ref parent) => name = parent,
+                None => break,
+            }
+        }
+
+        chain.reverse();
+        Ok(chain)
+    }
+
+    /// Checks every profile for problems, returning a description of each one.
+    pub fn validate(&self) -> Vec<String> {
+        self.profiles.iter()
+            .sorted_by(|a, b| a.0.cmp(b.0))
+            .flat_map(|(name, profile)| {
+                let mut problems = profile.validate();
+                if let Err(problem) = self.get_with_ancestors(name) {
+                    problems.push(problem);
+                }
+                problems
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -262,3 +535,357 @@ mod configuration_tests {
         assert_eq!(p.alternate_column_names["main"], vec!["alpha".to_string()]);
     }
 }
+
+#[cfg(test)]
+mod default_profile_tests {
+    use super::*;
+
+    #[test]
+    pub fn has_the_documented_columns_in_order() {
+        let p = Profile::default();
+        assert_eq!(p.columns, vec![
+            "LogDate", "LogLevel", "FileName", "MachineName", "AppName", "PID", "TID", "SysRef",
+            "Action", "Source", "CorrelationKey", "CallRecorderExecutionTime", "Http.RequestId",
+            "Http.RequestQueryString", "Http.Request.Path", "UserName", "UserIdentity", "Message",
+        ]);
+    }
+
+    #[test]
+    pub fn has_the_documented_alternate_column_names() {
+        let p = Profile::default();
+        assert_eq!(p.alternate_column_names.len(), DEFAULT_ALTERNATE_COLUMNS.len());
+        assert_eq!(p.alternate_column_names["AppName"], vec!["ApplicationName"]);
+        assert_eq!(p.alternate_column_names["Http.RequestId"], vec!["Owin.Request.Id"]);
+        assert_eq!(p.alternate_column_names["Http.RequestQueryString"], vec!["Owin.Request.QueryString"]);
+        assert_eq!(p.alternate_column_names["Http.Request.Path"], vec!["Owin.Request.Path"]);
+    }
+
+    #[test]
+    pub fn has_the_built_in_log_levels() {
+        let p = Profile::default();
+        assert_eq!(p.log_levels.len(), LOG_LEVELS.len());
+        assert_eq!(p.log_levels[0], "[INFO_]");
+        assert_eq!(p.log_levels[LOG_LEVELS.len() - 1], "[DEBG1]");
+    }
+}
+
+#[cfg(test)]
+mod from_json5_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_dumped_config_returns_same_profiles() {
+        let json = serde_json::to_string_pretty(&ProfileSet::default()).unwrap();
+        let profiles = ProfileSet::from_json5(&json).unwrap();
+
+        let default = ProfileSet::default();
+        let expected = default.get(DEFAULT_PROFILE_NAME).unwrap();
+        let actual = profiles.get(DEFAULT_PROFILE_NAME).unwrap();
+        assert_eq!(actual.columns, expected.columns);
+        assert_eq!(actual.max_message_length, expected.max_message_length);
+        assert_eq!(actual.column_regexes, expected.column_regexes);
+    }
+
+    #[test]
+    pub fn for_comments_and_trailing_commas_returns_profiles() {
+        let mut json = serde_json::to_string_pretty(&ProfileSet::default()).unwrap();
+        json = json.replacen("{", "{\n  // Explain why the profile is here.\n", 1);
+        let last_brace = json.rfind('}').unwrap();
+        json.insert(last_brace, ',');
+
+        let profiles = ProfileSet::from_json5(&json).unwrap();
+        assert!(profiles.get(DEFAULT_PROFILE_NAME).is_some());
+    }
+
+    #[test]
+    pub fn for_syntax_error_returns_location() {
+        let msg = ProfileSet::from_json5("{\n  \"p\": {\n    \"name\" \"p\"\n  }\n}").unwrap_err();
+        assert!(msg.starts_with("line 3, column"), "{}", msg);
+    }
+
+    #[test]
+    pub fn for_missing_field_returns_error() {
+        let msg = ProfileSet::from_json5("{ \"p\": { \"name\": \"p\" } }").unwrap_err();
+        assert!(msg.contains("missing field"), "{}", msg);
+    }
+}
+
+#[cfg(test)]
+mod explicit_config_path_tests {
+    use super::*;
+
+    #[test]
+    pub fn prefers_argument_then_environment() {
+        let arg = Some(PathBuf::from("project.lpf.json"));
+        let env = Some(OsString::from("/etc/lpf.json"));
+
+        assert_eq!(explicit_config_path(&arg, env.clone()), arg);
+        assert_eq!(explicit_config_path(&None, env), Some(PathBuf::from("/etc/lpf.json")));
+        assert_eq!(explicit_config_path(&None, Some(OsString::new())), None);
+        assert_eq!(explicit_config_path(&None, None), None);
+    }
+}
+
+#[cfg(test)]
+mod load_tests {
+    use super::*;
+    use crate::output::test_utils::make_temp_dir;
+
+    #[test]
+    pub fn for_missing_file_returns_default() {
+        let profiles = ProfileSet::load(&make_temp_dir("missing_config").join("config.json")).unwrap();
+        assert!(profiles.get(DEFAULT_PROFILE_NAME).is_some());
+    }
+
+    #[test]
+    pub fn for_malformed_file_returns_error_naming_file() {
+        let path = make_temp_dir("bad_config").join("config.json");
+        fs::write(&path, "{ \"p\": ").unwrap();
+
+        let msg = ProfileSet::load(&path).unwrap_err();
+        assert!(msg.starts_with(&format!("Error in {}: line 1", path.display())), "{}", msg);
+    }
+
+    #[test]
+    pub fn for_directory_returns_error_naming_it() {
+        let path = make_temp_dir("config_dir");
+        let msg = ProfileSet::load(&path).unwrap_err();
+        assert!(msg.starts_with(&format!("Error reading {}", path.display())), "{}", msg);
+    }
+}
+
+#[cfg(test)]
+mod get_with_ancestors_tests {
+    use super::*;
+
+    fn make_profile(name: &str, extends: Option<&str>) -> Profile {
+        let mut p = Profile::blank();
+        p.name = name.to_string();
+        p.extends = extends.map(|e| e.to_string());
+        p
+    }
+
+    fn make_profiles(profiles: Vec<Profile>) -> ProfileSet {
+        let mut set = ProfileSet::default();
+        for p in profiles {
+            set.insert(p);
+        }
+        set
+    }
+
+    #[test]
+    pub fn for_profile_without_extends_returns_just_the_profile() {
+        let profiles = make_profiles(vec![make_profile("a", None)]);
+        let names: Vec<_> = profiles.get_with_ancestors("a").unwrap().iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["a"]);
+    }
+
+    #[test]
+    pub fn for_two_levels_returns_most_distant_ancestor_first() {
+        let profiles = make_profiles(vec![make_profile("base", None), make_profile("mid", Some("base")), make_profile("svc", Some("mid"))]);
+        let names: Vec<_> = profiles.get_with_ancestors("svc").unwrap().iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["base", "mid", "svc"]);
+    }
+
+    #[test]
+    pub fn for_cycle_returns_error() {
+        let profiles = make_profiles(vec![make_profile("a", Some("b")), make_profile("b", Some("a"))]);
+        let msg = profiles.get_with_ancestors("a").unwrap_err();
+        assert_eq!(msg, "Profile 'a' extends itself: a -> b -> a");
+    }
+
+    #[test]
+    pub fn for_missing_parent_returns_error() {
+        let profiles = make_profiles(vec![make_profile("a", Some("nope"))]);
+        let msg = profiles.get_with_ancestors("a").unwrap_err();
+        assert_eq!(msg, "Profile 'a' extends profile 'nope', which does not exist");
+    }
+
+    #[test]
+    pub fn validate_reports_cycle() {
+        let profiles = make_profiles(vec![make_profile("a", Some("a"))]);
+        assert_eq!(profiles.validate(), vec!["Profile 'a' extends itself: a -> a".to_string()]);
+    }
+}
+
+#[cfg(test)]
+mod validate_tests {
+    use super::*;
+
+    #[test]
+    pub fn default_profile_is_valid() {
+        assert!(ProfileSet::default().validate().is_empty());
+    }
+
+    #[test]
+    pub fn for_invalid_column_regex_returns_descriptive_error() {
+        let mut p = Profile::blank();
+        p.name = "broken".to_string();
+        p.column_regexes.insert("SysRef".to_string(), "(".to_string());
+
+        let problems = p.validate();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("Profile 'broken'"));
+        assert!(problems[0].contains("column 'SysRef' is invalid"));
+        assert_eq!(p.regex_problems(), problems);
+    }
+
+    #[test]
+    pub fn for_invalid_category_regex_returns_error() {
+        let mut p = Profile::blank();
+        p.category_regexes.insert("error".to_string(), "[a-".to_string());
+
+        let problems = p.validate();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("category 'error' is invalid"));
+    }
+
+    #[test]
+    pub fn for_invalid_timestamp_format_returns_error() {
+        let mut p = Profile::blank();
+        p.timestamp_format = Some("%d/%m/%Y %q".to_string());
+
+        let problems = p.validate();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("Unsupported specifier '%q' in timestamp format"), "{}", problems[0]);
+        assert_eq!(p.regex_problems(), problems);
+    }
+
+    #[test]
+    pub fn for_duplicate_columns_returns_error() {
+        let mut p = Profile::blank();
+        p.columns = vec!["SysRef".to_string(), "Message".to_string(), "sysref".to_string()];
+
+        let problems = p.validate();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("column 'sysref' is specified more than once"));
+        assert!(p.regex_problems().is_empty());
+    }
+
+    #[test]
+    pub fn profile_set_reports_problems_from_all_profiles() {
+        let mut profiles = ProfileSet::default();
+        for name in &["one", "two"] {
+            let mut p = Profile::blank();
+            p.name = name.to_string();
+            p.column_regexes.insert("SysRef".to_string(), "(".to_string());
+            profiles.insert(p);
+        }
+
+        let problems = profiles.validate();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("Profile 'one'"));
+        assert!(problems[1].contains("Profile 'two'"));
+    }
+}
+
+#[cfg(test)]
+mod toml_tests {
+    use super::*;
+    use crate::arguments::Arguments;
+    use crate::configuration::{get_config, Configuration};
+    use crate::output::test_utils::make_temp_dir;
+
+    const JSON: &str = r#"{
+        "work": {
+            "name": "work",
+            "quiet": true,
+            "max_message_length": 500,
+            "columns": ["LogDate", "SysRef", "Message"],
+            "alternate_column_names": { "SysRef": ["Ref", "QuoteRef"] },
+            "column_headers": { "SysRef": "Quote" },
+            "file_patterns": ["app*.log"],
+            "column_regexes": {},
+            "from": "2018-09-26",
+            "to": null
+        }
+    }"#;
+
+    const TOML: &str = r#"
+        [work]
+        name = "work"
+        quiet = true
+        max_message_length = 500
+        columns = ["LogDate", "SysRef", "Message"]
+        file_patterns = ["app*.log"]
+        from = "2018-09-26"
+
+        [work.alternate_column_names]
+        SysRef = ["Ref", "QuoteRef"]
+
+        [work.column_headers]
+        SysRef = "Quote"
+
+        [work.column_regexes]
+    "#;
+
+    fn make_config(profiles: &ProfileSet) -> Configuration {
+        let args = Arguments { profile: "work".to_string(), no_default_profile: true, .. Arguments::default() };
+        get_config(profiles, &args).unwrap()
+    }
+
+    fn assert_same_config(a: &Configuration, b: &Configuration) {
+        assert_eq!(a.name, b.name);
+        assert_eq!(a.quiet, b.quiet);
+        assert_eq!(a.max_message_length, b.max_message_length);
+        assert_eq!(a.columns, b.columns);
+        assert_eq!(a.alternate_column_names, b.alternate_column_names);
+        assert_eq!(a.column_headers, b.column_headers);
+        assert_eq!(a.file_patterns, b.file_patterns);
+        assert_eq!(a.from, b.from);
+    }
+
+    #[test]
+    pub fn config_format_from_path_uses_extension() {
+        assert_eq!(ConfigFormat::from_path(Path::new("/home/me/.lpf.toml")), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::from_path(Path::new("/home/me/.lpf.TOML")), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::from_path(Path::new("/home/me/.lpf.json")), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_path(Path::new("config")), ConfigFormat::Json);
+    }
+
+    #[test]
+    pub fn toml_profile_gives_same_configuration_as_json() {
+        let from_json = ProfileSet::from_json5(JSON).unwrap();
+        let from_toml = ProfileSet::from_toml(TOML).unwrap();
+
+        let config = make_config(&from_toml);
+        assert_eq!(config.columns, vec!["LogDate", "SysRef", "Message"]);
+        assert_same_config(&config, &make_config(&from_json));
+    }
+
+    #[test]
+    pub fn dumped_toml_round_trips() {
+        let text = ProfileSet::default().to_text(ConfigFormat::Toml).unwrap();
+        let profiles = ProfileSet::from_toml(&text).unwrap();
+
+        let args = Arguments::default();
+        assert_same_config(&get_config(&profiles, &args).unwrap(), &get_config(&ProfileSet::default(), &args).unwrap());
+    }
+
+    #[test]
+    pub fn from_toml_for_malformed_text_returns_error() {
+        let msg = ProfileSet::from_toml("[work\nname = 1").unwrap_err();
+        assert!(msg.contains("line 1"), "{}", msg);
+    }
+
+    #[test]
+    pub fn load_reads_toml_file_by_extension() {
+        let path = make_temp_dir("load_toml").join("config.toml");
+        fs::write(&path, TOML).unwrap();
+
+        assert!(ProfileSet::load(&path).unwrap().get("work").is_some());
+    }
+
+    #[test]
+    pub fn config_file_path_prefers_json_then_toml() {
+        let dir = make_temp_dir("config_path");
+        let json_path = dir.join(".lpf.json");
+        let toml_path = dir.join(".lpf.toml");
+
+        assert_eq!(config_file_path(&dir), json_path);
+        fs::write(&toml_path, "").unwrap();
+        assert_eq!(config_file_path(&dir), toml_path);
+        fs::write(&json_path, "{}").unwrap();
+        assert_eq!(config_file_path(&dir), json_path);
+    }
+}
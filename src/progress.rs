@@ -0,0 +1,68 @@
+//! This module reports progress while the files are being parsed. Unless running quietly,
+//! a progress bar is drawn on stderr which advances as each file is parsed. The progress
+//! bar is only drawn if stderr is a terminal, otherwise the informational messages are
+//! printed in the normal way. It also keeps track of the files which were skipped, so
+//! that they can be listed in the summary.
+
+use std::sync::Mutex;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use crate::configuration::Configuration;
+use crate::inputs::{InputFile, Inputs};
+use crate::print_status;
+
+pub struct Progress {
+    bar: ProgressBar,
+    quiet: bool,
+    visible: bool,
+    skipped_files: Mutex<Vec<String>>,
+}
+
+impl Progress {
+    /// Creates the progress bar. Its length is the total size of the input files.
+    pub fn new(config: &Configuration, inputs: &Inputs) -> Self {
+        let target = if config.quiet { ProgressDrawTarget::hidden() } else { ProgressDrawTarget::stderr() };
+        let visible = !target.is_hidden();
+        let bar = ProgressBar::with_draw_target(inputs.total_bytes(), target);
+        bar.set_style(ProgressStyle::default_bar().template("{wide_bar} {bytes}/{total_bytes} {elapsed_precise}"));
+
+        Progress { bar, quiet: config.quiet, visible, skipped_files: Mutex::new(Vec::new()) }
+    }
+
+    /// Called when a file has been parsed. The message is printed above the progress bar.
+    /// This is safe to call from multiple threads.
+    pub fn file_parsed(&self, config: &Configuration, f: &InputFile, message: &str) {
+        if self.visible {
+            self.bar.println(message);
+        } else if !self.quiet {
+            print_status(config, message);
+        }
+
+        self.bar.inc(f.length as u64);
+    }
+
+    /// Called when a file is skipped rather than parsed. The warning is always printed,
+    /// even when running quietly. This is safe to call from multiple threads.
+    pub fn file_skipped(&self, f: &InputFile, reason: &str) {
+        let message = format!("Skipping {}, {}.", f.path_as_string, reason);
+        if self.visible {
+            self.bar.println(message);
+        } else {
+            eprintln!("{}", message);
+        }
+
+        self.skipped_files.lock().unwrap().push(f.path_as_string.clone());
+        self.bar.inc(f.length as u64);
+    }
+
+    /// Gets the files which were skipped, sorted by name.
+    pub fn skipped_files(&self) -> Vec<String> {
+        let mut skipped_files = self.skipped_files.lock().unwrap().clone();
+        skipped_files.sort();
+        skipped_files
+    }
+
+    /// Removes the progress bar, ready for the summary to be printed.
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
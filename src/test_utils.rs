@@ -0,0 +1,11 @@
+use std::path::PathBuf;
+
+/// Creates a new, empty, directory under the system temp directory for a test to write its files to.
+pub fn make_temp_dir(test_name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lfp-{}-{}", test_name, std::process::id()));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
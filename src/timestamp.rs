@@ -0,0 +1,235 @@
+//! This module handles custom timestamp layouts, for logs which do not use our
+//! standard "YYYY-MM-DD HH:MM:SS.FFFFFFF" form, for example "DD/MM/YYYY HH:MM:SS".
+//! The layout is expressed as a simple strftime-like pattern such as "%d/%m/%Y %H:%M:%S".
+//! Timestamps are converted to the standard form when they are extracted, so that the
+//! output is consistent and the log dates still sort correctly as strings.
+
+use std::borrow::Cow;
+use std::str::FromStr;
+use crate::byte_extensions::ByteExtensions;
+use crate::parsed_line::ParseError;
+
+/// The parts of a timestamp which can appear in a pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Fraction,
+}
+
+impl Field {
+    /// The number of digits expected for the field.
+    fn width(self) -> usize {
+        match self {
+            Field::Year => 4,
+            Field::Fraction => 7,
+            _ => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Item {
+    Digits(Field),
+    Literal(u8),
+}
+
+impl Item {
+    fn width(self) -> usize {
+        match self {
+            Item::Digits(field) => field.width(),
+            Item::Literal(_) => 1,
+        }
+    }
+}
+
+/// A compiled timestamp layout. The supported specifiers are %Y (4 digits), %m, %d, %H,
+/// %M, %S (2 digits each) and %f (7 digits of fractional seconds). Any other character
+/// must appear literally, and "%%" is a literal '%'. The fractional seconds are optional,
+/// but all the other specifiers are required.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimestampFormat {
+    items: Vec<Item>,
+    length: usize,
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let mut items = Vec::new();
+        let mut bytes = pattern.bytes();
+
+        while let Some(b) = bytes.next() {
+            if b != b'%' {
+                items.push(Item::Literal(b));
+                continue;
+            }
+
+            let item = match bytes.next() {
+                Some(b'Y') => Item::Digits(Field::Year),
+                Some(b'm') => Item::Digits(Field::Month),
+                Some(b'd') => Item::Digits(Field::Day),
+                Some(b'H') => Item::Digits(Field::Hour),
+                Some(b'M') => Item::Digits(Field::Minute),
+                Some(b'S') => Item::Digits(Field::Second),
+                Some(b'f') => Item::Digits(Field::Fraction),
+                Some(b'%') => Item::Literal(b'%'),
+                Some(other) => return Err(format!("Unsupported specifier '%{}' in timestamp format '{}'", other as char, pattern)),
+                None => return Err(format!("Timestamp format '{}' ends with a lone '%'", pattern)),
+            };
+            items.push(item);
+        }
+
+        const REQUIRED: [(Field, &str); 6] = [
+            (Field::Year, "%Y"), (Field::Month, "%m"), (Field::Day, "%d"),
+            (Field::Hour, "%H"), (Field::Minute, "%M"), (Field::Second, "%S")
+        ];
+
+        for &(field, specifier) in &REQUIRED {
+            match items.iter().filter(|&&item| item == Item::Digits(field)).count() {
+                1 => {},
+                0 => return Err(format!("Timestamp format '{}' does not contain {}", pattern, specifier)),
+                _ => return Err(format!("Timestamp format '{}' contains {} more than once", pattern, specifier)),
+            }
+        }
+
+        let length = items.iter().map(|item| item.width()).sum();
+        Ok(TimestampFormat { items, length })
+    }
+}
+
+impl TimestampFormat {
+    /// Extracts the timestamp from the start of the line, returning it in the standard
+    /// "YYYY-MM-DD HH:MM:SS.FFFFFFF" form (with zero fractional seconds if the layout has
+    /// none), and the remainder of the line. If `lenient_fraction` is true, the fractional seconds
+    /// are not required to be digits.
+    pub fn extract<'l>(&self, line: &'l [u8], lenient_fraction: bool) -> Result<(Cow<'l, [u8]>, &'l [u8]), ParseError> {
+        if line.len() < self.length {
+            return Err(ParseError::ShortLine { min_length: self.length });
+        }
+
+        let mut parts: [&[u8]; 7] = [b""; 7];
+        let mut idx = 0;
+        for &item in &self.items {
+            match item {
+                Item::Digits(field) => {
+                    let slice = &line[idx..idx + field.width()];
+                    let check_digits = !(lenient_fraction && field == Field::Fraction);
+                    if check_digits {
+                        if let Some(offset) = slice.iter().position(|c| !c.is_decimal_digit()) {
+                            return Err(ParseError::ExpectedDigit { index: idx + offset, found: slice[offset] });
+                        }
+                    }
+                    parts[field as usize] = slice;
+                },
+                Item::Literal(expected) => {
+                    if line[idx] != expected {
+                        return Err(ParseError::ExpectedChar { index: idx, expected, found: line[idx] });
+                    }
+                }
+            }
+
+            idx += item.width();
+        }
+
+        let mut canonical = Vec::with_capacity(27);
+        canonical.extend_from_slice(parts[Field::Year as usize]);
+        canonical.push(b'-');
+        canonical.extend_from_slice(parts[Field::Month as usize]);
+        canonical.push(b'-');
+        canonical.extend_from_slice(parts[Field::Day as usize]);
+        canonical.push(b' ');
+        canonical.extend_from_slice(parts[Field::Hour as usize]);
+        canonical.push(b':');
+        canonical.extend_from_slice(parts[Field::Minute as usize]);
+        canonical.push(b':');
+        canonical.extend_from_slice(parts[Field::Second as usize]);
+        canonical.push(b'.');
+        let fraction = parts[Field::Fraction as usize];
+        canonical.extend_from_slice(if fraction.is_empty() { b"0000000" } else { fraction });
+
+        Ok((Cow::Owned(canonical), &line[self.length..]))
+    }
+}
+
+#[cfg(test)]
+mod timestamp_format_tests {
+    use super::*;
+
+    #[test]
+    pub fn for_unsupported_specifier_returns_error() {
+        let msg = "%d/%m/%Y %H:%M:%S %Z".parse::<TimestampFormat>().expect_err("Parse should fail");
+        assert!(msg.contains("'%Z'"));
+    }
+
+    #[test]
+    pub fn for_missing_specifier_returns_error() {
+        let msg = "%d/%m/%Y %H:%M".parse::<TimestampFormat>().expect_err("Parse should fail");
+        assert!(msg.contains("does not contain %S"));
+    }
+
+    #[test]
+    pub fn for_repeated_specifier_returns_error() {
+        let msg = "%d/%m/%Y %H:%M:%S %d".parse::<TimestampFormat>().expect_err("Parse should fail");
+        assert!(msg.contains("%d more than once"));
+    }
+
+    #[test]
+    pub fn for_day_first_layout_returns_canonical_form() {
+        let format: TimestampFormat = "%d/%m/%Y %H:%M:%S".parse().unwrap();
+        let (log_date, remainder) = format.extract(b"26/09/2018 12:34:56 | Message", false).expect("Extract should succeed");
+        assert_eq!(log_date.as_ref(), b"2018-09-26 12:34:56.0000000");
+        assert_eq!(remainder, b" | Message");
+    }
+
+    #[test]
+    pub fn for_layout_with_fraction_returns_canonical_form() {
+        let format: TimestampFormat = "%d/%m/%Y %H:%M:%S,%f".parse().unwrap();
+        let (log_date, _) = format.extract(b"26/09/2018 12:34:56,7654321 | Message", false).expect("Extract should succeed");
+        assert_eq!(log_date.as_ref(), b"2018-09-26 12:34:56.7654321");
+    }
+
+    #[test]
+    pub fn canonical_form_sorts_chronologically() {
+        let format: TimestampFormat = "%d/%m/%Y %H:%M:%S".parse().unwrap();
+        let (earlier, _) = format.extract(b"31/12/2017 23:59:59", false).unwrap();
+        let (later, _) = format.extract(b"01/01/2018 00:00:00", false).unwrap();
+        assert!(earlier < later);
+    }
+
+    #[test]
+    pub fn for_bad_digit_returns_error() {
+        let format: TimestampFormat = "%d/%m/%Y %H:%M:%S".parse().unwrap();
+        let error = format.extract(b"26/0x/2018 12:34:56", false).expect_err("Extract should fail");
+        assert_eq!(error, ParseError::ExpectedDigit { index: 4, found: b'x' });
+        assert!(error.to_string().contains("Character 4"));
+    }
+
+    #[test]
+    pub fn for_bad_separator_returns_error() {
+        let format: TimestampFormat = "%d/%m/%Y %H:%M:%S".parse().unwrap();
+        let error = format.extract(b"26-09-2018 12:34:56", false).expect_err("Extract should fail");
+        assert_eq!(error, ParseError::ExpectedChar { index: 2, expected: b'/', found: b'-' });
+        assert!(error.to_string().contains("Character 2"));
+    }
+
+    #[test]
+    pub fn for_short_line_returns_error() {
+        let format: TimestampFormat = "%d/%m/%Y %H:%M:%S".parse().unwrap();
+        let error = format.extract(b"26/09/2018", false).expect_err("Extract should fail");
+        assert_eq!(error, ParseError::ShortLine { min_length: 19 });
+        assert!(error.to_string().contains("less than 19 characters"));
+    }
+
+    #[test]
+    pub fn for_bad_fraction_digit_and_lenient_returns_canonical_form() {
+        let format: TimestampFormat = "%d/%m/%Y %H:%M:%S.%f".parse().unwrap();
+        let (log_date, _) = format.extract(b"26/09/2018 12:34:56.76x4321", true).expect("Extract should succeed");
+        assert_eq!(log_date.as_ref(), b"2018-09-26 12:34:56.76x4321");
+    }
+}
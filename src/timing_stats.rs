@@ -0,0 +1,208 @@
+//! This module implements `--timing-stats`, which summarises the execution times recorded
+//! in the "CallRecorderExecutionTime" KVP, optionally broken down by another column.
+
+use std::collections::HashMap;
+use crate::configuration::Configuration;
+use crate::grouping::MISSING_KEY;
+use crate::output::get_output_value;
+use crate::parsed_line::ParseLineResult;
+
+/// The KVP which holds the execution time of a call, in milliseconds.
+pub const EXECUTION_TIME_KEY: &[u8] = b"CallRecorderExecutionTime";
+
+/// The name of the only group when the timings are not broken down by a column.
+const ALL_KEY: &str = "(all)";
+
+/// Summary statistics of the execution times in one group. Percentiles use the
+/// nearest-rank method, so they are always one of the recorded times.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimingStats {
+    pub group: String,
+    pub count: usize,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+}
+
+impl TimingStats {
+    /// Calculates the statistics of a non-empty set of times.
+    fn new(group: String, mut times: Vec<u64>) -> Self {
+        times.sort_unstable();
+        let sum: u64 = times.iter().sum();
+
+        TimingStats {
+            group,
+            count: times.len(),
+            min: times[0],
+            max: times[times.len() - 1],
+            mean: sum as f64 / times.len() as f64,
+            p50: percentile(&times, 50),
+            p95: percentile(&times, 95),
+            p99: percentile(&times, 99),
+        }
+    }
+}
+
+/// Gets the `pct` percentile of sorted, non-empty times, using the nearest-rank method.
+fn percentile(sorted_times: &[u64], pct: usize) -> u64 {
+    let rank = (pct * sorted_times.len()).div_ceil(100);
+    sorted_times[rank.max(1) - 1]
+}
+
+/// Parses an execution time. Anything that is not a whole number is ignored.
+fn parse_time(value: &[u8]) -> Option<u64> {
+    std::str::from_utf8(value).ok().and_then(|s| s.trim().parse::<u64>().ok())
+}
+
+/// Collects the execution times of the successfully parsed lines, grouped by the
+/// `timing_stats_by` column if there is one. Lines without a numeric time are skipped.
+/// Nothing is collected unless timing stats were asked for. The times of different sets
+/// of lines can be combined before they are summarised.
+pub fn collect_execution_times(config: &Configuration, results: &[ParseLineResult]) -> HashMap<String, Vec<u64>> {
+    if !config.timing_stats {
+        return HashMap::new();
+    }
+
+    let mut times: HashMap<&[u8], Vec<u64>> = HashMap::new();
+    for line in results.iter().filter_map(|r| r.as_ref().ok()) {
+        let time = match line.kvps.get_value(EXECUTION_TIME_KEY).and_then(|v| parse_time(v)) {
+            Some(time) => time,
+            None => continue,
+        };
+
+        let group = match config.timing_stats_by {
+            Some(ref column) => match get_output_value(config, line, column) {
+                b"" => MISSING_KEY.as_bytes(),
+                value => value,
+            },
+            None => ALL_KEY.as_bytes(),
+        };
+
+        times.entry(group).or_default().push(time);
+    }
+
+    times.into_iter()
+        .map(|(group, times)| (String::from_utf8_lossy(group).into_owned(), times))
+        .collect()
+}
+
+/// Summarises the execution times of each group, busiest group first.
+pub fn summarise_execution_times(times: &HashMap<String, Vec<u64>>) -> Vec<TimingStats> {
+    let mut stats: Vec<_> = times.iter()
+        .map(|(group, times)| TimingStats::new(group.clone(), times.clone()))
+        .collect();
+    stats.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.group.cmp(&b.group)));
+    stats
+}
+
+/// Formats the statistics as a table, one line per group, with a header line.
+pub fn format_timing_stats(config: &Configuration, stats: &[TimingStats]) -> Vec<String> {
+    let group_header = config.timing_stats_by.as_ref().map_or("Group", |column| config.column_header(column));
+    let width = stats.iter().map(|s| s.group.len()).chain(std::iter::once(group_header.len())).max().unwrap_or(0);
+
+    let mut lines = vec![format!(
+        "{:<width$}  {:>8}  {:>8}  {:>8}  {:>10}  {:>8}  {:>8}  {:>8}",
+        group_header, "Count", "Min", "Max", "Mean", "P50", "P95", "P99", width = width
+    )];
+
+    lines.extend(stats.iter().map(|s| format!(
+        "{:<width$}  {:>8}  {:>8}  {:>8}  {:>10.1}  {:>8}  {:>8}  {:>8}",
+        s.group, s.count, s.min, s.max, s.mean, s.p50, s.p95, s.p99, width = width
+    )));
+
+    lines
+}
+
+#[cfg(test)]
+mod timing_stats_tests {
+    use super::*;
+    use crate::arguments::Arguments;
+    use crate::configuration::get_config;
+    use crate::parsed_line::ParsedLine;
+    use crate::profiles::ProfileSet;
+
+    fn make_config(timing_stats_by: Option<&str>) -> Configuration {
+        let args = Arguments {
+            no_default_profile: true,
+            timing_stats: true,
+            timing_stats_by: timing_stats_by.map(|s| s.to_string()),
+            .. Arguments::default()
+        };
+        get_config(&ProfileSet::default(), &args).unwrap()
+    }
+
+    fn parse(lines: &[&'static str]) -> Vec<ParseLineResult<'static>> {
+        lines.iter().map(|line| ParsedLine::parse(line.as_bytes())).collect()
+    }
+
+    #[test]
+    pub fn percentile_uses_nearest_rank() {
+        let times: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&times, 50), 50);
+        assert_eq!(percentile(&times, 95), 95);
+        assert_eq!(percentile(&times, 99), 99);
+        assert_eq!(percentile(&[7], 99), 7);
+        assert_eq!(percentile(&[1, 2, 3], 50), 2);
+    }
+
+    #[test]
+    pub fn summarises_numeric_times_and_skips_the_rest() {
+        let config = make_config(None);
+        let results = parse(&[
+            "2018-09-26 12:34:56.1000000 | A CallRecorderExecutionTime=10",
+            "2018-09-26 12:34:56.2000000 | B CallRecorderExecutionTime=30",
+            "2018-09-26 12:34:56.3000000 | C CallRecorderExecutionTime=fast",
+            "2018-09-26 12:34:56.4000000 | D",
+            "not a log line",
+            "2018-09-26 12:34:56.5000000 | E CallRecorderExecutionTime=20",
+        ]);
+
+        let stats = summarise_execution_times(&collect_execution_times(&config, &results));
+        assert_eq!(stats, vec![TimingStats {
+            group: "(all)".to_string(),
+            count: 3,
+            min: 10,
+            max: 30,
+            mean: 20.0,
+            p50: 20,
+            p95: 30,
+            p99: 30,
+        }]);
+    }
+
+    #[test]
+    pub fn groups_by_column_busiest_first() {
+        let config = make_config(Some("Action"));
+        let results = parse(&[
+            "2018-09-26 12:34:56.1000000 | A Action=Save CallRecorderExecutionTime=10",
+            "2018-09-26 12:34:56.2000000 | B Action=Load CallRecorderExecutionTime=5",
+            "2018-09-26 12:34:56.3000000 | C Action=Load CallRecorderExecutionTime=7",
+            "2018-09-26 12:34:56.4000000 | D CallRecorderExecutionTime=1",
+        ]);
+
+        let stats = summarise_execution_times(&collect_execution_times(&config, &results));
+        let groups: Vec<_> = stats.iter().map(|s| (s.group.as_str(), s.count)).collect();
+        assert_eq!(groups, vec![("Load", 2), ("(none)", 1), ("Save", 1)]);
+    }
+
+    #[test]
+    pub fn without_timing_stats_collects_nothing() {
+        let config = get_config(&ProfileSet::default(), &Arguments::default()).unwrap();
+        let results = parse(&["2018-09-26 12:34:56.1000000 | A CallRecorderExecutionTime=10"]);
+        assert!(summarise_execution_times(&collect_execution_times(&config, &results)).is_empty());
+    }
+
+    #[test]
+    pub fn format_aligns_columns_under_header() {
+        let config = make_config(Some("Action"));
+        let stats = vec![TimingStats { group: "Save".to_string(), count: 1, min: 2, max: 3, mean: 2.5, p50: 2, p95: 3, p99: 3 }];
+        let lines = format_timing_stats(&config, &stats);
+        assert_eq!(lines, vec![
+            "Action     Count       Min       Max        Mean       P50       P95       P99",
+            "Save           1         2         3         2.5         2         3         3",
+        ]);
+    }
+}